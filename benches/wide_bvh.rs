@@ -0,0 +1,105 @@
+//! Compares binary and wide BVH traversal on the same scene. Run with
+//! `cargo bench --bench wide_bvh`.
+
+extern crate luminary;
+
+use std::f64;
+use std::hint::black_box;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use luminary::accelerators::bvh::{BvhAccel, SplitMethod};
+use luminary::accelerators::wide_bvh::{Bvh4Accel, Bvh8Accel};
+use luminary::core::medium::Medium;
+use luminary::core::primitive::{GeometricPrimitive, Primitive};
+use luminary::core::ray::Ray;
+use luminary::core::transform::Transform;
+use luminary::core::utils;
+use luminary::shapes::sphere::Sphere;
+
+use luminary::core::Point3f;
+use luminary::core::Vector3f;
+
+const N_PRIMITIVES: usize = 100_000;
+const N_RAYS: usize = 200_000;
+const N_RUNS: usize = 5;
+
+fn random(i: usize, k: f64) -> f64 {
+    utils::hash_float(&[i as f64, k])
+}
+
+fn sphere_field(n: usize) -> Vec<Arc<dyn Primitive>> {
+    (0..n)
+        .map(|i| {
+            let center = Vector3f::new(
+                20.0 * random(i, 0.0) - 10.0,
+                20.0 * random(i, 1.0) - 10.0,
+                20.0 * random(i, 2.0) - 10.0,
+            );
+            let radius = (0.2 + 0.5 * random(i, 3.0)) * (200.0 / n as f64).cbrt();
+            let sphere = Sphere::full(Transform::translate(center), radius);
+
+            Arc::new(GeometricPrimitive::new(Arc::new(sphere), None, None, None))
+                as Arc<dyn Primitive>
+        })
+        .collect()
+}
+
+fn random_ray(i: usize) -> Ray {
+    let o = Point3f::new(
+        30.0 * random(i, 10.0) - 15.0,
+        30.0 * random(i, 11.0) - 15.0,
+        30.0 * random(i, 12.0) - 15.0,
+    );
+    let target = Point3f::new(
+        10.0 * random(i, 13.0) - 5.0,
+        10.0 * random(i, 14.0) - 5.0,
+        10.0 * random(i, 15.0) - 5.0,
+    );
+
+    Ray::new(o, target - o, Medium {}, f64::INFINITY, 0.0)
+}
+
+/// The fastest of `N_RUNS` timings of `f`, to keep out scheduling noise.
+fn fastest<F: FnMut()>(mut f: F) -> Duration {
+    (0..N_RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            f();
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+fn main() {
+    let primitives = sphere_field(N_PRIMITIVES);
+    let rays: Vec<Ray> = (0..N_RAYS).map(random_ray).collect();
+
+    let bvh = BvhAccel::new(primitives.clone(), 4, SplitMethod::Sah);
+    let bvh4 = Bvh4Accel::new(primitives.clone(), 4, SplitMethod::Sah);
+    let bvh8 = Bvh8Accel::new(primitives, 4, SplitMethod::Sah);
+
+    let accels: [(&str, &dyn Primitive); 3] =
+        [("binary", &bvh), ("4-wide", &bvh4), ("8-wide", &bvh8)];
+
+    for &(name, accel) in &accels {
+        let mut hits = 0;
+        let closest = fastest(|| {
+            hits = rays
+                .iter()
+                .filter(|&&ray| accel.intersect(&mut { ray }).is_some())
+                .count();
+        });
+        let any = fastest(|| {
+            for ray in &rays {
+                black_box(accel.intersect_p(ray));
+            }
+        });
+
+        println!(
+            "{}: intersect {:?}, intersect_p {:?}, {} hits",
+            name, closest, any, hits
+        );
+    }
+}
@@ -0,0 +1,1688 @@
+use std::convert::TryFrom;
+use std::mem;
+use std::sync::Arc;
+use std::thread;
+
+use accelerators::sbvh::{sbvh_build, SpatialSplits};
+use accelerators::stats::{self, Accelerator, NodeInfo};
+use core::interaction::SurfaceInteraction;
+use core::light::AreaLight;
+use core::material::Material;
+use core::primitive::Primitive;
+use core::ray::Ray;
+use core::ray_packet::{lanes, RayPacket, RayPacket16};
+use core::utils;
+
+use core::Bounds3f;
+use core::Point3f;
+use core::Vector3f;
+
+pub const N_BUCKETS: usize = 12;
+/// Initial capacity of the traversal stacks. Nothing bounds how deep a tree
+/// gets, so the stacks grow past this when they need to.
+const TRAVERSAL_STACK_CAPACITY: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SplitMethod {
+    /// Surface area heuristic, evaluated over `N_BUCKETS` buckets per split.
+    Sah,
+    /// Splits at the midpoint of the centroid bounds.
+    Middle,
+    /// Splits so both children get the same number of primitives.
+    EqualCounts,
+    /// Linear BVH treelets over sorted Morton codes, built in parallel and
+    /// joined by an SAH tree. Much faster to build, a little slower to trace.
+    Hlbvh,
+    /// SAH with spatial splits that clip primitives against the split plane
+    /// and reference them from both sides, for long thin primitives whose
+    /// bounds would otherwise overlap heavily.
+    Sbvh,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct BvhPrimitiveInfo {
+    pub primitive_number: usize,
+    pub bounds: Bounds3f,
+    pub centroid: Point3f,
+}
+
+impl BvhPrimitiveInfo {
+    pub fn new(primitive_number: usize, bounds: Bounds3f) -> Self {
+        Self {
+            primitive_number,
+            bounds,
+            centroid: (bounds.p_min + bounds.p_max) * 0.5,
+        }
+    }
+}
+
+pub enum BvhBuildNode {
+    Leaf {
+        bounds: Bounds3f,
+        first_prim_offset: usize,
+        n_primitives: usize,
+    },
+    Interior {
+        bounds: Bounds3f,
+        split_axis: usize,
+        children: [Box<BvhBuildNode>; 2],
+    },
+}
+
+impl BvhBuildNode {
+    pub fn leaf(first_prim_offset: usize, n_primitives: usize, bounds: Bounds3f) -> Self {
+        BvhBuildNode::Leaf {
+            bounds,
+            first_prim_offset,
+            n_primitives,
+        }
+    }
+
+    pub fn interior(split_axis: usize, c0: BvhBuildNode, c1: BvhBuildNode) -> Self {
+        BvhBuildNode::Interior {
+            bounds: c0.bounds().union_bounds(c1.bounds()),
+            split_axis,
+            children: [Box::new(c0), Box::new(c1)],
+        }
+    }
+
+    pub fn bounds(&self) -> Bounds3f {
+        match *self {
+            BvhBuildNode::Leaf { bounds, .. } | BvhBuildNode::Interior { bounds, .. } => bounds,
+        }
+    }
+}
+
+/// A node of the flattened tree. Nodes are stored depth first, so the first
+/// child of an interior node directly follows it and `offset` points at the
+/// second one; for leaves `offset` is the first of its primitives.
+#[derive(Clone, Copy, Debug)]
+pub struct LinearBvhNode {
+    pub bounds: Bounds3f,
+    pub offset: usize,
+    pub n_primitives: u16,
+    pub axis: u8,
+}
+
+impl LinearBvhNode {
+    pub fn is_leaf(&self) -> bool {
+        self.n_primitives > 0
+    }
+}
+
+pub struct BvhAccel {
+    pub max_prims_in_node: usize,
+    pub split_method: SplitMethod,
+    pub primitives: Vec<Arc<dyn Primitive>>,
+    pub nodes: Vec<LinearBvhNode>,
+    /// The SAH cost of each node's subtree when it was built, relative to
+    /// the node's own surface area; see `refit_and_rebuild`.
+    pub built_costs: Vec<f64>,
+}
+
+impl BvhAccel {
+    pub fn new(
+        primitives: Vec<Arc<dyn Primitive>>,
+        max_prims_in_node: usize,
+        split_method: SplitMethod,
+    ) -> Self {
+        Self::build(
+            primitives,
+            max_prims_in_node,
+            split_method,
+            SpatialSplits::default(),
+        )
+    }
+
+    /// Builds an SBVH, with `spatial_splits` limiting where spatial splits
+    /// are tried and how many references they may add.
+    pub fn with_spatial_splits(
+        primitives: Vec<Arc<dyn Primitive>>,
+        max_prims_in_node: usize,
+        spatial_splits: SpatialSplits,
+    ) -> Self {
+        Self::build(
+            primitives,
+            max_prims_in_node,
+            SplitMethod::Sbvh,
+            spatial_splits,
+        )
+    }
+
+    fn build(
+        primitives: Vec<Arc<dyn Primitive>>,
+        max_prims_in_node: usize,
+        split_method: SplitMethod,
+        spatial_splits: SpatialSplits,
+    ) -> Self {
+        let max_prims_in_node = max_prims_in_node.clamp(1, 255);
+
+        if primitives.is_empty() {
+            return Self {
+                max_prims_in_node,
+                split_method,
+                primitives,
+                nodes: Vec::new(),
+                built_costs: Vec::new(),
+            };
+        }
+
+        let mut primitive_info: Vec<BvhPrimitiveInfo> = primitives
+            .iter()
+            .enumerate()
+            .map(|(i, p)| BvhPrimitiveInfo::new(i, p.world_bound()))
+            .collect();
+
+        let mut ordered_prims = Vec::with_capacity(primitives.len());
+        let root = match split_method {
+            SplitMethod::Hlbvh => {
+                hlbvh_build(&primitive_info, max_prims_in_node, &mut ordered_prims)
+            }
+            SplitMethod::Sbvh => sbvh_build(
+                &primitives,
+                &primitive_info,
+                max_prims_in_node,
+                spatial_splits,
+                &mut ordered_prims,
+            ),
+            _ => recursive_build(
+                &mut primitive_info,
+                max_prims_in_node,
+                split_method,
+                &mut ordered_prims,
+            ),
+        };
+
+        Self::from_build_tree(
+            primitives,
+            &ordered_prims,
+            &root,
+            max_prims_in_node,
+            split_method,
+        )
+    }
+
+    /// Flattens a finished build tree whose leaves index into
+    /// `ordered_prims`, which in turn indexes into `primitives`.
+    pub fn from_build_tree(
+        primitives: Vec<Arc<dyn Primitive>>,
+        ordered_prims: &[usize],
+        root: &BvhBuildNode,
+        max_prims_in_node: usize,
+        split_method: SplitMethod,
+    ) -> Self {
+        let mut nodes = Vec::new();
+        flatten_bvh_tree(root, &mut nodes);
+
+        Self::from_nodes(
+            ordered_prims
+                .iter()
+                .map(|&i| primitives[i].clone())
+                .collect(),
+            nodes,
+            max_prims_in_node,
+            split_method,
+        )
+    }
+
+    /// Wraps already flattened nodes whose leaves index into `primitives`,
+    /// e.g. ones read back from a cache.
+    pub fn from_nodes(
+        primitives: Vec<Arc<dyn Primitive>>,
+        nodes: Vec<LinearBvhNode>,
+        max_prims_in_node: usize,
+        split_method: SplitMethod,
+    ) -> Self {
+        Self {
+            max_prims_in_node,
+            split_method,
+            primitives,
+            built_costs: subtree_costs(&nodes),
+            nodes,
+        }
+    }
+
+    /// Recomputes every node's bounds bottom-up from the primitives' current
+    /// bounds, for geometry that moved without changing the tree's topology.
+    pub fn refit(&mut self) {
+        // Children always come after their parent in the flattened order.
+        for i in (0..self.nodes.len()).rev() {
+            let node = self.nodes[i];
+
+            self.nodes[i].bounds = if node.is_leaf() {
+                self.primitives[node.offset..node.offset + node.n_primitives as usize]
+                    .iter()
+                    .fold(Bounds3f::zero(), |b, p| b.union_bounds(p.world_bound()))
+            } else {
+                self.nodes[i + 1]
+                    .bounds
+                    .union_bounds(self.nodes[node.offset].bounds)
+            };
+        }
+    }
+
+    /// Refits the tree, then rebuilds with the SAH each topmost subtree whose
+    /// cost has grown past `threshold` times its cost when it was built.
+    /// Returns how many subtrees were rebuilt.
+    pub fn refit_and_rebuild(&mut self, threshold: f64) -> usize {
+        self.refit();
+
+        let costs = subtree_costs(&self.nodes);
+        let mut degraded = Vec::new();
+        let mut to_visit = vec![0];
+
+        while let Some(i) = to_visit.pop() {
+            let node = self.nodes[i];
+
+            if node.is_leaf() {
+                continue;
+            }
+
+            if costs[i] > threshold * self.built_costs[i] {
+                degraded.push(i);
+            } else {
+                to_visit.push(i + 1);
+                to_visit.push(node.offset);
+            }
+        }
+
+        // Rebuild from the back so splicing never moves a pending subtree.
+        degraded.sort_unstable();
+
+        for &i in degraded.iter().rev() {
+            self.rebuild_subtree(i);
+        }
+
+        degraded.len()
+    }
+
+    /// Replaces the subtree at `root` with a fresh SAH build over the same
+    /// primitives, which occupy one contiguous range of `primitives`.
+    fn rebuild_subtree(&mut self, root: usize) {
+        let end = subtree_end(&self.nodes, root);
+        let leaves = self.nodes[root..end].iter().filter(|node| node.is_leaf());
+        let first_prim = leaves.clone().map(|node| node.offset).min().unwrap();
+        let n_prims: usize = leaves.map(|node| node.n_primitives as usize).sum();
+
+        let range = first_prim..first_prim + n_prims;
+        let mut primitive_info: Vec<BvhPrimitiveInfo> = self.primitives[range.clone()]
+            .iter()
+            .enumerate()
+            .map(|(i, p)| BvhPrimitiveInfo::new(i, p.world_bound()))
+            .collect();
+
+        let mut ordered_prims = Vec::with_capacity(n_prims);
+        let tree = recursive_build(
+            &mut primitive_info,
+            self.max_prims_in_node,
+            SplitMethod::Sah,
+            &mut ordered_prims,
+        );
+
+        let old_prims = self.primitives[range.clone()].to_vec();
+
+        for (slot, &i) in self.primitives[range].iter_mut().zip(ordered_prims.iter()) {
+            *slot = old_prims[i].clone();
+        }
+
+        let mut subtree = Vec::new();
+        flatten_bvh_tree(&tree, &mut subtree);
+        let built_costs = subtree_costs(&subtree);
+
+        for node in &mut subtree {
+            node.offset += if node.is_leaf() { first_prim } else { root };
+        }
+
+        // Interior nodes outside the subtree that point past it move along
+        // with everything after it.
+        let shift = subtree.len() as isize - (end - root) as isize;
+
+        for (i, node) in self.nodes.iter_mut().enumerate() {
+            if !node.is_leaf() && (i < root || i >= end) && node.offset >= end {
+                node.offset = (node.offset as isize + shift) as usize;
+            }
+        }
+
+        self.nodes.splice(root..end, subtree);
+        self.built_costs.splice(root..end, built_costs);
+    }
+
+    /// Closest hits for every active ray of `packet`, shortening their
+    /// `t_max` as `intersect` does. Nodes are culled for the whole packet
+    /// with interval arithmetic before the rays are tested one by one, and
+    /// once too few rays still hit a subtree they trace it on their own.
+    /// Packets whose rays point different ways are traced ray by ray.
+    pub fn intersect_packet<const N: usize>(
+        &self,
+        packet: &mut RayPacket<N>,
+    ) -> Vec<Option<SurfaceInteraction>> {
+        let mut hits: Vec<Option<SurfaceInteraction>> = (0..N).map(|_| None).collect();
+
+        if self.nodes.is_empty() || packet.active == 0 {
+            return hits;
+        }
+
+        let dir_is_neg = match packet.common_dir_is_neg() {
+            Some(dir_is_neg) => dir_is_neg,
+            None => {
+                for lane in packet.active_lanes() {
+                    hits[lane] = self.intersect_subtree(0, &mut packet.rays[lane]);
+                }
+
+                return hits;
+            }
+        };
+
+        let mut inv_dirs = [Vector3f::zero(); N];
+
+        for lane in packet.active_lanes() {
+            let d = packet.rays[lane].d;
+            inv_dirs[lane] = Vector3f::new(1.0 / d.x, 1.0 / d.y, 1.0 / d.z);
+        }
+
+        let intervals = PacketIntervals::new(packet, &inv_dirs, dir_is_neg);
+        let min_coherent_rays = (packet.n_active() / 4).max(2);
+
+        let mut nodes_to_visit = Vec::with_capacity(TRAVERSAL_STACK_CAPACITY);
+        nodes_to_visit.push((0, packet.active));
+
+        while let Some((node_index, mask)) = nodes_to_visit.pop() {
+            let node = &self.nodes[node_index];
+
+            let max_t = lanes(mask)
+                .map(|lane| packet.rays[lane].t_max)
+                .fold(0.0, f64::max);
+
+            if !intervals.may_hit(&node.bounds, max_t) {
+                continue;
+            }
+
+            let mask = lanes(mask)
+                .filter(|&lane| {
+                    stats::count_node_visit();
+
+                    node.bounds.intersect_p_precomputed(
+                        &packet.rays[lane],
+                        inv_dirs[lane],
+                        dir_is_neg,
+                    )
+                })
+                .fold(0u32, |mask, lane| mask | 1 << lane);
+
+            if mask == 0 {
+                continue;
+            }
+
+            if (mask.count_ones() as usize) < min_coherent_rays {
+                for lane in lanes(mask) {
+                    if let Some(hit) = self.intersect_subtree(node_index, &mut packet.rays[lane]) {
+                        hits[lane] = Some(hit);
+                    }
+                }
+            } else if node.is_leaf() {
+                let first = node.offset;
+                let last = first + node.n_primitives as usize;
+                stats::count_primitive_tests((last - first) * mask.count_ones() as usize);
+
+                for primitive in &self.primitives[first..last] {
+                    for lane in lanes(mask) {
+                        if let Some(hit) = primitive.intersect(&mut packet.rays[lane]) {
+                            hits[lane] = Some(hit);
+                        }
+                    }
+                }
+            } else {
+                // Push the far child first so the near one is visited next.
+                let (near, far) = if dir_is_neg[node.axis as usize] == 1 {
+                    (node.offset, node_index + 1)
+                } else {
+                    (node_index + 1, node.offset)
+                };
+
+                nodes_to_visit.push((far, mask));
+                nodes_to_visit.push((near, mask));
+            }
+        }
+
+        hits
+    }
+
+    /// Closest-hit traversal of the subtree rooted at node `root`.
+    fn intersect_subtree(&self, root: usize, ray: &mut Ray) -> Option<SurfaceInteraction> {
+        let inv_dir = Vector3f::new(1.0 / ray.d.x, 1.0 / ray.d.y, 1.0 / ray.d.z);
+        let dir_is_neg = [
+            (inv_dir.x < 0.0) as usize,
+            (inv_dir.y < 0.0) as usize,
+            (inv_dir.z < 0.0) as usize,
+        ];
+
+        let mut isect = None;
+        let mut nodes_to_visit = Vec::with_capacity(TRAVERSAL_STACK_CAPACITY);
+        let mut current_node_index = root;
+
+        loop {
+            let node = &self.nodes[current_node_index];
+            stats::count_node_visit();
+
+            if node
+                .bounds
+                .intersect_p_precomputed(ray, inv_dir, dir_is_neg)
+            {
+                if node.is_leaf() {
+                    let first = node.offset;
+                    let last = first + node.n_primitives as usize;
+                    stats::count_primitive_tests(last - first);
+
+                    for primitive in &self.primitives[first..last] {
+                        if let Some(hit) = primitive.intersect(ray) {
+                            isect = Some(hit);
+                        }
+                    }
+
+                    match nodes_to_visit.pop() {
+                        Some(next) => current_node_index = next,
+                        None => break,
+                    }
+                } else if dir_is_neg[node.axis as usize] == 1 {
+                    // Visit the child nearer along the split axis first.
+                    nodes_to_visit.push(current_node_index + 1);
+                    current_node_index = node.offset;
+                } else {
+                    nodes_to_visit.push(node.offset);
+                    current_node_index += 1;
+                }
+            } else {
+                match nodes_to_visit.pop() {
+                    Some(next) => current_node_index = next,
+                    None => break,
+                }
+            }
+        }
+
+        isect
+    }
+}
+
+impl Accelerator for BvhAccel {
+    fn visit_nodes(&self, visit: &mut dyn FnMut(NodeInfo)) {
+        let mut nodes_to_visit = Vec::new();
+
+        if !self.nodes.is_empty() {
+            nodes_to_visit.push((0, 0));
+        }
+
+        while let Some((i, depth)) = nodes_to_visit.pop() {
+            let node = &self.nodes[i];
+
+            visit(NodeInfo {
+                depth,
+                bounds: node.bounds,
+                n_primitives: if node.is_leaf() {
+                    Some(node.n_primitives as usize)
+                } else {
+                    None
+                },
+            });
+
+            if !node.is_leaf() {
+                nodes_to_visit.push((node.offset, depth + 1));
+                nodes_to_visit.push((i + 1, depth + 1));
+            }
+        }
+    }
+
+    fn memory_bytes(&self) -> usize {
+        self.nodes.len() * mem::size_of::<LinearBvhNode>()
+            + self.built_costs.len() * mem::size_of::<f64>()
+            + self.primitives.len() * mem::size_of::<Arc<dyn Primitive>>()
+    }
+}
+
+/// Conservative bounds on where a packet's rays can enter and leave a box,
+/// from the intervals spanned by their origins and inverse directions. All
+/// rays share their direction signs, so the near and far planes are the
+/// same for every ray.
+struct PacketIntervals {
+    o_min: [f64; 3],
+    o_max: [f64; 3],
+    inv_min: [f64; 3],
+    inv_max: [f64; 3],
+    dir_is_neg: [usize; 3],
+}
+
+impl PacketIntervals {
+    fn new<const N: usize>(
+        packet: &RayPacket<N>,
+        inv_dirs: &[Vector3f; N],
+        dir_is_neg: [usize; 3],
+    ) -> Self {
+        let origins = packet.origin_bounds();
+        let mut inv_min = [f64::INFINITY; 3];
+        let mut inv_max = [f64::NEG_INFINITY; 3];
+
+        for lane in packet.active_lanes() {
+            for axis in 0..3 {
+                inv_min[axis] = inv_min[axis].min(inv_dirs[lane][axis]);
+                inv_max[axis] = inv_max[axis].max(inv_dirs[lane][axis]);
+            }
+        }
+
+        Self {
+            o_min: [origins.p_min.x, origins.p_min.y, origins.p_min.z],
+            o_max: [origins.p_max.x, origins.p_max.y, origins.p_max.z],
+            inv_min,
+            inv_max,
+            dir_is_neg,
+        }
+    }
+
+    /// False only if no ray of the packet can hit `bounds` before `max_t`.
+    fn may_hit(&self, bounds: &Bounds3f, max_t: f64) -> bool {
+        let mut t_min = 0.0;
+        let mut t_max = max_t;
+
+        for axis in 0..3 {
+            let near = bounds[self.dir_is_neg[axis]][axis];
+            let far = bounds[1 - self.dir_is_neg[axis]][axis];
+
+            let (near_min, _) = self.interval_product(near, axis);
+            let (_, far_max) = self.interval_product(far, axis);
+
+            t_min = near_min.max(t_min);
+            t_max = (far_max * (1.0 + 2.0 * utils::gamma(3))).min(t_max);
+        }
+
+        t_min <= t_max
+    }
+
+    /// The range of `(plane - o) * inv_d` over the packet's intervals.
+    fn interval_product(&self, plane: f64, axis: usize) -> (f64, f64) {
+        let products = [
+            (plane - self.o_min[axis]) * self.inv_min[axis],
+            (plane - self.o_min[axis]) * self.inv_max[axis],
+            (plane - self.o_max[axis]) * self.inv_min[axis],
+            (plane - self.o_max[axis]) * self.inv_max[axis],
+        ];
+
+        (
+            products.iter().cloned().fold(f64::INFINITY, f64::min),
+            products.iter().cloned().fold(f64::NEG_INFINITY, f64::max),
+        )
+    }
+}
+
+/// Index one past the last node of the subtree at `i`.
+fn subtree_end(nodes: &[LinearBvhNode], mut i: usize) -> usize {
+    while !nodes[i].is_leaf() {
+        i = nodes[i].offset;
+    }
+
+    i + 1
+}
+
+/// The SAH cost of each node's subtree, relative to the node's own area,
+/// counting a unit cost per interior node and per primitive.
+fn subtree_costs(nodes: &[LinearBvhNode]) -> Vec<f64> {
+    let mut weighted = vec![0.0; nodes.len()];
+    let mut costs = vec![0.0; nodes.len()];
+
+    for i in (0..nodes.len()).rev() {
+        let node = nodes[i];
+        let area = node.bounds.surface_area();
+
+        weighted[i] = if node.is_leaf() {
+            area * node.n_primitives as f64
+        } else {
+            area + weighted[i + 1] + weighted[node.offset]
+        };
+
+        costs[i] = if area > 0.0 { weighted[i] / area } else { 0.0 };
+    }
+
+    costs
+}
+
+impl Primitive for BvhAccel {
+    fn world_bound(&self) -> Bounds3f {
+        self.nodes
+            .first()
+            .map_or_else(Bounds3f::zero, |node| node.bounds)
+    }
+
+    fn intersect(&self, ray: &mut Ray) -> Option<SurfaceInteraction> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+
+        self.intersect_subtree(0, ray)
+    }
+
+    fn intersect_many(&self, rays: &mut [Ray]) -> Vec<Option<SurfaceInteraction>> {
+        let mut hits = Vec::with_capacity(rays.len());
+
+        for chunk in rays.chunks_mut(16) {
+            let mut packet = RayPacket16::new(chunk);
+            let packet_hits = self.intersect_packet(&mut packet);
+
+            for (ray, traced) in chunk.iter_mut().zip(&packet.rays) {
+                ray.t_max = traced.t_max;
+            }
+
+            hits.extend(packet_hits.into_iter().take(chunk.len()));
+        }
+
+        hits
+    }
+
+    fn intersect_p(&self, ray: &Ray) -> bool {
+        if self.nodes.is_empty() {
+            return false;
+        }
+
+        let inv_dir = Vector3f::new(1.0 / ray.d.x, 1.0 / ray.d.y, 1.0 / ray.d.z);
+        let dir_is_neg = [
+            (inv_dir.x < 0.0) as usize,
+            (inv_dir.y < 0.0) as usize,
+            (inv_dir.z < 0.0) as usize,
+        ];
+
+        let mut nodes_to_visit = Vec::with_capacity(TRAVERSAL_STACK_CAPACITY);
+        let mut current_node_index = 0;
+
+        loop {
+            let node = &self.nodes[current_node_index];
+            stats::count_node_visit();
+
+            if node
+                .bounds
+                .intersect_p_precomputed(ray, inv_dir, dir_is_neg)
+            {
+                if node.is_leaf() {
+                    let first = node.offset;
+                    let last = first + node.n_primitives as usize;
+                    stats::count_primitive_tests(last - first);
+
+                    if self.primitives[first..last]
+                        .iter()
+                        .any(|primitive| primitive.intersect_p(ray))
+                    {
+                        return true;
+                    }
+
+                    match nodes_to_visit.pop() {
+                        Some(next) => current_node_index = next,
+                        None => break,
+                    }
+                } else if dir_is_neg[node.axis as usize] == 1 {
+                    nodes_to_visit.push(current_node_index + 1);
+                    current_node_index = node.offset;
+                } else {
+                    nodes_to_visit.push(node.offset);
+                    current_node_index += 1;
+                }
+            } else {
+                match nodes_to_visit.pop() {
+                    Some(next) => current_node_index = next,
+                    None => break,
+                }
+            }
+        }
+
+        false
+    }
+
+    fn get_area_light(&self) -> Option<Arc<dyn AreaLight>> {
+        None
+    }
+
+    fn get_material(&self) -> Option<Arc<dyn Material>> {
+        None
+    }
+}
+
+fn recursive_build(
+    primitive_info: &mut [BvhPrimitiveInfo],
+    max_prims_in_node: usize,
+    split_method: SplitMethod,
+    ordered_prims: &mut Vec<usize>,
+) -> BvhBuildNode {
+    let bounds = primitive_info
+        .iter()
+        .fold(Bounds3f::zero(), |b, info| b.union_bounds(info.bounds));
+    let n_primitives = primitive_info.len();
+
+    if n_primitives == 1 {
+        return make_leaf(primitive_info, bounds, ordered_prims);
+    }
+
+    let centroid_bounds = primitive_info
+        .iter()
+        .fold(Bounds3f::zero(), |b, info| b.union(info.centroid));
+    let dim = centroid_bounds.maximum_extent();
+
+    // All centroids coincide, so no split can separate them; too many for
+    // one leaf are halved by count instead.
+    if centroid_bounds.p_max[dim] == centroid_bounds.p_min[dim] {
+        if n_primitives <= max_prims_in_node {
+            return make_leaf(primitive_info, bounds, ordered_prims);
+        }
+
+        let (left, right) = primitive_info.split_at_mut(n_primitives / 2);
+
+        return BvhBuildNode::interior(
+            dim,
+            recursive_build(left, max_prims_in_node, split_method, ordered_prims),
+            recursive_build(right, max_prims_in_node, split_method, ordered_prims),
+        );
+    }
+
+    let mid = match split_method {
+        SplitMethod::Middle => {
+            let p_mid = (centroid_bounds.p_min[dim] + centroid_bounds.p_max[dim]) / 2.0;
+            let mid = partition(primitive_info, |info| info.centroid[dim] < p_mid);
+
+            if mid == 0 || mid == n_primitives {
+                split_equal_counts(primitive_info, dim)
+            } else {
+                mid
+            }
+        }
+        SplitMethod::EqualCounts => split_equal_counts(primitive_info, dim),
+        SplitMethod::Sah => {
+            if n_primitives <= 2 {
+                split_equal_counts(primitive_info, dim)
+            } else {
+                match split_sah(
+                    primitive_info,
+                    bounds,
+                    centroid_bounds,
+                    dim,
+                    max_prims_in_node,
+                ) {
+                    Some(mid) => mid,
+                    None => return make_leaf(primitive_info, bounds, ordered_prims),
+                }
+            }
+        }
+        SplitMethod::Hlbvh | SplitMethod::Sbvh => {
+            unreachable!("HLBVH and SBVH trees have their own builders")
+        }
+    };
+
+    let (left, right) = primitive_info.split_at_mut(mid);
+
+    BvhBuildNode::interior(
+        dim,
+        recursive_build(left, max_prims_in_node, split_method, ordered_prims),
+        recursive_build(right, max_prims_in_node, split_method, ordered_prims),
+    )
+}
+
+fn make_leaf(
+    primitive_info: &[BvhPrimitiveInfo],
+    bounds: Bounds3f,
+    ordered_prims: &mut Vec<usize>,
+) -> BvhBuildNode {
+    let first_prim_offset = ordered_prims.len();
+    ordered_prims.extend(primitive_info.iter().map(|info| info.primitive_number));
+
+    BvhBuildNode::leaf(first_prim_offset, primitive_info.len(), bounds)
+}
+
+fn split_equal_counts(primitive_info: &mut [BvhPrimitiveInfo], dim: usize) -> usize {
+    let mid = primitive_info.len() / 2;
+    primitive_info.select_nth_unstable_by(mid, |a, b| {
+        a.centroid[dim].partial_cmp(&b.centroid[dim]).unwrap()
+    });
+
+    mid
+}
+
+/// Returns where to split `primitive_info` along `dim`, or `None` when
+/// keeping the primitives in a single leaf is cheaper than any split.
+fn split_sah(
+    primitive_info: &mut [BvhPrimitiveInfo],
+    bounds: Bounds3f,
+    centroid_bounds: Bounds3f,
+    dim: usize,
+    max_prims_in_node: usize,
+) -> Option<usize> {
+    let bucket_of = |info: &BvhPrimitiveInfo| {
+        let b = (N_BUCKETS as f64 * centroid_bounds.offset(info.centroid)[dim]) as usize;
+        b.min(N_BUCKETS - 1)
+    };
+
+    let (min_cost, min_cost_split_bucket) =
+        cheapest_bucket_split(primitive_info, bounds, |info| info.bounds, bucket_of);
+
+    let leaf_cost = primitive_info.len() as f64;
+
+    if primitive_info.len() > max_prims_in_node || min_cost < leaf_cost {
+        let mid = partition(primitive_info, |info| {
+            bucket_of(info) <= min_cost_split_bucket
+        });
+
+        if mid == 0 || mid == primitive_info.len() {
+            Some(split_equal_counts(primitive_info, dim))
+        } else {
+            Some(mid)
+        }
+    } else {
+        None
+    }
+}
+
+/// Bits of each axis in a Morton code, and how many of the code's leading
+/// bits a treelet shares.
+const MORTON_BITS: u32 = 10;
+const TREELET_BITS: u32 = 12;
+const RADIX_BITS: u32 = 6;
+const MIN_PRIMS_PER_THREAD: usize = 4096;
+
+#[derive(Clone, Copy, Debug, Default)]
+struct MortonPrimitive {
+    primitive_index: usize,
+    morton_code: u32,
+}
+
+fn hlbvh_build(
+    primitive_info: &[BvhPrimitiveInfo],
+    max_prims_in_node: usize,
+    ordered_prims: &mut Vec<usize>,
+) -> BvhBuildNode {
+    let centroid_bounds = primitive_info
+        .iter()
+        .fold(Bounds3f::zero(), |b, info| b.union(info.centroid));
+    let n_threads = thread_count(primitive_info.len());
+    let chunk_size = primitive_info.len().div_ceil(n_threads);
+
+    let mut morton_prims = vec![MortonPrimitive::default(); primitive_info.len()];
+
+    thread::scope(|scope| {
+        for (infos, mortons) in primitive_info
+            .chunks(chunk_size)
+            .zip(morton_prims.chunks_mut(chunk_size))
+        {
+            scope.spawn(move || {
+                let morton_scale = (1 << MORTON_BITS) as f64;
+
+                for (info, morton) in infos.iter().zip(mortons.iter_mut()) {
+                    *morton = MortonPrimitive {
+                        primitive_index: info.primitive_number,
+                        morton_code: encode_morton3(
+                            centroid_bounds.offset(info.centroid) * morton_scale,
+                        ),
+                    };
+                }
+            });
+        }
+    });
+
+    radix_sort(&mut morton_prims, n_threads);
+
+    // Treelets are runs of primitives whose codes share the leading bits,
+    // i.e. that fall in the same cell of a coarse grid over the centroids.
+    let mask = ((1 << TREELET_BITS) - 1) << (3 * MORTON_BITS - TREELET_BITS);
+    let mut treelets = Vec::new();
+    let mut start = 0;
+
+    for end in 1..=morton_prims.len() {
+        if end == morton_prims.len()
+            || morton_prims[start].morton_code & mask != morton_prims[end].morton_code & mask
+        {
+            treelets.push(start..end);
+            start = end;
+        }
+    }
+
+    let first_bit_index = (3 * MORTON_BITS - TREELET_BITS) as i32 - 1;
+    let treelets_per_thread = treelets.len().div_ceil(n_threads);
+    let sorted = &morton_prims;
+
+    let treelet_roots: Vec<BvhBuildNode> = thread::scope(|scope| {
+        let handles: Vec<_> = treelets
+            .chunks(treelets_per_thread)
+            .map(|group| {
+                scope.spawn(move || {
+                    group
+                        .iter()
+                        .map(|range| {
+                            emit_lbvh(
+                                primitive_info,
+                                &sorted[range.clone()],
+                                range.start,
+                                max_prims_in_node,
+                                first_bit_index,
+                            )
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect()
+    });
+
+    // Leaves index the sorted order directly, since every treelet emits its
+    // primitives in place.
+    ordered_prims.extend(morton_prims.iter().map(|m| m.primitive_index));
+
+    build_upper_sah(treelet_roots)
+}
+
+/// Builds the tree over one run of sorted Morton codes by splitting wherever
+/// `bit_index` changes, walking down the bits from most to least significant.
+fn emit_lbvh(
+    primitive_info: &[BvhPrimitiveInfo],
+    morton_prims: &[MortonPrimitive],
+    first_prim_offset: usize,
+    max_prims_in_node: usize,
+    bit_index: i32,
+) -> BvhBuildNode {
+    let n_primitives = morton_prims.len();
+
+    if n_primitives <= max_prims_in_node {
+        let bounds = morton_prims.iter().fold(Bounds3f::zero(), |b, m| {
+            b.union_bounds(primitive_info[m.primitive_index].bounds)
+        });
+
+        return BvhBuildNode::leaf(first_prim_offset, n_primitives, bounds);
+    }
+
+    // Out of bits with too many primitives left: their centroids share a
+    // grid cell, so any split is as good as another.
+    let split_offset = if bit_index < 0 {
+        n_primitives / 2
+    } else {
+        let mask = 1 << bit_index;
+
+        if morton_prims[0].morton_code & mask == morton_prims[n_primitives - 1].morton_code & mask {
+            return emit_lbvh(
+                primitive_info,
+                morton_prims,
+                first_prim_offset,
+                max_prims_in_node,
+                bit_index - 1,
+            );
+        }
+
+        morton_prims.partition_point(|m| m.morton_code & mask == 0)
+    };
+
+    let (left, right) = morton_prims.split_at(split_offset);
+
+    BvhBuildNode::interior(
+        bit_index.max(0) as usize % 3,
+        emit_lbvh(
+            primitive_info,
+            left,
+            first_prim_offset,
+            max_prims_in_node,
+            bit_index - 1,
+        ),
+        emit_lbvh(
+            primitive_info,
+            right,
+            first_prim_offset + split_offset,
+            max_prims_in_node,
+            bit_index - 1,
+        ),
+    )
+}
+
+/// Joins the treelets with an SAH tree over their bounds.
+fn build_upper_sah(mut nodes: Vec<BvhBuildNode>) -> BvhBuildNode {
+    if nodes.len() == 1 {
+        return nodes.pop().unwrap();
+    }
+
+    let bounds = nodes
+        .iter()
+        .fold(Bounds3f::zero(), |b, node| b.union_bounds(node.bounds()));
+    let centroid_of = |node: &BvhBuildNode| {
+        let b = node.bounds();
+        (b.p_min + b.p_max) * 0.5
+    };
+    let centroid_bounds = nodes
+        .iter()
+        .fold(Bounds3f::zero(), |b, node| b.union(centroid_of(node)));
+    let dim = centroid_bounds.maximum_extent();
+
+    let bucket_of = |node: &BvhBuildNode| {
+        let b = (N_BUCKETS as f64 * centroid_bounds.offset(centroid_of(node))[dim]) as usize;
+        b.min(N_BUCKETS - 1)
+    };
+
+    let (_, min_cost_split_bucket) =
+        cheapest_bucket_split(&nodes, bounds, BvhBuildNode::bounds, bucket_of);
+
+    let (mut left, mut right): (Vec<_>, Vec<_>) = nodes
+        .into_iter()
+        .partition(|node| bucket_of(node) <= min_cost_split_bucket);
+
+    if left.is_empty() || right.is_empty() {
+        left.append(&mut right);
+        right = left.split_off(left.len() / 2);
+    }
+
+    BvhBuildNode::interior(dim, build_upper_sah(left), build_upper_sah(right))
+}
+
+fn thread_count(n_items: usize) -> usize {
+    let available = thread::available_parallelism().map_or(1, |n| n.get());
+
+    available.min(n_items / MIN_PRIMS_PER_THREAD).max(1)
+}
+
+/// Spreads the low ten bits of `x` out so two zero bits follow each one.
+fn left_shift3(mut x: u32) -> u32 {
+    if x == 1 << MORTON_BITS {
+        x -= 1;
+    }
+
+    x = (x | (x << 16)) & 0b0000_0011_0000_0000_0000_0000_1111_1111;
+    x = (x | (x << 8)) & 0b0000_0011_0000_0000_1111_0000_0000_1111;
+    x = (x | (x << 4)) & 0b0000_0011_0000_1100_0011_0000_1100_0011;
+    x = (x | (x << 2)) & 0b0000_1001_0010_0100_1001_0010_0100_1001;
+
+    x
+}
+
+/// Interleaves the bits of `v`, whose components must lie in [0, 1024].
+fn encode_morton3(v: Vector3f) -> u32 {
+    (left_shift3(v.z as u32) << 2) | (left_shift3(v.y as u32) << 1) | left_shift3(v.x as u32)
+}
+
+/// Least significant digit radix sort on the Morton codes. Each pass counts
+/// digits per chunk in parallel, then every chunk scatters into its own
+/// disjoint slices of the output.
+fn radix_sort(morton_prims: &mut Vec<MortonPrimitive>, n_threads: usize) {
+    let n_buckets = 1 << RADIX_BITS;
+    let bit_mask = n_buckets as u32 - 1;
+    let chunk_size = morton_prims.len().div_ceil(n_threads).max(1);
+    let mut temp = vec![MortonPrimitive::default(); morton_prims.len()];
+
+    for pass in 0..3 * MORTON_BITS / RADIX_BITS {
+        let low_bit = pass * RADIX_BITS;
+        let bucket_of = |m: &MortonPrimitive| ((m.morton_code >> low_bit) & bit_mask) as usize;
+
+        {
+            let input: &[MortonPrimitive] = morton_prims;
+
+            let counts: Vec<Vec<usize>> = thread::scope(|scope| {
+                let handles: Vec<_> = input
+                    .chunks(chunk_size)
+                    .map(|chunk| {
+                        scope.spawn(move || {
+                            let mut count = vec![0; n_buckets];
+
+                            for m in chunk {
+                                count[bucket_of(m)] += 1;
+                            }
+
+                            count
+                        })
+                    })
+                    .collect();
+
+                handles
+                    .into_iter()
+                    .map(|handle| handle.join().unwrap())
+                    .collect()
+            });
+
+            let mut rest = &mut temp[..];
+            let mut destinations: Vec<Vec<&mut [MortonPrimitive]>> = counts
+                .iter()
+                .map(|_| Vec::with_capacity(n_buckets))
+                .collect();
+
+            for b in 0..n_buckets {
+                for (c, count) in counts.iter().enumerate() {
+                    let (destination, tail) = mem::take(&mut rest).split_at_mut(count[b]);
+                    destinations[c].push(destination);
+                    rest = tail;
+                }
+            }
+
+            thread::scope(|scope| {
+                for (chunk, mut destination) in input.chunks(chunk_size).zip(destinations) {
+                    scope.spawn(move || {
+                        let mut cursor = vec![0; n_buckets];
+
+                        for m in chunk {
+                            let b = bucket_of(m);
+                            destination[b][cursor[b]] = *m;
+                            cursor[b] += 1;
+                        }
+                    });
+                }
+            });
+        }
+
+        mem::swap(morton_prims, &mut temp);
+    }
+}
+
+/// Evaluates the SAH for splitting `items` after each of `N_BUCKETS`
+/// buckets, returning the lowest cost and the last bucket of the left side.
+pub fn cheapest_bucket_split<T, B, K>(
+    items: &[T],
+    bounds: Bounds3f,
+    bounds_of: B,
+    bucket_of: K,
+) -> (f64, usize)
+where
+    B: Fn(&T) -> Bounds3f,
+    K: Fn(&T) -> usize,
+{
+    let mut counts = [0usize; N_BUCKETS];
+    let mut bucket_bounds = [Bounds3f::zero(); N_BUCKETS];
+
+    for item in items {
+        let b = bucket_of(item);
+        counts[b] += 1;
+        bucket_bounds[b] = bucket_bounds[b].union_bounds(bounds_of(item));
+    }
+
+    // Cost of splitting after each bucket, with traversal and primitive
+    // intersection both counted as 1.
+    let mut min_cost = f64::INFINITY;
+    let mut min_cost_split_bucket = 0;
+
+    for i in 0..N_BUCKETS - 1 {
+        let (mut b0, mut b1) = (Bounds3f::zero(), Bounds3f::zero());
+        let (mut count0, mut count1) = (0, 0);
+
+        for j in 0..=i {
+            b0 = b0.union_bounds(bucket_bounds[j]);
+            count0 += counts[j];
+        }
+
+        for j in i + 1..N_BUCKETS {
+            b1 = b1.union_bounds(bucket_bounds[j]);
+            count1 += counts[j];
+        }
+
+        let area0 = if count0 > 0 { b0.surface_area() } else { 0.0 };
+        let area1 = if count1 > 0 { b1.surface_area() } else { 0.0 };
+        let cost = 1.0 + (count0 as f64 * area0 + count1 as f64 * area1) / bounds.surface_area();
+
+        if cost < min_cost {
+            min_cost = cost;
+            min_cost_split_bucket = i;
+        }
+    }
+
+    (min_cost, min_cost_split_bucket)
+}
+
+/// Moves the elements matching `pred` to the front, returning how many
+/// there are.
+fn partition<T, F: Fn(&T) -> bool>(items: &mut [T], pred: F) -> usize {
+    let mut first = 0;
+
+    for i in 0..items.len() {
+        if pred(&items[i]) {
+            items.swap(first, i);
+            first += 1;
+        }
+    }
+
+    first
+}
+
+fn flatten_bvh_tree(node: &BvhBuildNode, nodes: &mut Vec<LinearBvhNode>) -> usize {
+    let offset = nodes.len();
+
+    match *node {
+        BvhBuildNode::Leaf {
+            bounds,
+            first_prim_offset,
+            n_primitives,
+        } => nodes.push(LinearBvhNode {
+            bounds,
+            offset: first_prim_offset,
+            n_primitives: u16::try_from(n_primitives)
+                .expect("BVH leaf holds more primitives than its node can count"),
+            axis: 0,
+        }),
+        BvhBuildNode::Interior {
+            bounds,
+            split_axis,
+            ref children,
+        } => {
+            nodes.push(LinearBvhNode {
+                bounds,
+                offset: 0,
+                n_primitives: 0,
+                axis: split_axis as u8,
+            });
+
+            flatten_bvh_tree(&children[0], nodes);
+            nodes[offset].offset = flatten_bvh_tree(&children[1], nodes);
+        }
+    }
+
+    offset
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64;
+
+    use core::medium::Medium;
+    use core::primitive::{GeometricPrimitive, TransformedPrimitive};
+    use core::ray_packet::RayPacket8;
+    use core::transform::Transform;
+    use core::utils;
+    use shapes::sphere::Sphere;
+
+    const EPSILON: f64 = 0.0001;
+
+    fn random(i: usize, k: f64) -> f64 {
+        utils::hash_float(&[i as f64, k])
+    }
+
+    fn sphere_field(n: usize) -> Vec<Arc<dyn Primitive>> {
+        (0..n)
+            .map(|i| {
+                let center = Vector3f::new(
+                    20.0 * random(i, 0.0) - 10.0,
+                    20.0 * random(i, 1.0) - 10.0,
+                    20.0 * random(i, 2.0) - 10.0,
+                );
+                let sphere = Sphere::full(Transform::translate(center), 0.2 + 0.5 * random(i, 3.0));
+
+                Arc::new(GeometricPrimitive::new(Arc::new(sphere), None, None, None))
+                    as Arc<dyn Primitive>
+            })
+            .collect()
+    }
+
+    fn random_ray(i: usize) -> Ray {
+        let o = Point3f::new(
+            30.0 * random(i, 10.0) - 15.0,
+            30.0 * random(i, 11.0) - 15.0,
+            30.0 * random(i, 12.0) - 15.0,
+        );
+        let target = Point3f::new(
+            10.0 * random(i, 13.0) - 5.0,
+            10.0 * random(i, 14.0) - 5.0,
+            10.0 * random(i, 15.0) - 5.0,
+        );
+
+        Ray::new(o, target - o, Medium {}, f64::INFINITY, 0.0)
+    }
+
+    fn brute_force(primitives: &[Arc<dyn Primitive>], ray: &mut Ray) -> Option<SurfaceInteraction> {
+        let mut isect = None;
+
+        for primitive in primitives {
+            if let Some(hit) = primitive.intersect(ray) {
+                isect = Some(hit);
+            }
+        }
+
+        isect
+    }
+
+    fn check_matches_brute_force(split_method: SplitMethod, max_prims_in_node: usize) {
+        let primitives = sphere_field(200);
+        let bvh = BvhAccel::new(primitives.clone(), max_prims_in_node, split_method);
+
+        assert_eq!(bvh.primitives.len(), primitives.len());
+
+        let mut hits = 0;
+
+        for i in 0..500 {
+            let mut expected_ray = random_ray(i);
+            let mut ray = expected_ray;
+
+            let expected = brute_force(&primitives, &mut expected_ray);
+            let actual = bvh.intersect(&mut ray);
+
+            assert_eq!(expected.is_some(), actual.is_some());
+            assert_eq!(bvh.intersect_p(&random_ray(i)), expected.is_some());
+
+            if let (Some(expected), Some(actual)) = (expected, actual) {
+                hits += 1;
+                assert!((expected_ray.t_max - ray.t_max).abs() < EPSILON);
+                assert!((expected.p - actual.p).length() < EPSILON);
+            }
+        }
+
+        assert!(hits > 50);
+    }
+
+    #[test]
+    fn sah_matches_brute_force() {
+        check_matches_brute_force(SplitMethod::Sah, 4);
+    }
+
+    #[test]
+    fn middle_matches_brute_force() {
+        check_matches_brute_force(SplitMethod::Middle, 1);
+    }
+
+    #[test]
+    fn equal_counts_matches_brute_force() {
+        check_matches_brute_force(SplitMethod::EqualCounts, 2);
+    }
+
+    #[test]
+    fn hlbvh_matches_brute_force() {
+        check_matches_brute_force(SplitMethod::Hlbvh, 4);
+    }
+
+    #[test]
+    fn morton_codes_interleave_axes() {
+        assert_eq!(encode_morton3(Vector3f::new(1.0, 0.0, 0.0)), 0b001);
+        assert_eq!(encode_morton3(Vector3f::new(0.0, 1.0, 0.0)), 0b010);
+        assert_eq!(encode_morton3(Vector3f::new(0.0, 0.0, 1.0)), 0b100);
+        assert_eq!(encode_morton3(Vector3f::new(3.0, 0.0, 2.0)), 0b101_001);
+        assert_eq!(
+            encode_morton3(Vector3f::new(1024.0, 1024.0, 1024.0)),
+            (1 << 30) - 1
+        );
+    }
+
+    #[test]
+    fn radix_sort_orders_codes() {
+        let mut morton_prims: Vec<MortonPrimitive> = (0..10000)
+            .map(|i| MortonPrimitive {
+                primitive_index: i,
+                morton_code: (random(i, 20.0) * (1 << 30) as f64) as u32,
+            })
+            .collect();
+        let mut expected = morton_prims.clone();
+        expected.sort_by_key(|m| m.morton_code);
+
+        radix_sort(&mut morton_prims, 4);
+
+        for (m, e) in morton_prims.iter().zip(expected.iter()) {
+            assert_eq!(m.morton_code, e.morton_code);
+            assert_eq!(m.primitive_index, e.primitive_index);
+        }
+    }
+
+    #[test]
+    fn flattened_layout_is_depth_first() {
+        let bvh = BvhAccel::new(sphere_field(64), 1, SplitMethod::Sah);
+        let root = bvh.nodes[0];
+
+        assert!(!root.is_leaf());
+
+        for (i, node) in bvh.nodes.iter().enumerate() {
+            if node.is_leaf() {
+                assert!(node.offset + node.n_primitives as usize <= bvh.primitives.len());
+            } else {
+                // The first child follows its parent and both fit inside it.
+                assert!(node.offset > i + 1);
+
+                for child in &[bvh.nodes[i + 1], bvh.nodes[node.offset]] {
+                    let union = node.bounds.union_bounds(child.bounds);
+                    assert!((union.surface_area() - node.bounds.surface_area()).abs() < EPSILON);
+                }
+            }
+        }
+
+        let leaf_prims: usize = bvh
+            .nodes
+            .iter()
+            .filter(|node| node.is_leaf())
+            .map(|node| node.n_primitives as usize)
+            .sum();
+
+        assert_eq!(leaf_prims, 64);
+    }
+
+    fn moved(bvh: &mut BvhAccel, offset: &dyn Fn(usize) -> Vector3f) {
+        for (i, primitive) in bvh.primitives.iter_mut().enumerate() {
+            *primitive = Arc::new(TransformedPrimitive::with_transform(
+                primitive.clone(),
+                Transform::translate(offset(i)),
+            ));
+        }
+    }
+
+    fn check_matches_primitives(bvh: &BvhAccel) {
+        for i in 0..500 {
+            let mut expected_ray = random_ray(i);
+            let mut ray = expected_ray;
+
+            let expected = brute_force(&bvh.primitives, &mut expected_ray);
+            let actual = bvh.intersect(&mut ray);
+
+            assert_eq!(expected.is_some(), actual.is_some());
+            assert_eq!(bvh.intersect_p(&random_ray(i)), expected.is_some());
+            assert!((expected_ray.t_max - ray.t_max).abs() < EPSILON || expected.is_none());
+        }
+    }
+
+    #[test]
+    fn refit_follows_moving_geometry() {
+        let mut bvh = BvhAccel::new(sphere_field(200), 4, SplitMethod::Sah);
+        let n_nodes = bvh.nodes.len();
+
+        moved(&mut bvh, &|i| {
+            Vector3f::new(random(i, 30.0), random(i, 31.0), 0.0)
+        });
+        bvh.refit();
+
+        assert_eq!(bvh.nodes.len(), n_nodes);
+
+        let bounds = bvh
+            .primitives
+            .iter()
+            .fold(Bounds3f::zero(), |b, p| b.union_bounds(p.world_bound()));
+        assert!((bvh.world_bound().p_min - bounds.p_min).length() < EPSILON);
+        assert!((bvh.world_bound().p_max - bounds.p_max).length() < EPSILON);
+
+        check_matches_primitives(&bvh);
+
+        // Small motions leave the tree good enough to keep.
+        assert_eq!(bvh.refit_and_rebuild(2.0), 0);
+    }
+
+    #[test]
+    fn degraded_subtrees_are_rebuilt() {
+        let mut bvh = BvhAccel::new(sphere_field(200), 1, SplitMethod::Sah);
+
+        let centroids: Vec<Point3f> = bvh
+            .primitives
+            .iter()
+            .map(|p| BvhPrimitiveInfo::new(0, p.world_bound()).centroid)
+            .collect();
+
+        // Reverse the positions of the first 64 primitives, which scrambles
+        // the subtrees holding them while leaving the rest of the tree fit.
+        moved(&mut bvh, &|i| {
+            if i < 64 {
+                centroids[63 - i] - centroids[i]
+            } else {
+                Vector3f::new(0.0, 0.0, 0.0)
+            }
+        });
+        bvh.refit();
+
+        let refit_costs = subtree_costs(&bvh.nodes);
+        assert!(refit_costs[0] < 1.5 * bvh.built_costs[0]);
+
+        let rebuilt = bvh.refit_and_rebuild(1.5);
+
+        assert!(rebuilt > 0);
+        assert!(subtree_costs(&bvh.nodes)[0] < refit_costs[0]);
+        assert_eq!(bvh.primitives.len(), 200);
+        assert_eq!(bvh.built_costs.len(), bvh.nodes.len());
+
+        check_matches_primitives(&bvh);
+    }
+
+    #[test]
+    fn coincident_primitives_respect_leaf_size() {
+        let primitives: Vec<Arc<dyn Primitive>> = (0..300)
+            .map(|_| {
+                let sphere = Sphere::full(Transform::new(), 1.0);
+
+                Arc::new(GeometricPrimitive::new(Arc::new(sphere), None, None, None))
+                    as Arc<dyn Primitive>
+            })
+            .collect();
+
+        for &split_method in &[
+            SplitMethod::Sah,
+            SplitMethod::Middle,
+            SplitMethod::EqualCounts,
+        ] {
+            let bvh = BvhAccel::new(primitives.clone(), 4, split_method);
+
+            let mut ray = Ray::new(
+                Point3f::new(0.0, 0.0, -5.0),
+                Vector3f::new(0.0, 0.0, 1.0),
+                Medium {},
+                f64::INFINITY,
+                0.0,
+            );
+
+            // Leaf sizes are a histogram indexed by primitive count.
+            assert!(bvh.stats().leaf_sizes.len() <= 5);
+            assert!(bvh.intersect(&mut ray).is_some());
+            assert!((ray.t_max - 4.0).abs() < EPSILON);
+        }
+    }
+
+    #[test]
+    fn deep_trees_are_traversed() {
+        // Each sphere sits at 0.7 times the distance of the last, so middle
+        // splits peel off one sphere per level.
+        let primitives: Vec<Arc<dyn Primitive>> = (0..200)
+            .map(|i| {
+                let x = 1000.0 * 0.7f64.powi(i);
+                let sphere = Sphere::full(Transform::translate(Vector3f::new(x, 0.0, 0.0)), 1e-3);
+
+                Arc::new(GeometricPrimitive::new(Arc::new(sphere), None, None, None))
+                    as Arc<dyn Primitive>
+            })
+            .collect();
+        let bvh = BvhAccel::new(primitives.clone(), 1, SplitMethod::Middle);
+
+        assert!(bvh.stats().max_depth > TRAVERSAL_STACK_CAPACITY);
+
+        let mut rays: Vec<Ray> = (0..40)
+            .map(|i| {
+                Ray::new(
+                    Point3f::new(1000.0 * 0.7f64.powi(i), 0.0, -5.0),
+                    Vector3f::new(0.0, 0.0, 1.0),
+                    Medium {},
+                    f64::INFINITY,
+                    0.0,
+                )
+            })
+            .collect();
+        let hits = bvh.intersect_many(&mut rays.clone());
+
+        for (ray, hit) in rays.iter_mut().zip(hits) {
+            let mut expected = *ray;
+
+            assert!(brute_force(&primitives, &mut expected).is_some());
+            assert!(bvh.intersect_p(ray));
+            assert!(bvh.intersect(ray).is_some());
+            assert!(hit.is_some());
+            assert!((ray.t_max - expected.t_max).abs() < EPSILON);
+        }
+    }
+
+    #[test]
+    fn empty_bvh_misses() {
+        let bvh = BvhAccel::new(Vec::new(), 4, SplitMethod::Sah);
+        let mut ray = random_ray(0);
+
+        assert!(bvh.intersect(&mut ray).is_none());
+        assert!(!bvh.intersect_p(&ray));
+        assert!(ray.t_max.is_infinite());
+    }
+
+    /// A pinhole camera's rays through a `16 x 16` tile, so that consecutive
+    /// rays are coherent.
+    fn tile_ray(i: usize) -> Ray {
+        let o = Point3f::new(0.5, -0.5, -25.0);
+        let (x, y) = ((i % 16) as f64 / 16.0, (i / 16) as f64 / 16.0);
+        let d = Vector3f::new(0.4 * x - 0.1, 0.4 * y - 0.2, 1.0);
+
+        Ray::new(o, d, Medium {}, f64::INFINITY, 0.0)
+    }
+
+    fn check_intersect_many(bvh: &BvhAccel, rays: Vec<Ray>) -> usize {
+        let mut expected_rays = rays.clone();
+        let mut batch = rays;
+
+        let hits = bvh.intersect_many(&mut batch);
+        let mut n_hits = 0;
+
+        assert_eq!(hits.len(), batch.len());
+
+        for (i, hit) in hits.into_iter().enumerate() {
+            let expected = bvh.intersect(&mut expected_rays[i]);
+
+            assert_eq!(expected.is_some(), hit.is_some());
+
+            if let (Some(expected), Some(hit)) = (expected, hit) {
+                n_hits += 1;
+                assert!((expected_rays[i].t_max - batch[i].t_max).abs() < EPSILON);
+                assert!((expected.p - hit.p).length() < EPSILON);
+            }
+        }
+
+        n_hits
+    }
+
+    #[test]
+    fn intersect_many_matches_single_rays() {
+        let bvh = BvhAccel::new(sphere_field(200), 4, SplitMethod::Sah);
+
+        // Coherent rays take the packet path, scattered ones fall back.
+        assert!(check_intersect_many(&bvh, (0..256).map(tile_ray).collect()) > 20);
+        assert!(check_intersect_many(&bvh, (0..250).map(random_ray).collect()) > 20);
+
+        // Packets that only partly agree in direction, and a short batch.
+        let mixed = (0..64)
+            .map(|i| {
+                if i % 7 == 0 {
+                    random_ray(i)
+                } else {
+                    tile_ray(4 * i)
+                }
+            })
+            .collect();
+        check_intersect_many(&bvh, mixed);
+        check_intersect_many(&bvh, (0..5).map(tile_ray).collect());
+    }
+
+    #[test]
+    fn inactive_lanes_are_untouched() {
+        let bvh = BvhAccel::new(sphere_field(200), 4, SplitMethod::Sah);
+        let rays: Vec<Ray> = (0..8).map(|i| tile_ray(8 * 16 + 2 * i)).collect();
+        let mut packet = RayPacket8::new(&rays);
+        packet.active = 0b0101_0101;
+
+        let hits = bvh.intersect_packet(&mut packet);
+
+        for lane in 0..8 {
+            let mut ray = rays[lane];
+            let expected = bvh.intersect(&mut ray);
+
+            if packet.is_active(lane) {
+                assert_eq!(expected.is_some(), hits[lane].is_some());
+                assert!(ray.t_max == packet.rays[lane].t_max);
+            } else {
+                assert!(hits[lane].is_none());
+                assert!(packet.rays[lane].t_max == f64::INFINITY);
+            }
+        }
+    }
+}
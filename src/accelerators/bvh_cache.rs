@@ -0,0 +1,482 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+use std::process;
+use std::sync::Arc;
+
+use memmap2::Mmap;
+
+use accelerators::bvh::{BvhAccel, LinearBvhNode, SplitMethod};
+use core::primitive::Primitive;
+use core::utils::mix_bits;
+
+use core::Bounds3f;
+use core::Point3f;
+
+const MAGIC: &[u8; 8] = b"LUMBVH\0\0";
+/// Bumped whenever the layout below or the builders' output changes, so
+/// caches written by older versions are rebuilt instead of misread.
+pub const VERSION: u32 = 1;
+
+// Header: magic, version, max primitives per node, content key, node count
+// and primitive reference count, all little endian.
+const HEADER_SIZE: usize = 8 + 4 + 4 + 8 + 8 + 8;
+// Node: six bounds coordinates, offset, primitive count, axis and padding.
+const NODE_SIZE: usize = 6 * 8 + 8 + 2 + 1 + 5;
+const REFERENCE_SIZE: usize = 8;
+
+/// The key a cache is stored under: a hash of every primitive's world bound
+/// together with the build parameters. The builders only look at the bounds,
+/// except for the clipping done by `SplitMethod::Sbvh`, which is why SBVH
+/// builds are never cached.
+pub fn cache_key(
+    primitives: &[Arc<dyn Primitive>],
+    max_prims_in_node: usize,
+    split_method: SplitMethod,
+) -> u64 {
+    let method = match split_method {
+        SplitMethod::Sah => 0,
+        SplitMethod::Middle => 1,
+        SplitMethod::EqualCounts => 2,
+        SplitMethod::Hlbvh => 3,
+        SplitMethod::Sbvh => 4,
+    };
+
+    let key = [
+        VERSION as u64,
+        max_prims_in_node.clamp(1, 255) as u64,
+        method,
+        primitives.len() as u64,
+    ]
+    .iter()
+    .fold(0, |hash, &v| mix_bits(hash ^ v));
+
+    primitives.iter().fold(key, |hash, primitive| {
+        let b = primitive.world_bound();
+
+        [
+            b.p_min.x, b.p_min.y, b.p_min.z, b.p_max.x, b.p_max.y, b.p_max.z,
+        ]
+        .iter()
+        .fold(hash, |hash, v| mix_bits(hash ^ v.to_bits()))
+    })
+}
+
+/// Loads the BVH cached at `path` if it was built from `primitives` with the
+/// same parameters, and otherwise builds it and replaces the cache. A cache
+/// that cannot be written only costs the next render a rebuild, so write
+/// errors are ignored. `SplitMethod::Sbvh` always builds.
+pub fn load_or_build<P: AsRef<Path>>(
+    path: P,
+    primitives: Vec<Arc<dyn Primitive>>,
+    max_prims_in_node: usize,
+    split_method: SplitMethod,
+) -> BvhAccel {
+    if split_method == SplitMethod::Sbvh {
+        return BvhAccel::new(primitives, max_prims_in_node, split_method);
+    }
+
+    if let Ok(bvh) = load(&path, &primitives, max_prims_in_node, split_method) {
+        return bvh;
+    }
+
+    let bvh = BvhAccel::new(primitives.clone(), max_prims_in_node, split_method);
+    let _ = save(&path, &bvh, &primitives);
+
+    bvh
+}
+
+/// Maps a cache written by `save` into memory and reads it. Fails with
+/// `InvalidData` if the file was written by another version, for other
+/// primitives or parameters, or is malformed, and with `InvalidInput` for
+/// `SplitMethod::Sbvh`.
+pub fn load<P: AsRef<Path>>(
+    path: P,
+    primitives: &[Arc<dyn Primitive>],
+    max_prims_in_node: usize,
+    split_method: SplitMethod,
+) -> io::Result<BvhAccel> {
+    if split_method == SplitMethod::Sbvh {
+        return Err(uncacheable());
+    }
+
+    // Safe as long as nothing truncates the file while it is mapped. `save`
+    // only ever renames a finished file over it, which leaves this mapping
+    // on the old contents.
+    let file = File::open(path)?;
+    let bytes = unsafe { Mmap::map(&file)? };
+
+    if bytes.len() < HEADER_SIZE || &bytes[0..8] != MAGIC {
+        return Err(invalid_data("not a BVH cache"));
+    }
+
+    let mut reader = Reader {
+        bytes: &bytes,
+        pos: 8,
+    };
+
+    if reader.u32() != VERSION {
+        return Err(invalid_data("BVH cache version mismatch"));
+    }
+
+    let max_prims_in_node = max_prims_in_node.clamp(1, 255);
+
+    if reader.u32() as usize != max_prims_in_node
+        || reader.u64() != cache_key(primitives, max_prims_in_node, split_method)
+    {
+        return Err(invalid_data("stale BVH cache"));
+    }
+
+    let n_nodes = reader.u64() as usize;
+    let n_references = reader.u64() as usize;
+
+    let expected_size = n_nodes
+        .checked_mul(NODE_SIZE)
+        .and_then(|n| n.checked_add(n_references.checked_mul(REFERENCE_SIZE)?))
+        .and_then(|n| n.checked_add(HEADER_SIZE));
+
+    if expected_size != Some(bytes.len()) {
+        return Err(invalid_data("truncated BVH cache"));
+    }
+
+    let mut nodes = Vec::with_capacity(n_nodes);
+
+    for i in 0..n_nodes {
+        let p_min = Point3f::new(reader.f64(), reader.f64(), reader.f64());
+        let p_max = Point3f::new(reader.f64(), reader.f64(), reader.f64());
+        let node = LinearBvhNode {
+            bounds: Bounds3f { p_min, p_max },
+            offset: reader.u64() as usize,
+            n_primitives: reader.u16(),
+            axis: reader.u8(),
+        };
+        reader.pos += 5;
+
+        let valid = if node.is_leaf() {
+            node.offset
+                .checked_add(node.n_primitives as usize)
+                .is_some_and(|end| end <= n_references)
+        } else {
+            node.offset > i + 1 && node.offset < n_nodes && node.axis < 3
+        };
+
+        if !valid {
+            return Err(invalid_data("malformed BVH cache node"));
+        }
+
+        nodes.push(node);
+    }
+
+    let mut ordered = Vec::with_capacity(n_references);
+
+    for _ in 0..n_references {
+        let index = reader.u64() as usize;
+
+        if index >= primitives.len() {
+            return Err(invalid_data("malformed BVH cache primitive index"));
+        }
+
+        ordered.push(primitives[index].clone());
+    }
+
+    Ok(BvhAccel::from_nodes(
+        ordered,
+        nodes,
+        max_prims_in_node,
+        split_method,
+    ))
+}
+
+/// Writes `bvh`, which must have been built from `primitives`, to `path`.
+/// The file is written next to `path` and renamed into place, so concurrent
+/// renders never see a partial cache. SBVH trees depend on more than the
+/// bounds the key covers and are refused with `InvalidInput`.
+pub fn save<P: AsRef<Path>>(
+    path: P,
+    bvh: &BvhAccel,
+    primitives: &[Arc<dyn Primitive>],
+) -> io::Result<()> {
+    if bvh.split_method == SplitMethod::Sbvh {
+        return Err(uncacheable());
+    }
+
+    // Leaves hold clones of the input Arcs, so their addresses give back the
+    // input order.
+    let mut by_address: Vec<(usize, usize)> = primitives
+        .iter()
+        .enumerate()
+        .map(|(i, p)| (address(p), i))
+        .collect();
+    by_address.sort_unstable();
+
+    let mut bytes = Vec::with_capacity(
+        HEADER_SIZE + bvh.nodes.len() * NODE_SIZE + bvh.primitives.len() * REFERENCE_SIZE,
+    );
+
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    bytes.extend_from_slice(&(bvh.max_prims_in_node as u32).to_le_bytes());
+    bytes.extend_from_slice(
+        &cache_key(primitives, bvh.max_prims_in_node, bvh.split_method).to_le_bytes(),
+    );
+    bytes.extend_from_slice(&(bvh.nodes.len() as u64).to_le_bytes());
+    bytes.extend_from_slice(&(bvh.primitives.len() as u64).to_le_bytes());
+
+    for node in &bvh.nodes {
+        let b = node.bounds;
+
+        for v in &[
+            b.p_min.x, b.p_min.y, b.p_min.z, b.p_max.x, b.p_max.y, b.p_max.z,
+        ] {
+            bytes.extend_from_slice(&v.to_le_bytes());
+        }
+
+        bytes.extend_from_slice(&(node.offset as u64).to_le_bytes());
+        bytes.extend_from_slice(&node.n_primitives.to_le_bytes());
+        bytes.push(node.axis);
+        bytes.extend_from_slice(&[0; 5]);
+    }
+
+    for primitive in &bvh.primitives {
+        let index = by_address
+            .binary_search_by_key(&address(primitive), |&(a, _)| a)
+            .map(|i| by_address[i].1)
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "BVH was not built from these primitives",
+                )
+            })?;
+
+        bytes.extend_from_slice(&(index as u64).to_le_bytes());
+    }
+
+    let path = path.as_ref();
+    // Renders sharing a cache write their own temporary files, so none of
+    // them renames another's half written one into place.
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(format!(".{}.tmp", process::id()));
+
+    File::create(&temporary)?.write_all(&bytes)?;
+    fs::rename(&temporary, path)
+}
+
+fn address(primitive: &Arc<dyn Primitive>) -> usize {
+    Arc::as_ptr(primitive) as *const () as usize
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn uncacheable() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "SBVH builds depend on geometry the cache key does not cover",
+    )
+}
+
+/// Reads little endian values from a buffer whose size was checked up front.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take<const N: usize>(&mut self) -> [u8; N] {
+        let mut b = [0; N];
+        b.copy_from_slice(&self.bytes[self.pos..self.pos + N]);
+        self.pos += N;
+
+        b
+    }
+
+    fn u8(&mut self) -> u8 {
+        self.take::<1>()[0]
+    }
+
+    fn u16(&mut self) -> u16 {
+        u16::from_le_bytes(self.take())
+    }
+
+    fn u32(&mut self) -> u32 {
+        u32::from_le_bytes(self.take())
+    }
+
+    fn u64(&mut self) -> u64 {
+        u64::from_le_bytes(self.take())
+    }
+
+    fn f64(&mut self) -> f64 {
+        f64::from_bits(self.u64())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::f64;
+
+    use core::medium::Medium;
+    use core::primitive::GeometricPrimitive;
+    use core::ray::Ray;
+    use core::transform::Transform;
+    use core::utils;
+    use shapes::sphere::Sphere;
+
+    use core::Vector3f;
+
+    const EPSILON: f64 = 0.00001;
+
+    fn random(i: usize, k: f64) -> f64 {
+        utils::hash_float(&[i as f64, k])
+    }
+
+    fn spheres(n: usize, seed: f64) -> Vec<Arc<dyn Primitive>> {
+        (0..n)
+            .map(|i| {
+                let center = Vector3f::new(
+                    20.0 * random(i, seed) - 10.0,
+                    20.0 * random(i, seed + 1.0) - 10.0,
+                    20.0 * random(i, seed + 2.0) - 10.0,
+                );
+                let sphere = Sphere::full(Transform::translate(center), 0.5);
+
+                Arc::new(GeometricPrimitive::new(Arc::new(sphere), None, None, None))
+                    as Arc<dyn Primitive>
+            })
+            .collect()
+    }
+
+    fn cache_path(name: &str) -> ::std::path::PathBuf {
+        env::temp_dir().join(format!("luminary-{}-{}.bvh", name, ::std::process::id()))
+    }
+
+    #[test]
+    fn round_trip_matches_the_built_tree() {
+        let path = cache_path("round-trip");
+        let primitives = spheres(300, 0.0);
+
+        for &method in &[SplitMethod::Sah, SplitMethod::Hlbvh] {
+            let built = load_or_build(&path, primitives.clone(), 4, method);
+            let loaded = load(&path, &primitives, 4, method).unwrap();
+
+            assert_eq!(built.nodes.len(), loaded.nodes.len());
+            assert_eq!(built.built_costs, loaded.built_costs);
+
+            for (a, b) in built.nodes.iter().zip(&loaded.nodes) {
+                assert!((a.bounds.p_min - b.bounds.p_min).length() < EPSILON);
+                assert!((a.bounds.p_max - b.bounds.p_max).length() < EPSILON);
+                assert_eq!(
+                    (a.offset, a.n_primitives, a.axis),
+                    (b.offset, b.n_primitives, b.axis)
+                );
+            }
+
+            for (a, b) in built.primitives.iter().zip(&loaded.primitives) {
+                assert!(Arc::ptr_eq(a, b));
+            }
+
+            for i in 0..200 {
+                let o = Point3f::new(
+                    40.0 * random(i, 10.0) - 20.0,
+                    40.0 * random(i, 11.0) - 20.0,
+                    -30.0,
+                );
+                let mut expected = Ray::new(
+                    o,
+                    Vector3f::new(0.0, 0.0, 1.0),
+                    Medium {},
+                    f64::INFINITY,
+                    0.0,
+                );
+                let mut ray = expected;
+
+                let hit = built.intersect(&mut expected).is_some();
+
+                assert_eq!(hit, loaded.intersect(&mut ray).is_some());
+
+                if hit {
+                    assert!((expected.t_max - ray.t_max).abs() < EPSILON);
+                }
+            }
+        }
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn sbvh_builds_are_not_cached() {
+        let path = cache_path("sbvh");
+        let primitives = spheres(100, 0.0);
+
+        let bvh = load_or_build(&path, primitives.clone(), 4, SplitMethod::Sbvh);
+
+        assert!(!path.exists());
+        assert!(save(&path, &bvh, &primitives).is_err());
+        assert!(!path.exists());
+        assert!(load(&path, &primitives, 4, SplitMethod::Sbvh).is_err());
+    }
+
+    #[test]
+    fn changes_invalidate_the_cache() {
+        let path = cache_path("invalidate");
+        let primitives = spheres(100, 0.0);
+
+        load_or_build(&path, primitives.clone(), 4, SplitMethod::Sah);
+        assert!(load(&path, &primitives, 4, SplitMethod::Sah).is_ok());
+
+        // Other parameters or moved geometry must not pick up the cache.
+        assert!(load(&path, &primitives, 2, SplitMethod::Sah).is_err());
+        assert!(load(&path, &primitives, 4, SplitMethod::Middle).is_err());
+
+        let moved = spheres(100, 20.0);
+        assert!(load(&path, &moved, 4, SplitMethod::Sah).is_err());
+
+        // Rebuilding replaces the stale cache.
+        let rebuilt = load_or_build(&path, moved.clone(), 4, SplitMethod::Sah);
+        let loaded = load(&path, &moved, 4, SplitMethod::Sah).unwrap();
+        assert_eq!(rebuilt.nodes.len(), loaded.nodes.len());
+
+        // So does a cache from another version.
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[8..12].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        fs::write(&path, &bytes).unwrap();
+        assert!(load(&path, &moved, 4, SplitMethod::Sah).is_err());
+
+        // And a truncated one.
+        fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+        assert!(load(&path, &moved, 4, SplitMethod::Sah).is_err());
+
+        load_or_build(&path, moved.clone(), 4, SplitMethod::Sah);
+        assert!(load(&path, &moved, 4, SplitMethod::Sah).is_ok());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn malformed_caches_are_rejected() {
+        let path = cache_path("malformed");
+        let primitives = spheres(100, 0.0);
+
+        load_or_build(&path, primitives.clone(), 4, SplitMethod::Sah);
+        let bytes = fs::read(&path).unwrap();
+
+        // A leaf whose primitive range wraps around must not pass the bounds
+        // check.
+        let leaf = (0..)
+            .map(|i| HEADER_SIZE + i * NODE_SIZE)
+            .find(|&at| bytes[at + 56..at + 58] != [0, 0])
+            .unwrap();
+        let mut wrapped = bytes.clone();
+        wrapped[leaf + 48..leaf + 56].copy_from_slice(&u64::MAX.to_le_bytes());
+        fs::write(&path, &wrapped).unwrap();
+        assert!(load(&path, &primitives, 4, SplitMethod::Sah).is_err());
+
+        fs::write(&path, &[]).unwrap();
+        assert!(load(&path, &primitives, 4, SplitMethod::Sah).is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
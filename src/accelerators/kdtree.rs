@@ -0,0 +1,652 @@
+use std::mem;
+use std::sync::Arc;
+
+use accelerators::stats::{self, Accelerator, NodeInfo};
+use core::interaction::SurfaceInteraction;
+use core::light::AreaLight;
+use core::material::Material;
+use core::primitive::Primitive;
+use core::ray::Ray;
+
+use core::Bounds3f;
+use core::Vector3f;
+
+const MAX_TODO: usize = 64;
+const LEAF: u32 = 3;
+
+/// A kd-tree node packed into 8 bytes. The low two bits of `flags` hold the
+/// split axis, or 3 for leaves, and the rest the primitive count of a leaf
+/// or the index of an interior node's above child; its below child directly
+/// follows it. `data` is the split position as `f32` bits, the primitive
+/// of a leaf with just one, or an offset into `primitive_indices`.
+#[derive(Clone, Copy, Debug)]
+pub struct KdAccelNode {
+    data: u32,
+    flags: u32,
+}
+
+impl KdAccelNode {
+    pub fn leaf(prim_nums: &[usize], primitive_indices: &mut Vec<usize>) -> Self {
+        let data = match prim_nums.len() {
+            0 => 0,
+            1 => prim_nums[0] as u32,
+            _ => {
+                let offset = primitive_indices.len() as u32;
+                primitive_indices.extend_from_slice(prim_nums);
+                offset
+            }
+        };
+
+        Self {
+            data,
+            flags: LEAF | (prim_nums.len() as u32) << 2,
+        }
+    }
+
+    pub fn interior(axis: usize, above_child: usize, split: f32) -> Self {
+        Self {
+            data: split.to_bits(),
+            flags: axis as u32 | (above_child as u32) << 2,
+        }
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        self.flags & 3 == LEAF
+    }
+
+    pub fn split_axis(&self) -> usize {
+        (self.flags & 3) as usize
+    }
+
+    pub fn split_pos(&self) -> f64 {
+        f64::from(f32::from_bits(self.data))
+    }
+
+    pub fn n_primitives(&self) -> usize {
+        (self.flags >> 2) as usize
+    }
+
+    pub fn above_child(&self) -> usize {
+        (self.flags >> 2) as usize
+    }
+
+    /// Orders the children of the interior node at `node_index` as the ray
+    /// reaches them, along with the ray distance to the split plane.
+    fn children_along(
+        &self,
+        ray: &Ray,
+        inv_dir: Vector3f,
+        node_index: usize,
+    ) -> (usize, usize, f64) {
+        let axis = self.split_axis();
+        let split = self.split_pos();
+        let t_plane = (split - ray.o[axis]) * inv_dir[axis];
+
+        let below_first = ray.o[axis] < split || (ray.o[axis] == split && ray.d[axis] <= 0.0);
+
+        if below_first {
+            (node_index + 1, self.above_child(), t_plane)
+        } else {
+            (self.above_child(), node_index + 1, t_plane)
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+enum EdgeType {
+    Start,
+    End,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct BoundEdge {
+    t: f64,
+    edge_type: EdgeType,
+}
+
+pub struct KdTreeAccel {
+    pub isect_cost: f64,
+    pub traversal_cost: f64,
+    pub max_prims: usize,
+    pub empty_bonus: f64,
+    pub primitives: Vec<Arc<dyn Primitive>>,
+    pub primitive_indices: Vec<usize>,
+    pub nodes: Vec<KdAccelNode>,
+    pub bounds: Bounds3f,
+}
+
+impl KdTreeAccel {
+    /// Builds the tree with the SAH, where `empty_bonus` in [0, 1] favours
+    /// splits that cut off empty space. Without a `max_depth` it is chosen
+    /// from the primitive count.
+    pub fn new(
+        primitives: Vec<Arc<dyn Primitive>>,
+        isect_cost: f64,
+        traversal_cost: f64,
+        empty_bonus: f64,
+        max_prims: usize,
+        max_depth: Option<usize>,
+    ) -> Self {
+        let max_depth = max_depth
+            .unwrap_or_else(|| {
+                (8.0 + 1.3 * (primitives.len().max(1) as f64).log2()).round() as usize
+            })
+            .min(MAX_TODO);
+
+        let prim_bounds: Vec<Bounds3f> = primitives.iter().map(|p| p.world_bound()).collect();
+        let bounds = prim_bounds
+            .iter()
+            .fold(Bounds3f::zero(), |b, &prim| b.union_bounds(prim));
+
+        let mut accel = Self {
+            isect_cost,
+            traversal_cost,
+            max_prims,
+            empty_bonus,
+            primitives,
+            primitive_indices: Vec::new(),
+            nodes: Vec::new(),
+            bounds,
+        };
+
+        if !accel.primitives.is_empty() {
+            let prim_nums: Vec<usize> = (0..accel.primitives.len()).collect();
+            accel.build_tree(bounds, &prim_bounds, &prim_nums, max_depth, 0);
+        }
+
+        accel
+    }
+
+    fn build_tree(
+        &mut self,
+        node_bounds: Bounds3f,
+        all_prim_bounds: &[Bounds3f],
+        prim_nums: &[usize],
+        depth: usize,
+        mut bad_refines: usize,
+    ) {
+        let node_num = self.nodes.len();
+        let n_primitives = prim_nums.len();
+
+        if n_primitives <= self.max_prims || depth == 0 {
+            let node = KdAccelNode::leaf(prim_nums, &mut self.primitive_indices);
+            self.nodes.push(node);
+            return;
+        }
+
+        // Look for the cheapest split among the primitive bound edges,
+        // trying the other axes if the longest has none inside the node.
+        let mut best_axis = None;
+        let mut best_split = 0.0;
+        let mut best_cost = f64::INFINITY;
+        let old_cost = self.isect_cost * n_primitives as f64;
+        let inv_total_sa = 1.0 / node_bounds.surface_area();
+        let d = node_bounds.diagonal();
+        let mut axis = node_bounds.maximum_extent();
+
+        for _ in 0..3 {
+            let mut edges: Vec<BoundEdge> = Vec::with_capacity(2 * n_primitives);
+
+            for &prim_num in prim_nums {
+                let bounds = all_prim_bounds[prim_num];
+
+                edges.push(BoundEdge {
+                    t: bounds.p_min[axis],
+                    edge_type: EdgeType::Start,
+                });
+                edges.push(BoundEdge {
+                    t: bounds.p_max[axis],
+                    edge_type: EdgeType::End,
+                });
+            }
+
+            edges.sort_by(|e0, e1| {
+                (e0.t, e0.edge_type)
+                    .partial_cmp(&(e1.t, e1.edge_type))
+                    .unwrap()
+            });
+
+            let mut n_below = 0;
+            let mut n_above = n_primitives;
+            let other_axis0 = (axis + 1) % 3;
+            let other_axis1 = (axis + 2) % 3;
+
+            for edge in &edges {
+                if edge.edge_type == EdgeType::End {
+                    n_above -= 1;
+                }
+
+                let t = edge.t;
+
+                if t > node_bounds.p_min[axis] && t < node_bounds.p_max[axis] {
+                    let cap_area = d[other_axis0] * d[other_axis1];
+                    let side_length = d[other_axis0] + d[other_axis1];
+                    let below_sa = 2.0 * (cap_area + (t - node_bounds.p_min[axis]) * side_length);
+                    let above_sa = 2.0 * (cap_area + (node_bounds.p_max[axis] - t) * side_length);
+                    let p_below = below_sa * inv_total_sa;
+                    let p_above = above_sa * inv_total_sa;
+                    let eb = if n_above == 0 || n_below == 0 {
+                        self.empty_bonus
+                    } else {
+                        0.0
+                    };
+                    let cost = self.traversal_cost
+                        + self.isect_cost
+                            * (1.0 - eb)
+                            * (p_below * n_below as f64 + p_above * n_above as f64);
+
+                    if cost < best_cost {
+                        best_cost = cost;
+                        best_axis = Some(axis);
+                        best_split = t;
+                    }
+                }
+
+                if edge.edge_type == EdgeType::Start {
+                    n_below += 1;
+                }
+            }
+
+            if best_axis.is_some() {
+                break;
+            }
+
+            axis = (axis + 1) % 3;
+        }
+
+        if best_cost > old_cost {
+            bad_refines += 1;
+        }
+
+        let axis = match best_axis {
+            Some(axis) if !(best_cost > 4.0 * old_cost && n_primitives < 16) && bad_refines < 3 => {
+                axis
+            }
+            _ => {
+                let node = KdAccelNode::leaf(prim_nums, &mut self.primitive_indices);
+                self.nodes.push(node);
+                return;
+            }
+        };
+
+        // Nodes store the split as an `f32`, so classify against the rounded
+        // plane that traversal will see. Primitives lying in it go to both.
+        let split = best_split as f32;
+        let t_split = f64::from(split);
+        let mut prims0 = Vec::new();
+        let mut prims1 = Vec::new();
+
+        for &prim_num in prim_nums {
+            let bounds = all_prim_bounds[prim_num];
+            let below = bounds.p_min[axis] < t_split;
+            let above = bounds.p_max[axis] > t_split;
+
+            if below || !above {
+                prims0.push(prim_num);
+            }
+
+            if above || !below {
+                prims1.push(prim_num);
+            }
+        }
+
+        let mut bounds0 = node_bounds;
+        let mut bounds1 = node_bounds;
+        bounds0.p_max[axis] = t_split;
+        bounds1.p_min[axis] = t_split;
+
+        self.nodes.push(KdAccelNode::interior(axis, 0, split));
+        self.build_tree(bounds0, all_prim_bounds, &prims0, depth - 1, bad_refines);
+
+        let above_child = self.nodes.len();
+        self.nodes[node_num] = KdAccelNode::interior(axis, above_child, split);
+        self.build_tree(bounds1, all_prim_bounds, &prims1, depth - 1, bad_refines);
+    }
+
+    /// Calls `f` on each primitive of the leaf `node`, stopping early once it
+    /// returns `true`.
+    fn any_leaf_primitive<F>(&self, node: &KdAccelNode, mut f: F) -> bool
+    where
+        F: FnMut(&Arc<dyn Primitive>) -> bool,
+    {
+        stats::count_primitive_tests(node.n_primitives());
+
+        match node.n_primitives() {
+            0 => false,
+            1 => f(&self.primitives[node.data as usize]),
+            n => {
+                let offset = node.data as usize;
+
+                self.primitive_indices[offset..offset + n]
+                    .iter()
+                    .any(|&prim_num| f(&self.primitives[prim_num]))
+            }
+        }
+    }
+}
+
+impl Primitive for KdTreeAccel {
+    fn world_bound(&self) -> Bounds3f {
+        self.bounds
+    }
+
+    fn intersect(&self, ray: &mut Ray) -> Option<SurfaceInteraction> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+
+        let (mut t_min, mut t_max) = self.bounds.intersect_p(ray)?;
+
+        let inv_dir = Vector3f::new(1.0 / ray.d.x, 1.0 / ray.d.y, 1.0 / ray.d.z);
+        let mut todo = [(0, 0.0, 0.0); MAX_TODO];
+        let mut todo_pos = 0;
+        let mut node_index = 0;
+        let mut isect = None;
+
+        loop {
+            // Stop once a hit is closer than anything left to visit.
+            if ray.t_max < t_min {
+                break;
+            }
+
+            let node = self.nodes[node_index];
+            stats::count_node_visit();
+
+            if !node.is_leaf() {
+                let (first_child, second_child, t_plane) =
+                    node.children_along(ray, inv_dir, node_index);
+
+                if t_plane > t_max || t_plane <= 0.0 {
+                    node_index = first_child;
+                } else if t_plane < t_min {
+                    node_index = second_child;
+                } else {
+                    todo[todo_pos] = (second_child, t_plane, t_max);
+                    todo_pos += 1;
+                    node_index = first_child;
+                    t_max = t_plane;
+                }
+
+                continue;
+            }
+
+            self.any_leaf_primitive(&node, |primitive| {
+                if let Some(hit) = primitive.intersect(ray) {
+                    isect = Some(hit);
+                }
+
+                false
+            });
+
+            if todo_pos == 0 {
+                break;
+            }
+
+            todo_pos -= 1;
+            let (next, next_t_min, next_t_max) = todo[todo_pos];
+            node_index = next;
+            t_min = next_t_min;
+            t_max = next_t_max;
+        }
+
+        isect
+    }
+
+    fn intersect_p(&self, ray: &Ray) -> bool {
+        if self.nodes.is_empty() {
+            return false;
+        }
+
+        let (mut t_min, mut t_max) = match self.bounds.intersect_p(ray) {
+            Some(t) => t,
+            None => return false,
+        };
+
+        let inv_dir = Vector3f::new(1.0 / ray.d.x, 1.0 / ray.d.y, 1.0 / ray.d.z);
+        let mut todo = [(0, 0.0, 0.0); MAX_TODO];
+        let mut todo_pos = 0;
+        let mut node_index = 0;
+
+        loop {
+            let node = self.nodes[node_index];
+            stats::count_node_visit();
+
+            if !node.is_leaf() {
+                let (first_child, second_child, t_plane) =
+                    node.children_along(ray, inv_dir, node_index);
+
+                if t_plane > t_max || t_plane <= 0.0 {
+                    node_index = first_child;
+                } else if t_plane < t_min {
+                    node_index = second_child;
+                } else {
+                    todo[todo_pos] = (second_child, t_plane, t_max);
+                    todo_pos += 1;
+                    node_index = first_child;
+                    t_max = t_plane;
+                }
+
+                continue;
+            }
+
+            if self.any_leaf_primitive(&node, |primitive| primitive.intersect_p(ray)) {
+                return true;
+            }
+
+            if todo_pos == 0 {
+                break;
+            }
+
+            todo_pos -= 1;
+            let (next, next_t_min, next_t_max) = todo[todo_pos];
+            node_index = next;
+            t_min = next_t_min;
+            t_max = next_t_max;
+        }
+
+        false
+    }
+
+    fn get_area_light(&self) -> Option<Arc<dyn AreaLight>> {
+        None
+    }
+
+    fn get_material(&self) -> Option<Arc<dyn Material>> {
+        None
+    }
+}
+
+impl Accelerator for KdTreeAccel {
+    fn visit_nodes(&self, visit: &mut dyn FnMut(NodeInfo)) {
+        let mut nodes_to_visit = Vec::new();
+
+        if !self.nodes.is_empty() {
+            nodes_to_visit.push((0, 0, self.bounds));
+        }
+
+        while let Some((i, depth, bounds)) = nodes_to_visit.pop() {
+            let node = self.nodes[i];
+
+            if node.is_leaf() {
+                visit(NodeInfo {
+                    depth,
+                    bounds,
+                    n_primitives: Some(node.n_primitives()),
+                });
+
+                continue;
+            }
+
+            visit(NodeInfo {
+                depth,
+                bounds,
+                n_primitives: None,
+            });
+
+            let axis = node.split_axis();
+            let mut below = bounds;
+            let mut above = bounds;
+            below.p_max[axis] = node.split_pos();
+            above.p_min[axis] = node.split_pos();
+
+            nodes_to_visit.push((node.above_child(), depth + 1, above));
+            nodes_to_visit.push((i + 1, depth + 1, below));
+        }
+    }
+
+    fn memory_bytes(&self) -> usize {
+        self.nodes.len() * mem::size_of::<KdAccelNode>()
+            + self.primitive_indices.len() * mem::size_of::<usize>()
+            + self.primitives.len() * mem::size_of::<Arc<dyn Primitive>>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64;
+    use std::mem;
+
+    use core::medium::Medium;
+    use core::primitive::GeometricPrimitive;
+    use core::transform::Transform;
+    use core::utils;
+    use shapes::sphere::Sphere;
+    use shapes::triangle::{Triangle, TriangleMesh};
+
+    use core::Point3f;
+
+    const EPSILON: f64 = 0.0001;
+
+    fn random(i: usize, k: f64) -> f64 {
+        utils::hash_float(&[i as f64, k])
+    }
+
+    fn scene() -> Vec<Arc<dyn Primitive>> {
+        let mut primitives: Vec<Arc<dyn Primitive>> = (0..200)
+            .map(|i| {
+                let center = Vector3f::new(
+                    20.0 * random(i, 0.0) - 10.0,
+                    20.0 * random(i, 1.0) - 10.0,
+                    20.0 * random(i, 2.0) - 10.0,
+                );
+                let sphere = Sphere::full(Transform::translate(center), 0.2 + 0.5 * random(i, 3.0));
+
+                Arc::new(GeometricPrimitive::new(Arc::new(sphere), None, None, None))
+                    as Arc<dyn Primitive>
+            })
+            .collect();
+
+        // An axis-aligned floor, whose flat bounds lie exactly in a plane.
+        let mesh = Arc::new(TriangleMesh::new(
+            Transform::new(),
+            false,
+            vec![0, 1, 2, 0, 2, 3],
+            vec![
+                Point3f::new(-12.0, -12.0, 0.0),
+                Point3f::new(12.0, -12.0, 0.0),
+                Point3f::new(12.0, 12.0, 0.0),
+                Point3f::new(-12.0, 12.0, 0.0),
+            ],
+            None,
+            None,
+            None,
+        ));
+
+        for i in 0..2 {
+            let triangle = Triangle::new(mesh.clone(), i);
+            primitives.push(Arc::new(GeometricPrimitive::new(
+                Arc::new(triangle),
+                None,
+                None,
+                None,
+            )));
+        }
+
+        primitives
+    }
+
+    fn random_ray(i: usize) -> Ray {
+        let o = Point3f::new(
+            30.0 * random(i, 10.0) - 15.0,
+            30.0 * random(i, 11.0) - 15.0,
+            30.0 * random(i, 12.0) - 15.0,
+        );
+        let target = Point3f::new(
+            10.0 * random(i, 13.0) - 5.0,
+            10.0 * random(i, 14.0) - 5.0,
+            10.0 * random(i, 15.0) - 5.0,
+        );
+
+        Ray::new(o, target - o, Medium {}, f64::INFINITY, 0.0)
+    }
+
+    fn check_matches_brute_force(accel: &KdTreeAccel, primitives: &[Arc<dyn Primitive>]) {
+        let mut hits = 0;
+
+        for i in 0..500 {
+            let mut expected_ray = random_ray(i);
+            let mut ray = expected_ray;
+
+            let mut expected = None;
+
+            for primitive in primitives {
+                if let Some(hit) = primitive.intersect(&mut expected_ray) {
+                    expected = Some(hit);
+                }
+            }
+
+            let actual = accel.intersect(&mut ray);
+
+            assert_eq!(expected.is_some(), actual.is_some());
+            assert_eq!(accel.intersect_p(&random_ray(i)), expected.is_some());
+
+            if let (Some(expected), Some(actual)) = (expected, actual) {
+                hits += 1;
+                assert!((expected_ray.t_max - ray.t_max).abs() < EPSILON);
+                assert!((expected.p - actual.p).length() < EPSILON);
+            }
+        }
+
+        assert!(hits > 50);
+    }
+
+    #[test]
+    fn nodes_are_eight_bytes() {
+        assert_eq!(mem::size_of::<KdAccelNode>(), 8);
+
+        let node = KdAccelNode::interior(2, 12345, 1.5);
+
+        assert!(!node.is_leaf());
+        assert_eq!(node.split_axis(), 2);
+        assert_eq!(node.above_child(), 12345);
+        assert!((node.split_pos() - 1.5).abs() < EPSILON);
+    }
+
+    #[test]
+    fn matches_brute_force() {
+        let primitives = scene();
+        let accel = KdTreeAccel::new(primitives.clone(), 80.0, 1.0, 0.5, 1, None);
+
+        assert!(accel.nodes.len() > 1);
+        check_matches_brute_force(&accel, &primitives);
+    }
+
+    #[test]
+    fn shallow_tree_matches_brute_force() {
+        let primitives = scene();
+        let accel = KdTreeAccel::new(primitives.clone(), 10.0, 5.0, 0.0, 8, Some(3));
+
+        assert!(accel.nodes.len() <= 15);
+        check_matches_brute_force(&accel, &primitives);
+    }
+
+    #[test]
+    fn empty_tree_misses() {
+        let accel = KdTreeAccel::new(Vec::new(), 80.0, 1.0, 0.5, 1, None);
+        let mut ray = random_ray(0);
+
+        assert!(accel.intersect(&mut ray).is_none());
+        assert!(!accel.intersect_p(&ray));
+    }
+}
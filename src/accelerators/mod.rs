@@ -0,0 +1,7 @@
+pub mod bvh;
+pub mod bvh_cache;
+pub mod kdtree;
+pub mod sbvh;
+pub mod stats;
+pub mod two_level;
+pub mod wide_bvh;
@@ -0,0 +1,558 @@
+use std::sync::Arc;
+
+use accelerators::bvh::{cheapest_bucket_split, BvhBuildNode, BvhPrimitiveInfo, N_BUCKETS};
+use core::primitive::Primitive;
+
+use core::Bounds3f;
+
+const N_SPATIAL_BINS: usize = 32;
+const MAX_SPATIAL_DEPTH: usize = 48;
+
+/// A primitive, or the part of one that fell on one side of a spatial split.
+#[derive(Clone, Copy, Debug)]
+struct Reference {
+    primitive_number: usize,
+    bounds: Bounds3f,
+}
+
+impl Reference {
+    fn centroid(&self, axis: usize) -> f64 {
+        (self.bounds.p_min[axis] + self.bounds.p_max[axis]) * 0.5
+    }
+}
+
+/// Limits on spatial splits. They are only tried where the children of the
+/// best object split overlap by more than `alpha` times the surface area of
+/// the whole scene, and may add at most `duplication_budget` times as many
+/// references as there are primitives, shared among subtrees by size.
+#[derive(Clone, Copy, Debug)]
+pub struct SpatialSplits {
+    pub alpha: f64,
+    pub duplication_budget: f64,
+}
+
+impl Default for SpatialSplits {
+    fn default() -> Self {
+        Self {
+            alpha: 1e-5,
+            duplication_budget: 1.0,
+        }
+    }
+}
+
+enum Split {
+    Object { axis: usize, bucket: usize },
+    Spatial { axis: usize, position: f64 },
+}
+
+struct SbvhBuilder<'a> {
+    primitives: &'a [Arc<dyn Primitive>],
+    max_prims_in_node: usize,
+    min_overlap: f64,
+}
+
+/// Builds a BVH that also considers spatial splits, following Stich et al.,
+/// "Spatial Splits in Bounding Volume Hierarchies".
+pub fn sbvh_build(
+    primitives: &[Arc<dyn Primitive>],
+    primitive_info: &[BvhPrimitiveInfo],
+    max_prims_in_node: usize,
+    spatial_splits: SpatialSplits,
+    ordered_prims: &mut Vec<usize>,
+) -> BvhBuildNode {
+    let references: Vec<Reference> = primitive_info
+        .iter()
+        .map(|info| Reference {
+            primitive_number: info.primitive_number,
+            bounds: info.bounds,
+        })
+        .collect();
+    let root_bounds = union_all(&references);
+
+    let builder = SbvhBuilder {
+        primitives,
+        max_prims_in_node,
+        min_overlap: spatial_splits.alpha * root_bounds.surface_area(),
+    };
+    let budget = (spatial_splits.duplication_budget * references.len() as f64) as usize;
+
+    builder.build(references, 0, budget, ordered_prims)
+}
+
+impl<'a> SbvhBuilder<'a> {
+    fn build(
+        &self,
+        references: Vec<Reference>,
+        depth: usize,
+        budget: usize,
+        ordered_prims: &mut Vec<usize>,
+    ) -> BvhBuildNode {
+        let bounds = union_all(&references);
+        let n_references = references.len();
+
+        if n_references == 1 {
+            return make_leaf(&references, bounds, ordered_prims);
+        }
+
+        let mut best = None;
+        let mut best_cost = f64::INFINITY;
+        let mut object_overlap = 0.0;
+
+        if let Some((cost, axis, bucket, overlap)) = self.find_object_split(&references, bounds) {
+            best = Some(Split::Object { axis, bucket });
+            best_cost = cost;
+            object_overlap = overlap;
+        }
+
+        if depth < MAX_SPATIAL_DEPTH && (best.is_none() || object_overlap > self.min_overlap) {
+            if let Some((cost, axis, position)) =
+                self.find_spatial_split(&references, bounds, budget)
+            {
+                if cost < best_cost {
+                    best = Some(Split::Spatial { axis, position });
+                    best_cost = cost;
+                }
+            }
+        }
+
+        let leaf_cost = n_references as f64;
+
+        if n_references <= self.max_prims_in_node && best_cost >= leaf_cost {
+            return make_leaf(&references, bounds, ordered_prims);
+        }
+
+        let (axis, mut left, mut right) = match best {
+            Some(Split::Object { axis, bucket }) => {
+                let centroid_bounds = centroid_bounds(&references);
+                let (left, right) = references
+                    .into_iter()
+                    .partition(|r| object_bucket(r, &centroid_bounds, axis) <= bucket);
+
+                (axis, left, right)
+            }
+            Some(Split::Spatial { axis, position }) => {
+                let (left, right) = self.split_references(references, bounds, axis, position);
+
+                (axis, left, right)
+            }
+            // Nothing separates the references, as when their centroids
+            // coincide and spatial splits are too deep or out of budget, so
+            // too many for one leaf are halved by count below.
+            None => (bounds.maximum_extent(), references, Vec::new()),
+        };
+
+        if left.is_empty() || right.is_empty() {
+            left.append(&mut right);
+            left.sort_by(|a, b| a.centroid(axis).partial_cmp(&b.centroid(axis)).unwrap());
+            right = left.split_off(left.len() / 2);
+        }
+
+        // Whatever budget this split didn't use is shared by reference count.
+        let n_split = left.len() + right.len();
+        let remaining = budget.saturating_sub(n_split - n_references);
+        let left_budget = remaining * left.len() / n_split;
+
+        BvhBuildNode::interior(
+            axis,
+            self.build(left, depth + 1, left_budget, ordered_prims),
+            self.build(right, depth + 1, remaining - left_budget, ordered_prims),
+        )
+    }
+
+    /// Returns the cost, axis and last left bucket of the best SAH split by
+    /// reference centroids, along with how much its children overlap.
+    fn find_object_split(
+        &self,
+        references: &[Reference],
+        bounds: Bounds3f,
+    ) -> Option<(f64, usize, usize, f64)> {
+        let centroid_bounds = centroid_bounds(references);
+        let axis = centroid_bounds.maximum_extent();
+
+        if centroid_bounds.p_max[axis] == centroid_bounds.p_min[axis] {
+            return None;
+        }
+
+        let bucket_of = |r: &Reference| object_bucket(r, &centroid_bounds, axis);
+        let (cost, bucket) = cheapest_bucket_split(references, bounds, |r| r.bounds, bucket_of);
+
+        let (mut left, mut right) = (Bounds3f::zero(), Bounds3f::zero());
+
+        for r in references {
+            if bucket_of(r) <= bucket {
+                left = left.union_bounds(r.bounds);
+            } else {
+                right = right.union_bounds(r.bounds);
+            }
+        }
+
+        let overlap = left.intersection(right);
+        let overlap_area = if overlap.is_empty() {
+            0.0
+        } else {
+            overlap.surface_area()
+        };
+
+        Some((cost, axis, bucket, overlap_area))
+    }
+
+    /// Returns the cost, axis and plane of the best split between evenly
+    /// spaced bins that duplicates at most `budget` references, with
+    /// references clipped to every bin they overlap.
+    fn find_spatial_split(
+        &self,
+        references: &[Reference],
+        bounds: Bounds3f,
+        budget: usize,
+    ) -> Option<(f64, usize, f64)> {
+        let mut best = None;
+        let mut best_cost = f64::INFINITY;
+
+        for axis in 0..3 {
+            let origin = bounds.p_min[axis];
+            let extent = bounds.p_max[axis] - origin;
+
+            if extent <= 0.0 {
+                continue;
+            }
+
+            let bin_width = extent / N_SPATIAL_BINS as f64;
+            let bin_of = |x: f64| (((x - origin) / bin_width) as usize).min(N_SPATIAL_BINS - 1);
+
+            let mut bin_bounds = [Bounds3f::zero(); N_SPATIAL_BINS];
+            let mut entries = [0usize; N_SPATIAL_BINS];
+            let mut exits = [0usize; N_SPATIAL_BINS];
+
+            for r in references {
+                let first = bin_of(r.bounds.p_min[axis]);
+                let last = bin_of(r.bounds.p_max[axis]);
+
+                for (bin, b) in bin_bounds.iter_mut().enumerate().take(last + 1).skip(first) {
+                    let mut slab = bounds;
+                    slab.p_min[axis] = origin + bin as f64 * bin_width;
+                    slab.p_max[axis] = origin + (bin + 1) as f64 * bin_width;
+
+                    let clipped = self.clip(r, slab);
+
+                    if !clipped.is_empty() {
+                        *b = b.union_bounds(clipped);
+                    }
+                }
+
+                entries[first] += 1;
+                exits[last] += 1;
+            }
+
+            // Sweep from the right to accumulate what lies past each plane.
+            let mut right_bounds = [Bounds3f::zero(); N_SPATIAL_BINS];
+            let mut right_counts = [0usize; N_SPATIAL_BINS];
+            let (mut b, mut count) = (Bounds3f::zero(), 0);
+
+            for i in (1..N_SPATIAL_BINS).rev() {
+                b = b.union_bounds(bin_bounds[i]);
+                count += exits[i];
+                right_bounds[i] = b;
+                right_counts[i] = count;
+            }
+
+            let (mut left_bounds, mut left_count) = (Bounds3f::zero(), 0);
+
+            for i in 0..N_SPATIAL_BINS - 1 {
+                left_bounds = left_bounds.union_bounds(bin_bounds[i]);
+                left_count += entries[i];
+
+                if left_count + right_counts[i + 1] - references.len() > budget {
+                    continue;
+                }
+
+                let cost = 1.0
+                    + (area(left_bounds, left_count)
+                        + area(right_bounds[i + 1], right_counts[i + 1]))
+                        / bounds.surface_area();
+
+                if cost < best_cost {
+                    best_cost = cost;
+                    best = Some((cost, axis, origin + (i + 1) as f64 * bin_width));
+                }
+            }
+        }
+
+        best
+    }
+
+    /// Sends each reference to the side of the plane it lies on, clipping
+    /// the ones that straddle it into a reference for either side.
+    fn split_references(
+        &self,
+        references: Vec<Reference>,
+        bounds: Bounds3f,
+        axis: usize,
+        position: f64,
+    ) -> (Vec<Reference>, Vec<Reference>) {
+        let mut left_box = bounds;
+        let mut right_box = bounds;
+        left_box.p_max[axis] = position;
+        right_box.p_min[axis] = position;
+
+        let mut left = Vec::with_capacity(references.len());
+        let mut right = Vec::with_capacity(references.len());
+
+        for r in references {
+            if r.bounds.p_max[axis] <= position {
+                left.push(r);
+            } else if r.bounds.p_min[axis] >= position {
+                right.push(r);
+            } else {
+                let left_bounds = self.clip(&r, left_box);
+                let right_bounds = self.clip(&r, right_box);
+
+                if !left_bounds.is_empty() {
+                    left.push(Reference {
+                        bounds: left_bounds,
+                        ..r
+                    });
+                }
+
+                if !right_bounds.is_empty() {
+                    right.push(Reference {
+                        bounds: right_bounds,
+                        ..r
+                    });
+                }
+
+                // Numerical trouble clipped both halves away; keep it whole.
+                if left_bounds.is_empty() && right_bounds.is_empty() {
+                    left.push(r);
+                }
+            }
+        }
+
+        (left, right)
+    }
+
+    fn clip(&self, r: &Reference, clip: Bounds3f) -> Bounds3f {
+        self.primitives[r.primitive_number].clipped_world_bound(r.bounds.intersection(clip))
+    }
+}
+
+fn make_leaf(
+    references: &[Reference],
+    bounds: Bounds3f,
+    ordered_prims: &mut Vec<usize>,
+) -> BvhBuildNode {
+    let first_prim_offset = ordered_prims.len();
+    ordered_prims.extend(references.iter().map(|r| r.primitive_number));
+
+    BvhBuildNode::leaf(first_prim_offset, references.len(), bounds)
+}
+
+fn union_all(references: &[Reference]) -> Bounds3f {
+    references
+        .iter()
+        .fold(Bounds3f::zero(), |b, r| b.union_bounds(r.bounds))
+}
+
+fn centroid_bounds(references: &[Reference]) -> Bounds3f {
+    references.iter().fold(Bounds3f::zero(), |b, r| {
+        b.union((r.bounds.p_min + r.bounds.p_max) * 0.5)
+    })
+}
+
+fn object_bucket(r: &Reference, centroid_bounds: &Bounds3f, axis: usize) -> usize {
+    let centroid = (r.bounds.p_min + r.bounds.p_max) * 0.5;
+    let b = (N_BUCKETS as f64 * centroid_bounds.offset(centroid)[axis]) as usize;
+
+    b.min(N_BUCKETS - 1)
+}
+
+fn area(bounds: Bounds3f, count: usize) -> f64 {
+    if count > 0 {
+        count as f64 * bounds.surface_area()
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64;
+
+    use accelerators::bvh::{BvhAccel, SplitMethod};
+    use core::medium::Medium;
+    use core::primitive::GeometricPrimitive;
+    use core::ray::Ray;
+    use core::transform::Transform;
+    use core::utils;
+    use shapes::sphere::Sphere;
+    use shapes::triangle::{Triangle, TriangleMesh};
+
+    use core::Point3f;
+
+    const EPSILON: f64 = 0.0001;
+
+    fn random(i: usize, k: f64) -> f64 {
+        utils::hash_float(&[i as f64, k])
+    }
+
+    /// Parallel strips running diagonally across the scene, so that each
+    /// one's bounds take in most of its neighbours.
+    fn slivers(n: usize) -> Vec<Arc<dyn Primitive>> {
+        let mut p = Vec::new();
+
+        for i in 0..n {
+            let y = 20.0 * i as f64 / n as f64 - 10.0;
+
+            p.push(Point3f::new(-10.0, y - 10.0, -10.0));
+            p.push(Point3f::new(10.0, y + 10.0, 10.0));
+            p.push(Point3f::new(10.0, y + 10.0 + 20.0 / n as f64, 10.0));
+        }
+
+        let mesh = Arc::new(TriangleMesh::new(
+            Transform::new(),
+            false,
+            (0..3 * n).collect(),
+            p,
+            None,
+            None,
+            None,
+        ));
+
+        (0..n)
+            .map(|i| {
+                let triangle = Triangle::new(mesh.clone(), i);
+
+                Arc::new(GeometricPrimitive::new(
+                    Arc::new(triangle),
+                    None,
+                    None,
+                    None,
+                )) as Arc<dyn Primitive>
+            })
+            .collect()
+    }
+
+    fn random_ray(i: usize) -> Ray {
+        let o = Point3f::new(
+            30.0 * random(i, 10.0) - 15.0,
+            30.0 * random(i, 11.0) - 15.0,
+            30.0 * random(i, 12.0) - 15.0,
+        );
+        let target = Point3f::new(
+            10.0 * random(i, 13.0) - 5.0,
+            10.0 * random(i, 14.0) - 5.0,
+            10.0 * random(i, 15.0) - 5.0,
+        );
+
+        Ray::new(o, target - o, Medium {}, f64::INFINITY, 0.0)
+    }
+
+    /// Expected cost of tracing a random ray under the SAH.
+    fn sah_cost(bvh: &BvhAccel) -> f64 {
+        let root_area = bvh.nodes[0].bounds.surface_area();
+
+        bvh.nodes
+            .iter()
+            .map(|node| {
+                let cost = if node.is_leaf() {
+                    node.n_primitives as f64
+                } else {
+                    1.0
+                };
+
+                cost * node.bounds.surface_area() / root_area
+            })
+            .sum()
+    }
+
+    #[test]
+    fn matches_brute_force() {
+        let primitives = slivers(300);
+        let sbvh = BvhAccel::new(primitives.clone(), 4, SplitMethod::Sbvh);
+
+        let mut hits = 0;
+
+        for i in 0..1000 {
+            let mut expected_ray = random_ray(i);
+            let mut ray = expected_ray;
+
+            let mut expected = None;
+
+            for primitive in &primitives {
+                if let Some(hit) = primitive.intersect(&mut expected_ray) {
+                    expected = Some(hit);
+                }
+            }
+
+            let actual = sbvh.intersect(&mut ray);
+
+            assert_eq!(expected.is_some(), actual.is_some());
+            assert_eq!(sbvh.intersect_p(&random_ray(i)), expected.is_some());
+
+            if let (Some(expected), Some(actual)) = (expected, actual) {
+                hits += 1;
+                assert!((expected_ray.t_max - ray.t_max).abs() < EPSILON);
+                assert!((expected.p - actual.p).length() < EPSILON);
+            }
+        }
+
+        assert!(hits > 20);
+    }
+
+    #[test]
+    fn spatial_splits_tighten_leaves() {
+        let primitives = slivers(300);
+        let sah = BvhAccel::new(primitives.clone(), 4, SplitMethod::Sah);
+        let sbvh = BvhAccel::with_spatial_splits(primitives.clone(), 4, SpatialSplits::default());
+
+        // References are duplicated, and rays expect far fewer tests.
+        assert!(sbvh.primitives.len() > primitives.len());
+        assert!(sbvh.primitives.len() <= 2 * primitives.len());
+        assert!(sah_cost(&sbvh) < 0.5 * sah_cost(&sah));
+    }
+
+    #[test]
+    fn duplication_stays_within_limits() {
+        let primitives = slivers(300);
+        let no_overlap_allowed = BvhAccel::with_spatial_splits(
+            primitives.clone(),
+            4,
+            SpatialSplits {
+                alpha: 1.0,
+                duplication_budget: 1.0,
+            },
+        );
+        let small_budget = BvhAccel::with_spatial_splits(
+            primitives.clone(),
+            4,
+            SpatialSplits {
+                alpha: 1e-5,
+                duplication_budget: 0.1,
+            },
+        );
+
+        assert_eq!(no_overlap_allowed.primitives.len(), primitives.len());
+        assert!(small_budget.primitives.len() <= 330);
+    }
+
+    #[test]
+    fn coincident_centroids_respect_leaf_size() {
+        let primitives: Vec<Arc<dyn Primitive>> = (0..300)
+            .map(|_| {
+                Arc::new(GeometricPrimitive::new(
+                    Arc::new(Sphere::full(Transform::new(), 1.0)),
+                    None,
+                    None,
+                    None,
+                )) as Arc<dyn Primitive>
+            })
+            .collect();
+        let sbvh = BvhAccel::new(primitives.clone(), 4, SplitMethod::Sbvh);
+
+        assert!(sbvh.primitives.len() <= 2 * primitives.len());
+        assert!(sbvh
+            .nodes
+            .iter()
+            .all(|node| !node.is_leaf() || node.n_primitives <= 4));
+    }
+}
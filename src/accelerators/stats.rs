@@ -0,0 +1,402 @@
+use std::cell::Cell;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ops::{AddAssign, Range};
+use std::path::Path;
+
+use core::primitive::Primitive;
+
+use core::Bounds3f;
+
+/// One node as seen by `Accelerator::visit_nodes`.
+#[derive(Clone, Copy, Debug)]
+pub struct NodeInfo {
+    /// Zero for the root.
+    pub depth: usize,
+    pub bounds: Bounds3f,
+    /// The primitive count of a leaf, or `None` for interior nodes.
+    pub n_primitives: Option<usize>,
+}
+
+/// A primitive aggregate whose structure can be inspected.
+pub trait Accelerator: Primitive {
+    /// Calls `visit` on every node, each parent before its children.
+    fn visit_nodes(&self, visit: &mut dyn FnMut(NodeInfo));
+
+    /// Bytes taken by the nodes and primitive lists, not by the primitives
+    /// themselves.
+    fn memory_bytes(&self) -> usize;
+
+    fn stats(&self) -> AccelStats {
+        let mut stats = AccelStats {
+            memory_bytes: self.memory_bytes(),
+            ..AccelStats::default()
+        };
+        let mut root_area = None;
+
+        self.visit_nodes(&mut |node| {
+            let area = node.bounds.surface_area();
+            let root_area = *root_area.get_or_insert(area);
+            let weight = if root_area > 0.0 {
+                area / root_area
+            } else {
+                1.0
+            };
+
+            stats.max_depth = stats.max_depth.max(node.depth);
+
+            match node.n_primitives {
+                Some(n) => {
+                    if stats.leaf_sizes.len() <= n {
+                        stats.leaf_sizes.resize(n + 1, 0);
+                    }
+
+                    stats.leaf_sizes[n] += 1;
+                    stats.sah_cost += weight * n as f64;
+                }
+                None => {
+                    stats.n_interior_nodes += 1;
+                    stats.sah_cost += weight;
+                }
+            }
+        });
+
+        stats
+    }
+}
+
+/// A summary of an accelerator, for telling a poorly built one apart.
+#[derive(Clone, Debug, Default)]
+pub struct AccelStats {
+    pub n_interior_nodes: usize,
+    /// The number of leaves holding each primitive count.
+    pub leaf_sizes: Vec<usize>,
+    pub max_depth: usize,
+    /// The expected cost of a ray through the root, counting one per node
+    /// visited and per primitive tested, weighted by surface area.
+    pub sah_cost: f64,
+    pub memory_bytes: usize,
+}
+
+impl AccelStats {
+    pub fn n_leaves(&self) -> usize {
+        self.leaf_sizes.iter().sum()
+    }
+
+    pub fn n_nodes(&self) -> usize {
+        self.n_interior_nodes + self.n_leaves()
+    }
+
+    /// Primitives summed over all leaves, which exceeds the primitive count
+    /// when primitives are referenced from several leaves.
+    pub fn n_primitive_references(&self) -> usize {
+        self.leaf_sizes
+            .iter()
+            .enumerate()
+            .map(|(n, &c)| n * c)
+            .sum()
+    }
+}
+
+/// Traversal work done on the current thread, as counted by the
+/// accelerators' `intersect` and `intersect_p`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RayCounters {
+    pub nodes_visited: u64,
+    pub primitives_tested: u64,
+}
+
+impl AddAssign for RayCounters {
+    fn add_assign(&mut self, other: RayCounters) {
+        self.nodes_visited += other.nodes_visited;
+        self.primitives_tested += other.primitives_tested;
+    }
+}
+
+thread_local! {
+    static RAY_COUNTERS: Cell<RayCounters> = Cell::new(RayCounters::default());
+}
+
+pub fn count_node_visit() {
+    RAY_COUNTERS.with(|c| {
+        let mut counters = c.get();
+        counters.nodes_visited += 1;
+        c.set(counters);
+    });
+}
+
+pub fn count_primitive_tests(n: usize) {
+    RAY_COUNTERS.with(|c| {
+        let mut counters = c.get();
+        counters.primitives_tested += n as u64;
+        c.set(counters);
+    });
+}
+
+/// Returns the work counted on this thread since the last call and starts
+/// counting afresh, so calling it around a ray gives that ray's cost.
+pub fn take_ray_counters() -> RayCounters {
+    RAY_COUNTERS.with(|c| c.replace(RayCounters::default()))
+}
+
+/// Per-pixel traversal counters, for a debug integrator to show where the
+/// accelerator does the most work.
+pub struct Heatmap {
+    pub width: usize,
+    pub height: usize,
+    pub counters: Vec<RayCounters>,
+}
+
+impl Heatmap {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            counters: vec![RayCounters::default(); width * height],
+        }
+    }
+
+    /// Adds the counters of one more ray through pixel `(x, y)`.
+    pub fn add(&mut self, x: usize, y: usize, counters: RayCounters) {
+        self.counters[y * self.width + x] += counters;
+    }
+
+    /// Maps `metric` of each pixel through a blue, green, yellow and red
+    /// ramp, scaled so the busiest pixel is red.
+    pub fn to_rgb<F: Fn(&RayCounters) -> u64>(&self, metric: F) -> Vec<[f64; 3]> {
+        let max = self.counters.iter().map(&metric).max().unwrap_or(0).max(1);
+
+        self.counters
+            .iter()
+            .map(|c| heat_color(metric(c) as f64 / max as f64))
+            .collect()
+    }
+
+    /// Writes the heatmap as a binary PPM.
+    pub fn write_ppm<P, F>(&self, path: P, metric: F) -> io::Result<()>
+    where
+        P: AsRef<Path>,
+        F: Fn(&RayCounters) -> u64,
+    {
+        let mut out = BufWriter::new(File::create(path)?);
+
+        write!(out, "P6\n{} {}\n255\n", self.width, self.height)?;
+
+        for rgb in self.to_rgb(metric) {
+            out.write_all(&[
+                (rgb[0] * 255.0).round() as u8,
+                (rgb[1] * 255.0).round() as u8,
+                (rgb[2] * 255.0).round() as u8,
+            ])?;
+        }
+
+        out.flush()
+    }
+}
+
+fn heat_color(v: f64) -> [f64; 3] {
+    const RAMP: [[f64; 3]; 5] = [
+        [0.0, 0.0, 0.5],
+        [0.0, 0.5, 1.0],
+        [0.0, 1.0, 0.0],
+        [1.0, 1.0, 0.0],
+        [1.0, 0.0, 0.0],
+    ];
+
+    let x = v.clamp(0.0, 1.0) * (RAMP.len() - 1) as f64;
+    let i = (x as usize).min(RAMP.len() - 2);
+    let t = x - i as f64;
+
+    [
+        RAMP[i][0] + (RAMP[i + 1][0] - RAMP[i][0]) * t,
+        RAMP[i][1] + (RAMP[i + 1][1] - RAMP[i][1]) * t,
+        RAMP[i][2] + (RAMP[i + 1][2] - RAMP[i][2]) * t,
+    ]
+}
+
+/// Writes the boxes of the nodes whose depth lies in `depths` as an OBJ
+/// made of line elements, one closed wireframe box per node.
+pub fn write_wireframe_obj<A, W>(accel: &A, depths: Range<usize>, out: &mut W) -> io::Result<()>
+where
+    A: Accelerator + ?Sized,
+    W: Write,
+{
+    const EDGES: [(usize, usize); 12] = [
+        (0, 1),
+        (2, 3),
+        (4, 5),
+        (6, 7),
+        (0, 2),
+        (1, 3),
+        (4, 6),
+        (5, 7),
+        (0, 4),
+        (1, 5),
+        (2, 6),
+        (3, 7),
+    ];
+
+    let mut boxes = Vec::new();
+
+    accel.visit_nodes(&mut |node| {
+        if depths.contains(&node.depth) && !node.bounds.is_empty() {
+            boxes.push(node.bounds);
+        }
+    });
+
+    for (i, bounds) in boxes.iter().enumerate() {
+        writeln!(out, "o node{}", i)?;
+
+        for corner in 0..8 {
+            let p = bounds.corner(corner);
+            writeln!(out, "v {} {} {}", p.x, p.y, p.z)?;
+        }
+
+        for &(a, b) in &EDGES {
+            writeln!(out, "l {} {}", 8 * i + a + 1, 8 * i + b + 1)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64;
+    use std::sync::Arc;
+
+    use accelerators::bvh::{BvhAccel, SplitMethod};
+    use accelerators::kdtree::KdTreeAccel;
+    use accelerators::wide_bvh::Bvh4Accel;
+    use core::medium::Medium;
+    use core::primitive::GeometricPrimitive;
+    use core::ray::Ray;
+    use core::transform::Transform;
+    use core::utils;
+    use shapes::sphere::Sphere;
+
+    use core::Point3f;
+    use core::Vector3f;
+
+    const EPSILON: f64 = 0.0001;
+
+    fn random(i: usize, k: f64) -> f64 {
+        utils::hash_float(&[i as f64, k])
+    }
+
+    fn sphere_field(n: usize) -> Vec<Arc<dyn Primitive>> {
+        (0..n)
+            .map(|i| {
+                let center = Vector3f::new(
+                    20.0 * random(i, 0.0) - 10.0,
+                    20.0 * random(i, 1.0) - 10.0,
+                    20.0 * random(i, 2.0) - 10.0,
+                );
+                let sphere = Sphere::full(Transform::translate(center), 0.5);
+
+                Arc::new(GeometricPrimitive::new(Arc::new(sphere), None, None, None))
+                    as Arc<dyn Primitive>
+            })
+            .collect()
+    }
+
+    #[test]
+    fn stats_describe_the_tree() {
+        let bvh = BvhAccel::new(sphere_field(200), 4, SplitMethod::Sah);
+        let stats = bvh.stats();
+
+        assert_eq!(stats.n_nodes(), bvh.nodes.len());
+        assert_eq!(stats.n_leaves(), stats.n_interior_nodes + 1);
+        assert_eq!(stats.n_primitive_references(), 200);
+        assert!(stats.leaf_sizes.len() <= 5 && stats.leaf_sizes[0] == 0);
+        assert!(stats.max_depth >= 6 && stats.max_depth < 64);
+        assert!((stats.sah_cost - bvh.built_costs[0]).abs() < EPSILON);
+        assert!(stats.memory_bytes > 0);
+
+        let wide = Bvh4Accel::new(sphere_field(200), 4, SplitMethod::Sah);
+        let wide_stats = wide.stats();
+
+        assert_eq!(wide_stats.n_primitive_references(), 200);
+        assert_eq!(wide_stats.n_interior_nodes, wide.nodes.len());
+        assert!(wide_stats.max_depth < stats.max_depth);
+
+        let kd = KdTreeAccel::new(sphere_field(200), 80.0, 1.0, 0.5, 1, None);
+        let kd_stats = kd.stats();
+
+        assert_eq!(kd_stats.n_nodes(), kd.nodes.len());
+        assert!(kd_stats.n_primitive_references() >= 200);
+    }
+
+    #[test]
+    fn counters_follow_each_ray() {
+        let bvh = BvhAccel::new(sphere_field(200), 4, SplitMethod::Sah);
+        take_ray_counters();
+
+        let o = Point3f::new(0.0, 0.0, -30.0);
+        let mut ray = Ray::new(
+            o,
+            Vector3f::new(0.0, 0.0, 1.0),
+            Medium {},
+            f64::INFINITY,
+            0.0,
+        );
+        bvh.intersect(&mut ray);
+
+        let counters = take_ray_counters();
+        assert!(counters.nodes_visited > 0 && counters.primitives_tested > 0);
+        assert!(counters.nodes_visited <= bvh.nodes.len() as u64);
+        assert_eq!(take_ray_counters(), RayCounters::default());
+
+        // A ray that misses everything only touches the root.
+        let mut ray = Ray::new(
+            o,
+            Vector3f::new(0.0, 0.0, -1.0),
+            Medium {},
+            f64::INFINITY,
+            0.0,
+        );
+        bvh.intersect(&mut ray);
+        assert_eq!(
+            take_ray_counters(),
+            RayCounters {
+                nodes_visited: 1,
+                primitives_tested: 0,
+            }
+        );
+
+        let mut heatmap = Heatmap::new(2, 1);
+        heatmap.add(1, 0, counters);
+        let rgb = heatmap.to_rgb(|c| c.nodes_visited);
+
+        assert_eq!(rgb[0], [0.0, 0.0, 0.5]);
+        assert_eq!(rgb[1], [1.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn wireframe_has_a_box_per_node() {
+        let bvh = BvhAccel::new(sphere_field(200), 4, SplitMethod::Sah);
+        let mut obj = Vec::new();
+
+        write_wireframe_obj(&bvh, 1..3, &mut obj).unwrap();
+        let obj = String::from_utf8(obj).unwrap();
+
+        let mut n_boxes = 0;
+        bvh.visit_nodes(&mut |node| {
+            if node.depth == 1 || node.depth == 2 {
+                n_boxes += 1;
+            }
+        });
+
+        assert!(n_boxes >= 4);
+        assert_eq!(
+            obj.lines().filter(|l| l.starts_with("v ")).count(),
+            8 * n_boxes
+        );
+        assert_eq!(
+            obj.lines().filter(|l| l.starts_with("l ")).count(),
+            12 * n_boxes
+        );
+        assert!(obj.ends_with(&format!("l {} {}\n", 8 * n_boxes - 4, 8 * n_boxes)));
+    }
+}
@@ -0,0 +1,287 @@
+use std::mem;
+use std::sync::Arc;
+
+use accelerators::bvh::{BvhAccel, SplitMethod};
+use accelerators::stats::{Accelerator, NodeInfo};
+use core::interaction::SurfaceInteraction;
+use core::light::AreaLight;
+use core::material::Material;
+use core::primitive::Primitive;
+use core::ray::Ray;
+use core::transform::Transform;
+
+use core::Bounds3f;
+
+/// One placement of a shared bottom-level structure. Unlike
+/// `TransformedPrimitive` the transform can't be animated, so rays skip
+/// interpolating it and the world bound is found once up front.
+pub struct Instance {
+    pub blas: Arc<dyn Primitive>,
+    pub object_to_world: Transform,
+    pub world_to_object: Transform,
+    pub world_bound: Bounds3f,
+}
+
+impl Instance {
+    pub fn new(blas: Arc<dyn Primitive>, object_to_world: Transform) -> Self {
+        Self {
+            world_bound: object_to_world.transform(blas.world_bound()),
+            world_to_object: object_to_world.inverse(),
+            blas,
+            object_to_world,
+        }
+    }
+}
+
+impl Primitive for Instance {
+    fn world_bound(&self) -> Bounds3f {
+        self.world_bound
+    }
+
+    fn intersect(&self, r: &mut Ray) -> Option<SurfaceInteraction> {
+        let mut ray = self.world_to_object.transform(*r);
+
+        let isect = self.blas.intersect(&mut ray)?;
+        r.t_max = ray.t_max;
+
+        if self.object_to_world.is_identity() {
+            Some(isect)
+        } else {
+            Some(self.object_to_world.transform(isect))
+        }
+    }
+
+    fn intersect_p(&self, r: &Ray) -> bool {
+        self.blas.intersect_p(&self.world_to_object.transform(*r))
+    }
+
+    fn get_area_light(&self) -> Option<Arc<dyn AreaLight>> {
+        None
+    }
+
+    fn get_material(&self) -> Option<Arc<dyn Material>> {
+        None
+    }
+}
+
+/// A top-level BVH over instances of shared bottom-level structures, so
+/// memory grows with the number of instances rather than their geometry.
+pub struct TwoLevelAccel {
+    pub instances: Vec<Arc<Instance>>,
+    pub tlas: BvhAccel,
+}
+
+impl TwoLevelAccel {
+    pub fn new(instances: Vec<Instance>) -> Self {
+        let instances: Vec<Arc<Instance>> = instances.into_iter().map(Arc::new).collect();
+
+        Self {
+            tlas: build_tlas(&instances),
+            instances,
+        }
+    }
+
+    /// Moves an instance. The top-level tree only sees the change after
+    /// `rebuild_tlas`, so several instances can be moved per rebuild.
+    pub fn set_transform(&mut self, index: usize, object_to_world: Transform) {
+        let blas = self.instances[index].blas.clone();
+
+        self.instances[index] = Arc::new(Instance::new(blas, object_to_world));
+    }
+
+    /// Rebuilds the top-level tree from the current instances, leaving the
+    /// bottom-level structures untouched.
+    pub fn rebuild_tlas(&mut self) {
+        self.tlas = build_tlas(&self.instances);
+    }
+}
+
+fn build_tlas(instances: &[Arc<Instance>]) -> BvhAccel {
+    let primitives = instances
+        .iter()
+        .map(|instance| instance.clone() as Arc<dyn Primitive>)
+        .collect();
+
+    BvhAccel::new(primitives, 1, SplitMethod::Sah)
+}
+
+impl Primitive for TwoLevelAccel {
+    fn world_bound(&self) -> Bounds3f {
+        self.tlas.world_bound()
+    }
+
+    fn intersect(&self, ray: &mut Ray) -> Option<SurfaceInteraction> {
+        self.tlas.intersect(ray)
+    }
+
+    fn intersect_p(&self, ray: &Ray) -> bool {
+        self.tlas.intersect_p(ray)
+    }
+
+    fn get_area_light(&self) -> Option<Arc<dyn AreaLight>> {
+        None
+    }
+
+    fn get_material(&self) -> Option<Arc<dyn Material>> {
+        None
+    }
+}
+
+/// Reports the top-level tree; the bottom-level structures are opaque
+/// primitives here and can be inspected on their own.
+impl Accelerator for TwoLevelAccel {
+    fn visit_nodes(&self, visit: &mut dyn FnMut(NodeInfo)) {
+        self.tlas.visit_nodes(visit);
+    }
+
+    fn memory_bytes(&self) -> usize {
+        self.tlas.memory_bytes()
+            + self.instances.len() * (mem::size_of::<Arc<Instance>>() + mem::size_of::<Instance>())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64;
+
+    use core::medium::Medium;
+    use core::primitive::{GeometricPrimitive, TransformedPrimitive};
+    use core::utils;
+    use shapes::sphere::Sphere;
+
+    use core::Point3f;
+    use core::Vector3f;
+
+    const EPSILON: f64 = 0.0001;
+
+    fn random(i: usize, k: f64) -> f64 {
+        utils::hash_float(&[i as f64, k])
+    }
+
+    /// A small cluster of spheres standing in for a mesh.
+    fn blas() -> Arc<dyn Primitive> {
+        let primitives = (0..8)
+            .map(|i| {
+                let center = Vector3f::new(
+                    2.0 * random(i, 0.0) - 1.0,
+                    2.0 * random(i, 1.0) - 1.0,
+                    2.0 * random(i, 2.0) - 1.0,
+                );
+                let sphere = Sphere::full(Transform::translate(center), 0.3);
+
+                Arc::new(GeometricPrimitive::new(Arc::new(sphere), None, None, None))
+                    as Arc<dyn Primitive>
+            })
+            .collect();
+
+        Arc::new(BvhAccel::new(primitives, 2, SplitMethod::Sah))
+    }
+
+    fn placement(i: usize) -> Transform {
+        Transform::translate(Vector3f::new(
+            40.0 * random(i, 5.0) - 20.0,
+            40.0 * random(i, 6.0) - 20.0,
+            40.0 * random(i, 7.0) - 20.0,
+        )) * Transform::rotate(360.0 * random(i, 8.0), Vector3f::new(0.0, 0.0, 1.0))
+            * Transform::scale(1.0, 0.5 + random(i, 9.0), 1.0)
+    }
+
+    fn random_ray(i: usize) -> Ray {
+        let o = Point3f::new(
+            60.0 * random(i, 10.0) - 30.0,
+            60.0 * random(i, 11.0) - 30.0,
+            60.0 * random(i, 12.0) - 30.0,
+        );
+        let target = Point3f::new(
+            20.0 * random(i, 13.0) - 10.0,
+            20.0 * random(i, 14.0) - 10.0,
+            20.0 * random(i, 15.0) - 10.0,
+        );
+
+        Ray::new(o, target - o, Medium {}, f64::INFINITY, 0.0)
+    }
+
+    fn check_matches_flat(accel: &TwoLevelAccel, flat: &[Arc<dyn Primitive>]) {
+        let mut hits = 0;
+
+        for i in 0..500 {
+            let mut expected_ray = random_ray(i);
+            let mut ray = expected_ray;
+
+            let mut expected = None;
+
+            for primitive in flat {
+                if let Some(hit) = primitive.intersect(&mut expected_ray) {
+                    expected = Some(hit);
+                }
+            }
+
+            let actual = accel.intersect(&mut ray);
+
+            assert_eq!(expected.is_some(), actual.is_some());
+            assert_eq!(accel.intersect_p(&random_ray(i)), expected.is_some());
+
+            if let (Some(expected), Some(actual)) = (expected, actual) {
+                hits += 1;
+                assert!((expected_ray.t_max - ray.t_max).abs() < EPSILON);
+                assert!((expected.p - actual.p).length() < EPSILON);
+                assert!((expected.n - actual.n).length() < EPSILON);
+            }
+        }
+
+        assert!(hits > 50);
+    }
+
+    #[test]
+    fn matches_transformed_primitives() {
+        let shared = blas();
+        let accel = TwoLevelAccel::new(
+            (0..300)
+                .map(|i| Instance::new(shared.clone(), placement(i)))
+                .collect(),
+        );
+        let flat: Vec<Arc<dyn Primitive>> = (0..300)
+            .map(|i| {
+                Arc::new(TransformedPrimitive::with_transform(
+                    shared.clone(),
+                    placement(i),
+                )) as Arc<dyn Primitive>
+            })
+            .collect();
+
+        // Every instance points at the one bottom-level tree.
+        assert_eq!(Arc::strong_count(&shared), 601);
+
+        check_matches_flat(&accel, &flat);
+    }
+
+    #[test]
+    fn tlas_rebuilds_alone() {
+        let shared = blas();
+        let mut accel = TwoLevelAccel::new(
+            (0..300)
+                .map(|i| Instance::new(shared.clone(), placement(i)))
+                .collect(),
+        );
+
+        for i in 0..300 {
+            accel.set_transform(i, placement(i + 1000));
+        }
+
+        accel.rebuild_tlas();
+
+        let flat: Vec<Arc<dyn Primitive>> = (0..300)
+            .map(|i| {
+                Arc::new(TransformedPrimitive::with_transform(
+                    shared.clone(),
+                    placement(i + 1000),
+                )) as Arc<dyn Primitive>
+            })
+            .collect();
+
+        assert_eq!(Arc::strong_count(&shared), 601);
+
+        check_matches_flat(&accel, &flat);
+    }
+}
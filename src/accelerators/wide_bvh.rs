@@ -0,0 +1,528 @@
+use std::f64;
+use std::mem;
+use std::sync::Arc;
+
+use accelerators::bvh::{BvhAccel, LinearBvhNode, SplitMethod};
+use accelerators::stats::{self, Accelerator, NodeInfo};
+use core::interaction::SurfaceInteraction;
+use core::light::AreaLight;
+use core::material::Material;
+use core::primitive::Primitive;
+use core::ray::Ray;
+use core::utils;
+
+use core::Bounds3f;
+use core::Point3f;
+
+/// Initial capacity of the traversal stack, which grows for deeper trees.
+const TRAVERSAL_STACK_CAPACITY: usize = 64 * 8;
+
+/// A node with up to `N` children whose bounds are stored as separate
+/// arrays per coordinate, so one ray can be tested against all of them in a
+/// loop the compiler vectorises. Unused slots have empty bounds.
+#[derive(Clone, Copy, Debug)]
+pub struct WideBvhNode<const N: usize> {
+    pub min_x: [f64; N],
+    pub min_y: [f64; N],
+    pub min_z: [f64; N],
+    pub max_x: [f64; N],
+    pub max_y: [f64; N],
+    pub max_z: [f64; N],
+    /// The child's node index, or its first primitive for leaf children.
+    pub offset: [usize; N],
+    /// How many primitives a leaf child has, zero for interior children.
+    pub n_primitives: [u16; N],
+    pub n_children: usize,
+}
+
+impl<const N: usize> WideBvhNode<N> {
+    pub fn empty() -> Self {
+        Self {
+            min_x: [f64::INFINITY; N],
+            min_y: [f64::INFINITY; N],
+            min_z: [f64::INFINITY; N],
+            max_x: [f64::NEG_INFINITY; N],
+            max_y: [f64::NEG_INFINITY; N],
+            max_z: [f64::NEG_INFINITY; N],
+            offset: [0; N],
+            n_primitives: [0; N],
+            n_children: 0,
+        }
+    }
+
+    pub fn child_bounds(&self, i: usize) -> Bounds3f {
+        Bounds3f {
+            p_min: Point3f::new(self.min_x[i], self.min_y[i], self.min_z[i]),
+            p_max: Point3f::new(self.max_x[i], self.max_y[i], self.max_z[i]),
+        }
+    }
+
+    fn set_child_bounds(&mut self, i: usize, bounds: Bounds3f) {
+        self.min_x[i] = bounds.p_min.x;
+        self.min_y[i] = bounds.p_min.y;
+        self.min_z[i] = bounds.p_min.z;
+        self.max_x[i] = bounds.p_max.x;
+        self.max_y[i] = bounds.p_max.y;
+        self.max_z[i] = bounds.p_max.z;
+    }
+
+    /// Tests the ray against every child box at once, returning the entry
+    /// distance of each child hit and infinity for the rest.
+    pub fn intersect_children(
+        &self,
+        ray: &Ray,
+        inv_dir: [f64; 3],
+        dir_is_neg: [bool; 3],
+    ) -> [f64; N] {
+        let (near_x, far_x) = if dir_is_neg[0] {
+            (&self.max_x, &self.min_x)
+        } else {
+            (&self.min_x, &self.max_x)
+        };
+        let (near_y, far_y) = if dir_is_neg[1] {
+            (&self.max_y, &self.min_y)
+        } else {
+            (&self.min_y, &self.max_y)
+        };
+        let (near_z, far_z) = if dir_is_neg[2] {
+            (&self.max_z, &self.min_z)
+        } else {
+            (&self.min_z, &self.max_z)
+        };
+
+        let far_scale = 1.0 + 2.0 * utils::gamma(3);
+        let mut t_entry = [f64::INFINITY; N];
+
+        for i in 0..N {
+            let t_near = ((near_x[i] - ray.o.x) * inv_dir[0])
+                .max((near_y[i] - ray.o.y) * inv_dir[1])
+                .max((near_z[i] - ray.o.z) * inv_dir[2])
+                .max(0.0);
+            let t_far = ((far_x[i] - ray.o.x) * inv_dir[0])
+                .min((far_y[i] - ray.o.y) * inv_dir[1])
+                .min((far_z[i] - ray.o.z) * inv_dir[2])
+                * far_scale;
+
+            if t_near <= t_far && t_near < ray.t_max {
+                t_entry[i] = t_near;
+            }
+        }
+
+        t_entry
+    }
+}
+
+/// A BVH of width `N`, built by collapsing a binary `BvhAccel` so each node
+/// takes in the children and grandchildren with the largest surface area.
+pub struct WideBvhAccel<const N: usize> {
+    pub primitives: Vec<Arc<dyn Primitive>>,
+    pub nodes: Vec<WideBvhNode<N>>,
+    pub bounds: Bounds3f,
+}
+
+pub type Bvh4Accel = WideBvhAccel<4>;
+pub type Bvh8Accel = WideBvhAccel<8>;
+
+impl<const N: usize> WideBvhAccel<N> {
+    pub fn new(
+        primitives: Vec<Arc<dyn Primitive>>,
+        max_prims_in_node: usize,
+        split_method: SplitMethod,
+    ) -> Self {
+        Self::from_bvh(BvhAccel::new(primitives, max_prims_in_node, split_method))
+    }
+
+    pub fn from_bvh(bvh: BvhAccel) -> Self {
+        assert!((2..=8).contains(&N), "wide BVH nodes have 2 to 8 children");
+
+        let bounds = bvh.world_bound();
+        let mut nodes = Vec::new();
+
+        if !bvh.nodes.is_empty() {
+            collapse(&bvh.nodes, 0, &mut nodes);
+        }
+
+        Self {
+            primitives: bvh.primitives,
+            nodes,
+            bounds,
+        }
+    }
+
+    /// Visits the leaves hit by `ray` nearest first, handing `visit` their
+    /// primitives until it returns `true`. The ray passed to `visit` may
+    /// be shortened by it, which culls farther children.
+    fn traverse<F>(&self, ray: &mut Ray, mut visit: F) -> bool
+    where
+        F: FnMut(&[Arc<dyn Primitive>], &mut Ray) -> bool,
+    {
+        if self.nodes.is_empty() {
+            return false;
+        }
+
+        let inv_dir = [1.0 / ray.d.x, 1.0 / ray.d.y, 1.0 / ray.d.z];
+        let dir_is_neg = [inv_dir[0] < 0.0, inv_dir[1] < 0.0, inv_dir[2] < 0.0];
+
+        // Entries are (entry distance, offset, primitive count), with a count
+        // of zero meaning an interior node.
+        let mut stack = Vec::with_capacity(TRAVERSAL_STACK_CAPACITY);
+        stack.push((0.0, 0, 0u16));
+
+        while let Some((t_entry, offset, n_primitives)) = stack.pop() {
+            if t_entry >= ray.t_max {
+                continue;
+            }
+
+            if n_primitives > 0 {
+                let primitives = &self.primitives[offset..offset + n_primitives as usize];
+                stats::count_primitive_tests(primitives.len());
+
+                if visit(primitives, ray) {
+                    return true;
+                }
+
+                continue;
+            }
+
+            let node = &self.nodes[offset];
+            stats::count_node_visit();
+
+            let t_children = node.intersect_children(ray, inv_dir, dir_is_neg);
+
+            // Push the hit children farthest first so the nearest is popped
+            // next, keeping the pushed entries sorted by insertion.
+            let first = stack.len();
+
+            for (i, &t_child) in t_children.iter().enumerate().take(node.n_children) {
+                if t_child == f64::INFINITY {
+                    continue;
+                }
+
+                stack.push((t_child, node.offset[i], node.n_primitives[i]));
+                let mut j = stack.len() - 1;
+
+                while j > first && stack[j - 1].0 < stack[j].0 {
+                    stack.swap(j - 1, j);
+                    j -= 1;
+                }
+            }
+        }
+
+        false
+    }
+}
+
+fn collapse<const N: usize>(
+    bvh_nodes: &[LinearBvhNode],
+    index: usize,
+    nodes: &mut Vec<WideBvhNode<N>>,
+) -> usize {
+    let node_index = nodes.len();
+    nodes.push(WideBvhNode::empty());
+
+    // Open up the biggest interior child until the node is full.
+    let mut children = if bvh_nodes[index].is_leaf() {
+        vec![index]
+    } else {
+        vec![index + 1, bvh_nodes[index].offset]
+    };
+
+    while children.len() < N {
+        let largest = children
+            .iter()
+            .enumerate()
+            .filter(|&(_, &c)| !bvh_nodes[c].is_leaf())
+            .max_by(|&(_, &a), &(_, &b)| {
+                let area_a = bvh_nodes[a].bounds.surface_area();
+                let area_b = bvh_nodes[b].bounds.surface_area();
+                area_a.partial_cmp(&area_b).unwrap()
+            })
+            .map(|(i, _)| i);
+
+        match largest {
+            Some(i) => {
+                let c = children.remove(i);
+                children.insert(i, bvh_nodes[c].offset);
+                children.insert(i, c + 1);
+            }
+            None => break,
+        }
+    }
+
+    let mut node = WideBvhNode::empty();
+    node.n_children = children.len();
+
+    for (i, &c) in children.iter().enumerate() {
+        let child = bvh_nodes[c];
+        node.set_child_bounds(i, child.bounds);
+
+        if child.is_leaf() {
+            node.offset[i] = child.offset;
+            node.n_primitives[i] = child.n_primitives;
+        } else {
+            node.offset[i] = collapse(bvh_nodes, c, nodes);
+        }
+    }
+
+    nodes[node_index] = node;
+    node_index
+}
+
+impl<const N: usize> Primitive for WideBvhAccel<N> {
+    fn world_bound(&self) -> Bounds3f {
+        self.bounds
+    }
+
+    fn intersect(&self, ray: &mut Ray) -> Option<SurfaceInteraction> {
+        let mut isect = None;
+
+        self.traverse(ray, |primitives, ray| {
+            for primitive in primitives {
+                if let Some(hit) = primitive.intersect(ray) {
+                    isect = Some(hit);
+                }
+            }
+
+            false
+        });
+
+        isect
+    }
+
+    fn intersect_p(&self, ray: &Ray) -> bool {
+        self.traverse(&mut ray.clone(), |primitives, ray| {
+            primitives
+                .iter()
+                .any(|primitive| primitive.intersect_p(ray))
+        })
+    }
+
+    fn get_area_light(&self) -> Option<Arc<dyn AreaLight>> {
+        None
+    }
+
+    fn get_material(&self) -> Option<Arc<dyn Material>> {
+        None
+    }
+}
+
+impl<const N: usize> Accelerator for WideBvhAccel<N> {
+    fn visit_nodes(&self, visit: &mut dyn FnMut(NodeInfo)) {
+        let mut nodes_to_visit = Vec::new();
+
+        if !self.nodes.is_empty() {
+            nodes_to_visit.push((0, 0, self.bounds));
+        }
+
+        while let Some((i, depth, bounds)) = nodes_to_visit.pop() {
+            let node = &self.nodes[i];
+
+            visit(NodeInfo {
+                depth,
+                bounds,
+                n_primitives: None,
+            });
+
+            // Leaf children live in their parent, so report them here.
+            for c in (0..node.n_children).rev() {
+                if node.n_primitives[c] > 0 {
+                    visit(NodeInfo {
+                        depth: depth + 1,
+                        bounds: node.child_bounds(c),
+                        n_primitives: Some(node.n_primitives[c] as usize),
+                    });
+                } else {
+                    nodes_to_visit.push((node.offset[c], depth + 1, node.child_bounds(c)));
+                }
+            }
+        }
+    }
+
+    fn memory_bytes(&self) -> usize {
+        self.nodes.len() * mem::size_of::<WideBvhNode<N>>()
+            + self.primitives.len() * mem::size_of::<Arc<dyn Primitive>>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use accelerators::stats::Accelerator;
+    use core::medium::Medium;
+    use core::primitive::GeometricPrimitive;
+    use core::transform::Transform;
+    use shapes::sphere::Sphere;
+    use shapes::triangle::{Triangle, TriangleMesh};
+
+    use core::Vector3f;
+
+    const EPSILON: f64 = 0.0001;
+
+    fn random(i: usize, k: f64) -> f64 {
+        utils::hash_float(&[i as f64, k])
+    }
+
+    fn sphere_field(n: usize) -> Vec<Arc<dyn Primitive>> {
+        (0..n)
+            .map(|i| {
+                let center = Vector3f::new(
+                    20.0 * random(i, 0.0) - 10.0,
+                    20.0 * random(i, 1.0) - 10.0,
+                    20.0 * random(i, 2.0) - 10.0,
+                );
+                let radius = (0.2 + 0.5 * random(i, 3.0)) * (200.0 / n as f64).cbrt();
+                let sphere = Sphere::full(Transform::translate(center), radius);
+
+                Arc::new(GeometricPrimitive::new(Arc::new(sphere), None, None, None))
+                    as Arc<dyn Primitive>
+            })
+            .collect()
+    }
+
+    fn random_ray(i: usize) -> Ray {
+        let o = Point3f::new(
+            30.0 * random(i, 10.0) - 15.0,
+            30.0 * random(i, 11.0) - 15.0,
+            30.0 * random(i, 12.0) - 15.0,
+        );
+        let target = Point3f::new(
+            10.0 * random(i, 13.0) - 5.0,
+            10.0 * random(i, 14.0) - 5.0,
+            10.0 * random(i, 15.0) - 5.0,
+        );
+
+        Ray::new(o, target - o, Medium {}, f64::INFINITY, 0.0)
+    }
+
+    fn check_matches_binary<const N: usize>(max_prims_in_node: usize) {
+        let primitives = sphere_field(200);
+        let bvh = BvhAccel::new(primitives.clone(), max_prims_in_node, SplitMethod::Sah);
+        let wide = WideBvhAccel::<N>::new(primitives, max_prims_in_node, SplitMethod::Sah);
+
+        assert!(wide.nodes.len() < bvh.nodes.len() / 2);
+        assert!(wide.nodes.iter().all(|node| node.n_children <= N));
+
+        let mut hits = 0;
+
+        for i in 0..500 {
+            let mut expected_ray = random_ray(i);
+            let mut ray = expected_ray;
+
+            let expected = bvh.intersect(&mut expected_ray);
+            let actual = wide.intersect(&mut ray);
+
+            assert_eq!(expected.is_some(), actual.is_some());
+            assert_eq!(wide.intersect_p(&random_ray(i)), expected.is_some());
+
+            if let (Some(expected), Some(actual)) = (expected, actual) {
+                hits += 1;
+                assert!((expected_ray.t_max - ray.t_max).abs() < EPSILON);
+                assert!((expected.p - actual.p).length() < EPSILON);
+            }
+        }
+
+        assert!(hits > 50);
+    }
+
+    #[test]
+    fn bvh4_matches_binary_bvh() {
+        check_matches_binary::<4>(1);
+    }
+
+    #[test]
+    fn bvh8_matches_binary_bvh() {
+        check_matches_binary::<8>(4);
+    }
+
+    #[test]
+    fn deep_trees_are_traversed() {
+        // Each triangle sits at under half the distance of the last, so middle
+        // splits peel off one triangle per level. The triangles face the
+        // ray along x, which enters every node on the way down.
+        let n = 600;
+        let p = (0..n)
+            .flat_map(|i| {
+                let x = 1000.0 * 0.49f64.powi(i);
+                let s = 0.1 * x;
+
+                vec![
+                    Point3f::new(x, -s, -s),
+                    Point3f::new(x, s, -s),
+                    Point3f::new(x, 0.0, s),
+                ]
+            })
+            .collect();
+        let mesh = TriangleMesh::new(
+            Transform::new(),
+            false,
+            (0..3 * n as usize).collect(),
+            p,
+            None,
+            None,
+            None,
+        );
+        let primitives: Vec<Arc<dyn Primitive>> = Triangle::from_mesh(Arc::new(mesh))
+            .into_iter()
+            .map(|triangle| {
+                Arc::new(GeometricPrimitive::new(
+                    Arc::new(triangle),
+                    None,
+                    None,
+                    None,
+                )) as Arc<dyn Primitive>
+            })
+            .collect();
+        let bvh = BvhAccel::new(primitives.clone(), 1, SplitMethod::Middle);
+        let bvh4 = Bvh4Accel::new(primitives.clone(), 1, SplitMethod::Middle);
+        let bvh8 = Bvh8Accel::new(primitives, 1, SplitMethod::Middle);
+
+        assert!(bvh.stats().max_depth > TRAVERSAL_STACK_CAPACITY);
+
+        let ray = Ray::new(
+            Point3f::new(0.0, 0.0, 0.0),
+            Vector3f::new(1.0, 0.0, 0.0),
+            Medium {},
+            f64::INFINITY,
+            0.0,
+        );
+        let mut expected = ray;
+        assert!(bvh.intersect(&mut expected).is_some());
+
+        for wide in &[&bvh4 as &dyn Primitive, &bvh8] {
+            let mut actual = ray;
+
+            assert!(wide.intersect_p(&ray));
+            assert!(wide.intersect(&mut actual).is_some());
+            assert!((actual.t_max - expected.t_max).abs() < EPSILON);
+        }
+    }
+
+    #[test]
+    fn collapse_keeps_every_primitive() {
+        let wide = Bvh4Accel::new(sphere_field(100), 2, SplitMethod::Sah);
+
+        let leaf_prims: usize = wide
+            .nodes
+            .iter()
+            .flat_map(|node| node.n_primitives[..node.n_children].iter())
+            .map(|&n| n as usize)
+            .sum();
+
+        assert_eq!(leaf_prims, 100);
+
+        let primitives = sphere_field(1);
+        let bvh = BvhAccel::new(primitives.clone(), 4, SplitMethod::Sah);
+        let single = Bvh8Accel::new(primitives, 4, SplitMethod::Sah);
+
+        assert_eq!(single.nodes.len(), 1);
+        assert_eq!(single.nodes[0].n_children, 1);
+
+        for i in 0..100 {
+            assert_eq!(
+                single.intersect_p(&random_ray(i)),
+                bvh.intersect_p(&random_ray(i))
+            );
+        }
+    }
+}
@@ -0,0 +1,265 @@
+use core::animated_transform::AnimatedTransform;
+use core::camera::{Camera, CameraSample};
+use core::medium::Medium;
+use core::ray::Ray;
+use core::utils;
+
+use core::Point2i;
+use core::Point3f;
+use core::Vector3f;
+
+/// A face of a cube map, named for the camera space axis it looks down.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CubeFace {
+    PositiveX,
+    NegativeX,
+    PositiveY,
+    NegativeY,
+    PositiveZ,
+    NegativeZ,
+}
+
+impl CubeFace {
+    /// The faces in the order cube map textures usually store them.
+    pub const ALL: [CubeFace; 6] = [
+        CubeFace::PositiveX,
+        CubeFace::NegativeX,
+        CubeFace::PositiveY,
+        CubeFace::NegativeY,
+        CubeFace::PositiveZ,
+        CubeFace::NegativeZ,
+    ];
+
+    /// The unnormalized direction through the point `(u, v)` of the face,
+    /// with both in [-1, 1] and `v` running down the image. Faces are
+    /// oriented as in the usual cube map texture layout, so +z has +x to
+    /// the right and +y up like the other cameras.
+    pub fn direction(self, u: f64, v: f64) -> Vector3f {
+        match self {
+            CubeFace::PositiveX => Vector3f::new(1.0, -v, -u),
+            CubeFace::NegativeX => Vector3f::new(-1.0, -v, u),
+            CubeFace::PositiveY => Vector3f::new(u, 1.0, v),
+            CubeFace::NegativeY => Vector3f::new(u, -1.0, -v),
+            CubeFace::PositiveZ => Vector3f::new(u, -v, 1.0),
+            CubeFace::NegativeZ => Vector3f::new(-u, -v, -1.0),
+        }
+    }
+}
+
+/// How a `CubemapCamera` arranges the faces on its film.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CubemapLayout {
+    /// All six faces on a film four faces wide and three high, with -x, +z,
+    /// +x and -z along the middle row and +y and -y above and below +z.
+    HorizontalCross,
+    /// A single face filling the film.
+    Face(CubeFace),
+}
+
+/// A camera that sees in every direction from a single point through the
+/// six 90 degree faces of a cube.
+#[derive(Clone, Copy, Debug)]
+pub struct CubemapCamera {
+    pub camera_to_world: AnimatedTransform,
+    pub shutter_open: f64,
+    pub shutter_close: f64,
+    pub full_resolution: Point2i,
+    pub layout: CubemapLayout,
+    pub medium: Medium,
+}
+
+impl CubemapCamera {
+    pub fn new(
+        camera_to_world: AnimatedTransform,
+        full_resolution: Point2i,
+        layout: CubemapLayout,
+        medium: Medium,
+    ) -> Self {
+        Self {
+            camera_to_world,
+            shutter_open: 0.0,
+            shutter_close: 1.0,
+            full_resolution,
+            layout,
+            medium,
+        }
+    }
+
+    /// One camera per face, in `CubeFace::ALL` order, for rendering the
+    /// faces to separate images of `face_resolution`.
+    pub fn faces(
+        camera_to_world: AnimatedTransform,
+        face_resolution: Point2i,
+        medium: Medium,
+    ) -> Vec<Self> {
+        CubeFace::ALL
+            .iter()
+            .map(|&face| {
+                Self::new(
+                    camera_to_world,
+                    face_resolution,
+                    CubemapLayout::Face(face),
+                    medium,
+                )
+            })
+            .collect()
+    }
+
+    pub fn with_shutter(mut self, shutter_open: f64, shutter_close: f64) -> Self {
+        self.shutter_open = shutter_open;
+        self.shutter_close = shutter_close;
+        self
+    }
+
+    /// The face a film position falls on and where on it, in [0, 1]
+    /// squared, or `None` for the empty parts of a cross.
+    fn face_point(&self, x: f64, y: f64) -> Option<(CubeFace, f64, f64)> {
+        let s = x / self.full_resolution.x as f64;
+        let t = y / self.full_resolution.y as f64;
+
+        match self.layout {
+            CubemapLayout::Face(face) => Some((face, s, t)),
+            CubemapLayout::HorizontalCross => {
+                let column = (s * 4.0).floor().clamp(0.0, 3.0);
+                let row = (t * 3.0).floor().clamp(0.0, 2.0);
+
+                let face = match (column as usize, row as usize) {
+                    (1, 0) => CubeFace::PositiveY,
+                    (0, 1) => CubeFace::NegativeX,
+                    (1, 1) => CubeFace::PositiveZ,
+                    (2, 1) => CubeFace::PositiveX,
+                    (3, 1) => CubeFace::NegativeZ,
+                    (1, 2) => CubeFace::NegativeY,
+                    _ => return None,
+                };
+
+                Some((face, s * 4.0 - column, t * 3.0 - row))
+            }
+        }
+    }
+}
+
+impl Camera for CubemapCamera {
+    fn generate_ray(&self, sample: CameraSample) -> (Ray, f64) {
+        let (face, s, t) = match self.face_point(sample.p_film.x, sample.p_film.y) {
+            Some(point) => point,
+            None => return (Ray::zero(), 0.0),
+        };
+
+        let time = utils::lerp(sample.time, self.shutter_open, self.shutter_close);
+        let ray = Ray::new(
+            Point3f::zero(),
+            face.direction(2.0 * s - 1.0, 2.0 * t - 1.0).normalize(),
+            self.medium,
+            f64::INFINITY,
+            time,
+        );
+
+        (self.camera_to_world.interpolate(time).transform(ray), 1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use cameras::perspective::PerspectiveCamera;
+    use core::camera::default_screen_window;
+    use core::transform::Transform;
+
+    use core::Point2f;
+
+    const EPSILON: f64 = 0.00001;
+
+    fn camera_to_world() -> AnimatedTransform {
+        AnimatedTransform::from(
+            Transform::translate(Vector3f::new(1.0, 2.0, 3.0))
+                * Transform::rotate(30.0, Vector3f::new(0.0, 1.0, 0.0)),
+        )
+    }
+
+    fn sample(x: f64, y: f64) -> CameraSample {
+        CameraSample::new(Point2f::new(x, y), Point2f::new(0.5, 0.5), 0.5)
+    }
+
+    #[test]
+    fn positive_z_face_matches_a_perspective_camera() {
+        let resolution = Point2i::new(64, 64);
+        let perspective = PerspectiveCamera::new(
+            camera_to_world(),
+            default_screen_window(resolution),
+            resolution,
+            90.0,
+            Medium {},
+        );
+        let cubemap = CubemapCamera::new(
+            camera_to_world(),
+            resolution,
+            CubemapLayout::Face(CubeFace::PositiveZ),
+            Medium {},
+        );
+
+        for &(x, y) in &[(0.0, 0.0), (32.0, 32.0), (10.5, 50.25), (64.0, 3.0)] {
+            let (a, _) = perspective.generate_ray(sample(x, y));
+            let (b, weight) = cubemap.generate_ray(sample(x, y));
+
+            assert_eq!(weight, 1.0);
+            assert!((a.o - b.o).length() < EPSILON);
+            assert!((a.d - b.d).length() < EPSILON);
+        }
+    }
+
+    #[test]
+    fn separate_faces_cover_each_axis() {
+        let cameras = CubemapCamera::faces(
+            AnimatedTransform::from(Transform::new()),
+            Point2i::new(16, 16),
+            Medium {},
+        );
+        let axes = [
+            Vector3f::new(1.0, 0.0, 0.0),
+            Vector3f::new(-1.0, 0.0, 0.0),
+            Vector3f::new(0.0, 1.0, 0.0),
+            Vector3f::new(0.0, -1.0, 0.0),
+            Vector3f::new(0.0, 0.0, 1.0),
+            Vector3f::new(0.0, 0.0, -1.0),
+        ];
+
+        assert_eq!(cameras.len(), 6);
+
+        for (camera, &axis) in cameras.iter().zip(axes.iter()) {
+            let (ray, _) = camera.generate_ray(sample(8.0, 8.0));
+
+            assert!((ray.d - axis).length() < EPSILON);
+        }
+    }
+
+    #[test]
+    fn cross_faces_meet_along_shared_edges() {
+        let camera = CubemapCamera::new(
+            AnimatedTransform::from(Transform::new()),
+            Point2i::new(400, 300),
+            CubemapLayout::HorizontalCross,
+            Medium {},
+        );
+
+        // Just either side of each edge between neighbouring cells.
+        for &(x, y, dx, dy) in &[
+            (200.0, 150.0, 1.0, 0.0),
+            (100.0, 130.0, 1.0, 0.0),
+            (300.0, 170.0, 1.0, 0.0),
+            (150.0, 100.0, 0.0, 1.0),
+            (120.0, 200.0, 0.0, 1.0),
+        ] {
+            let (a, wa) = camera.generate_ray(sample(x - 1e-6 * dx, y - 1e-6 * dy));
+            let (b, wb) = camera.generate_ray(sample(x + 1e-6 * dx, y + 1e-6 * dy));
+
+            assert_eq!(wa, 1.0);
+            assert_eq!(wb, 1.0);
+            assert!((a.d - b.d).length() < 1e-4);
+        }
+
+        assert_eq!(camera.generate_ray(sample(50.0, 50.0)).1, 0.0);
+        assert_eq!(camera.generate_ray(sample(350.0, 250.0)).1, 0.0);
+    }
+}
@@ -0,0 +1,176 @@
+use core::animated_transform::AnimatedTransform;
+use core::camera::{Camera, CameraSample};
+use core::medium::Medium;
+use core::ray::Ray;
+use core::spherical::{
+    direction_to_equirect, equal_area_sphere_to_square, equal_area_square_to_sphere,
+    equirect_to_direction, wrap_equal_area_square,
+};
+use core::utils;
+
+use core::Point2f;
+use core::Point2i;
+use core::Point3f;
+use core::Vector3f;
+
+/// How an `EnvironmentCamera` lays the sphere of directions out on its film.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EnvironmentMapping {
+    /// Latitude-longitude, with +z along the top row.
+    Equirectangular,
+    /// Equal-area octahedral, with +z at the center of a square film.
+    Octahedral,
+}
+
+impl EnvironmentMapping {
+    /// The camera space direction for a point on the film scaled to the unit
+    /// square.
+    pub fn direction(self, p: Point2f) -> Vector3f {
+        match self {
+            EnvironmentMapping::Equirectangular => equirect_to_direction(p),
+            EnvironmentMapping::Octahedral => {
+                equal_area_square_to_sphere(wrap_equal_area_square(p))
+            }
+        }
+    }
+
+    /// Where on the unit square the camera space direction `d` lands, so
+    /// that a render can be looked up like an environment map.
+    pub fn film_point(self, d: Vector3f) -> Point2f {
+        match self {
+            EnvironmentMapping::Equirectangular => direction_to_equirect(d),
+            EnvironmentMapping::Octahedral => equal_area_sphere_to_square(d),
+        }
+    }
+}
+
+/// A camera that sees in every direction from a single point. Both mappings
+/// follow the environment light conventions in `spherical`, so a render can
+/// be used as the map of an environment light whose light to world
+/// transform matches `camera_to_world`.
+pub struct EnvironmentCamera {
+    pub camera_to_world: AnimatedTransform,
+    pub shutter_open: f64,
+    pub shutter_close: f64,
+    pub full_resolution: Point2i,
+    pub mapping: EnvironmentMapping,
+    pub medium: Medium,
+}
+
+impl EnvironmentCamera {
+    pub fn new(
+        camera_to_world: AnimatedTransform,
+        full_resolution: Point2i,
+        mapping: EnvironmentMapping,
+        medium: Medium,
+    ) -> Self {
+        Self {
+            camera_to_world,
+            shutter_open: 0.0,
+            shutter_close: 1.0,
+            full_resolution,
+            mapping,
+            medium,
+        }
+    }
+
+    pub fn with_shutter(mut self, shutter_open: f64, shutter_close: f64) -> Self {
+        self.shutter_open = shutter_open;
+        self.shutter_close = shutter_close;
+        self
+    }
+}
+
+impl Camera for EnvironmentCamera {
+    fn generate_ray(&self, sample: CameraSample) -> (Ray, f64) {
+        let p = Point2f::new(
+            sample.p_film.x / self.full_resolution.x as f64,
+            sample.p_film.y / self.full_resolution.y as f64,
+        );
+        let time = utils::lerp(sample.time, self.shutter_open, self.shutter_close);
+        let ray = Ray::new(
+            Point3f::zero(),
+            self.mapping.direction(p),
+            self.medium,
+            f64::INFINITY,
+            time,
+        );
+
+        (self.camera_to_world.interpolate(time).transform(ray), 1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use core::transform::Transform;
+    use core::utils::hash_float;
+
+    const EPSILON: f64 = 0.00001;
+
+    fn camera(mapping: EnvironmentMapping, resolution: Point2i) -> EnvironmentCamera {
+        EnvironmentCamera::new(
+            AnimatedTransform::from(
+                Transform::translate(Vector3f::new(1.0, 2.0, 3.0))
+                    * Transform::rotate(90.0, Vector3f::new(1.0, 0.0, 0.0)),
+            ),
+            resolution,
+            mapping,
+            Medium {},
+        )
+    }
+
+    fn sample(x: f64, y: f64) -> CameraSample {
+        CameraSample::new(Point2f::new(x, y), Point2f::new(0.5, 0.5), 0.5)
+    }
+
+    #[test]
+    fn rays_leave_the_camera_position() {
+        let camera = camera(EnvironmentMapping::Equirectangular, Point2i::new(400, 200));
+
+        // The rotation about x turns camera +z into world -y.
+        let (top, weight) = camera.generate_ray(sample(123.0, 0.0));
+        assert_eq!(weight, 1.0);
+        assert!((top.o - Point3f::new(1.0, 2.0, 3.0)).length() < EPSILON);
+        assert!((top.d - Vector3f::new(0.0, -1.0, 0.0)).length() < EPSILON);
+
+        let (horizon, _) = camera.generate_ray(sample(0.0, 100.0));
+        assert!((horizon.d - Vector3f::new(1.0, 0.0, 0.0)).length() < EPSILON);
+    }
+
+    #[test]
+    fn film_points_match_environment_lookups() {
+        let world_to_camera = Transform::rotate(-90.0, Vector3f::new(1.0, 0.0, 0.0));
+
+        for &(mapping, resolution) in &[
+            (EnvironmentMapping::Equirectangular, Point2i::new(256, 128)),
+            (EnvironmentMapping::Octahedral, Point2i::new(128, 128)),
+        ] {
+            let camera = camera(mapping, resolution);
+
+            for i in 0..50 {
+                let x = hash_float(&[i as f64, 0.0]) * resolution.x as f64;
+                let y = hash_float(&[i as f64, 1.0]) * resolution.y as f64;
+
+                let (ray, _) = camera.generate_ray(sample(x, y));
+                let p = mapping.film_point(world_to_camera.transform(ray.d));
+
+                assert!((p.x * resolution.x as f64 - x).abs() < EPSILON);
+                assert!((p.y * resolution.y as f64 - y).abs() < EPSILON);
+            }
+        }
+    }
+
+    #[test]
+    fn differentials_cross_the_octahedral_border() {
+        let camera = camera(EnvironmentMapping::Octahedral, Point2i::new(64, 64));
+        let (rd, weight) = camera.generate_ray_differential(sample(63.99, 20.0));
+        let (rx, _) = camera.generate_ray(sample(63.0, 20.0));
+
+        assert_eq!(weight, 1.0);
+        assert!(rd.has_differentials);
+        let step = (rx.d - rd.ray.d).length();
+        assert!((rd.rx_direction.unwrap() - rd.ray.d).length() < 2.0 * step);
+    }
+}
@@ -0,0 +1,246 @@
+use core::animated_transform::AnimatedTransform;
+use core::camera::{Camera, CameraSample};
+use core::medium::Medium;
+use core::ray::Ray;
+use core::spherical::spherical_direction;
+use core::utils;
+
+use core::Point2i;
+use core::Point3f;
+
+/// How a fisheye lens maps the angle `theta` from its axis to the distance
+/// `r` from the center of the image.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FisheyeProjection {
+    /// `r = theta`, so angles are evenly spaced across the image.
+    Equidistant,
+    /// `r = 2 sin(theta / 2)`, so areas are proportional to solid angle.
+    Equisolid,
+    /// `r = sin(theta)`, which only sees the forward hemisphere.
+    Orthographic,
+    /// `r = 2 tan(theta / 2)`, which keeps shapes locally undistorted.
+    Stereographic,
+}
+
+impl FisheyeProjection {
+    pub fn radius(self, theta: f64) -> f64 {
+        match self {
+            FisheyeProjection::Equidistant => theta,
+            FisheyeProjection::Equisolid => 2.0 * (theta / 2.0).sin(),
+            FisheyeProjection::Orthographic => theta.sin(),
+            FisheyeProjection::Stereographic => 2.0 * (theta / 2.0).tan(),
+        }
+    }
+
+    pub fn theta(self, r: f64) -> f64 {
+        match self {
+            FisheyeProjection::Equidistant => r,
+            FisheyeProjection::Equisolid => 2.0 * utils::safe_asin(r / 2.0),
+            FisheyeProjection::Orthographic => utils::safe_asin(r),
+            FisheyeProjection::Stereographic => 2.0 * (r / 2.0).atan(),
+        }
+    }
+
+    /// The widest field of view in degrees the projection can cover;
+    /// stereographic images grow without bound as it approaches 360.
+    pub fn max_fov(self) -> f64 {
+        match self {
+            FisheyeProjection::Orthographic => 180.0,
+            _ => 360.0,
+        }
+    }
+}
+
+/// A camera looking down its +z axis through a fisheye lens. The image is
+/// a circle inscribed in the film's shorter side that spans `fov` degrees
+/// across its diameter, and film outside of it sees nothing.
+pub struct FisheyeCamera {
+    pub camera_to_world: AnimatedTransform,
+    pub shutter_open: f64,
+    pub shutter_close: f64,
+    pub full_resolution: Point2i,
+    pub projection: FisheyeProjection,
+    pub fov: f64,
+    pub medium: Medium,
+}
+
+impl FisheyeCamera {
+    /// Returns `None` when `fov` is beyond what `projection` can cover.
+    pub fn new(
+        camera_to_world: AnimatedTransform,
+        full_resolution: Point2i,
+        projection: FisheyeProjection,
+        fov: f64,
+        medium: Medium,
+    ) -> Option<Self> {
+        let stereographic = projection == FisheyeProjection::Stereographic;
+
+        if !(fov > 0.0 && fov <= projection.max_fov()) || (stereographic && fov >= 360.0) {
+            return None;
+        }
+
+        Some(Self {
+            camera_to_world,
+            shutter_open: 0.0,
+            shutter_close: 1.0,
+            full_resolution,
+            projection,
+            fov,
+            medium,
+        })
+    }
+
+    pub fn with_shutter(mut self, shutter_open: f64, shutter_close: f64) -> Self {
+        self.shutter_open = shutter_open;
+        self.shutter_close = shutter_close;
+        self
+    }
+}
+
+impl Camera for FisheyeCamera {
+    fn generate_ray(&self, sample: CameraSample) -> (Ray, f64) {
+        let half_width = self.full_resolution.x as f64 / 2.0;
+        let half_height = self.full_resolution.y as f64 / 2.0;
+        let image_radius = half_width.min(half_height);
+
+        // Offsets from the image center with y up, in image radii.
+        let x = (sample.p_film.x - half_width) / image_radius;
+        let y = (half_height - sample.p_film.y) / image_radius;
+        let r = (x * x + y * y).sqrt();
+
+        if r > 1.0 {
+            return (Ray::zero(), 0.0);
+        }
+
+        let theta_max = self.fov.to_radians() / 2.0;
+        let theta = self.projection.theta(r * self.projection.radius(theta_max));
+        let phi = y.atan2(x);
+
+        let time = utils::lerp(sample.time, self.shutter_open, self.shutter_close);
+        let ray = Ray::new(
+            Point3f::zero(),
+            spherical_direction(theta.sin(), theta.cos(), phi),
+            self.medium,
+            f64::INFINITY,
+            time,
+        );
+
+        (self.camera_to_world.interpolate(time).transform(ray), 1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::PI;
+
+    use core::spherical::spherical_theta;
+    use core::transform::Transform;
+
+    use core::Point2f;
+    use core::Vector3f;
+
+    const EPSILON: f64 = 0.00001;
+
+    fn camera(projection: FisheyeProjection, fov: f64) -> FisheyeCamera {
+        FisheyeCamera::new(
+            AnimatedTransform::from(Transform::translate(Vector3f::new(0.0, 1.0, 0.0))),
+            Point2i::new(300, 200),
+            projection,
+            fov,
+            Medium {},
+        )
+        .unwrap()
+    }
+
+    fn sample(x: f64, y: f64) -> CameraSample {
+        CameraSample::new(Point2f::new(x, y), Point2f::new(0.5, 0.5), 0.5)
+    }
+
+    #[test]
+    fn projections_invert() {
+        for &projection in &[
+            FisheyeProjection::Equidistant,
+            FisheyeProjection::Equisolid,
+            FisheyeProjection::Orthographic,
+            FisheyeProjection::Stereographic,
+        ] {
+            for i in 1..10 {
+                let theta = i as f64 * 0.15;
+
+                assert!((projection.theta(projection.radius(theta)) - theta).abs() < EPSILON);
+            }
+        }
+    }
+
+    #[test]
+    fn image_circle_spans_the_field_of_view() {
+        for &(projection, fov) in &[
+            (FisheyeProjection::Equidistant, 360.0),
+            (FisheyeProjection::Equisolid, 220.0),
+            (FisheyeProjection::Orthographic, 180.0),
+            (FisheyeProjection::Stereographic, 270.0),
+        ] {
+            let camera = camera(projection, fov);
+
+            let (center, weight) = camera.generate_ray(sample(150.0, 100.0));
+            assert_eq!(weight, 1.0);
+            assert!((center.o - Point3f::new(0.0, 1.0, 0.0)).length() < EPSILON);
+            assert!((center.d - Vector3f::new(0.0, 0.0, 1.0)).length() < EPSILON);
+
+            // The rim of the circle, straight up from the center.
+            let (top, _) = camera.generate_ray(sample(150.0, 1e-9));
+            assert!((spherical_theta(top.d) - (fov / 2.0).to_radians()).abs() < 1e-4);
+            assert!(top.d.y > 0.0 || fov >= 360.0);
+
+            // The film's corners are outside of the image circle.
+            assert_eq!(camera.generate_ray(sample(1.0, 1.0)).1, 0.0);
+        }
+    }
+
+    #[test]
+    fn unreachable_fields_of_view_are_rejected() {
+        let new = |projection, fov| {
+            FisheyeCamera::new(
+                AnimatedTransform::from(Transform::new()),
+                Point2i::new(64, 64),
+                projection,
+                fov,
+                Medium {},
+            )
+        };
+
+        assert!(new(FisheyeProjection::Orthographic, 200.0).is_none());
+        assert!(new(FisheyeProjection::Stereographic, 360.0).is_none());
+        assert!(new(FisheyeProjection::Equidistant, 0.0).is_none());
+        assert!(new(FisheyeProjection::Equisolid, 360.0).is_some());
+    }
+
+    #[test]
+    fn equidistant_spaces_angles_evenly() {
+        let camera = camera(FisheyeProjection::Equidistant, 180.0);
+
+        for &x in &[175.0, 200.0, 225.0] {
+            let (ray, _) = camera.generate_ray(sample(x, 100.0));
+            let expected = (x - 150.0) / 100.0 * PI / 2.0;
+
+            assert!((spherical_theta(ray.d) - expected).abs() < EPSILON);
+            assert!(ray.d.x > 0.0 && ray.d.y.abs() < EPSILON);
+        }
+    }
+
+    #[test]
+    fn differentials_fall_back_at_the_rim() {
+        let camera = camera(FisheyeProjection::Equisolid, 180.0);
+        let (rd, weight) = camera.generate_ray_differential(sample(249.99, 100.0));
+
+        // Just past the pixel is outside of the image circle, so the step is
+        // taken back towards the center instead.
+        let (inside, _) = camera.generate_ray(sample(248.99, 100.0));
+        let step = (rd.ray.d - inside.d).length();
+
+        assert_eq!(weight, 1.0);
+        assert!(rd.has_differentials);
+        assert!(((rd.rx_direction.unwrap() - rd.ray.d).length() - step).abs() < 0.1 * step);
+    }
+}
@@ -0,0 +1,6 @@
+pub mod cubemap;
+pub mod environment;
+pub mod fisheye;
+pub mod orthographic;
+pub mod perspective;
+pub mod realistic;
@@ -0,0 +1,208 @@
+use core::animated_transform::AnimatedTransform;
+use core::camera::{Camera, CameraSample, ProjectiveCamera};
+use core::medium::Medium;
+use core::ray::Ray;
+use core::ray_differential::RayDifferential;
+use core::transform::Transform;
+
+use core::Bounds2f;
+use core::Point2i;
+use core::Point3f;
+use core::Vector3f;
+
+/// A camera whose rays all run parallel to its +z axis, with the screen
+/// window given in camera space units.
+pub struct OrthographicCamera {
+    pub projective: ProjectiveCamera,
+    /// How far the ray origin moves per pixel.
+    pub dx_camera: Vector3f,
+    pub dy_camera: Vector3f,
+}
+
+impl OrthographicCamera {
+    pub fn new(
+        camera_to_world: AnimatedTransform,
+        screen_window: Bounds2f,
+        full_resolution: Point2i,
+        medium: Medium,
+    ) -> Self {
+        let projective = ProjectiveCamera::new(
+            camera_to_world,
+            Transform::orthographic(0.0, 1.0),
+            screen_window,
+            full_resolution,
+            medium,
+        );
+
+        let r = projective.raster_to_camera;
+
+        Self {
+            dx_camera: r.transform(Vector3f::new(1.0, 0.0, 0.0)),
+            dy_camera: r.transform(Vector3f::new(0.0, 1.0, 0.0)),
+            projective,
+        }
+    }
+
+    pub fn with_lens(mut self, lens_radius: f64, focal_distance: f64) -> Self {
+        self.projective = self.projective.with_lens(lens_radius, focal_distance);
+        self
+    }
+
+    pub fn with_shutter(mut self, shutter_open: f64, shutter_close: f64) -> Self {
+        self.projective = self.projective.with_shutter(shutter_open, shutter_close);
+        self
+    }
+
+    /// The camera space ray leaving `p_camera`, moved across the lens by
+    /// the sample `p_lens` and aimed back at its point on the plane of
+    /// focus when the lens has an aperture.
+    fn camera_ray(&self, p_camera: Point3f, sample: &CameraSample) -> (Point3f, Vector3f) {
+        let d = Vector3f::new(0.0, 0.0, 1.0);
+
+        if self.projective.lens_radius > 0.0 {
+            let (p_lens, ft) = self.projective.lens_point(sample.p_lens, d);
+            let p_focus = p_camera + d * ft;
+            let o = p_camera + (p_lens - Point3f::zero());
+
+            (o, (p_focus - o).normalize())
+        } else {
+            (p_camera, d)
+        }
+    }
+
+    fn p_camera(&self, sample: &CameraSample) -> Point3f {
+        self.projective.raster_to_camera.transform(Point3f::new(
+            sample.p_film.x,
+            sample.p_film.y,
+            0.0,
+        ))
+    }
+}
+
+impl Camera for OrthographicCamera {
+    fn generate_ray(&self, sample: CameraSample) -> (Ray, f64) {
+        let (o, d) = self.camera_ray(self.p_camera(&sample), &sample);
+        let time = self.projective.time(sample.time);
+        let ray = Ray::new(o, d, self.projective.medium, f64::INFINITY, time);
+
+        (self.projective.camera_to_world_at(ray, time), 1.0)
+    }
+
+    fn generate_ray_differential(&self, sample: CameraSample) -> (RayDifferential, f64) {
+        let p_camera = self.p_camera(&sample);
+        let (o, d) = self.camera_ray(p_camera, &sample);
+        let time = self.projective.time(sample.time);
+
+        // Without a lens the offset rays are the main one shifted by a
+        // constant amount per pixel.
+        let (rx_origin, rx_direction) = self.camera_ray(p_camera + self.dx_camera, &sample);
+        let (ry_origin, ry_direction) = self.camera_ray(p_camera + self.dy_camera, &sample);
+
+        let rd = RayDifferential {
+            ray: Ray::new(o, d, self.projective.medium, f64::INFINITY, time),
+            rx_origin: Some(rx_origin),
+            ry_origin: Some(ry_origin),
+            rx_direction: Some(rx_direction),
+            ry_direction: Some(ry_direction),
+            has_differentials: true,
+        };
+
+        (self.projective.camera_to_world_at(rd, time), 1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use core::Point2f;
+
+    const EPSILON: f64 = 0.00001;
+
+    fn camera(lens_radius: f64) -> OrthographicCamera {
+        OrthographicCamera::new(
+            AnimatedTransform::from(
+                Transform::translate(Vector3f::new(0.0, 0.0, -10.0))
+                    * Transform::rotate(90.0, Vector3f::new(0.0, 1.0, 0.0)),
+            ),
+            Bounds2f::new(Point2f::new(-4.0, -2.0), Point2f::new(4.0, 2.0)),
+            Point2i::new(400, 200),
+            Medium {},
+        )
+        .with_lens(lens_radius, 3.0)
+    }
+
+    fn sample(x: f64, y: f64) -> CameraSample {
+        CameraSample::new(Point2f::new(x, y), Point2f::new(0.2, 0.9), 0.5)
+    }
+
+    #[test]
+    fn rays_are_parallel_across_the_screen_window() {
+        let camera = camera(0.0);
+
+        // The camera looks down world +x after the rotation.
+        for &(x, y) in &[(0.0, 0.0), (200.0, 100.0), (400.0, 200.0), (37.0, 151.0)] {
+            let (ray, weight) = camera.generate_ray(sample(x, y));
+
+            assert_eq!(weight, 1.0);
+            assert!((ray.d - Vector3f::new(1.0, 0.0, 0.0)).length() < EPSILON);
+
+            let expected = Point3f::new(0.0, 2.0 - y / 50.0, -10.0 - (x / 50.0 - 4.0));
+            assert!((ray.o - expected).length() < EPSILON);
+        }
+    }
+
+    #[test]
+    fn differentials_are_constant_offsets() {
+        let camera = camera(0.0);
+
+        for &(x, y) in &[(10.0, 20.0), (300.0, 50.0)] {
+            let (rd, _) = camera.generate_ray_differential(sample(x, y));
+
+            assert!(rd.has_differentials);
+            assert!(
+                (rd.rx_origin.unwrap() - rd.ray.o - Vector3f::new(0.0, 0.0, -0.02)).length()
+                    < EPSILON
+            );
+            assert!(
+                (rd.ry_origin.unwrap() - rd.ray.o - Vector3f::new(0.0, -0.02, 0.0)).length()
+                    < EPSILON
+            );
+            assert!((rd.rx_direction.unwrap() - rd.ray.d).length() < EPSILON);
+            assert!((rd.ry_direction.unwrap() - rd.ray.d).length() < EPSILON);
+        }
+    }
+
+    #[test]
+    fn thin_lens_focuses_each_pixel() {
+        let (pinhole, _) = camera(0.0).generate_ray(sample(120.0, 80.0));
+        let in_focus = pinhole.at(3.0);
+        let camera = camera(0.5);
+
+        for &p_lens in &[Point2f::new(0.1, 0.1), Point2f::new(0.9, 0.4)] {
+            let mut s = sample(120.0, 80.0);
+            s.p_lens = p_lens;
+
+            let (ray, _) = camera.generate_ray(s);
+            let p = ray.at((in_focus.x - ray.o.x) / ray.d.x);
+
+            assert!((ray.o - pinhole.o).length() > 0.01);
+            assert!((p - in_focus).length() < EPSILON);
+        }
+
+        let (rd, _) = camera.generate_ray_differential(sample(120.0, 80.0));
+        let (rx, _) = camera.generate_ray(sample(121.0, 80.0));
+
+        assert!((rd.rx_origin.unwrap() - rx.o).length() < EPSILON);
+        assert!((rd.rx_direction.unwrap() - rx.d).length() < EPSILON);
+    }
+
+    #[test]
+    fn shutter_sets_the_ray_time() {
+        let camera = camera(0.0).with_shutter(-1.0, 1.0);
+        let mut s = sample(10.0, 10.0);
+        s.time = 0.75;
+
+        assert!((camera.generate_ray(s).0.time - 0.5).abs() < EPSILON);
+    }
+}
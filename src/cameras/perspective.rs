@@ -0,0 +1,208 @@
+use core::animated_transform::AnimatedTransform;
+use core::camera::{Camera, CameraSample, ProjectiveCamera};
+use core::medium::Medium;
+use core::ray::Ray;
+use core::ray_differential::RayDifferential;
+use core::transform::Transform;
+
+use core::Bounds2f;
+use core::Point2i;
+use core::Point3f;
+use core::Vector3f;
+
+/// A pinhole or thin lens camera looking down its +z axis, with `fov`
+/// spanning the shorter side of the screen window.
+pub struct PerspectiveCamera {
+    pub projective: ProjectiveCamera,
+    /// How far the camera space point on the near plane moves per pixel.
+    pub dx_camera: Vector3f,
+    pub dy_camera: Vector3f,
+}
+
+impl PerspectiveCamera {
+    pub fn new(
+        camera_to_world: AnimatedTransform,
+        screen_window: Bounds2f,
+        full_resolution: Point2i,
+        fov: f64,
+        medium: Medium,
+    ) -> Self {
+        let projective = ProjectiveCamera::new(
+            camera_to_world,
+            Transform::perspective(fov, 1e-2, 1000.0),
+            screen_window,
+            full_resolution,
+            medium,
+        );
+
+        let r = projective.raster_to_camera;
+        let origin = r.transform(Point3f::new(0.0, 0.0, 0.0));
+
+        Self {
+            dx_camera: r.transform(Point3f::new(1.0, 0.0, 0.0)) - origin,
+            dy_camera: r.transform(Point3f::new(0.0, 1.0, 0.0)) - origin,
+            projective,
+        }
+    }
+
+    pub fn with_lens(mut self, lens_radius: f64, focal_distance: f64) -> Self {
+        self.projective = self.projective.with_lens(lens_radius, focal_distance);
+        self
+    }
+
+    pub fn with_shutter(mut self, shutter_open: f64, shutter_close: f64) -> Self {
+        self.projective = self.projective.with_shutter(shutter_open, shutter_close);
+        self
+    }
+
+    /// The camera space ray through `p_camera` on the near plane, bent
+    /// through the lens sample `p_lens` when the lens has an aperture.
+    fn camera_ray(&self, p_camera: Point3f, sample: &CameraSample) -> (Point3f, Vector3f) {
+        let d = (p_camera - Point3f::zero()).normalize();
+
+        if self.projective.lens_radius > 0.0 {
+            let (p_lens, ft) = self.projective.lens_point(sample.p_lens, d);
+            let p_focus = Point3f::zero() + d * ft;
+
+            (p_lens, (p_focus - p_lens).normalize())
+        } else {
+            (Point3f::zero(), d)
+        }
+    }
+
+    fn p_camera(&self, sample: &CameraSample) -> Point3f {
+        self.projective.raster_to_camera.transform(Point3f::new(
+            sample.p_film.x,
+            sample.p_film.y,
+            0.0,
+        ))
+    }
+}
+
+impl Camera for PerspectiveCamera {
+    fn generate_ray(&self, sample: CameraSample) -> (Ray, f64) {
+        let (o, d) = self.camera_ray(self.p_camera(&sample), &sample);
+        let time = self.projective.time(sample.time);
+        let ray = Ray::new(o, d, self.projective.medium, f64::INFINITY, time);
+
+        (self.projective.camera_to_world_at(ray, time), 1.0)
+    }
+
+    fn generate_ray_differential(&self, sample: CameraSample) -> (RayDifferential, f64) {
+        let p_camera = self.p_camera(&sample);
+        let (o, d) = self.camera_ray(p_camera, &sample);
+        let time = self.projective.time(sample.time);
+
+        // With a lens, the offset rays leave from the same lens point as the
+        // main ray and pass through their own points on the plane of focus.
+        let (rx_origin, rx_direction) = self.camera_ray(p_camera + self.dx_camera, &sample);
+        let (ry_origin, ry_direction) = self.camera_ray(p_camera + self.dy_camera, &sample);
+
+        let rd = RayDifferential {
+            ray: Ray::new(o, d, self.projective.medium, f64::INFINITY, time),
+            rx_origin: Some(rx_origin),
+            ry_origin: Some(ry_origin),
+            rx_direction: Some(rx_direction),
+            ry_direction: Some(ry_direction),
+            has_differentials: true,
+        };
+
+        (self.projective.camera_to_world_at(rd, time), 1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use core::camera::default_screen_window;
+
+    use core::Point2f;
+
+    const EPSILON: f64 = 0.00001;
+
+    fn camera(lens_radius: f64) -> PerspectiveCamera {
+        let resolution = Point2i::new(200, 100);
+
+        PerspectiveCamera::new(
+            AnimatedTransform::from(Transform::translate(Vector3f::new(1.0, 2.0, 3.0))),
+            default_screen_window(resolution),
+            resolution,
+            90.0,
+            Medium {},
+        )
+        .with_lens(lens_radius, 5.0)
+    }
+
+    fn sample(x: f64, y: f64) -> CameraSample {
+        CameraSample::new(Point2f::new(x, y), Point2f::new(0.8, 0.3), 0.5)
+    }
+
+    #[test]
+    fn rays_follow_the_field_of_view() {
+        let camera = camera(0.0);
+
+        let (center, weight) = camera.generate_ray(sample(100.0, 50.0));
+        assert_eq!(weight, 1.0);
+        assert!((center.o - Point3f::new(1.0, 2.0, 3.0)).length() < EPSILON);
+        assert!((center.d - Vector3f::new(0.0, 0.0, 1.0)).length() < EPSILON);
+
+        // 90 degrees span the shorter, vertical side; raster y runs down.
+        let (corner, _) = camera.generate_ray(sample(0.0, 0.0));
+        let expected = Vector3f::new(-2.0, 1.0, 1.0).normalize();
+        assert!((corner.d - expected).length() < EPSILON);
+    }
+
+    #[test]
+    fn thin_lens_rays_meet_on_the_plane_of_focus() {
+        let camera = camera(0.5);
+        let mut focus: Option<Point3f> = None;
+
+        for &p_lens in &[
+            Point2f::new(0.1, 0.9),
+            Point2f::new(0.7, 0.2),
+            Point2f::new(0.5, 0.5),
+        ] {
+            let mut s = sample(30.0, 70.0);
+            s.p_lens = p_lens;
+
+            let (ray, _) = camera.generate_ray(s);
+            assert!((ray.d.length() - 1.0).abs() < EPSILON);
+
+            let p = ray.at((5.0 + 3.0 - ray.o.z) / ray.d.z);
+            if let Some(f) = focus {
+                assert!((f - p).length() < EPSILON);
+            }
+
+            focus = Some(p);
+        }
+    }
+
+    #[test]
+    fn shutter_sets_the_ray_time() {
+        let camera = camera(0.0).with_shutter(2.0, 4.0);
+        let mut s = sample(10.0, 10.0);
+        s.time = 0.25;
+
+        assert!((camera.generate_ray(s).0.time - 2.5).abs() < EPSILON);
+    }
+
+    #[test]
+    fn differentials_match_neighbouring_pixels() {
+        for &lens_radius in &[0.0, 0.5] {
+            let camera = camera(lens_radius);
+            let (rd, weight) = camera.generate_ray_differential(sample(30.0, 70.0));
+
+            assert_eq!(weight, 1.0);
+            assert!(rd.has_differentials);
+
+            let (rx, _) = camera.generate_ray(sample(31.0, 70.0));
+            let (ry, _) = camera.generate_ray(sample(30.0, 71.0));
+
+            assert!((rd.rx_origin.unwrap() - rx.o).length() < EPSILON);
+            assert!((rd.ry_origin.unwrap() - ry.o).length() < EPSILON);
+            assert!((rd.rx_direction.unwrap() - rx.d).length() < EPSILON);
+            assert!((rd.ry_direction.unwrap() - ry.d).length() < EPSILON);
+        }
+    }
+}
@@ -0,0 +1,622 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use core::animated_transform::AnimatedTransform;
+use core::camera::{Camera, CameraSample};
+use core::medium::Medium;
+use core::ray::Ray;
+use core::transform::Transform;
+use core::utils;
+
+use core::Bounds2f;
+use core::Point2f;
+use core::Point2i;
+use core::Point3f;
+use core::Vector3f;
+
+/// How many radial intervals of the film get their own exit pupil bounds,
+/// and how many rays each interval is bounded with.
+const EXIT_PUPIL_INTERVALS: usize = 64;
+const EXIT_PUPIL_SAMPLES: usize = 1 << 14;
+
+/// One surface of a lens system, in meters. A `curvature_radius` of zero
+/// marks the aperture stop, and `eta` is the index of refraction of the
+/// medium behind the surface, with zero meaning air.
+#[derive(Clone, Copy, Debug)]
+pub struct LensElementInterface {
+    pub curvature_radius: f64,
+    pub thickness: f64,
+    pub eta: f64,
+    pub aperture_radius: f64,
+}
+
+/// Parses a lens prescription in the usual table format: one surface per
+/// line, front to back, giving its curvature radius, its thickness along
+/// the axis, its index of refraction and its aperture diameter, all in
+/// millimeters. Lines starting with `#` are comments.
+pub fn parse_lens_table(text: &str) -> io::Result<Vec<LensElementInterface>> {
+    let mut elements = Vec::new();
+
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let values = line
+            .split_whitespace()
+            .map(|token| {
+                token
+                    .parse::<f64>()
+                    .map_err(|_| invalid_data("malformed lens table entry"))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        if values.len() != 4 {
+            return Err(invalid_data("lens table rows need four values"));
+        }
+
+        elements.push(LensElementInterface {
+            curvature_radius: values[0] * 0.001,
+            thickness: values[1] * 0.001,
+            eta: values[2],
+            aperture_radius: values[3] * 0.001 / 2.0,
+        });
+    }
+
+    if elements.is_empty() {
+        return Err(invalid_data("empty lens table"));
+    }
+
+    Ok(elements)
+}
+
+pub fn read_lens_table<P: AsRef<Path>>(path: P) -> io::Result<Vec<LensElementInterface>> {
+    parse_lens_table(&fs::read_to_string(path)?)
+}
+
+/// A camera that traces each ray from the film through a real lens system.
+/// The lens sits along camera space +z with its rear element facing the
+/// film at z = 0, and the film is `film_diagonal` meters across.
+pub struct RealisticCamera {
+    pub camera_to_world: AnimatedTransform,
+    pub shutter_open: f64,
+    pub shutter_close: f64,
+    pub full_resolution: Point2i,
+    pub film_diagonal: f64,
+    pub element_interfaces: Vec<LensElementInterface>,
+    /// Bounds on the rear element of the rays that make it through the lens
+    /// from each radial interval of the film, for a film point on +x.
+    pub exit_pupil_bounds: Vec<Bounds2f>,
+    pub medium: Medium,
+}
+
+impl RealisticCamera {
+    /// Sets up the lens system with its aperture stop opened to
+    /// `aperture_diameter`, on a film whose diagonal is `film_diagonal`,
+    /// both in millimeters like the lens table, and moves the film to focus
+    /// at `focus_distance` meters from it. Returns `None` when the lens
+    /// can't focus there.
+    pub fn new(
+        camera_to_world: AnimatedTransform,
+        mut element_interfaces: Vec<LensElementInterface>,
+        aperture_diameter: f64,
+        focus_distance: f64,
+        full_resolution: Point2i,
+        film_diagonal: f64,
+        medium: Medium,
+    ) -> Option<Self> {
+        // The stop can't open wider than the table allows.
+        for element in element_interfaces.iter_mut() {
+            if element.curvature_radius == 0.0 {
+                element.aperture_radius =
+                    element.aperture_radius.min(aperture_diameter * 0.001 / 2.0);
+            }
+        }
+
+        let mut camera = Self {
+            camera_to_world,
+            shutter_open: 0.0,
+            shutter_close: 1.0,
+            full_resolution,
+            film_diagonal: film_diagonal * 0.001,
+            element_interfaces,
+            exit_pupil_bounds: Vec::new(),
+            medium,
+        };
+
+        let thickness = camera.focus_thick_lens(focus_distance)?;
+        camera.element_interfaces.last_mut().unwrap().thickness = thickness;
+
+        camera.exit_pupil_bounds = (0..EXIT_PUPIL_INTERVALS)
+            .map(|i| {
+                let r = camera.film_diagonal / 2.0;
+
+                camera.bound_exit_pupil(
+                    i as f64 / EXIT_PUPIL_INTERVALS as f64 * r,
+                    (i + 1) as f64 / EXIT_PUPIL_INTERVALS as f64 * r,
+                )
+            })
+            .collect();
+
+        Some(camera)
+    }
+
+    pub fn with_shutter(mut self, shutter_open: f64, shutter_close: f64) -> Self {
+        self.shutter_open = shutter_open;
+        self.shutter_close = shutter_close;
+        self
+    }
+
+    fn lens_rear_z(&self) -> f64 {
+        self.element_interfaces.last().unwrap().thickness
+    }
+
+    fn lens_front_z(&self) -> f64 {
+        self.element_interfaces.iter().map(|e| e.thickness).sum()
+    }
+
+    fn rear_element_radius(&self) -> f64 {
+        self.element_interfaces.last().unwrap().aperture_radius
+    }
+
+    /// The film's extent in camera space, centered on the lens axis.
+    pub fn physical_extent(&self) -> Bounds2f {
+        let aspect = self.full_resolution.y as f64 / self.full_resolution.x as f64;
+        let x = (self.film_diagonal * self.film_diagonal / (1.0 + aspect * aspect)).sqrt();
+        let y = aspect * x;
+
+        Bounds2f::new(
+            Point2f::new(-x / 2.0, -y / 2.0),
+            Point2f::new(x / 2.0, y / 2.0),
+        )
+    }
+
+    /// Follows a camera space ray leaving the film through every lens
+    /// surface, returning the ray that leaves the front element or `None`
+    /// when it's blocked along the way.
+    pub fn trace_lenses_from_film(&self, r_camera: Ray) -> Option<Ray> {
+        let camera_to_lens = Transform::scale(1.0, 1.0, -1.0);
+        let mut r_lens = camera_to_lens.transform(r_camera);
+        let mut element_z = 0.0;
+
+        for (i, element) in self.element_interfaces.iter().enumerate().rev() {
+            element_z -= element.thickness;
+
+            let (t, n) = self.intersect_element(element, element_z, &r_lens)?;
+            let p_hit = r_lens.at(t);
+
+            if p_hit.x * p_hit.x + p_hit.y * p_hit.y
+                > element.aperture_radius * element.aperture_radius
+            {
+                return None;
+            }
+
+            r_lens.o = p_hit;
+
+            if let Some(n) = n {
+                let eta_i = element.eta;
+                let eta_t = if i > 0 && self.element_interfaces[i - 1].eta != 0.0 {
+                    self.element_interfaces[i - 1].eta
+                } else {
+                    1.0
+                };
+
+                r_lens.d = refract(-r_lens.d.normalize(), n, eta_i / eta_t)?;
+            }
+        }
+
+        Some(camera_to_lens.transform(r_lens))
+    }
+
+    /// Follows a camera space ray from the scene back through the lens to
+    /// the film side of the rear element.
+    pub fn trace_lenses_from_scene(&self, r_camera: Ray) -> Option<Ray> {
+        let camera_to_lens = Transform::scale(1.0, 1.0, -1.0);
+        let mut r_lens = camera_to_lens.transform(r_camera);
+        let mut element_z = -self.lens_front_z();
+
+        for (i, element) in self.element_interfaces.iter().enumerate() {
+            let (t, n) = self.intersect_element(element, element_z, &r_lens)?;
+            let p_hit = r_lens.at(t);
+
+            if p_hit.x * p_hit.x + p_hit.y * p_hit.y
+                > element.aperture_radius * element.aperture_radius
+            {
+                return None;
+            }
+
+            r_lens.o = p_hit;
+
+            if let Some(n) = n {
+                let eta_i = if i == 0 || self.element_interfaces[i - 1].eta == 0.0 {
+                    1.0
+                } else {
+                    self.element_interfaces[i - 1].eta
+                };
+                let eta_t = if element.eta != 0.0 { element.eta } else { 1.0 };
+
+                r_lens.d = refract(-r_lens.d.normalize(), n, eta_i / eta_t)?;
+            }
+
+            element_z += element.thickness;
+        }
+
+        Some(camera_to_lens.transform(r_lens))
+    }
+
+    /// Where a lens space ray meets the surface at `element_z` on the axis,
+    /// with the surface normal facing the ray, or no normal for the flat
+    /// aperture stop.
+    fn intersect_element(
+        &self,
+        element: &LensElementInterface,
+        element_z: f64,
+        r: &Ray,
+    ) -> Option<(f64, Option<Vector3f>)> {
+        if element.curvature_radius == 0.0 {
+            if r.d.z == 0.0 {
+                return None;
+            }
+
+            let t = (element_z - r.o.z) / r.d.z;
+
+            return if t < 0.0 { None } else { Some((t, None)) };
+        }
+
+        let radius = element.curvature_radius;
+        let o = r.o - Vector3f::new(0.0, 0.0, element_z + radius);
+
+        let a = r.d.length_squared();
+        let b = 2.0 * (r.d.x * o.x + r.d.y * o.y + r.d.z * o.z);
+        let c = o.x * o.x + o.y * o.y + o.z * o.z - radius * radius;
+        let (t0, t1) = utils::solve_quadratic(a, b, c)?;
+
+        // Of the two hits on the sphere, the surface is the one on the side
+        // of its center the ray comes from.
+        let use_closer_t = (r.d.z > 0.0) ^ (radius < 0.0);
+        let t = if use_closer_t { t0.min(t1) } else { t0.max(t1) };
+
+        if t < 0.0 {
+            return None;
+        }
+
+        let n = Vector3f::from(o + r.d * t).normalize();
+        let n = if n.dot(-r.d) < 0.0 { -n } else { n };
+
+        Some((t, Some(n)))
+    }
+
+    /// The focal point and principal plane on the axis for a paraxial ray
+    /// `r_in` entering the lens and the `r_out` that leaves it.
+    fn cardinal_points(r_in: &Ray, r_out: &Ray) -> (f64, f64) {
+        let tf = -r_out.o.x / r_out.d.x;
+        let fz = -r_out.at(tf).z;
+        let tp = (r_in.o.x - r_out.o.x) / r_out.d.x;
+        let pz = -r_out.at(tp).z;
+
+        (pz, fz)
+    }
+
+    /// The principal planes and focal points of the thick lens that best
+    /// matches the system, found with paraxial rays from either side.
+    fn thick_lens_approximation(&self) -> Option<([f64; 2], [f64; 2])> {
+        let x = 0.001 * self.film_diagonal;
+
+        let r_scene = Ray::new(
+            Point3f::new(x, 0.0, self.lens_front_z() + 1.0),
+            Vector3f::new(0.0, 0.0, -1.0),
+            self.medium,
+            f64::INFINITY,
+            0.0,
+        );
+        let r_film = self.trace_lenses_from_scene(r_scene)?;
+        let (pz0, fz0) = Self::cardinal_points(&r_scene, &r_film);
+
+        let r_film = Ray::new(
+            Point3f::new(x, 0.0, self.lens_rear_z() - 1.0),
+            Vector3f::new(0.0, 0.0, 1.0),
+            self.medium,
+            f64::INFINITY,
+            0.0,
+        );
+        let r_scene = self.trace_lenses_from_film(r_film)?;
+        let (pz1, fz1) = Self::cardinal_points(&r_film, &r_scene);
+
+        Some(([pz0, pz1], [fz0, fz1]))
+    }
+
+    /// The rear element's distance from the film that focuses the thick
+    /// lens approximation at `focus_distance`.
+    fn focus_thick_lens(&self, focus_distance: f64) -> Option<f64> {
+        let (pz, fz) = self.thick_lens_approximation()?;
+
+        let f = fz[0] - pz[0];
+        let z = -focus_distance;
+        let c = (pz[1] - z - pz[0]) * (pz[1] - z - 4.0 * f - pz[0]);
+
+        if c <= 0.0 {
+            return None;
+        }
+
+        let delta = 0.5 * (pz[1] - z + pz[0] - c.sqrt());
+
+        Some(self.lens_rear_z() + delta)
+    }
+
+    /// Bounds on the rear element of the rays that get through the lens
+    /// from film points at distances between `p_film_x0` and `p_film_x1`
+    /// along +x.
+    fn bound_exit_pupil(&self, p_film_x0: f64, p_film_x1: f64) -> Bounds2f {
+        let rear_radius = self.rear_element_radius();
+        let proj_rear_bounds = Bounds2f::new(
+            Point2f::new(-1.5 * rear_radius, -1.5 * rear_radius),
+            Point2f::new(1.5 * rear_radius, 1.5 * rear_radius),
+        );
+
+        let mut pupil_bounds = Bounds2f::zero();
+        let mut n_exiting_rays = 0;
+
+        for i in 0..EXIT_PUPIL_SAMPLES {
+            let p_film = Point3f::new(
+                utils::lerp(
+                    (i as f64 + 0.5) / EXIT_PUPIL_SAMPLES as f64,
+                    p_film_x0,
+                    p_film_x1,
+                ),
+                0.0,
+                0.0,
+            );
+            let u = Point2f::new(
+                utils::hash_float(&[i as f64, 0.0]),
+                utils::hash_float(&[i as f64, 1.0]),
+            );
+            let p = proj_rear_bounds.lerp(u);
+            let p_rear = Point3f::new(p.x, p.y, self.lens_rear_z());
+
+            if pupil_bounds.inside(p)
+                || self
+                    .trace_lenses_from_film(Ray::new(
+                        p_film,
+                        p_rear - p_film,
+                        self.medium,
+                        f64::INFINITY,
+                        0.0,
+                    ))
+                    .is_some()
+            {
+                pupil_bounds = pupil_bounds.union(p);
+                n_exiting_rays += 1;
+            }
+        }
+
+        if n_exiting_rays == 0 {
+            return proj_rear_bounds;
+        }
+
+        // Pad for the gaps between the samples.
+        pupil_bounds
+            .expand(2.0 * proj_rear_bounds.diagonal().length() / (EXIT_PUPIL_SAMPLES as f64).sqrt())
+    }
+
+    /// A point on the rear element for the sample `u`, taken from the exit
+    /// pupil bounds for `p_film` rotated around to it, along with the area
+    /// of those bounds.
+    fn sample_exit_pupil(&self, p_film: Point2f, u: Point2f) -> (Point3f, f64) {
+        let r_film = (p_film.x * p_film.x + p_film.y * p_film.y).sqrt();
+        let r_index =
+            (r_film / (self.film_diagonal / 2.0) * self.exit_pupil_bounds.len() as f64) as usize;
+        let pupil_bounds = self.exit_pupil_bounds[r_index.min(self.exit_pupil_bounds.len() - 1)];
+
+        let p_lens = pupil_bounds.lerp(u);
+        let (sin_theta, cos_theta) = if r_film != 0.0 {
+            (p_film.y / r_film, p_film.x / r_film)
+        } else {
+            (0.0, 1.0)
+        };
+
+        (
+            Point3f::new(
+                cos_theta * p_lens.x - sin_theta * p_lens.y,
+                sin_theta * p_lens.x + cos_theta * p_lens.y,
+                self.lens_rear_z(),
+            ),
+            pupil_bounds.area(),
+        )
+    }
+}
+
+impl Camera for RealisticCamera {
+    /// The weight falls off with the cosine to the fourth of the angle the
+    /// ray leaves the film at and with the size of the exit pupil, relative
+    /// to the film's center, which gives the lens's natural vignetting.
+    fn generate_ray(&self, sample: CameraSample) -> (Ray, f64) {
+        let s = Point2f::new(
+            sample.p_film.x / self.full_resolution.x as f64,
+            sample.p_film.y / self.full_resolution.y as f64,
+        );
+
+        // The lens flips the image, so the film is flipped to match.
+        let p_film2 = self.physical_extent().lerp(s);
+        let p_film = Point3f::new(-p_film2.x, p_film2.y, 0.0);
+
+        let (p_rear, exit_pupil_bounds_area) =
+            self.sample_exit_pupil(Point2f::new(p_film.x, p_film.y), sample.p_lens);
+
+        let time = utils::lerp(sample.time, self.shutter_open, self.shutter_close);
+        let r_film = Ray::new(p_film, p_rear - p_film, self.medium, f64::INFINITY, time);
+
+        let mut ray = match self.trace_lenses_from_film(r_film) {
+            Some(ray) => self.camera_to_world.interpolate(time).transform(ray),
+            None => return (Ray::zero(), 0.0),
+        };
+        ray.d = ray.d.normalize();
+
+        let cos_theta = r_film.d.normalize().z;
+        let cos_4_theta = (cos_theta * cos_theta) * (cos_theta * cos_theta);
+
+        (
+            ray,
+            cos_4_theta * exit_pupil_bounds_area / self.exit_pupil_bounds[0].area(),
+        )
+    }
+}
+
+/// The direction `wi` leaves in after passing into a medium through a
+/// surface with normal `n` on its side, with `eta` the ratio of the indices
+/// of refraction, or `None` under total internal reflection.
+fn refract(wi: Vector3f, n: Vector3f, eta: f64) -> Option<Vector3f> {
+    let cos_theta_i = n.dot(wi);
+    let sin_2_theta_i = (1.0 - cos_theta_i * cos_theta_i).max(0.0);
+    let sin_2_theta_t = eta * eta * sin_2_theta_i;
+
+    if sin_2_theta_t >= 1.0 {
+        return None;
+    }
+
+    let cos_theta_t = (1.0 - sin_2_theta_t).sqrt();
+
+    Some(-wi * eta + n * (eta * cos_theta_i - cos_theta_t))
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EPSILON: f64 = 0.00001;
+
+    // A double Gauss lens scaled to a 50mm focal length.
+    const DOUBLE_GAUSS: &str = "
+        # radius  thickness  eta    aperture
+        29.475    3.76       1.67   25.2
+        84.83     0.12       1      25.2
+        19.275    4.025      1.67   23
+        40.77     3.275      1.699  23
+        12.75     5.705      1      18
+        0         4.5        0      17.1
+        -14.495   1.18       1.603  17
+        40.77     6.065      1.658  20
+        -20.385   0.19       1      20
+        437.065   3.22       1.717  20
+        -39.73    0          1      20
+    ";
+
+    fn camera(aperture_diameter: f64, focus_distance: f64) -> RealisticCamera {
+        RealisticCamera::new(
+            AnimatedTransform::from(Transform::new()),
+            parse_lens_table(DOUBLE_GAUSS).unwrap(),
+            aperture_diameter,
+            focus_distance,
+            Point2i::new(60, 40),
+            35.0,
+            Medium {},
+        )
+        .unwrap()
+    }
+
+    fn sample(x: f64, y: f64, u: f64, v: f64) -> CameraSample {
+        CameraSample::new(Point2f::new(x, y), Point2f::new(u, v), 0.5)
+    }
+
+    #[test]
+    fn lens_table_parses_in_meters() {
+        let elements = parse_lens_table(DOUBLE_GAUSS).unwrap();
+
+        assert_eq!(elements.len(), 11);
+        assert!((elements[0].curvature_radius - 0.029475).abs() < EPSILON);
+        assert!((elements[0].thickness - 0.00376).abs() < EPSILON);
+        assert!((elements[0].aperture_radius - 0.0126).abs() < EPSILON);
+        assert_eq!(elements[5].eta, 0.0);
+
+        assert!(parse_lens_table("1 2 3").is_err());
+        assert!(parse_lens_table("1 2 x 4").is_err());
+        assert!(parse_lens_table("# nothing\n").is_err());
+    }
+
+    #[test]
+    fn rays_focus_at_the_focus_distance() {
+        let camera = camera(4.0, 2.0);
+
+        // Rays from the film's center cross the axis near the focus
+        // distance, wherever they pass through the pupil.
+        let mut n_rays = 0;
+
+        for i in 0..32 {
+            let u = utils::hash_float(&[i as f64, 0.0]);
+            let (ray, weight) = camera.generate_ray(sample(30.0, 20.0, u, 0.5));
+
+            if weight == 0.0 || ray.d.x.abs() < 1e-6 {
+                continue;
+            }
+
+            let p = ray.at(-ray.o.x / ray.d.x);
+
+            assert!((p.z - 2.0).abs() < 0.05);
+            n_rays += 1;
+        }
+
+        assert!(n_rays > 16);
+    }
+
+    #[test]
+    fn exit_pupil_bounds_hold_the_rays_that_get_through() {
+        let camera = camera(17.1, 5.0);
+        let rear_radius = camera.rear_element_radius();
+
+        for i in 0..2000 {
+            let x = utils::hash_float(&[i as f64, 0.0]) * camera.film_diagonal / 2.0;
+            let p_rear = Point3f::new(
+                (2.0 * utils::hash_float(&[i as f64, 1.0]) - 1.0) * 1.5 * rear_radius,
+                (2.0 * utils::hash_float(&[i as f64, 2.0]) - 1.0) * 1.5 * rear_radius,
+                camera.lens_rear_z(),
+            );
+            let p_film = Point3f::new(x, 0.0, 0.0);
+            let ray = Ray::new(p_film, p_rear - p_film, Medium {}, f64::INFINITY, 0.0);
+
+            if camera.trace_lenses_from_film(ray).is_some() {
+                let index =
+                    (x / (camera.film_diagonal / 2.0) * EXIT_PUPIL_INTERVALS as f64) as usize;
+
+                assert!(camera.exit_pupil_bounds[index].inside(Point2f::new(p_rear.x, p_rear.y)));
+            }
+        }
+    }
+
+    #[test]
+    fn corners_are_vignetted() {
+        let camera = camera(17.1, 5.0);
+        let average_weight = |x: f64, y: f64| {
+            (0..64)
+                .map(|i| {
+                    let u = utils::hash_float(&[i as f64, 0.0]);
+                    let v = utils::hash_float(&[i as f64, 1.0]);
+
+                    camera.generate_ray(sample(x, y, u, v)).1
+                })
+                .sum::<f64>()
+                / 64.0
+        };
+
+        let center = average_weight(30.0, 20.0);
+        let corner = average_weight(0.5, 0.5);
+
+        assert!(center > 0.5);
+        assert!(corner < 0.8 * center);
+    }
+
+    #[test]
+    fn image_is_upright() {
+        let camera = camera(4.0, 5.0).with_shutter(1.0, 3.0);
+
+        // The top left of the image sees up and to the left.
+        let (ray, weight) = camera.generate_ray(sample(5.0, 5.0, 0.5, 0.5));
+
+        assert!(weight > 0.0);
+        assert!(ray.d.x < 0.0 && ray.d.y > 0.0 && ray.d.z > 0.0);
+        assert!((ray.time - 2.0).abs() < EPSILON);
+    }
+}
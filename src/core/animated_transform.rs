@@ -0,0 +1,293 @@
+use core::matrix44::Matrix44;
+use core::quaternion::Quaternion;
+use core::transform::Transform;
+use core::utils::safe_acos;
+
+use core::Bounds3f;
+use core::Vector3f;
+
+// Number of times sampled over the shutter interval when bounding the
+// motion of a rotating transform.
+const MOTION_BOUNDS_STEPS: usize = 128;
+
+/// A transformation that varies over `[start_time, end_time]`, interpolated
+/// by decomposing both keyframes into translation, rotation and scale.
+#[derive(Clone, Copy, Debug)]
+pub struct AnimatedTransform {
+    pub start_transform: Transform,
+    pub end_transform: Transform,
+    pub start_time: f64,
+    pub end_time: f64,
+    actually_animated: bool,
+    t: [Vector3f; 2],
+    r: [Quaternion; 2],
+    s: [Matrix44; 2],
+}
+
+impl AnimatedTransform {
+    pub fn new(
+        start_transform: Transform,
+        start_time: f64,
+        end_transform: Transform,
+        end_time: f64,
+    ) -> Self {
+        let (t0, r0, s0) = decompose(start_transform.m);
+        let (t1, mut r1, s1) = decompose(end_transform.m);
+
+        // Take the shortest path between the two rotations.
+        if r0.dot(r1) < 0.0 {
+            r1 = -r1;
+        }
+
+        Self {
+            start_transform,
+            end_transform,
+            start_time,
+            end_time,
+            actually_animated: start_transform != end_transform,
+            t: [t0, t1],
+            r: [r0, r1],
+            s: [s0, s1],
+        }
+    }
+
+    pub fn is_animated(&self) -> bool {
+        self.actually_animated
+    }
+
+    pub fn interpolate(&self, time: f64) -> Transform {
+        if !self.actually_animated || time <= self.start_time {
+            return self.start_transform;
+        }
+
+        if time >= self.end_time {
+            return self.end_transform;
+        }
+
+        let dt = (time - self.start_time) / (self.end_time - self.start_time);
+
+        let trans = self.t[0] * (1.0 - dt) + self.t[1] * dt;
+        let rotate = self.r[0].slerp(self.r[1], dt);
+
+        let mut scale = Matrix44::zero();
+
+        for i in 0..4 {
+            for j in 0..4 {
+                scale[i][j] = (1.0 - dt) * self.s[0][i][j] + dt * self.s[1][i][j];
+            }
+        }
+
+        Transform::translate(trans) * rotate.to_transform() * Transform::from(scale)
+    }
+
+    /// Bounds `b` over the whole time range.
+    pub fn motion_bounds(&self, b: Bounds3f) -> Bounds3f {
+        if !self.actually_animated {
+            return self.start_transform.transform(b);
+        }
+
+        let mut result = Bounds3f::zero();
+
+        for i in 0..MOTION_BOUNDS_STEPS {
+            let dt = i as f64 / (MOTION_BOUNDS_STEPS - 1) as f64;
+            let time = (1.0 - dt) * self.start_time + dt * self.end_time;
+
+            result = result.union_bounds(self.interpolate(time).transform(b));
+        }
+
+        result.expand(self.max_chord_error(b))
+    }
+
+    /// How far a point of `b` can stray between two consecutive times
+    /// sampled by `motion_bounds` from the straight line joining its
+    /// positions at those times, which the union of the sampled bounds
+    /// already covers.
+    ///
+    /// Translation and scale are linear in time, so only the rotation bends
+    /// a point's path. Over the whole time range it turns by `theta`, giving
+    /// an acceleration of at most `theta^2 |S x| + 2 theta |S' x|`, and a
+    /// path of bounded acceleration `a` strays at most `a h^2 / 8` from the
+    /// chord over a step of length `h`.
+    fn max_chord_error(&self, b: Bounds3f) -> f64 {
+        let theta = 2.0 * safe_acos(self.r[0].dot(self.r[1]));
+
+        // The Frobenius norm bounds how much a matrix stretches a vector,
+        // and the norm of the interpolated scale never exceeds both ends'.
+        let norm = |m: Matrix44| {
+            (0..3)
+                .flat_map(|i| (0..3).map(move |j| m[i][j] * m[i][j]))
+                .sum::<f64>()
+                .sqrt()
+        };
+
+        let mut ds = Matrix44::zero();
+
+        for i in 0..4 {
+            for j in 0..4 {
+                ds[i][j] = self.s[1][i][j] - self.s[0][i][j];
+            }
+        }
+
+        let radius = (0..8)
+            .map(|i| Vector3f::from(b.corner(i)).length())
+            .fold(0.0, f64::max);
+        let scale = norm(self.s[0]).max(norm(self.s[1]));
+
+        let acceleration = (theta * theta * scale + 2.0 * theta * norm(ds)) * radius;
+        let h = 1.0 / (MOTION_BOUNDS_STEPS - 1) as f64;
+
+        acceleration * h * h / 8.0
+    }
+}
+
+impl From<Transform> for AnimatedTransform {
+    fn from(t: Transform) -> Self {
+        Self::new(t, 0.0, t, 1.0)
+    }
+}
+
+/// Splits `m` into a translation, a rotation and a remaining scale/shear
+/// matrix using polar decomposition.
+fn decompose(m: Matrix44) -> (Vector3f, Quaternion, Matrix44) {
+    let t = Vector3f::new(m[0][3], m[1][3], m[2][3]);
+
+    let mut upper = m;
+
+    for i in 0..3 {
+        upper[i][3] = 0.0;
+        upper[3][i] = 0.0;
+    }
+
+    upper[3][3] = 1.0;
+
+    // Average the matrix with its inverse transpose until it converges to
+    // the rotation.
+    let mut r = upper;
+
+    for _ in 0..100 {
+        let r_it = r.transpose().inverse();
+        let mut r_next = Matrix44::zero();
+
+        for i in 0..4 {
+            for j in 0..4 {
+                r_next[i][j] = 0.5 * (r[i][j] + r_it[i][j]);
+            }
+        }
+
+        let mut norm: f64 = 0.0;
+
+        for i in 0..3 {
+            let n = (r[i][0] - r_next[i][0]).abs()
+                + (r[i][1] - r_next[i][1]).abs()
+                + (r[i][2] - r_next[i][2]).abs();
+
+            norm = norm.max(n);
+        }
+
+        r = r_next;
+
+        if norm <= 0.0001 {
+            break;
+        }
+    }
+
+    let rotation = Quaternion::from(Transform::from(r));
+    let s = r.inverse().mul(upper);
+
+    (t, rotation, s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use core::Point3f;
+
+    const EPSILON: f64 = 0.0001;
+
+    fn assert_point_eq(expected: Point3f, value: Point3f) {
+        assert!(expected.distance(value) < EPSILON);
+    }
+
+    #[test]
+    fn static_transform_is_not_animated() {
+        let t = Transform::translate(Vector3f::new(1.0, 2.0, 3.0));
+        let animated = AnimatedTransform::from(t);
+
+        assert!(!animated.is_animated());
+        assert_eq!(t, animated.interpolate(0.5));
+    }
+
+    #[test]
+    fn interpolate_translation() {
+        let animated = AnimatedTransform::new(
+            Transform::new(),
+            0.0,
+            Transform::translate(Vector3f::new(2.0, 0.0, 0.0)),
+            1.0,
+        );
+
+        let p = animated.interpolate(0.25).transform(Point3f::zero());
+
+        assert_point_eq(Point3f::new(0.5, 0.0, 0.0), p);
+    }
+
+    #[test]
+    fn interpolate_rotation_and_scale() {
+        let animated = AnimatedTransform::new(
+            Transform::new(),
+            0.0,
+            Transform::rotate(90.0, Vector3f::new(0.0, 0.0, 1.0)) * Transform::scale(3.0, 3.0, 3.0),
+            1.0,
+        );
+
+        let p = animated
+            .interpolate(0.5)
+            .transform(Point3f::new(1.0, 0.0, 0.0));
+        let expected = Point3f::new(2.0 * 0.5f64.sqrt(), 2.0 * 0.5f64.sqrt(), 0.0);
+
+        assert_point_eq(expected, p);
+    }
+
+    #[test]
+    fn motion_bounds_cover_endpoints() {
+        let animated = AnimatedTransform::new(
+            Transform::new(),
+            0.0,
+            Transform::translate(Vector3f::new(4.0, 0.0, 0.0)),
+            1.0,
+        );
+
+        let b = Bounds3f::new(Point3f::zero(), Point3f::new(1.0, 1.0, 1.0));
+        let result = animated.motion_bounds(b);
+
+        assert!(result.inside(Point3f::zero()));
+        assert!(result.inside(Point3f::new(5.0, 1.0, 1.0)));
+    }
+
+    #[test]
+    fn motion_bounds_cover_rotation_between_samples() {
+        // Halfway through, the box faces +x, which falls between two of the
+        // sampled times.
+        let animated = AnimatedTransform::new(
+            Transform::rotate(-80.0, Vector3f::new(0.0, 0.0, 1.0)),
+            0.0,
+            Transform::rotate(80.0, Vector3f::new(0.0, 0.0, 1.0)) * Transform::scale(1.5, 1.0, 1.0),
+            1.0,
+        );
+
+        let b = Bounds3f::new(Point3f::new(1.0, -0.5, -0.5), Point3f::new(2.0, 0.5, 0.5));
+        let result = animated.motion_bounds(b);
+
+        let mut points: Vec<Point3f> = (0..8).map(|i| b.corner(i)).collect();
+        points.push(Point3f::new(2.0, 0.0, 0.0));
+
+        for i in 0..=1000 {
+            let t = animated.interpolate(i as f64 / 1000.0);
+
+            for &p in &points {
+                assert!(result.inside(t.transform(p)));
+            }
+        }
+    }
+}
@@ -1,9 +1,11 @@
 use std::ops::Index;
 
 use core::point2::Point2;
+use core::utils;
 use core::value::Value;
+use core::vector2::Vector2;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Copy, Debug)]
 pub struct Bounds2<T: Value> {
     p_min: Point2<T>,
     p_max: Point2<T>,
@@ -42,6 +44,50 @@ impl<T: Value> Bounds2<T> {
             },
         }
     }
+
+    pub fn union(self, p: Point2<T>) -> Self {
+        Self {
+            p_min: Point2 {
+                x: self.p_min.x.min(p.x),
+                y: self.p_min.y.min(p.y),
+            },
+            p_max: Point2 {
+                x: self.p_max.x.max(p.x),
+                y: self.p_max.y.max(p.y),
+            },
+        }
+    }
+
+    pub fn inside(self, p: Point2<T>) -> bool {
+        let x = (p.x >= self.p_min.x) && (p.x <= self.p_max.x);
+        let y = (p.y >= self.p_min.y) && (p.y <= self.p_max.y);
+
+        x && y
+    }
+
+    pub fn expand(self, delta: T) -> Self {
+        Self {
+            p_min: self.p_min - Vector2 { x: delta, y: delta },
+            p_max: self.p_max + Vector2 { x: delta, y: delta },
+        }
+    }
+
+    pub fn diagonal(self) -> Vector2<T> {
+        self.p_max - self.p_min
+    }
+
+    pub fn area(self) -> T {
+        let d = self.diagonal();
+
+        d.x * d.y
+    }
+
+    pub fn lerp(self, t: Point2<T>) -> Point2<T> {
+        Point2 {
+            x: utils::lerp(t.x, self.p_min.x, self.p_max.x),
+            y: utils::lerp(t.y, self.p_min.y, self.p_max.y),
+        }
+    }
 }
 
 impl<T: Value> From<Point2<T>> for Bounds2<T> {
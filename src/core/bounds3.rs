@@ -1,6 +1,8 @@
+use std::mem;
 use std::ops::Index;
 
 use core::point3::Point3;
+use core::ray::Ray;
 use core::utils;
 use core::transform::Transform;
 use core::transformable::Transformable;
@@ -9,8 +11,8 @@ use core::vector3::Vector3;
 
 #[derive(Clone, Copy, Debug)]
 pub struct Bounds3<T: Value> {
-    p_min: Point3<T>,
-    p_max: Point3<T>,
+    pub p_min: Point3<T>,
+    pub p_max: Point3<T>,
 }
 
 impl<T: Value> Bounds3<T> {
@@ -71,9 +73,24 @@ impl<T: Value> Bounds3<T> {
                 z: self.p_min.z.min(p.z),
             },
             p_max: Point3 {
-                x: self.p_max.x.min(p.x),
-                y: self.p_max.y.min(p.y),
-                z: self.p_max.z.min(p.z),
+                x: self.p_max.x.max(p.x),
+                y: self.p_max.y.max(p.y),
+                z: self.p_max.z.max(p.z),
+            },
+        }
+    }
+
+    pub fn union_bounds(self, b: Bounds3<T>) -> Self {
+        Self {
+            p_min: Point3 {
+                x: self.p_min.x.min(b.p_min.x),
+                y: self.p_min.y.min(b.p_min.y),
+                z: self.p_min.z.min(b.p_min.z),
+            },
+            p_max: Point3 {
+                x: self.p_max.x.max(b.p_max.x),
+                y: self.p_max.y.max(b.p_max.y),
+                z: self.p_max.z.max(b.p_max.z),
             },
         }
     }
@@ -93,10 +110,16 @@ impl<T: Value> Bounds3<T> {
         }
     }
 
+    /// True for bounds that contain no points, such as the `intersection`
+    /// of disjoint boxes.
+    pub fn is_empty(self) -> bool {
+        self.p_min.x > self.p_max.x || self.p_min.y > self.p_max.y || self.p_min.z > self.p_max.z
+    }
+
     pub fn overlap(self, b: Bounds3<T>) -> bool {
         let x = (self.p_max.x >= b.p_min.x) && (self.p_min.x <= b.p_max.x);
         let y = (self.p_max.y >= b.p_min.y) && (self.p_min.y <= b.p_max.y);
-        let z = (self.p_max.z >= b.p_min.x) && (self.p_min.z <= b.p_max.z);
+        let z = (self.p_max.z >= b.p_min.z) && (self.p_min.z <= b.p_max.z);
 
         x && y && z
     }
@@ -172,7 +195,7 @@ impl<T: Value> Bounds3<T> {
         }
 
         if self.p_max.y > self.p_min.y {
-            o.y = o.x / (self.p_max.y - self.p_min.y);
+            o.y = o.y / (self.p_max.y - self.p_min.y);
         }
 
         if self.p_max.z > self.p_min.z {
@@ -195,6 +218,91 @@ impl<T: Value> Bounds3<T> {
     }
 }
 
+impl Bounds3<f64> {
+    /// Returns the parametric range of `ray` inside the box, if any.
+    pub fn intersect_p(&self, ray: &Ray) -> Option<(f64, f64)> {
+        let mut t0 = 0.0;
+        let mut t1 = ray.t_max;
+
+        for i in 0..3 {
+            let inv_ray_dir = 1.0 / ray.d[i];
+
+            let mut t_near = (self.p_min[i] - ray.o[i]) * inv_ray_dir;
+            let mut t_far = (self.p_max[i] - ray.o[i]) * inv_ray_dir;
+
+            if t_near > t_far {
+                mem::swap(&mut t_near, &mut t_far);
+            }
+
+            // Grow t_far to stay conservative in the face of rounding error.
+            t_far *= 1.0 + 2.0 * utils::gamma(3);
+
+            if t_near > t0 {
+                t0 = t_near;
+            }
+
+            if t_far < t1 {
+                t1 = t_far;
+            }
+
+            if t0 > t1 {
+                return None;
+            }
+        }
+
+        Some((t0, t1))
+    }
+
+    /// Faster slab test for traversals that test many boxes against one
+    /// ray, taking its reciprocal direction and which components of it are
+    /// negative.
+    pub fn intersect_p_precomputed(
+        &self,
+        ray: &Ray,
+        inv_dir: Vector3<f64>,
+        dir_is_neg: [usize; 3],
+    ) -> bool {
+        let mut t_min = (self[dir_is_neg[0]].x - ray.o.x) * inv_dir.x;
+        let mut t_max = (self[1 - dir_is_neg[0]].x - ray.o.x) * inv_dir.x;
+        let ty_min = (self[dir_is_neg[1]].y - ray.o.y) * inv_dir.y;
+        let mut ty_max = (self[1 - dir_is_neg[1]].y - ray.o.y) * inv_dir.y;
+
+        t_max *= 1.0 + 2.0 * utils::gamma(3);
+        ty_max *= 1.0 + 2.0 * utils::gamma(3);
+
+        if t_min > ty_max || ty_min > t_max {
+            return false;
+        }
+
+        if ty_min > t_min {
+            t_min = ty_min;
+        }
+
+        if ty_max < t_max {
+            t_max = ty_max;
+        }
+
+        let tz_min = (self[dir_is_neg[2]].z - ray.o.z) * inv_dir.z;
+        let mut tz_max = (self[1 - dir_is_neg[2]].z - ray.o.z) * inv_dir.z;
+
+        tz_max *= 1.0 + 2.0 * utils::gamma(3);
+
+        if t_min > tz_max || tz_min > t_max {
+            return false;
+        }
+
+        if tz_min > t_min {
+            t_min = tz_min;
+        }
+
+        if tz_max < t_max {
+            t_max = tz_max;
+        }
+
+        t_min < ray.t_max && t_max > 0.0
+    }
+}
+
 impl<T: Value> Transformable for Bounds3<T> {
     fn transform(self, t: Transform) -> Self {
         let mut result = Bounds3::from(t.transform(Point3 {
@@ -267,3 +375,47 @@ impl<T: Value> Index<usize> for Bounds3<T> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn union() {
+        let b = Bounds3::new(Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, 1.0, 1.0));
+
+        let grown = b.union(Point3::new(2.0, -1.0, 3.0));
+
+        assert_eq!(0.0, grown.p_min.x);
+        assert_eq!(-1.0, grown.p_min.y);
+        assert_eq!(0.0, grown.p_min.z);
+        assert_eq!(2.0, grown.p_max.x);
+        assert_eq!(1.0, grown.p_max.y);
+        assert_eq!(3.0, grown.p_max.z);
+    }
+
+    #[test]
+    fn overlap() {
+        let b = Bounds3::new(Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, 1.0, 1.0));
+
+        // Apart only along z, where the other box starts within this one's
+        // x range.
+        let above = Bounds3::new(Point3::new(0.5, 0.5, 2.0), Point3::new(1.5, 1.5, 3.0));
+        let touching = Bounds3::new(Point3::new(0.5, 0.5, 1.0), Point3::new(1.5, 1.5, 3.0));
+
+        assert!(!b.overlap(above));
+        assert!(!above.overlap(b));
+        assert!(b.overlap(touching));
+    }
+
+    #[test]
+    fn offset() {
+        let b = Bounds3::new(Point3::new(0.0, 0.0, 0.0), Point3::new(2.0, 4.0, 8.0));
+
+        let o = b.offset(Point3::new(1.5, 1.0, 2.0));
+
+        assert_eq!(0.75, o.x);
+        assert_eq!(0.25, o.y);
+        assert_eq!(0.25, o.z);
+    }
+}
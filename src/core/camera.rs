@@ -0,0 +1,191 @@
+use core::animated_transform::AnimatedTransform;
+use core::medium::Medium;
+use core::ray::Ray;
+use core::ray_differential::RayDifferential;
+use core::sampling::concentric_sample_disk;
+use core::transform::Transform;
+use core::transformable::Transformable;
+use core::utils;
+
+use core::Bounds2f;
+use core::Point2f;
+use core::Point2i;
+use core::Point3f;
+use core::Vector3f;
+
+/// Where on the film, where on the lens and when in the shutter interval a
+/// camera ray is generated. Film positions are in raster space, the lens
+/// position and time in [0, 1).
+#[derive(Clone, Copy, Debug)]
+pub struct CameraSample {
+    pub p_film: Point2f,
+    pub p_lens: Point2f,
+    pub time: f64,
+}
+
+impl CameraSample {
+    pub fn new(p_film: Point2f, p_lens: Point2f, time: f64) -> Self {
+        Self {
+            p_film,
+            p_lens,
+            time,
+        }
+    }
+}
+
+pub trait Camera: Send + Sync {
+    /// Returns the world space ray for `sample` and how much the radiance
+    /// along it counts towards the image, with a weight of zero meaning
+    /// there is no ray.
+    fn generate_ray(&self, sample: CameraSample) -> (Ray, f64);
+
+    /// Like `generate_ray`, but also fills in the rays for film positions one
+    /// pixel over in x and y. The default finds them by generating rays for
+    /// slightly shifted samples.
+    fn generate_ray_differential(&self, sample: CameraSample) -> (RayDifferential, f64) {
+        let (ray, weight) = self.generate_ray(sample);
+
+        if weight == 0.0 {
+            return (RayDifferential::from(ray), 0.0);
+        }
+
+        let mut rd = RayDifferential::from(ray);
+
+        for axis in 0..2 {
+            let shifted = [0.05, -0.05].iter().find_map(|&eps| {
+                let mut s = sample;
+                s.p_film[axis] += eps;
+
+                let (r, w) = self.generate_ray(s);
+
+                if w == 0.0 {
+                    None
+                } else {
+                    Some(((r.o - ray.o) * (1.0 / eps), (r.d - ray.d) * (1.0 / eps)))
+                }
+            });
+
+            let (do_dp, dd_dp) = match shifted {
+                Some(derivatives) => derivatives,
+                None => return (rd, 0.0),
+            };
+
+            if axis == 0 {
+                rd.rx_origin = Some(ray.o + do_dp);
+                rd.rx_direction = Some(ray.d + dd_dp);
+            } else {
+                rd.ry_origin = Some(ray.o + do_dp);
+                rd.ry_direction = Some(ray.d + dd_dp);
+            }
+        }
+
+        rd.has_differentials = true;
+
+        (rd, weight)
+    }
+}
+
+/// What cameras that project the scene through a `Transform` share: the
+/// chain from raster space through screen space to camera space, the thin
+/// lens and the shutter interval.
+#[derive(Clone, Copy, Debug)]
+pub struct ProjectiveCamera {
+    pub camera_to_world: AnimatedTransform,
+    pub shutter_open: f64,
+    pub shutter_close: f64,
+    pub camera_to_screen: Transform,
+    pub raster_to_camera: Transform,
+    pub screen_to_raster: Transform,
+    pub raster_to_screen: Transform,
+    pub lens_radius: f64,
+    pub focal_distance: f64,
+    pub medium: Medium,
+}
+
+impl ProjectiveCamera {
+    /// Maps `screen_window` onto a film of `full_resolution` pixels, with
+    /// raster y running downwards. The camera starts out as a pinhole with
+    /// the shutter open over [0, 1].
+    pub fn new(
+        camera_to_world: AnimatedTransform,
+        camera_to_screen: Transform,
+        screen_window: Bounds2f,
+        full_resolution: Point2i,
+        medium: Medium,
+    ) -> Self {
+        let screen_to_raster =
+            Transform::scale(full_resolution.x as f64, full_resolution.y as f64, 1.0)
+                * Transform::scale(
+                    1.0 / (screen_window[1].x - screen_window[0].x),
+                    1.0 / (screen_window[0].y - screen_window[1].y),
+                    1.0,
+                )
+                * Transform::translate(Vector3f::new(
+                    -screen_window[0].x,
+                    -screen_window[1].y,
+                    0.0,
+                ));
+        let raster_to_screen = screen_to_raster.inverse();
+
+        Self {
+            camera_to_world,
+            shutter_open: 0.0,
+            shutter_close: 1.0,
+            camera_to_screen,
+            raster_to_camera: camera_to_screen.inverse() * raster_to_screen,
+            screen_to_raster,
+            raster_to_screen,
+            lens_radius: 0.0,
+            focal_distance: 1e6,
+            medium,
+        }
+    }
+
+    /// Gives the camera a thin lens of `lens_radius` focused at
+    /// `focal_distance`, for depth of field.
+    pub fn with_lens(mut self, lens_radius: f64, focal_distance: f64) -> Self {
+        self.lens_radius = lens_radius;
+        self.focal_distance = focal_distance;
+        self
+    }
+
+    pub fn with_shutter(mut self, shutter_open: f64, shutter_close: f64) -> Self {
+        self.shutter_open = shutter_open;
+        self.shutter_close = shutter_close;
+        self
+    }
+
+    /// The time within the shutter interval for a sample's `time` in [0, 1).
+    pub fn time(&self, u: f64) -> f64 {
+        utils::lerp(u, self.shutter_open, self.shutter_close)
+    }
+
+    /// The point on the lens for the sample `p_lens`, and the distance
+    /// along a camera space ray with direction `d` to the plane of focus.
+    pub fn lens_point(&self, p_lens: Point2f, d: Vector3f) -> (Point3f, f64) {
+        let p = concentric_sample_disk(p_lens) * self.lens_radius;
+
+        (Point3f::new(p.x, p.y, 0.0), self.focal_distance / d.z)
+    }
+
+    /// Moves a camera space ray, or anything else, to world space as the
+    /// camera is placed at `time`.
+    pub fn camera_to_world_at<T: Transformable>(&self, ray: T, time: f64) -> T {
+        self.camera_to_world.interpolate(time).transform(ray)
+    }
+}
+
+/// The screen window pbrt uses by default: [-1, 1] along the shorter image
+/// axis, stretched along the longer one to keep pixels square.
+pub fn default_screen_window(full_resolution: Point2i) -> Bounds2f {
+    let aspect = full_resolution.x as f64 / full_resolution.y as f64;
+
+    if aspect > 1.0 {
+        Bounds2f::new(Point2f::new(-aspect, -1.0), Point2f::new(aspect, 1.0))
+    } else {
+        Bounds2f::new(
+            Point2f::new(-1.0, -1.0 / aspect),
+            Point2f::new(1.0, 1.0 / aspect),
+        )
+    }
+}
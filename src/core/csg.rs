@@ -0,0 +1,480 @@
+use std::cmp::Ordering;
+use std::f64;
+use std::sync::Arc;
+
+use core::interaction::SurfaceInteraction;
+use core::light::AreaLight;
+use core::material::Material;
+use core::medium::MediumInterface;
+use core::primitive::Primitive;
+use core::ray::Ray;
+use core::shape::Shape;
+
+use core::Bounds3f;
+
+// Upper limit on the surface crossings gathered from one shape, guarding
+// against shapes that keep reporting the same hit. A shape crossed more
+// often is taken to end at the last exit gathered.
+const MAX_CROSSINGS: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CsgOperation {
+    Union,
+    Intersection,
+    Difference,
+}
+
+impl CsgOperation {
+    fn inside(self, in_a: bool, in_b: bool) -> bool {
+        match self {
+            CsgOperation::Union => in_a || in_b,
+            CsgOperation::Intersection => in_a && in_b,
+            CsgOperation::Difference => in_a && !in_b,
+        }
+    }
+}
+
+/// A solid built from closed shapes. Crossings of each shape alternate
+/// between entries and exits, so the way its normals face doesn't matter.
+#[derive(Clone)]
+pub enum CsgNode {
+    Shape(Arc<dyn Shape>),
+    Operation(CsgOperation, Box<CsgNode>, Box<CsgNode>),
+}
+
+/// A stretch of the ray inside a solid. The bounding hits are missing where
+/// the solid extends past either end of the ray.
+#[derive(Clone)]
+struct Interval {
+    t0: f64,
+    t1: f64,
+    enter: Option<SurfaceInteraction>,
+    exit: Option<SurfaceInteraction>,
+}
+
+struct Crossing {
+    t: f64,
+    from_b: bool,
+    entering: bool,
+    isect: Option<SurfaceInteraction>,
+}
+
+impl CsgNode {
+    pub fn shape(shape: Arc<dyn Shape>) -> Self {
+        CsgNode::Shape(shape)
+    }
+
+    pub fn union(self, other: Self) -> Self {
+        CsgNode::Operation(CsgOperation::Union, Box::new(self), Box::new(other))
+    }
+
+    pub fn intersection(self, other: Self) -> Self {
+        CsgNode::Operation(CsgOperation::Intersection, Box::new(self), Box::new(other))
+    }
+
+    pub fn difference(self, other: Self) -> Self {
+        CsgNode::Operation(CsgOperation::Difference, Box::new(self), Box::new(other))
+    }
+
+    pub fn world_bound(&self) -> Bounds3f {
+        match *self {
+            CsgNode::Shape(ref shape) => shape.world_bound(),
+            CsgNode::Operation(operation, ref a, ref b) => match operation {
+                CsgOperation::Union => a.world_bound().union_bounds(b.world_bound()),
+                CsgOperation::Intersection => a.world_bound().intersection(b.world_bound()),
+                CsgOperation::Difference => a.world_bound(),
+            },
+        }
+    }
+
+    /// All the stretches of the whole line through `ray` that lie inside the
+    /// solid, in order.
+    fn intervals(&self, ray: &Ray) -> Vec<Interval> {
+        match *self {
+            CsgNode::Shape(ref shape) => shape_intervals(shape.as_ref(), ray),
+            CsgNode::Operation(operation, ref a, ref b) => {
+                let a = a.intervals(ray);
+
+                // Skip the second operand when it cannot change the result.
+                if a.is_empty() && operation != CsgOperation::Union {
+                    return a;
+                }
+
+                combine(operation, a, b.intervals(ray))
+            }
+        }
+    }
+}
+
+/// Gathers every crossing of `shape` along the line through the ray by
+/// restarting just past each hit, then pairs entries with exits.
+fn shape_intervals(shape: &dyn Shape, ray: &Ray) -> Vec<Interval> {
+    // Start behind the ray origin and outside the shape's bounds, so the
+    // first crossing is known to be an entry wherever the origin lies.
+    let (center, radius) = shape.world_bound().bounding_sphere();
+    let t_start = -(ray.o.distance(center) + 2.0 * radius) / ray.d.length();
+
+    let mut r = *ray;
+    r.o = ray.at(t_start);
+    r.t_max = f64::INFINITY;
+
+    let inv_length_squared = 1.0 / ray.d.length_squared();
+    let mut crossings = Vec::new();
+    let mut truncated = true;
+
+    while crossings.len() < MAX_CROSSINGS {
+        let isect = match shape.intersect(&r) {
+            Some((_, isect)) => isect,
+            None => {
+                truncated = false;
+                break;
+            }
+        };
+
+        let t = (isect.p - ray.o).dot(ray.d) * inv_length_squared;
+
+        r = isect.spawn_ray(ray.d);
+        r.medium = ray.medium;
+        crossings.push((t, isect));
+    }
+
+    let mut intervals = Vec::new();
+    let mut open: Option<(f64, SurfaceInteraction)> = None;
+
+    for (t, isect) in crossings {
+        match open.take() {
+            None => open = Some((t, isect)),
+            Some((t0, enter)) => intervals.push(Interval {
+                t0,
+                t1: t,
+                enter: Some(enter),
+                exit: Some(isect),
+            }),
+        }
+    }
+
+    // An entry without an exit means the shape's far side was missed, which
+    // leaves it open to infinity, unless gathering was cut short before the
+    // exit came up.
+    if let Some((t0, enter)) = open {
+        if !truncated {
+            intervals.push(Interval {
+                t0,
+                t1: f64::INFINITY,
+                enter: Some(enter),
+                exit: None,
+            });
+        }
+    }
+
+    intervals
+}
+
+/// Merges two interval lists by walking their boundaries in order and
+/// tracking whether the ray is inside each operand.
+fn combine(operation: CsgOperation, a: Vec<Interval>, b: Vec<Interval>) -> Vec<Interval> {
+    let mut crossings = Vec::with_capacity(2 * (a.len() + b.len()));
+
+    for (from_b, intervals) in [(false, a), (true, b)] {
+        for interval in intervals {
+            crossings.push(Crossing {
+                t: interval.t0,
+                from_b,
+                entering: true,
+                isect: interval.enter,
+            });
+            crossings.push(Crossing {
+                t: interval.t1,
+                from_b,
+                entering: false,
+                isect: interval.exit,
+            });
+        }
+    }
+
+    crossings.sort_by(|c0, c1| c0.t.partial_cmp(&c1.t).unwrap_or(Ordering::Equal));
+
+    let mut result = Vec::new();
+    let mut in_a = false;
+    let mut in_b = false;
+    let mut open: Option<(f64, Option<SurfaceInteraction>)> = None;
+
+    for crossing in crossings {
+        if crossing.from_b {
+            in_b = crossing.entering;
+        } else {
+            in_a = crossing.entering;
+        }
+
+        let inside = operation.inside(in_a, in_b);
+
+        if inside == open.is_some() {
+            continue;
+        }
+
+        // Surfaces of a subtracted solid face into the result.
+        let mut isect = crossing.isect;
+
+        if operation == CsgOperation::Difference && crossing.from_b {
+            isect = isect.map(flip_normal);
+        }
+
+        if inside {
+            open = Some((crossing.t, isect));
+        } else if let Some((t0, enter)) = open.take() {
+            result.push(Interval {
+                t0,
+                t1: crossing.t,
+                enter,
+                exit: isect,
+            });
+        }
+    }
+
+    result
+}
+
+fn flip_normal(mut isect: SurfaceInteraction) -> SurfaceInteraction {
+    isect.n = -isect.n;
+    isect.shading.n = -isect.shading.n;
+    isect
+}
+
+/// A constructive solid geometry tree rendered as a single primitive.
+pub struct CsgPrimitive {
+    pub root: CsgNode,
+    pub material: Option<Arc<dyn Material>>,
+    pub area_light: Option<Arc<dyn AreaLight>>,
+    pub medium_interface: Option<MediumInterface>,
+}
+
+impl CsgPrimitive {
+    pub fn new(
+        root: CsgNode,
+        material: Option<Arc<dyn Material>>,
+        area_light: Option<Arc<dyn AreaLight>>,
+        medium_interface: Option<MediumInterface>,
+    ) -> Self {
+        Self {
+            root,
+            material,
+            area_light,
+            medium_interface,
+        }
+    }
+
+    /// The first boundary of the solid in front of the ray origin.
+    fn closest_hit(&self, ray: &Ray) -> Option<(f64, SurfaceInteraction)> {
+        for interval in self.root.intervals(ray) {
+            let boundaries = [(interval.t0, interval.enter), (interval.t1, interval.exit)];
+
+            for (t, isect) in boundaries {
+                if t >= ray.t_max {
+                    return None;
+                }
+
+                if t > 0.0 {
+                    if let Some(isect) = isect {
+                        return Some((t, isect));
+                    }
+                }
+            }
+        }
+
+        None
+    }
+}
+
+impl Primitive for CsgPrimitive {
+    fn world_bound(&self) -> Bounds3f {
+        self.root.world_bound()
+    }
+
+    fn intersect(&self, ray: &mut Ray) -> Option<SurfaceInteraction> {
+        let (t_hit, mut isect) = self.closest_hit(ray)?;
+
+        ray.t_max = t_hit;
+        isect.wo = -ray.d;
+        isect.medium_interface = self
+            .medium_interface
+            .unwrap_or_else(|| MediumInterface::from(ray.medium));
+        isect.material = self.material.clone();
+        isect.area_light = self.area_light.clone();
+
+        Some(isect)
+    }
+
+    fn intersect_p(&self, ray: &Ray) -> bool {
+        self.closest_hit(ray).is_some()
+    }
+
+    fn get_area_light(&self) -> Option<Arc<dyn AreaLight>> {
+        self.area_light.clone()
+    }
+
+    fn get_material(&self) -> Option<Arc<dyn Material>> {
+        self.material.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use core::medium::Medium;
+    use core::transform::Transform;
+    use shapes::sdf::{Sdf, SdfShape};
+    use shapes::sphere::Sphere;
+
+    use core::Point3f;
+    use core::Vector3f;
+
+    const EPSILON: f64 = 0.0001;
+
+    fn sphere(x: f64, radius: f64) -> CsgNode {
+        CsgNode::shape(Arc::new(Sphere::full(
+            Transform::translate(Vector3f::new(x, 0.0, 0.0)),
+            radius,
+        )))
+    }
+
+    fn ray_along_x(x: f64) -> Ray {
+        Ray::new(
+            Point3f::new(x, 0.0, 0.0),
+            Vector3f::new(1.0, 0.0, 0.0),
+            Medium {},
+            f64::INFINITY,
+            0.0,
+        )
+    }
+
+    fn hit(root: CsgNode, ray: &mut Ray) -> Option<SurfaceInteraction> {
+        CsgPrimitive::new(root, None, None, None).intersect(ray)
+    }
+
+    #[test]
+    fn union_hits_first_surface() {
+        let mut ray = ray_along_x(-5.0);
+        let isect = hit(sphere(-0.5, 1.0).union(sphere(0.5, 1.0)), &mut ray).unwrap();
+
+        assert!((isect.p.x + 1.5).abs() < EPSILON);
+        assert!((ray.t_max - 3.5).abs() < EPSILON);
+
+        // The overlap hides the inner surfaces.
+        let mut ray = ray_along_x(0.0);
+        let isect = hit(sphere(-0.5, 1.0).union(sphere(0.5, 1.0)), &mut ray).unwrap();
+
+        assert!((isect.p.x - 1.5).abs() < EPSILON);
+        assert!((isect.n.x - 1.0).abs() < EPSILON);
+    }
+
+    #[test]
+    fn intersection_keeps_lens() {
+        let mut ray = ray_along_x(-5.0);
+        let isect = hit(sphere(-0.5, 1.0).intersection(sphere(0.5, 1.0)), &mut ray).unwrap();
+
+        assert!((isect.p.x + 0.5).abs() < EPSILON);
+        assert!((isect.n.x + 1.0).abs() < EPSILON);
+    }
+
+    #[test]
+    fn difference_flips_carved_normal() {
+        let mut ray = ray_along_x(-5.0);
+        let root = sphere(0.0, 2.0).difference(sphere(-2.0, 1.0));
+        let isect = hit(root, &mut ray).unwrap();
+
+        // The ray enters through the wall of the carved out sphere, whose
+        // normal now points back towards its center.
+        assert!((isect.p.x + 1.0).abs() < EPSILON);
+        assert!((isect.n.x + 1.0).abs() < EPSILON);
+    }
+
+    #[test]
+    fn difference_can_remove_everything() {
+        let root = sphere(0.0, 1.0).difference(sphere(0.0, 2.0));
+        let primitive = CsgPrimitive::new(root, None, None, None);
+
+        assert!(!primitive.intersect_p(&ray_along_x(-5.0)));
+    }
+
+    #[test]
+    fn inward_normals_keep_their_inside() {
+        // Mirroring flips the normals a shape reports, as does reversing its
+        // orientation, but neither changes which side is inside.
+        let mirrored = |x: f64, radius: f64| {
+            CsgNode::shape(Arc::new(Sphere::full(
+                Transform::translate(Vector3f::new(x, 0.0, 0.0)) * Transform::scale(-1.0, 1.0, 1.0),
+                radius,
+            )))
+        };
+        let reversed = |x: f64, radius: f64| {
+            CsgNode::shape(Arc::new(Sphere::new(
+                Transform::translate(Vector3f::new(x, 0.0, 0.0)),
+                true,
+                radius,
+                -radius,
+                radius,
+                360.0,
+            )))
+        };
+
+        let mut ray = ray_along_x(-5.0);
+        let isect = hit(mirrored(-0.5, 1.0).intersection(sphere(0.5, 1.0)), &mut ray).unwrap();
+
+        assert!((isect.p.x + 0.5).abs() < EPSILON);
+
+        let mut ray = ray_along_x(0.0);
+        let isect = hit(sphere(-0.5, 1.0).union(mirrored(0.5, 1.0)), &mut ray).unwrap();
+
+        assert!((isect.p.x - 1.5).abs() < EPSILON);
+
+        let mut ray = ray_along_x(-5.0);
+        let isect = hit(sphere(0.0, 2.0).difference(reversed(-2.0, 1.0)), &mut ray).unwrap();
+
+        assert!((isect.p.x + 1.0).abs() < EPSILON);
+    }
+
+    #[test]
+    fn too_many_crossings_end_the_shape() {
+        // A row of 100 spheres gives far more crossings than are gathered.
+        let row = SdfShape::new(
+            Transform::new(),
+            false,
+            Sdf::sphere(0.25).repeat(Vector3f::new(1.0, 0.0, 0.0)),
+            Bounds3f::new(
+                Point3f::new(-50.0, -1.0, -1.0),
+                Point3f::new(50.0, 1.0, 1.0),
+            ),
+            0.00001,
+        );
+        let intervals = shape_intervals(&row, &ray_along_x(-60.0));
+
+        assert_eq!(intervals.len(), MAX_CROSSINGS / 2);
+        assert!(intervals.iter().all(|interval| interval.t1.is_finite()));
+        assert!((intervals[0].t0 - 10.25).abs() < EPSILON);
+    }
+
+    #[test]
+    fn respects_t_max() {
+        let mut ray = ray_along_x(-5.0);
+        ray.t_max = 3.0;
+
+        assert!(hit(sphere(0.0, 1.0), &mut ray).is_none());
+    }
+
+    #[test]
+    fn bounds_follow_operation() {
+        let union = sphere(-1.0, 1.0).union(sphere(1.0, 1.0)).world_bound();
+        let intersection = sphere(-0.5, 1.0)
+            .intersection(sphere(0.5, 1.0))
+            .world_bound();
+        let difference = sphere(0.0, 1.0).difference(sphere(3.0, 3.0)).world_bound();
+
+        assert!((union.p_min.x + 2.0).abs() < EPSILON && (union.p_max.x - 2.0).abs() < EPSILON);
+        assert!(
+            (intersection.p_min.x + 0.5).abs() < EPSILON
+                && (intersection.p_max.x - 0.5).abs() < EPSILON
+        );
+        assert!((difference.p_max.x - 1.0).abs() < EPSILON);
+    }
+}
@@ -0,0 +1,464 @@
+use std::f64;
+use std::sync::Arc;
+
+use core::light::AreaLight;
+use core::material::Material;
+use core::medium::{Medium, MediumInterface};
+use core::ray::Ray;
+use core::ray_differential::RayDifferential;
+use core::transform::Transform;
+use core::transformable::Transformable;
+use core::utils::{next_float_down, next_float_up, solve_linear_system_2x2};
+
+use core::Normal3f;
+use core::Point2f;
+use core::Point3f;
+use core::Vector3f;
+
+pub const SHADOW_EPSILON: f64 = 0.0001;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Interaction {
+    pub p: Point3f,
+    pub time: f64,
+    pub p_error: Vector3f,
+    pub wo: Vector3f,
+    pub n: Normal3f,
+    pub medium_interface: MediumInterface,
+}
+
+impl Interaction {
+    pub fn new(p: Point3f, n: Normal3f, p_error: Vector3f, wo: Vector3f, time: f64) -> Self {
+        Self {
+            p,
+            time,
+            p_error,
+            wo,
+            n,
+            medium_interface: MediumInterface::from(Medium {}),
+        }
+    }
+
+    pub fn from_point(p: Point3f, time: f64) -> Self {
+        Self::new(
+            p,
+            Normal3f::zero(),
+            Vector3f::zero(),
+            Vector3f::zero(),
+            time,
+        )
+    }
+
+    pub fn is_surface_interaction(&self) -> bool {
+        self.n.x != 0.0 || self.n.y != 0.0 || self.n.z != 0.0
+    }
+
+    /// The medium a ray leaving in direction `w` travels through.
+    pub fn get_medium(&self, w: Vector3f) -> Medium {
+        if w.dot(Vector3f::from(self.n)) > 0.0 {
+            self.medium_interface.outside
+        } else {
+            self.medium_interface.inside
+        }
+    }
+
+    pub fn spawn_ray(&self, d: Vector3f) -> Ray {
+        let o = offset_ray_origin(self.p, self.p_error, self.n, d);
+
+        Ray::new(o, d, self.get_medium(d), f64::INFINITY, self.time)
+    }
+
+    pub fn spawn_ray_to(&self, p: Point3f) -> Ray {
+        let o = offset_ray_origin(self.p, self.p_error, self.n, p - self.p);
+        let d = p - o;
+
+        Ray::new(o, d, self.get_medium(d), 1.0 - SHADOW_EPSILON, self.time)
+    }
+}
+
+impl From<&SurfaceInteraction> for Interaction {
+    fn from(si: &SurfaceInteraction) -> Self {
+        Self {
+            p: si.p,
+            time: si.time,
+            p_error: si.p_error,
+            wo: si.wo,
+            n: si.n,
+            medium_interface: si.medium_interface,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Shading {
+    pub n: Normal3f,
+    pub dpdu: Vector3f,
+    pub dpdv: Vector3f,
+    pub dndu: Normal3f,
+    pub dndv: Normal3f,
+}
+
+/// Local differential geometry at a ray-surface hit. `n`, `dpdu` and
+/// friends describe the true surface; `shading` holds the possibly
+/// perturbed frame from interpolated normals or bump mapping. `material`
+/// and `area_light` are those of the primitive that was hit, filled in by
+/// `GeometricPrimitive::intersect`.
+#[derive(Clone)]
+pub struct SurfaceInteraction {
+    pub p: Point3f,
+    pub time: f64,
+    pub p_error: Vector3f,
+    pub wo: Vector3f,
+    pub n: Normal3f,
+    pub medium_interface: MediumInterface,
+    pub uv: Point2f,
+    pub dpdu: Vector3f,
+    pub dpdv: Vector3f,
+    pub dndu: Normal3f,
+    pub dndv: Normal3f,
+    pub shading: Shading,
+    pub dpdx: Vector3f,
+    pub dpdy: Vector3f,
+    pub dudx: f64,
+    pub dvdx: f64,
+    pub dudy: f64,
+    pub dvdy: f64,
+    pub material: Option<Arc<dyn Material>>,
+    pub area_light: Option<Arc<dyn AreaLight>>,
+}
+
+impl SurfaceInteraction {
+    /// Builds the record from the surface partial derivatives, with the
+    /// normal `dpdu x dpdv` and no normal derivatives; see
+    /// `with_normal_derivatives` and `with_flipped_normal`.
+    pub fn new(
+        p: Point3f,
+        p_error: Vector3f,
+        uv: Point2f,
+        wo: Vector3f,
+        dpdu: Vector3f,
+        dpdv: Vector3f,
+        time: f64,
+    ) -> Self {
+        let n = Normal3f::from(dpdu.cross(dpdv).normalize());
+
+        Self {
+            p,
+            time,
+            p_error,
+            wo,
+            n,
+            medium_interface: MediumInterface::from(Medium {}),
+            uv,
+            dpdu,
+            dpdv,
+            dndu: Normal3f::zero(),
+            dndv: Normal3f::zero(),
+            shading: Shading {
+                n,
+                dpdu,
+                dpdv,
+                dndu: Normal3f::zero(),
+                dndv: Normal3f::zero(),
+            },
+            dpdx: Vector3f::zero(),
+            dpdy: Vector3f::zero(),
+            dudx: 0.0,
+            dvdx: 0.0,
+            dudy: 0.0,
+            dvdy: 0.0,
+            material: None,
+            area_light: None,
+        }
+    }
+
+    /// Sets how the normal changes across the surface, for both the true
+    /// surface and the shading frame.
+    pub fn with_normal_derivatives(mut self, dndu: Normal3f, dndv: Normal3f) -> Self {
+        self.dndu = dndu;
+        self.dndv = dndv;
+        self.shading.dndu = dndu;
+        self.shading.dndv = dndv;
+        self
+    }
+
+    /// Negates the normals when `flip` is set, to account for reversed
+    /// orientation or a handedness-swapping transform.
+    pub fn with_flipped_normal(mut self, flip: bool) -> Self {
+        if flip {
+            self.n = -self.n;
+            self.shading.n = -self.shading.n;
+        }
+        self
+    }
+
+    /// Replaces the shading frame. When `orientation_is_authoritative` the
+    /// geometric normal is flipped to the shading normal's side, otherwise
+    /// the shading normal follows the geometric one.
+    pub fn set_shading_geometry(
+        &mut self,
+        dpdus: Vector3f,
+        dpdvs: Vector3f,
+        dndus: Normal3f,
+        dndvs: Normal3f,
+        orientation_is_authoritative: bool,
+    ) {
+        let mut n = Normal3f::from(dpdus.cross(dpdvs).normalize());
+
+        if orientation_is_authoritative {
+            self.n = self.n.face_forward(Vector3f::from(n));
+        } else {
+            n = n.face_forward(Vector3f::from(self.n));
+        }
+
+        self.shading = Shading {
+            n,
+            dpdu: dpdus,
+            dpdv: dpdvs,
+            dndu: dndus,
+            dndv: dndvs,
+        };
+    }
+
+    /// Estimates the screen-space derivatives of `p` and `uv` by
+    /// intersecting the offset rays of `ray` with the tangent plane.
+    pub fn compute_differentials(&mut self, ray: &RayDifferential) {
+        if let Some((dpdx, dpdy)) = self.tangent_plane_offsets(ray) {
+            self.dpdx = dpdx;
+            self.dpdy = dpdy;
+
+            let n = self.n;
+            let dim = if n.x.abs() > n.y.abs() && n.x.abs() > n.z.abs() {
+                [1, 2]
+            } else if n.y.abs() > n.z.abs() {
+                [0, 2]
+            } else {
+                [0, 1]
+            };
+
+            let a = [
+                [self.dpdu[dim[0]], self.dpdv[dim[0]]],
+                [self.dpdu[dim[1]], self.dpdv[dim[1]]],
+            ];
+            let bx = [dpdx[dim[0]], dpdx[dim[1]]];
+            let by = [dpdy[dim[0]], dpdy[dim[1]]];
+
+            let (dudx, dvdx) = solve_linear_system_2x2(a, bx).unwrap_or((0.0, 0.0));
+            let (dudy, dvdy) = solve_linear_system_2x2(a, by).unwrap_or((0.0, 0.0));
+
+            self.dudx = dudx;
+            self.dvdx = dvdx;
+            self.dudy = dudy;
+            self.dvdy = dvdy;
+        } else {
+            self.dpdx = Vector3f::zero();
+            self.dpdy = Vector3f::zero();
+            self.dudx = 0.0;
+            self.dvdx = 0.0;
+            self.dudy = 0.0;
+            self.dvdy = 0.0;
+        }
+    }
+
+    fn tangent_plane_offsets(&self, ray: &RayDifferential) -> Option<(Vector3f, Vector3f)> {
+        if !ray.has_differentials {
+            return None;
+        }
+
+        let rx_origin = ray.rx_origin?;
+        let ry_origin = ray.ry_origin?;
+        let rx_direction = ray.rx_direction?;
+        let ry_direction = ray.ry_direction?;
+
+        let n = Vector3f::from(self.n);
+        let d = n.dot(Vector3f::from(self.p));
+
+        let tx = -(n.dot(Vector3f::from(rx_origin)) - d) / n.dot(rx_direction);
+        let ty = -(n.dot(Vector3f::from(ry_origin)) - d) / n.dot(ry_direction);
+
+        if !tx.is_finite() || !ty.is_finite() {
+            return None;
+        }
+
+        let px = rx_origin + rx_direction * tx;
+        let py = ry_origin + ry_direction * ty;
+
+        Some((px - self.p, py - self.p))
+    }
+
+    pub fn spawn_ray(&self, d: Vector3f) -> Ray {
+        Interaction::from(self).spawn_ray(d)
+    }
+
+    pub fn spawn_ray_to(&self, p: Point3f) -> Ray {
+        Interaction::from(self).spawn_ray_to(p)
+    }
+}
+
+impl Transformable for SurfaceInteraction {
+    fn transform(self, t: Transform) -> Self {
+        let (p, p_error) = t.transform_point_with_error(self.p, self.p_error);
+        let n = t.transform(self.n).normalize();
+
+        let mut shading_n = t.transform(self.shading.n).normalize();
+        shading_n = shading_n.face_forward(Vector3f::from(n));
+
+        Self {
+            p,
+            time: self.time,
+            p_error,
+            wo: t.transform(self.wo).normalize(),
+            n,
+            medium_interface: self.medium_interface,
+            uv: self.uv,
+            dpdu: t.transform(self.dpdu),
+            dpdv: t.transform(self.dpdv),
+            dndu: t.transform(self.dndu),
+            dndv: t.transform(self.dndv),
+            shading: Shading {
+                n: shading_n,
+                dpdu: t.transform(self.shading.dpdu),
+                dpdv: t.transform(self.shading.dpdv),
+                dndu: t.transform(self.shading.dndu),
+                dndv: t.transform(self.shading.dndv),
+            },
+            dpdx: t.transform(self.dpdx),
+            dpdy: t.transform(self.dpdy),
+            dudx: self.dudx,
+            dvdx: self.dvdx,
+            dudy: self.dudy,
+            dvdy: self.dvdy,
+            material: self.material,
+            area_light: self.area_light,
+        }
+    }
+}
+
+/// Pushes `p` along the normal by enough to get past its floating point
+/// error bounds so a ray leaving in direction `w` cannot re-hit the surface
+/// it starts on.
+pub fn offset_ray_origin(p: Point3f, p_error: Vector3f, n: Normal3f, w: Vector3f) -> Point3f {
+    let n = Vector3f::from(n);
+    let d = n.abs().dot(p_error);
+
+    let mut offset = n * d;
+
+    if w.dot(n) < 0.0 {
+        offset = -offset;
+    }
+
+    let mut po = p + offset;
+
+    for i in 0..3 {
+        if offset[i] > 0.0 {
+            po[i] = next_float_up(po[i]);
+        } else if offset[i] < 0.0 {
+            po[i] = next_float_down(po[i]);
+        }
+    }
+
+    po
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EPSILON: f64 = 0.00001;
+
+    fn plane_interaction() -> SurfaceInteraction {
+        SurfaceInteraction::new(
+            Point3f::new(0.0, 0.0, 0.0),
+            Vector3f::zero(),
+            Point2f::new(0.5, 0.5),
+            Vector3f::new(0.0, 0.0, 1.0),
+            Vector3f::new(2.0, 0.0, 0.0),
+            Vector3f::new(0.0, 2.0, 0.0),
+            0.0,
+        )
+    }
+
+    #[test]
+    fn offset_ray_origin_follows_direction() {
+        let p = Point3f::new(0.0, 0.0, 1.0);
+        let p_error = Vector3f::new(0.001, 0.001, 0.001);
+        let n = Normal3f::new(0.0, 0.0, 1.0);
+
+        let above = offset_ray_origin(p, p_error, n, Vector3f::new(0.0, 0.0, 1.0));
+        let below = offset_ray_origin(p, p_error, n, Vector3f::new(0.0, 0.0, -1.0));
+
+        assert!(above.z > 1.001);
+        assert!(below.z < 0.999);
+    }
+
+    #[test]
+    fn surface_interaction_normal() {
+        let si = plane_interaction();
+
+        assert!((si.n.z - 1.0).abs() < EPSILON);
+        assert!((si.shading.n.z - 1.0).abs() < EPSILON);
+    }
+
+    #[test]
+    fn set_shading_geometry_authoritative() {
+        let mut si = plane_interaction();
+
+        si.set_shading_geometry(
+            Vector3f::new(0.0, 1.0, 0.0),
+            Vector3f::new(1.0, 0.0, 0.0),
+            Normal3f::zero(),
+            Normal3f::zero(),
+            true,
+        );
+
+        assert!((si.shading.n.z + 1.0).abs() < EPSILON);
+        assert!((si.n.z + 1.0).abs() < EPSILON);
+    }
+
+    #[test]
+    fn compute_differentials_on_plane() {
+        let mut si = plane_interaction();
+
+        let mut ray = RayDifferential::new(
+            Point3f::new(0.0, 0.0, 1.0),
+            Vector3f::new(0.0, 0.0, -1.0),
+            Medium {},
+            f64::INFINITY,
+            0.0,
+        );
+        ray.rx_origin = Some(Point3f::new(0.1, 0.0, 1.0));
+        ray.ry_origin = Some(Point3f::new(0.0, 0.1, 1.0));
+        ray.rx_direction = Some(Vector3f::new(0.0, 0.0, -1.0));
+        ray.ry_direction = Some(Vector3f::new(0.0, 0.0, -1.0));
+        ray.has_differentials = true;
+
+        si.compute_differentials(&ray);
+
+        assert!((si.dpdx.x - 0.1).abs() < EPSILON);
+        assert!((si.dpdy.y - 0.1).abs() < EPSILON);
+        assert!((si.dudx - 0.05).abs() < EPSILON);
+        assert!(si.dvdx.abs() < EPSILON);
+        assert!((si.dvdy - 0.05).abs() < EPSILON);
+    }
+
+    #[test]
+    fn spawn_ray_to_stops_short() {
+        let si = plane_interaction();
+        let ray = si.spawn_ray_to(Point3f::new(0.0, 0.0, 2.0));
+
+        assert!(ray.t_max < 1.0);
+        assert!(ray.o.z >= 0.0);
+    }
+
+    #[test]
+    fn transform_moves_point_and_normal() {
+        let si = plane_interaction();
+        let t = Transform::translate(Vector3f::new(1.0, 2.0, 3.0));
+
+        let result = si.transform(t);
+
+        assert!((result.p.x - 1.0).abs() < EPSILON);
+        assert!((result.p.z - 3.0).abs() < EPSILON);
+        assert!((result.n.z - 1.0).abs() < EPSILON);
+    }
+}
@@ -0,0 +1,2 @@
+/// A light that emits from the surface of a shape.
+pub trait AreaLight: Send + Sync {}
@@ -0,0 +1,3 @@
+/// Surface appearance attached to a primitive. Scattering functions are
+/// added here once BSDFs exist.
+pub trait Material: Send + Sync {}
@@ -1,6 +1,6 @@
 use std::ops::{Index, IndexMut};
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Matrix44 {
     m: [[f64; 4]; 4],
 }
@@ -77,22 +77,14 @@ impl Matrix44 {
 
         for i in 0..3 {
             let mut pivot = i;
-            let mut pivot_size = s[i][i];
+            let mut pivot_size = s[i][i].abs();
 
-            if pivot_size < 0.0 {
-                pivot_size = -pivot_size;
-
-                for j in (i + 1)..4 {
-                    let mut tmp = s[j][i];
-
-                    if tmp < 0.0 {
-                        tmp = -tmp;
+            for j in (i + 1)..4 {
+                let tmp = s[j][i].abs();
 
-                        if tmp > pivot_size {
-                            pivot = j;
-                            pivot_size = tmp;
-                        }
-                    }
+                if tmp > pivot_size {
+                    pivot = j;
+                    pivot_size = tmp;
                 }
             }
 
@@ -230,4 +222,25 @@ mod tests {
             result,
         );
     }
+
+    #[cfg_attr(rustfmt, rustfmt_skip)]
+    #[test]
+    fn inverse_zero_diagonal() {
+        let matrix = Matrix44::new(
+            0.0, -3.0, 0.0, 0.0,
+            3.0, 0.0, 0.0, 0.0,
+            0.0, 0.0, 3.0, 0.0,
+            0.0, 0.0, 0.0, 1.0,
+        );
+
+        let result = matrix.inverse();
+
+        assert_matrix_values(
+            0.0, 1.0 / 3.0, 0.0, 0.0,
+            -1.0 / 3.0, 0.0, 0.0, 0.0,
+            0.0, 0.0, 1.0 / 3.0, 0.0,
+            0.0, 0.0, 0.0, 1.0,
+            result,
+        );
+    }
 }
@@ -1,2 +1,22 @@
 #[derive(Clone, Copy, Debug)]
 pub struct Medium {}
+
+/// The media on either side of a surface. `inside` is the side the surface
+/// normal points away from.
+#[derive(Clone, Copy, Debug)]
+pub struct MediumInterface {
+    pub inside: Medium,
+    pub outside: Medium,
+}
+
+impl MediumInterface {
+    pub fn new(inside: Medium, outside: Medium) -> Self {
+        Self { inside, outside }
+    }
+}
+
+impl From<Medium> for MediumInterface {
+    fn from(medium: Medium) -> Self {
+        Self::new(medium, medium)
+    }
+}
@@ -1,12 +1,25 @@
+pub mod animated_transform;
 pub mod bounds2;
 pub mod bounds3;
+pub mod camera;
+pub mod csg;
+pub mod interaction;
+pub mod light;
+pub mod material;
 pub mod matrix44;
 pub mod medium;
 pub mod normal3;
 pub mod point2;
 pub mod point3;
+pub mod primitive;
+pub mod quaternion;
 pub mod ray;
 pub mod ray_differential;
+pub mod ray_packet;
+pub mod sampling;
+pub mod shape;
+pub mod spherical;
+pub mod texture;
 pub mod transform;
 pub mod transformable;
 pub mod utils;
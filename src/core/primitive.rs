@@ -0,0 +1,443 @@
+use std::sync::Arc;
+
+use core::animated_transform::AnimatedTransform;
+use core::interaction::SurfaceInteraction;
+use core::light::AreaLight;
+use core::material::Material;
+use core::medium::MediumInterface;
+use core::ray::Ray;
+use core::shape::Shape;
+use core::texture::Texture;
+use core::transform::Transform;
+use core::utils::hash_float;
+
+use core::Bounds3f;
+
+pub trait Primitive: Send + Sync {
+    fn world_bound(&self) -> Bounds3f;
+
+    /// Bounds the part of the primitive inside `clip`; see
+    /// `Shape::clipped_world_bound`.
+    fn clipped_world_bound(&self, clip: Bounds3f) -> Bounds3f {
+        self.world_bound().intersection(clip)
+    }
+
+    /// Finds the closest hit along `ray`, shortening `ray.t_max` to it so
+    /// later tests can reject anything farther away.
+    fn intersect(&self, ray: &mut Ray) -> Option<SurfaceInteraction>;
+
+    fn intersect_p(&self, ray: &Ray) -> bool;
+
+    /// Intersects a batch of rays as `intersect` would one by one.
+    /// Accelerators override this to trace coherent rays together.
+    fn intersect_many(&self, rays: &mut [Ray]) -> Vec<Option<SurfaceInteraction>> {
+        rays.iter_mut().map(|ray| self.intersect(ray)).collect()
+    }
+
+    /// Only primitives that own their appearance answer this; aggregates
+    /// and instances return `None`, and hits through them carry the area
+    /// light and material on the `SurfaceInteraction` instead.
+    fn get_area_light(&self) -> Option<Arc<dyn AreaLight>>;
+
+    fn get_material(&self) -> Option<Arc<dyn Material>>;
+}
+
+/// A shape together with how it looks. A `medium_interface` of `None`
+/// means the shape does not separate two media and rays keep theirs.
+pub struct GeometricPrimitive {
+    pub shape: Arc<dyn Shape>,
+    pub material: Option<Arc<dyn Material>>,
+    pub area_light: Option<Arc<dyn AreaLight>>,
+    pub medium_interface: Option<MediumInterface>,
+    /// Cuts away the parts of the surface where it evaluates to zero.
+    pub alpha_mask: Option<Arc<dyn Texture<f64>>>,
+}
+
+impl GeometricPrimitive {
+    pub fn new(
+        shape: Arc<dyn Shape>,
+        material: Option<Arc<dyn Material>>,
+        area_light: Option<Arc<dyn AreaLight>>,
+        medium_interface: Option<MediumInterface>,
+    ) -> Self {
+        Self {
+            shape,
+            material,
+            area_light,
+            medium_interface,
+            alpha_mask: None,
+        }
+    }
+
+    pub fn with_alpha_mask(mut self, alpha_mask: Arc<dyn Texture<f64>>) -> Self {
+        self.alpha_mask = Some(alpha_mask);
+        self
+    }
+
+    /// The closest hit on the shape that survives the alpha mask. Rays
+    /// carry on from cut out hits, so the far side of a shape can still be
+    /// seen through a hole in its near side.
+    fn unmasked_hit(&self, ray: &Ray) -> Option<(f64, SurfaceInteraction)> {
+        let (t_hit, isect) = self.shape.intersect(ray)?;
+
+        match self.alpha_mask {
+            Some(ref alpha_mask) if !alpha_test(alpha_mask.as_ref(), &isect, ray) => {
+                let mut next = isect.spawn_ray(ray.d);
+                next.medium = ray.medium;
+                next.t_max = ray.t_max - t_hit;
+
+                self.unmasked_hit(&next)
+                    .map(|(t, isect)| (t_hit + t, isect))
+            }
+            _ => Some((t_hit, isect)),
+        }
+    }
+}
+
+impl Primitive for GeometricPrimitive {
+    fn world_bound(&self) -> Bounds3f {
+        self.shape.world_bound()
+    }
+
+    fn clipped_world_bound(&self, clip: Bounds3f) -> Bounds3f {
+        self.shape.clipped_world_bound(clip)
+    }
+
+    fn intersect(&self, ray: &mut Ray) -> Option<SurfaceInteraction> {
+        let (t_hit, mut isect) = self.unmasked_hit(ray)?;
+
+        ray.t_max = t_hit;
+        isect.medium_interface = self
+            .medium_interface
+            .unwrap_or_else(|| MediumInterface::from(ray.medium));
+        isect.material = self.material.clone();
+        isect.area_light = self.area_light.clone();
+
+        Some(isect)
+    }
+
+    fn intersect_p(&self, ray: &Ray) -> bool {
+        if self.alpha_mask.is_some() {
+            self.unmasked_hit(ray).is_some()
+        } else {
+            self.shape.intersect_p(ray)
+        }
+    }
+
+    fn get_area_light(&self) -> Option<Arc<dyn AreaLight>> {
+        self.area_light.clone()
+    }
+
+    fn get_material(&self) -> Option<Arc<dyn Material>> {
+        self.material.clone()
+    }
+}
+
+/// An instance of a shared primitive placed in the world by a possibly
+/// animated transform. Rays are moved into the primitive's space rather
+/// than copying its geometry, so instances cost a transform each.
+pub struct TransformedPrimitive {
+    pub primitive: Arc<dyn Primitive>,
+    pub primitive_to_world: AnimatedTransform,
+}
+
+impl TransformedPrimitive {
+    pub fn new(primitive: Arc<dyn Primitive>, primitive_to_world: AnimatedTransform) -> Self {
+        Self {
+            primitive,
+            primitive_to_world,
+        }
+    }
+
+    pub fn with_transform(primitive: Arc<dyn Primitive>, primitive_to_world: Transform) -> Self {
+        Self::new(primitive, AnimatedTransform::from(primitive_to_world))
+    }
+}
+
+impl Primitive for TransformedPrimitive {
+    fn world_bound(&self) -> Bounds3f {
+        self.primitive_to_world
+            .motion_bounds(self.primitive.world_bound())
+    }
+
+    fn intersect(&self, r: &mut Ray) -> Option<SurfaceInteraction> {
+        let interpolated = self.primitive_to_world.interpolate(r.time);
+        let mut ray = interpolated.inverse().transform(*r);
+
+        let isect = self.primitive.intersect(&mut ray)?;
+        r.t_max = ray.t_max;
+
+        if interpolated.is_identity() {
+            Some(isect)
+        } else {
+            Some(interpolated.transform(isect))
+        }
+    }
+
+    fn intersect_p(&self, r: &Ray) -> bool {
+        let interpolated = self.primitive_to_world.interpolate(r.time);
+
+        self.primitive
+            .intersect_p(&interpolated.inverse().transform(*r))
+    }
+
+    fn get_area_light(&self) -> Option<Arc<dyn AreaLight>> {
+        None
+    }
+
+    fn get_material(&self) -> Option<Arc<dyn Material>> {
+        None
+    }
+}
+
+/// Whether a hit survives the alpha mask. Partial coverage is resolved by
+/// hashing the ray, so the same ray, whether traced for the closest hit or
+/// as a shadow ray, always gets the same answer.
+pub fn alpha_test(alpha_mask: &dyn Texture<f64>, isect: &SurfaceInteraction, ray: &Ray) -> bool {
+    let alpha = alpha_mask.evaluate(isect);
+
+    if alpha >= 1.0 {
+        return true;
+    }
+
+    if alpha <= 0.0 {
+        return false;
+    }
+
+    hash_float(&[ray.o.x, ray.o.y, ray.o.z, ray.d.x, ray.d.y, ray.d.z]) < alpha
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64;
+
+    use accelerators::bvh::{BvhAccel, SplitMethod};
+    use core::medium::Medium;
+    use shapes::sphere::Sphere;
+    use shapes::triangle::{Triangle, TriangleMesh};
+    use textures::constant::ConstantTexture;
+
+    use core::Point2f;
+    use core::Point3f;
+    use core::Vector3f;
+
+    const EPSILON: f64 = 0.0001;
+
+    struct Matte;
+
+    impl Material for Matte {}
+
+    struct LeftHalf;
+
+    impl Texture<f64> for LeftHalf {
+        fn evaluate(&self, si: &SurfaceInteraction) -> f64 {
+            if si.uv.x < 0.5 {
+                1.0
+            } else {
+                0.0
+            }
+        }
+    }
+
+    struct UpperHalf;
+
+    impl Texture<f64> for UpperHalf {
+        fn evaluate(&self, si: &SurfaceInteraction) -> f64 {
+            if si.p.z > 0.0 {
+                1.0
+            } else {
+                0.0
+            }
+        }
+    }
+
+    fn masked_triangle(alpha_mask: Arc<dyn Texture<f64>>) -> GeometricPrimitive {
+        let mesh = TriangleMesh::new(
+            Transform::new(),
+            false,
+            vec![0, 1, 2],
+            vec![
+                Point3f::new(0.0, 0.0, 1.0),
+                Point3f::new(1.0, 0.0, 1.0),
+                Point3f::new(0.0, 1.0, 1.0),
+            ],
+            None,
+            None,
+            Some(vec![
+                Point2f::new(0.0, 0.0),
+                Point2f::new(1.0, 0.0),
+                Point2f::new(0.0, 1.0),
+            ]),
+        );
+        let triangle = Triangle::from_mesh(Arc::new(mesh)).remove(0);
+
+        GeometricPrimitive::new(Arc::new(triangle), None, None, None).with_alpha_mask(alpha_mask)
+    }
+
+    fn ray_up(x: f64, y: f64) -> Ray {
+        Ray::new(
+            Point3f::new(x, y, 0.0),
+            Vector3f::new(0.0, 0.0, 1.0),
+            Medium {},
+            f64::INFINITY,
+            0.0,
+        )
+    }
+
+    fn unit_sphere() -> Arc<dyn Primitive> {
+        let sphere = Sphere::full(Transform::new(), 1.0);
+
+        Arc::new(GeometricPrimitive::new(Arc::new(sphere), None, None, None))
+    }
+
+    fn ray_along_z(x: f64) -> Ray {
+        Ray::new(
+            Point3f::new(x, 0.0, -10.0),
+            Vector3f::new(0.0, 0.0, 1.0),
+            Medium {},
+            f64::INFINITY,
+            0.0,
+        )
+    }
+
+    #[test]
+    fn geometric_primitive_shortens_ray() {
+        let primitive = unit_sphere();
+        let mut ray = ray_along_z(0.0);
+
+        let isect = primitive.intersect(&mut ray).unwrap();
+
+        assert!((ray.t_max - 9.0).abs() < EPSILON);
+        assert!((isect.p.z + 1.0).abs() < EPSILON);
+    }
+
+    #[test]
+    fn instances_share_geometry() {
+        let shared = unit_sphere();
+        let left = TransformedPrimitive::with_transform(
+            shared.clone(),
+            Transform::translate(Vector3f::new(-3.0, 0.0, 0.0)),
+        );
+        let right = TransformedPrimitive::with_transform(
+            shared.clone(),
+            Transform::translate(Vector3f::new(3.0, 0.0, 0.0)) * Transform::scale(2.0, 2.0, 2.0),
+        );
+
+        let mut ray = ray_along_z(3.0);
+        assert!(left.intersect(&mut ray.clone()).is_none());
+
+        let isect = right.intersect(&mut ray).unwrap();
+
+        assert!((isect.p.x - 3.0).abs() < EPSILON);
+        assert!((isect.p.z + 2.0).abs() < EPSILON);
+        assert!((isect.n.z + 1.0).abs() < EPSILON);
+        assert!((ray.t_max - 8.0).abs() < EPSILON);
+
+        let bound = right.world_bound();
+        assert!(bound.inside(Point3f::new(5.0, 2.0, 2.0)));
+        assert!(!bound.inside(Point3f::new(0.0, 0.0, 0.0)));
+    }
+
+    #[test]
+    fn animated_instance_moves_with_time() {
+        let primitive = TransformedPrimitive::new(
+            unit_sphere(),
+            AnimatedTransform::new(
+                Transform::new(),
+                0.0,
+                Transform::translate(Vector3f::new(4.0, 0.0, 0.0)),
+                1.0,
+            ),
+        );
+
+        let mut ray = ray_along_z(2.0);
+        ray.time = 0.5;
+
+        assert!(primitive.intersect_p(&ray));
+        assert!(primitive.intersect(&mut ray).is_some());
+
+        let mut ray = ray_along_z(2.0);
+        ray.time = 0.0;
+
+        assert!(!primitive.intersect_p(&ray));
+        assert!(primitive.intersect(&mut ray).is_none());
+    }
+
+    #[test]
+    fn hits_keep_their_material_through_instances() {
+        let matte: Arc<dyn Material> = Arc::new(Matte);
+        let sphere = Sphere::full(Transform::new(), 1.0);
+        let shared: Arc<dyn Primitive> = Arc::new(GeometricPrimitive::new(
+            Arc::new(sphere),
+            Some(matte.clone()),
+            None,
+            None,
+        ));
+        let instances = vec![-3.0, 3.0]
+            .into_iter()
+            .map(|x| {
+                Arc::new(TransformedPrimitive::with_transform(
+                    shared.clone(),
+                    Transform::translate(Vector3f::new(x, 0.0, 0.0)),
+                )) as Arc<dyn Primitive>
+            })
+            .collect();
+        let bvh = BvhAccel::new(instances, 1, SplitMethod::Sah);
+
+        let isect = bvh.intersect(&mut ray_along_z(3.0)).unwrap();
+
+        assert!(bvh.get_material().is_none());
+        assert!(Arc::ptr_eq(&isect.material.unwrap(), &matte));
+        assert!(isect.area_light.is_none());
+    }
+
+    #[test]
+    fn alpha_mask_cuts_out_hits() {
+        let triangle = masked_triangle(Arc::new(LeftHalf));
+
+        assert!(triangle.intersect(&mut ray_up(0.25, 0.25)).is_some());
+        assert!(triangle.intersect(&mut ray_up(0.75, 0.1)).is_none());
+        assert!(!triangle.intersect_p(&ray_up(0.75, 0.1)));
+    }
+
+    #[test]
+    fn partial_alpha_is_stochastic_and_consistent() {
+        let triangle = masked_triangle(Arc::new(ConstantTexture::new(0.25)));
+        let mut hits = 0;
+
+        for i in 0..1000 {
+            let ray = ray_up(0.01 + 0.0004 * i as f64, 0.3);
+            let hit = triangle.intersect(&mut ray.clone()).is_some();
+
+            assert_eq!(hit, triangle.intersect_p(&ray));
+
+            if hit {
+                hits += 1;
+            }
+        }
+
+        assert!(hits > 200 && hits < 300);
+    }
+
+    #[test]
+    fn alpha_mask_sees_through_to_the_far_side() {
+        let sphere = Sphere::full(Transform::new(), 1.0);
+        let primitive = GeometricPrimitive::new(Arc::new(sphere), None, None, None)
+            .with_alpha_mask(Arc::new(UpperHalf));
+
+        let mut ray = ray_along_z(0.0);
+        let isect = primitive.intersect(&mut ray).unwrap();
+
+        assert!((isect.p.z - 1.0).abs() < EPSILON);
+        assert!((ray.t_max - 11.0).abs() < EPSILON);
+        assert!(primitive.intersect_p(&ray_along_z(0.0)));
+
+        // Nothing is left once the far side is out of reach.
+        let mut short = ray_along_z(0.0);
+        short.t_max = 10.5;
+
+        assert!(primitive.intersect(&mut short).is_none());
+        assert!(!primitive.intersect_p(&short));
+    }
+}
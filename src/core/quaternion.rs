@@ -0,0 +1,196 @@
+use std::ops::{Add, Mul, Neg, Sub};
+
+use core::matrix44::Matrix44;
+use core::transform::Transform;
+use core::utils::clamp;
+
+use core::Vector3f;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Quaternion {
+    pub v: Vector3f,
+    pub w: f64,
+}
+
+impl Quaternion {
+    pub fn new(v: Vector3f, w: f64) -> Self {
+        Self { v, w }
+    }
+
+    pub fn identity() -> Self {
+        Self::new(Vector3f::zero(), 1.0)
+    }
+
+    pub fn dot(self, other: Self) -> f64 {
+        self.v.dot(other.v) + self.w * other.w
+    }
+
+    pub fn normalize(self) -> Self {
+        let length = self.dot(self).sqrt();
+
+        Self::new(self.v / length, self.w / length)
+    }
+
+    pub fn slerp(self, other: Self, t: f64) -> Self {
+        let cos_theta = self.dot(other);
+
+        if cos_theta > 0.9995 {
+            return (self * (1.0 - t) + other * t).normalize();
+        }
+
+        let theta = clamp(cos_theta, -1.0, 1.0).acos();
+        let theta_p = theta * t;
+        let q_perp = (other - self * cos_theta).normalize();
+
+        self * theta_p.cos() + q_perp * theta_p.sin()
+    }
+
+    #[cfg_attr(rustfmt, rustfmt_skip)]
+    pub fn to_transform(self) -> Transform {
+        let (x, y, z) = (self.v.x, self.v.y, self.v.z);
+
+        let xx = x * x;
+        let yy = y * y;
+        let zz = z * z;
+        let xy = x * y;
+        let xz = x * z;
+        let yz = y * z;
+        let wx = x * self.w;
+        let wy = y * self.w;
+        let wz = z * self.w;
+
+        let m = Matrix44::new(
+            1.0 - 2.0 * (yy + zz), 2.0 * (xy + wz), 2.0 * (xz - wy), 0.0,
+            2.0 * (xy - wz), 1.0 - 2.0 * (xx + zz), 2.0 * (yz + wx), 0.0,
+            2.0 * (xz + wy), 2.0 * (yz - wx), 1.0 - 2.0 * (xx + yy), 0.0,
+            0.0, 0.0, 0.0, 1.0,
+        );
+
+        // The matrix above rotates row vectors, so transpose it for ours.
+        Transform::from((m.transpose(), m))
+    }
+}
+
+impl From<Transform> for Quaternion {
+    fn from(t: Transform) -> Self {
+        let m = t.m;
+        let trace = m[0][0] + m[1][1] + m[2][2];
+
+        if trace > 0.0 {
+            let s = (trace + 1.0).sqrt();
+            let w = s / 2.0;
+            let s = 0.5 / s;
+
+            let v = Vector3f::new(
+                (m[2][1] - m[1][2]) * s,
+                (m[0][2] - m[2][0]) * s,
+                (m[1][0] - m[0][1]) * s,
+            );
+
+            return Self::new(v, w);
+        }
+
+        let next = [1, 2, 0];
+        let mut q = [0.0; 3];
+
+        let mut i = 0;
+
+        if m[1][1] > m[0][0] {
+            i = 1;
+        }
+
+        if m[2][2] > m[i][i] {
+            i = 2;
+        }
+
+        let j = next[i];
+        let k = next[j];
+
+        let mut s = ((m[i][i] - (m[j][j] + m[k][k])) + 1.0).sqrt();
+        q[i] = s * 0.5;
+
+        if s != 0.0 {
+            s = 0.5 / s;
+        }
+
+        let w = (m[k][j] - m[j][k]) * s;
+        q[j] = (m[j][i] + m[i][j]) * s;
+        q[k] = (m[k][i] + m[i][k]) * s;
+
+        Self::new(Vector3f::new(q[0], q[1], q[2]), w)
+    }
+}
+
+impl Add for Quaternion {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self::new(self.v + other.v, self.w + other.w)
+    }
+}
+
+impl Sub for Quaternion {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        Self::new(self.v - other.v, self.w - other.w)
+    }
+}
+
+impl Mul<f64> for Quaternion {
+    type Output = Self;
+
+    fn mul(self, other: f64) -> Self {
+        Self::new(self.v * other, self.w * other)
+    }
+}
+
+impl Neg for Quaternion {
+    type Output = Self;
+
+    fn neg(self) -> Self {
+        Self::new(-self.v, -self.w)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EPSILON: f64 = 0.00001;
+
+    fn assert_transform_eq(expected: Transform, value: Transform) {
+        for i in 0..4 {
+            for j in 0..4 {
+                assert!((expected.m[i][j] - value.m[i][j]).abs() < EPSILON);
+            }
+        }
+    }
+
+    #[test]
+    fn transform_round_trip() {
+        let t = Transform::rotate(35.0, Vector3f::new(1.0, 2.0, -0.5));
+
+        assert_transform_eq(t, Quaternion::from(t).to_transform());
+    }
+
+    #[test]
+    fn transform_round_trip_large_angle() {
+        let t = Transform::rotate(170.0, Vector3f::new(0.0, 1.0, 0.0));
+
+        assert_transform_eq(t, Quaternion::from(t).to_transform());
+    }
+
+    #[test]
+    fn slerp_halfway() {
+        let q0 = Quaternion::identity();
+        let q1 = Quaternion::from(Transform::rotate(90.0, Vector3f::new(0.0, 0.0, 1.0)));
+
+        let result = q0.slerp(q1, 0.5).to_transform();
+
+        assert_transform_eq(
+            Transform::rotate(45.0, Vector3f::new(0.0, 0.0, 1.0)),
+            result,
+        );
+    }
+}
@@ -3,6 +3,8 @@ use core::Vector3f;
 
 use core::medium::Medium;
 use core::ray::Ray;
+use core::transform::Transform;
+use core::transformable::Transformable;
 
 #[derive(Clone, Debug)]
 pub struct RayDifferential {
@@ -27,9 +29,11 @@ impl RayDifferential {
         self.ray.at(t)
     }
 
-    pub fn scale_differentials(mut self, s: f64) {
+    /// Moves the offset rays towards the main one, for when the image is
+    /// sampled more than once per pixel.
+    pub fn scale_differentials(&mut self, s: f64) {
         self.rx_origin = Some(self.ray.o + (self.rx_origin.unwrap() - self.ray.o) * s);
-        self.rx_origin = Some(self.ray.o + (self.ry_origin.unwrap() - self.ray.o) * s);
+        self.ry_origin = Some(self.ray.o + (self.ry_origin.unwrap() - self.ray.o) * s);
 
         self.rx_direction = Some(self.ray.d + (self.rx_direction.unwrap() - self.ray.d) * s);
         self.ry_direction = Some(self.ray.d + (self.ry_direction.unwrap() - self.ray.d) * s);
@@ -48,3 +52,45 @@ impl From<Ray> for RayDifferential {
         }
     }
 }
+
+impl Transformable for RayDifferential {
+    fn transform(self, t: Transform) -> Self {
+        Self {
+            ray: t.transform(self.ray),
+            rx_origin: self.rx_origin.map(|o| t.transform(o)),
+            ry_origin: self.ry_origin.map(|o| t.transform(o)),
+            rx_direction: self.rx_direction.map(|d| t.transform(d)),
+            ry_direction: self.ry_direction.map(|d| t.transform(d)),
+            has_differentials: self.has_differentials,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EPSILON: f64 = 0.00001;
+
+    #[test]
+    fn scale_differentials_moves_both_offsets() {
+        let mut ray = RayDifferential::new(
+            Point3f::new(0.0, 0.0, 0.0),
+            Vector3f::new(0.0, 0.0, 1.0),
+            Medium {},
+            1.0,
+            0.0,
+        );
+        ray.rx_origin = Some(Point3f::new(1.0, 0.0, 0.0));
+        ray.ry_origin = Some(Point3f::new(0.0, 1.0, 0.0));
+        ray.rx_direction = Some(Vector3f::new(1.0, 0.0, 1.0));
+        ray.ry_direction = Some(Vector3f::new(0.0, 1.0, 1.0));
+
+        ray.scale_differentials(0.25);
+
+        assert!((ray.rx_origin.unwrap() - Point3f::new(0.25, 0.0, 0.0)).length() < EPSILON);
+        assert!((ray.ry_origin.unwrap() - Point3f::new(0.0, 0.25, 0.0)).length() < EPSILON);
+        assert!((ray.rx_direction.unwrap() - Vector3f::new(0.25, 0.0, 1.0)).length() < EPSILON);
+        assert!((ray.ry_direction.unwrap() - Vector3f::new(0.0, 0.25, 1.0)).length() < EPSILON);
+    }
+}
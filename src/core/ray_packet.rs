@@ -0,0 +1,143 @@
+use core::ray::Ray;
+
+use core::Bounds3f;
+
+/// Rays traced together through an accelerator, such as the primary or
+/// shadow rays of a tile. Lanes whose bit is clear in `active` are padding
+/// or no longer wanted and are left untouched.
+#[derive(Clone, Copy, Debug)]
+pub struct RayPacket<const N: usize> {
+    pub rays: [Ray; N],
+    pub active: u32,
+}
+
+pub type RayPacket8 = RayPacket<8>;
+pub type RayPacket16 = RayPacket<16>;
+
+impl<const N: usize> RayPacket<N> {
+    /// Packs up to `N` rays, padding the remaining lanes with inactive rays.
+    pub fn new(rays: &[Ray]) -> Self {
+        assert!(N <= 32 && rays.len() <= N);
+
+        let mut packet = Self {
+            rays: [Ray::zero(); N],
+            active: ((1u64 << rays.len()) - 1) as u32,
+        };
+        packet.rays[..rays.len()].copy_from_slice(rays);
+
+        packet
+    }
+
+    pub fn is_active(&self, lane: usize) -> bool {
+        self.active & (1 << lane) != 0
+    }
+
+    pub fn n_active(&self) -> usize {
+        self.active.count_ones() as usize
+    }
+
+    pub fn active_lanes(&self) -> impl Iterator<Item = usize> {
+        lanes(self.active)
+    }
+
+    /// The direction signs, as used for `dir_is_neg`, shared by all active
+    /// rays, or `None` if any two rays point different ways along an axis
+    /// or a ray runs parallel to one. Only such packets can be bounded by
+    /// intervals of inverse directions.
+    pub fn common_dir_is_neg(&self) -> Option<[usize; 3]> {
+        let mut lanes = self.active_lanes();
+        let first = self.rays[lanes.next()?].d;
+
+        if first.x == 0.0 || first.y == 0.0 || first.z == 0.0 {
+            return None;
+        }
+
+        let signs = [
+            (first.x < 0.0) as usize,
+            (first.y < 0.0) as usize,
+            (first.z < 0.0) as usize,
+        ];
+
+        for lane in lanes {
+            let d = self.rays[lane].d;
+
+            for (axis, &sign) in signs.iter().enumerate() {
+                let expected = if sign == 1 { -1.0 } else { 1.0 };
+
+                if d[axis] * expected <= 0.0 {
+                    return None;
+                }
+            }
+        }
+
+        Some(signs)
+    }
+
+    /// Bounds the origins of the active rays.
+    pub fn origin_bounds(&self) -> Bounds3f {
+        self.active_lanes()
+            .fold(Bounds3f::zero(), |b, lane| b.union(self.rays[lane].o))
+    }
+}
+
+/// Iterates over the lanes set in `mask`, lowest first.
+pub fn lanes(mut mask: u32) -> impl Iterator<Item = usize> {
+    (0..mask.count_ones()).map(move |_| {
+        let lane = mask.trailing_zeros() as usize;
+        mask &= mask - 1;
+
+        lane
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use core::medium::Medium;
+
+    use core::Point3f;
+    use core::Vector3f;
+
+    fn ray(o: Point3f, d: Vector3f) -> Ray {
+        Ray::new(o, d, Medium {}, 10.0, 0.0)
+    }
+
+    #[test]
+    fn pads_with_inactive_lanes() {
+        let o = Point3f::new(1.0, 2.0, 3.0);
+        let packet = RayPacket8::new(&[ray(o, Vector3f::new(1.0, 1.0, 1.0)); 5]);
+
+        assert_eq!(packet.n_active(), 5);
+        assert!(packet.is_active(4) && !packet.is_active(5));
+        assert_eq!(
+            packet.active_lanes().collect::<Vec<_>>(),
+            vec![0, 1, 2, 3, 4]
+        );
+        assert_eq!(lanes(0b1010_0001).collect::<Vec<_>>(), vec![0, 5, 7]);
+
+        let full = RayPacket::<32>::new(&[ray(o, Vector3f::new(1.0, 1.0, 1.0)); 32]);
+        assert_eq!(full.active, !0);
+    }
+
+    #[test]
+    fn direction_signs_must_agree() {
+        let o = Point3f::new(0.0, 0.0, 0.0);
+        let mut packet = RayPacket8::new(&[
+            ray(o, Vector3f::new(1.0, -1.0, 2.0)),
+            ray(o, Vector3f::new(0.5, -2.0, 1.0)),
+        ]);
+
+        assert_eq!(packet.common_dir_is_neg(), Some([0, 1, 0]));
+
+        packet.rays[1].d.z = -1.0;
+        assert_eq!(packet.common_dir_is_neg(), None);
+
+        // Inactive lanes do not count.
+        packet.active = 0b01;
+        assert_eq!(packet.common_dir_is_neg(), Some([0, 1, 0]));
+
+        packet.rays[0].d.y = 0.0;
+        assert_eq!(packet.common_dir_is_neg(), None);
+    }
+}
@@ -0,0 +1,234 @@
+use std::f64::consts::{FRAC_PI_2, FRAC_PI_4, PI};
+
+use core::utils::{clamp, safe_asin, safe_sqrt};
+
+use core::Point2f;
+use core::Point3f;
+use core::Vector3f;
+
+pub fn uniform_sample_hemisphere(u: Point2f) -> Vector3f {
+    let z = u.x;
+    let r = safe_sqrt(1.0 - z * z);
+    let phi = 2.0 * PI * u.y;
+
+    Vector3f::new(r * phi.cos(), r * phi.sin(), z)
+}
+
+pub fn uniform_hemisphere_pdf() -> f64 {
+    1.0 / (2.0 * PI)
+}
+
+pub fn uniform_sample_sphere(u: Point2f) -> Vector3f {
+    let z = 1.0 - 2.0 * u.x;
+    let r = safe_sqrt(1.0 - z * z);
+    let phi = 2.0 * PI * u.y;
+
+    Vector3f::new(r * phi.cos(), r * phi.sin(), z)
+}
+
+pub fn uniform_sphere_pdf() -> f64 {
+    1.0 / (4.0 * PI)
+}
+
+pub fn uniform_sample_cone(u: Point2f, cos_theta_max: f64) -> Vector3f {
+    let cos_theta = (1.0 - u.x) + u.x * cos_theta_max;
+    let sin_theta = safe_sqrt(1.0 - cos_theta * cos_theta);
+    let phi = u.y * 2.0 * PI;
+
+    Vector3f::new(phi.cos() * sin_theta, phi.sin() * sin_theta, cos_theta)
+}
+
+pub fn uniform_cone_pdf(cos_theta_max: f64) -> f64 {
+    1.0 / (2.0 * PI * (1.0 - cos_theta_max))
+}
+
+pub fn concentric_sample_disk(u: Point2f) -> Point2f {
+    let u_offset = Point2f::new(2.0 * u.x - 1.0, 2.0 * u.y - 1.0);
+
+    if u_offset.x == 0.0 && u_offset.y == 0.0 {
+        return Point2f::zero();
+    }
+
+    let (r, theta) = if u_offset.x.abs() > u_offset.y.abs() {
+        (u_offset.x, FRAC_PI_4 * (u_offset.y / u_offset.x))
+    } else {
+        (
+            u_offset.y,
+            FRAC_PI_2 - FRAC_PI_4 * (u_offset.x / u_offset.y),
+        )
+    };
+
+    Point2f::new(r * theta.cos(), r * theta.sin())
+}
+
+/// Returns the first two barycentric coordinates of a point distributed
+/// uniformly over a triangle.
+pub fn uniform_sample_triangle(u: Point2f) -> Point2f {
+    let su0 = u.x.sqrt();
+
+    Point2f::new(1.0 - su0, u.y * su0)
+}
+
+/// Solid angle subtended by the triangle `(p0, p1, p2)` as seen from `p`.
+pub fn spherical_triangle_area(p0: Point3f, p1: Point3f, p2: Point3f, p: Point3f) -> f64 {
+    let a = (p0 - p).normalize();
+    let b = (p1 - p).normalize();
+    let c = (p2 - p).normalize();
+
+    let numerator = a.dot(b.cross(c));
+    let denominator = 1.0 + a.dot(b) + a.dot(c) + b.dot(c);
+
+    (2.0 * numerator.atan2(denominator)).abs()
+}
+
+/// Samples a direction inside the spherical triangle that `(p0, p1, p2)`
+/// projects to around `p` (Arvo 1995). Returns the barycentric coordinates
+/// of the point the direction hits on the triangle along with the solid
+/// angle pdf, or `None` if the triangle is degenerate as seen from `p`.
+pub fn sample_spherical_triangle(
+    p0: Point3f,
+    p1: Point3f,
+    p2: Point3f,
+    p: Point3f,
+    u: Point2f,
+) -> Option<([f64; 3], f64)> {
+    let a = (p0 - p).normalize();
+    let b = (p1 - p).normalize();
+    let c = (p2 - p).normalize();
+
+    let n_ab = a.cross(b);
+    let n_bc = b.cross(c);
+    let n_ca = c.cross(a);
+
+    if n_ab.length_squared() == 0.0 || n_bc.length_squared() == 0.0 || n_ca.length_squared() == 0.0
+    {
+        return None;
+    }
+
+    let n_ab = n_ab.normalize();
+    let n_bc = n_bc.normalize();
+    let n_ca = n_ca.normalize();
+
+    let alpha = angle_between(n_ab, -n_ca);
+    let beta = angle_between(n_bc, -n_ab);
+    let gamma = angle_between(n_ca, -n_bc);
+
+    // Pick the sub-triangle area uniformly and find the matching point on
+    // the arc between a and c.
+    let a_pi = alpha + beta + gamma;
+    let ap_pi = (1.0 - u.x) * PI + u.x * a_pi;
+    let area = a_pi - PI;
+    let pdf = if area <= 0.0 { 0.0 } else { 1.0 / area };
+
+    let cos_alpha = alpha.cos();
+    let sin_alpha = alpha.sin();
+    let sin_phi = ap_pi.sin() * cos_alpha - ap_pi.cos() * sin_alpha;
+    let cos_phi = ap_pi.cos() * cos_alpha + ap_pi.sin() * sin_alpha;
+
+    let k1 = cos_phi + cos_alpha;
+    let k2 = sin_phi - sin_alpha * a.dot(b);
+    let cos_bp = clamp(
+        (k2 + (k2 * cos_phi - k1 * sin_phi) * cos_alpha)
+            / ((k2 * sin_phi + k1 * cos_phi) * sin_alpha),
+        -1.0,
+        1.0,
+    );
+    let sin_bp = safe_sqrt(1.0 - cos_bp * cos_bp);
+    let cp = a * cos_bp + gram_schmidt(c, a).normalize() * sin_bp;
+
+    // Then sample uniformly along the arc between b and c'.
+    let cos_theta = 1.0 - u.y * (1.0 - cp.dot(b));
+    let sin_theta = safe_sqrt(1.0 - cos_theta * cos_theta);
+    let w = b * cos_theta + gram_schmidt(cp, b).normalize() * sin_theta;
+
+    // Intersect the sampled direction with the triangle's plane to recover
+    // barycentrics.
+    let e1 = p1 - p0;
+    let e2 = p2 - p0;
+    let s1 = w.cross(e2);
+    let divisor = s1.dot(e1);
+
+    if divisor == 0.0 {
+        return Some(([1.0 / 3.0, 1.0 / 3.0, 1.0 / 3.0], pdf));
+    }
+
+    let inv_divisor = 1.0 / divisor;
+    let s = p - p0;
+    let mut b1 = clamp(s.dot(s1) * inv_divisor, 0.0, 1.0);
+    let mut b2 = clamp(w.dot(s.cross(e1)) * inv_divisor, 0.0, 1.0);
+
+    if b1 + b2 > 1.0 {
+        let sum = b1 + b2;
+        b1 /= sum;
+        b2 /= sum;
+    }
+
+    Some(([1.0 - b1 - b2, b1, b2], pdf))
+}
+
+fn angle_between(v1: Vector3f, v2: Vector3f) -> f64 {
+    if v1.dot(v2) < 0.0 {
+        PI - 2.0 * safe_asin((v1 + v2).length() / 2.0)
+    } else {
+        2.0 * safe_asin((v2 - v1).length() / 2.0)
+    }
+}
+
+fn gram_schmidt(v: Vector3f, w: Vector3f) -> Vector3f {
+    v - w * v.dot(w)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EPSILON: f64 = 0.00001;
+
+    #[test]
+    fn uniform_sample_sphere_unit_length() {
+        let v = uniform_sample_sphere(Point2f::new(0.3, 0.7));
+
+        assert!((v.length() - 1.0).abs() < EPSILON);
+    }
+
+    #[test]
+    fn uniform_sample_cone_inside_cone() {
+        let cos_theta_max = 0.9;
+        let v = uniform_sample_cone(Point2f::new(0.99, 0.25), cos_theta_max);
+
+        assert!(v.z >= cos_theta_max - EPSILON);
+    }
+
+    #[test]
+    fn concentric_sample_disk_inside_disk() {
+        let p = concentric_sample_disk(Point2f::new(0.9, 0.1));
+
+        assert!(p.x * p.x + p.y * p.y <= 1.0 + EPSILON);
+    }
+
+    #[test]
+    fn spherical_triangle_area_octant() {
+        let area = spherical_triangle_area(
+            Point3f::new(1.0, 0.0, 0.0),
+            Point3f::new(0.0, 1.0, 0.0),
+            Point3f::new(0.0, 0.0, 1.0),
+            Point3f::zero(),
+        );
+
+        assert!((area - PI / 2.0).abs() < EPSILON);
+    }
+
+    #[test]
+    fn sample_spherical_triangle_octant() {
+        let p0 = Point3f::new(1.0, 0.0, 0.0);
+        let p1 = Point3f::new(0.0, 1.0, 0.0);
+        let p2 = Point3f::new(0.0, 0.0, 1.0);
+
+        let (b, pdf) =
+            sample_spherical_triangle(p0, p1, p2, Point3f::zero(), Point2f::new(0.4, 0.6)).unwrap();
+
+        assert!((pdf - 2.0 / PI).abs() < EPSILON);
+        assert!((b[0] + b[1] + b[2] - 1.0).abs() < EPSILON);
+        assert!(b.iter().all(|&b| b >= 0.0));
+    }
+}
@@ -0,0 +1,101 @@
+use core::interaction::{Interaction, SurfaceInteraction};
+use core::ray::Ray;
+
+use core::Bounds3f;
+use core::Point2f;
+use core::Vector3f;
+
+pub trait Shape: Send + Sync {
+    fn object_bound(&self) -> Bounds3f;
+
+    fn world_bound(&self) -> Bounds3f;
+
+    /// Bounds the part of the shape inside `clip`, for builders that split
+    /// shapes between nodes. Shapes that can't do better than clipping their
+    /// bounds keep this default.
+    fn clipped_world_bound(&self, clip: Bounds3f) -> Bounds3f {
+        self.world_bound().intersection(clip)
+    }
+
+    /// Returns the parametric distance along `ray` and the local geometry of
+    /// the closest intersection, if any.
+    fn intersect(&self, ray: &Ray) -> Option<(f64, SurfaceInteraction)>;
+
+    fn intersect_p(&self, ray: &Ray) -> bool {
+        self.intersect(ray).is_some()
+    }
+
+    fn area(&self) -> f64;
+
+    /// Samples a point uniformly by area, returning it with its pdf with
+    /// respect to surface area.
+    fn sample_area(&self, u: Point2f) -> (Interaction, f64);
+
+    /// Samples a point as seen from `reference`, returning it with its pdf
+    /// with respect to solid angle at `reference`.
+    fn sample_from_ref(&self, reference: &Interaction, u: Point2f) -> (Interaction, f64) {
+        sample_from_ref_by_area(self, reference, u)
+    }
+
+    /// Solid angle pdf of sampling direction `wi` from `reference` with
+    /// `sample_from_ref`.
+    fn pdf_from_ref(&self, reference: &Interaction, wi: Vector3f) -> f64 {
+        pdf_from_ref_by_area(self, reference, wi)
+    }
+}
+
+/// Samples `shape` by area and converts the area pdf to solid angle at
+/// `reference`. Shapes with a better strategy fall back to this when it
+/// does not apply.
+pub fn sample_from_ref_by_area<S: Shape + ?Sized>(
+    shape: &S,
+    reference: &Interaction,
+    u: Point2f,
+) -> (Interaction, f64) {
+    let (intr, pdf) = shape.sample_area(u);
+    let pdf = area_to_solid_angle_pdf(reference, &intr, pdf);
+
+    (intr, pdf)
+}
+
+/// Converts the area pdf of sampling `intr` to solid angle at `reference`,
+/// or zero where the conversion is degenerate.
+pub fn area_to_solid_angle_pdf(reference: &Interaction, intr: &Interaction, pdf: f64) -> f64 {
+    let wi = intr.p - reference.p;
+
+    if pdf == 0.0 || wi.length_squared() == 0.0 {
+        return 0.0;
+    }
+
+    let wi = wi.normalize();
+    let cos_theta = Vector3f::from(intr.n).abs_dot(-wi);
+    let pdf = pdf * reference.p.distance_squared(intr.p) / cos_theta;
+
+    if pdf.is_infinite() {
+        0.0
+    } else {
+        pdf
+    }
+}
+
+pub fn pdf_from_ref_by_area<S: Shape + ?Sized>(
+    shape: &S,
+    reference: &Interaction,
+    wi: Vector3f,
+) -> f64 {
+    let ray = reference.spawn_ray(wi);
+
+    let isect = match shape.intersect(&ray) {
+        Some((_, isect)) => isect,
+        None => return 0.0,
+    };
+
+    let cos_theta = Vector3f::from(isect.n).abs_dot(-wi);
+    let pdf = reference.p.distance_squared(isect.p) / (cos_theta * shape.area());
+
+    if pdf.is_infinite() {
+        0.0
+    } else {
+        pdf
+    }
+}
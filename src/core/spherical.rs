@@ -0,0 +1,219 @@
+use std::f64::consts::PI;
+
+use core::utils::{clamp, safe_sqrt};
+
+use core::Point2f;
+use core::Vector3f;
+
+pub fn spherical_direction(sin_theta: f64, cos_theta: f64, phi: f64) -> Vector3f {
+    Vector3f::new(
+        clamp(sin_theta, -1.0, 1.0) * phi.cos(),
+        clamp(sin_theta, -1.0, 1.0) * phi.sin(),
+        clamp(cos_theta, -1.0, 1.0),
+    )
+}
+
+pub fn spherical_direction_in(
+    sin_theta: f64,
+    cos_theta: f64,
+    phi: f64,
+    x: Vector3f,
+    y: Vector3f,
+    z: Vector3f,
+) -> Vector3f {
+    x * (sin_theta * phi.cos()) + y * (sin_theta * phi.sin()) + z * cos_theta
+}
+
+pub fn spherical_theta(v: Vector3f) -> f64 {
+    clamp(v.z, -1.0, 1.0).acos()
+}
+
+pub fn spherical_phi(v: Vector3f) -> f64 {
+    let p = v.y.atan2(v.x);
+
+    if p < 0.0 {
+        p + 2.0 * PI
+    } else {
+        p
+    }
+}
+
+/// The direction for a point of a latitude-longitude map, with `u` going
+/// once around +z and `v` running from +z down to -z. This is how pbrt's
+/// infinite area light looks up its environment map in light space.
+pub fn equirect_to_direction(p: Point2f) -> Vector3f {
+    let theta = p.y * PI;
+    let phi = p.x * 2.0 * PI;
+
+    spherical_direction(theta.sin(), theta.cos(), phi)
+}
+
+pub fn direction_to_equirect(v: Vector3f) -> Point2f {
+    Point2f::new(spherical_phi(v) / (2.0 * PI), spherical_theta(v) / PI)
+}
+
+/// Clary's equal-area mapping from the unit square to the sphere through an
+/// octahedron: the square's center is +z, its corners -z, and every region
+/// of the square covers a solid angle proportional to its area.
+pub fn equal_area_square_to_sphere(p: Point2f) -> Vector3f {
+    let u = 2.0 * p.x - 1.0;
+    let v = 2.0 * p.y - 1.0;
+    let up = u.abs();
+    let vp = v.abs();
+
+    let signed_distance = 1.0 - (up + vp);
+    let r = 1.0 - signed_distance.abs();
+
+    let phi = if r == 0.0 { 1.0 } else { (vp - up) / r + 1.0 } * PI / 4.0;
+    let z = (1.0 - r * r).copysign(signed_distance);
+
+    let cos_phi = phi.cos().copysign(u);
+    let sin_phi = phi.sin().copysign(v);
+
+    Vector3f::new(
+        cos_phi * r * safe_sqrt(2.0 - r * r),
+        sin_phi * r * safe_sqrt(2.0 - r * r),
+        z,
+    )
+}
+
+pub fn equal_area_sphere_to_square(d: Vector3f) -> Point2f {
+    let x = d.x.abs();
+    let y = d.y.abs();
+    let z = d.z.abs();
+
+    let r = safe_sqrt(1.0 - z);
+    let a = x.max(y);
+    let b = if a == 0.0 { 0.0 } else { x.min(y) / a };
+
+    let mut phi = b.atan() * 2.0 / PI;
+    if x < y {
+        phi = 1.0 - phi;
+    }
+
+    let mut v = phi * r;
+    let mut u = r - v;
+
+    if d.z < 0.0 {
+        let t = u;
+        u = 1.0 - v;
+        v = 1.0 - t;
+    }
+
+    Point2f::new((u.copysign(d.x) + 1.0) * 0.5, (v.copysign(d.y) + 1.0) * 0.5)
+}
+
+/// Folds a point just outside the unit square back in, the way the
+/// octahedral mapping continues across the square's edges.
+pub fn wrap_equal_area_square(mut p: Point2f) -> Point2f {
+    if p.x < 0.0 {
+        p.x = -p.x;
+        p.y = 1.0 - p.y;
+    } else if p.x > 1.0 {
+        p.x = 2.0 - p.x;
+        p.y = 1.0 - p.y;
+    }
+
+    if p.y < 0.0 {
+        p.x = 1.0 - p.x;
+        p.y = -p.y;
+    } else if p.y > 1.0 {
+        p.x = 1.0 - p.x;
+        p.y = 2.0 - p.y;
+    }
+
+    p
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use core::utils::hash_float;
+
+    const EPSILON: f64 = 0.00001;
+
+    #[test]
+    fn spherical_round_trip() {
+        let v = Vector3f::new(1.0, -2.0, 0.5).normalize();
+
+        let theta = spherical_theta(v);
+        let phi = spherical_phi(v);
+        let result = spherical_direction(theta.sin(), theta.cos(), phi);
+
+        assert!((result.x - v.x).abs() < EPSILON);
+        assert!((result.y - v.y).abs() < EPSILON);
+        assert!((result.z - v.z).abs() < EPSILON);
+    }
+
+    #[test]
+    fn spherical_phi_positive() {
+        let phi = spherical_phi(Vector3f::new(0.0, -1.0, 0.0));
+
+        assert!((phi - 1.5 * PI).abs() < EPSILON);
+    }
+
+    #[test]
+    fn equirect_round_trip() {
+        let v = Vector3f::new(-0.3, -0.8, 0.4).normalize();
+        let p = direction_to_equirect(v);
+
+        assert!((equirect_to_direction(p) - v).length() < EPSILON);
+        assert!((equirect_to_direction(Point2f::new(0.25, 0.0)).z - 1.0).abs() < EPSILON);
+        assert!(
+            (equirect_to_direction(Point2f::new(0.25, 0.5)) - Vector3f::new(0.0, 1.0, 0.0))
+                .length()
+                < EPSILON
+        );
+    }
+
+    #[test]
+    fn equal_area_round_trip() {
+        for i in 0..200 {
+            let p = Point2f::new(hash_float(&[i as f64, 0.0]), hash_float(&[i as f64, 1.0]));
+            let d = equal_area_square_to_sphere(p);
+            let q = equal_area_sphere_to_square(d);
+
+            assert!((d.length() - 1.0).abs() < EPSILON);
+            assert!((q.x - p.x).abs() < EPSILON);
+            assert!((q.y - p.y).abs() < EPSILON);
+        }
+
+        let center = equal_area_square_to_sphere(Point2f::new(0.5, 0.5));
+        let corner = equal_area_square_to_sphere(Point2f::new(0.0, 1.0));
+        let edge = equal_area_square_to_sphere(Point2f::new(1.0, 0.5));
+
+        assert!((center.z - 1.0).abs() < EPSILON);
+        assert!((corner.z + 1.0).abs() < EPSILON);
+        assert!((edge - Vector3f::new(1.0, 0.0, 0.0)).length() < EPSILON);
+    }
+
+    #[test]
+    fn equal_area_preserves_solid_angle() {
+        // The cap above z = 0.5 is a quarter of the sphere's solid angle.
+        let n = 64;
+        let mut in_cap = 0;
+
+        for y in 0..n {
+            for x in 0..n {
+                let p = Point2f::new((x as f64 + 0.5) / n as f64, (y as f64 + 0.5) / n as f64);
+
+                if equal_area_square_to_sphere(p).z > 0.5 {
+                    in_cap += 1;
+                }
+            }
+        }
+
+        assert!((in_cap as f64 / (n * n) as f64 - 0.25).abs() < 0.01);
+    }
+
+    #[test]
+    fn wrapped_square_is_continuous() {
+        // Stepping over the edge moves about as far as stepping along inside.
+        let p = equal_area_square_to_sphere(Point2f::new(0.99, 0.3));
+        let inside = equal_area_square_to_sphere(Point2f::new(0.97, 0.3));
+        let outside = equal_area_square_to_sphere(wrap_equal_area_square(Point2f::new(1.01, 0.3)));
+
+        assert!((outside - p).length() < 1.5 * (inside - p).length());
+    }
+}
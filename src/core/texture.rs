@@ -0,0 +1,6 @@
+use core::interaction::SurfaceInteraction;
+
+/// A quantity that varies over surfaces, looked up at a hit point.
+pub trait Texture<T>: Send + Sync {
+    fn evaluate(&self, si: &SurfaceInteraction) -> T;
+}
@@ -3,9 +3,12 @@ use std::ops::Mul;
 use core::matrix44::Matrix44;
 use core::transformable::Transformable;
 
+use core::utils::gamma;
+
+use core::Point3f;
 use core::Vector3f;
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Transform {
     pub m: Matrix44,
     pub m_inv: Matrix44,
@@ -55,6 +58,61 @@ impl Transform {
         }
     }
 
+    #[cfg_attr(rustfmt, rustfmt_skip)]
+    pub fn rotate(theta: f64, axis: Vector3f) -> Self {
+        let a = axis.normalize();
+        let sin_theta = theta.to_radians().sin();
+        let cos_theta = theta.to_radians().cos();
+
+        let m = Matrix44::new(
+            a.x * a.x + (1.0 - a.x * a.x) * cos_theta,
+            a.x * a.y * (1.0 - cos_theta) - a.z * sin_theta,
+            a.x * a.z * (1.0 - cos_theta) + a.y * sin_theta,
+            0.0,
+            a.x * a.y * (1.0 - cos_theta) + a.z * sin_theta,
+            a.y * a.y + (1.0 - a.y * a.y) * cos_theta,
+            a.y * a.z * (1.0 - cos_theta) - a.x * sin_theta,
+            0.0,
+            a.x * a.z * (1.0 - cos_theta) - a.y * sin_theta,
+            a.y * a.z * (1.0 - cos_theta) + a.x * sin_theta,
+            a.z * a.z + (1.0 - a.z * a.z) * cos_theta,
+            0.0,
+            0.0, 0.0, 0.0, 1.0,
+        );
+
+        Self {
+            m,
+            m_inv: m.transpose(),
+        }
+    }
+
+    /// Maps camera space z in `[z_near, z_far]` to [0, 1], leaving x and y.
+    pub fn orthographic(z_near: f64, z_far: f64) -> Self {
+        Self::scale(1.0, 1.0, 1.0 / (z_far - z_near))
+            * Self::translate(Vector3f::new(0.0, 0.0, -z_near))
+    }
+
+    /// Projects camera space onto the screen, dividing x and y by z so that
+    /// the field of view `fov`, in degrees, spans [-1, 1] and mapping z in
+    /// `[n, f]` to [0, 1].
+    #[cfg_attr(rustfmt, rustfmt_skip)]
+    pub fn perspective(fov: f64, n: f64, f: f64) -> Self {
+        let persp = Matrix44::new(
+            1.0, 0.0, 0.0, 0.0,
+            0.0, 1.0, 0.0, 0.0,
+            0.0, 0.0, f / (f - n), -f * n / (f - n),
+            0.0, 0.0, 1.0, 0.0,
+        );
+
+        let inv_tan_ang = 1.0 / (fov.to_radians() / 2.0).tan();
+
+        Self::scale(inv_tan_ang, inv_tan_ang, 1.0) * Self::from(persp)
+    }
+
+    pub fn is_identity(self) -> bool {
+        self.m == Matrix44::identity()
+    }
+
     pub fn inverse(self) -> Self {
         Self {
             m: self.m_inv,
@@ -72,6 +130,37 @@ impl Transform {
     pub fn transform<T: Transformable>(self, transformable: T) -> T {
         transformable.transform(self)
     }
+
+    #[cfg_attr(rustfmt, rustfmt_skip)]
+    pub fn swaps_handedness(self) -> bool {
+        let m = self.m;
+
+        let det = m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1]) -
+                  m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0]) +
+                  m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0]);
+
+        det < 0.0
+    }
+
+    /// Transforms `p` and grows its absolute error bounds `p_error` to
+    /// account for the rounding error the transformation introduces.
+    #[cfg_attr(rustfmt, rustfmt_skip)]
+    pub fn transform_point_with_error(self, p: Point3f, p_error: Vector3f) -> (Point3f, Vector3f) {
+        let m = self.m;
+        let mut abs_error = Vector3f::zero();
+
+        for i in 0..3 {
+            abs_error[i] = (gamma(3) + 1.0) * (m[i][0].abs() * p_error.x +
+                                               m[i][1].abs() * p_error.y +
+                                               m[i][2].abs() * p_error.z) +
+                           gamma(3) * ((m[i][0] * p.x).abs() +
+                                       (m[i][1] * p.y).abs() +
+                                       (m[i][2] * p.z).abs() +
+                                       m[i][3].abs());
+        }
+
+        (self.transform(p), abs_error)
+    }
 }
 
 impl From<[[f64; 4]; 4]> for Transform {
@@ -103,7 +192,7 @@ impl Mul for Transform {
     fn mul(self, other: Transform) -> Self {
         Self {
             m: self.m.mul(other.m),
-            m_inv: other.m.mul(self.m_inv),
+            m_inv: other.m_inv.mul(self.m_inv),
         }
     }
 }
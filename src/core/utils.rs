@@ -1,5 +1,9 @@
+use std::f64;
+
 use core::value::Value;
 
+pub const MACHINE_EPSILON: f64 = f64::EPSILON * 0.5;
+
 pub fn has_nans_3<Value: PartialEq>(x: Value, y: Value, z: Value) -> bool {
     x != x || y != y || z != z
 }
@@ -9,9 +13,129 @@ pub fn has_nans_2<Value: PartialEq>(x: Value, y: Value) -> bool {
 }
 
 pub fn lerp<T: Value>(t: T, v1: T, v2: T) -> T {
-    let negative_one = T::zero() - T::one();
+    (T::one() - t) * v1 + t * v2
+}
+
+pub fn gamma(n: i32) -> f64 {
+    let n = n as f64;
+
+    (n * MACHINE_EPSILON) / (1.0 - n * MACHINE_EPSILON)
+}
+
+pub fn next_float_up(v: f64) -> f64 {
+    if v.is_infinite() && v > 0.0 {
+        return v;
+    }
+
+    let v = if v == -0.0 { 0.0 } else { v };
+    let bits = v.to_bits();
+
+    if v >= 0.0 {
+        f64::from_bits(bits + 1)
+    } else {
+        f64::from_bits(bits - 1)
+    }
+}
+
+pub fn next_float_down(v: f64) -> f64 {
+    if v.is_infinite() && v < 0.0 {
+        return v;
+    }
+
+    let v = if v == 0.0 { -0.0 } else { v };
+    let bits = v.to_bits();
+
+    if v > 0.0 {
+        f64::from_bits(bits - 1)
+    } else {
+        f64::from_bits(bits + 1)
+    }
+}
+
+pub fn clamp<T: Value>(val: T, low: T, high: T) -> T {
+    if val < low {
+        low
+    } else if val > high {
+        high
+    } else {
+        val
+    }
+}
 
-    (negative_one - t) * v1 + t * v2
+pub fn safe_sqrt(x: f64) -> f64 {
+    x.max(0.0).sqrt()
+}
+
+pub fn safe_asin(x: f64) -> f64 {
+    clamp(x, -1.0, 1.0).asin()
+}
+
+pub fn safe_acos(x: f64) -> f64 {
+    clamp(x, -1.0, 1.0).acos()
+}
+
+/// Solves the 2x2 system `a x = b`, returning `None` when `a` is close to
+/// singular.
+pub fn solve_linear_system_2x2(a: [[f64; 2]; 2], b: [f64; 2]) -> Option<(f64, f64)> {
+    let det = a[0][0] * a[1][1] - a[0][1] * a[1][0];
+
+    if det.abs() < 1e-10 {
+        return None;
+    }
+
+    let x0 = (a[1][1] * b[0] - a[0][1] * b[1]) / det;
+    let x1 = (a[0][0] * b[1] - a[1][0] * b[0]) / det;
+
+    if x0.is_nan() || x1.is_nan() {
+        return None;
+    }
+
+    Some((x0, x1))
+}
+
+/// Solves `a t^2 + b t + c = 0`, returning the roots in ascending order.
+pub fn solve_quadratic(a: f64, b: f64, c: f64) -> Option<(f64, f64)> {
+    let discrim = b * b - 4.0 * a * c;
+
+    if discrim < 0.0 {
+        return None;
+    }
+
+    let root_discrim = discrim.sqrt();
+    let q = if b < 0.0 {
+        -0.5 * (b - root_discrim)
+    } else {
+        -0.5 * (b + root_discrim)
+    };
+
+    let t0 = q / a;
+    let t1 = c / q;
+
+    if t0 > t1 {
+        Some((t1, t0))
+    } else {
+        Some((t0, t1))
+    }
+}
+
+/// Scrambles the bits of `v` so that nearby inputs give unrelated outputs.
+pub fn mix_bits(mut v: u64) -> u64 {
+    v ^= v >> 31;
+    v = v.wrapping_mul(0x7fb5_d329_728e_a185);
+    v ^= v >> 27;
+    v = v.wrapping_mul(0x81da_def4_bc2d_d44d);
+    v ^= v >> 33;
+
+    v
+}
+
+/// Hashes `values` to a number in `[0, 1)`.
+pub fn hash_float(values: &[f64]) -> f64 {
+    let hash = values
+        .iter()
+        .fold(0, |hash: u64, v| mix_bits(hash ^ v.to_bits()));
+
+    (hash >> 11) as f64 / (1u64 << 53) as f64
 }
 
 #[cfg(test)]
@@ -52,4 +176,73 @@ mod tests {
 
         assert_eq!(false, has_nans_2(x, y))
     }
+
+    #[test]
+    fn next_float_up_down() {
+        assert!(next_float_up(1.0) > 1.0);
+        assert!(next_float_down(1.0) < 1.0);
+        assert!(next_float_up(0.0) > 0.0);
+        assert!(next_float_down(0.0) < 0.0);
+    }
+
+    #[test]
+    fn lerp_endpoints() {
+        assert_eq!(2.0, lerp(0.0, 2.0, 4.0));
+        assert_eq!(3.0, lerp(0.5, 2.0, 4.0));
+        assert_eq!(4.0, lerp(1.0, 2.0, 4.0));
+    }
+
+    #[test]
+    fn clamp_inside() {
+        assert_eq!(0.5, clamp(0.5, 0.0, 1.0));
+    }
+
+    #[test]
+    fn clamp_outside() {
+        assert_eq!(0.0, clamp(-0.5, 0.0, 1.0));
+        assert_eq!(1.0, clamp(1.5, 0.0, 1.0));
+    }
+
+    #[test]
+    fn solve_quadratic_roots() {
+        let (t0, t1) = solve_quadratic(1.0, -3.0, 2.0).unwrap();
+
+        assert_eq!(1.0, t0);
+        assert_eq!(2.0, t1);
+    }
+
+    #[test]
+    fn solve_quadratic_no_roots() {
+        assert!(solve_quadratic(1.0, 0.0, 1.0).is_none());
+    }
+
+    #[test]
+    fn solve_linear_system_2x2_solves() {
+        let (x0, x1) = solve_linear_system_2x2([[2.0, 1.0], [1.0, 3.0]], [3.0, 5.0]).unwrap();
+
+        assert!((x0 - 0.8).abs() < 1e-10);
+        assert!((x1 - 1.4).abs() < 1e-10);
+    }
+
+    #[test]
+    fn solve_linear_system_2x2_singular() {
+        assert!(solve_linear_system_2x2([[1.0, 2.0], [2.0, 4.0]], [1.0, 1.0]).is_none());
+    }
+
+    #[test]
+    fn safe_sqrt_negative() {
+        assert_eq!(0.0, safe_sqrt(-1e-12));
+    }
+
+    #[test]
+    fn hash_float_in_unit_interval() {
+        for i in 0..100 {
+            let h = hash_float(&[i as f64, 0.5, -2.0]);
+
+            assert!(h >= 0.0 && h < 1.0);
+        }
+
+        assert_eq!(hash_float(&[1.0, 2.0]), hash_float(&[1.0, 2.0]));
+        assert_ne!(hash_float(&[1.0, 2.0]), hash_float(&[2.0, 1.0]));
+    }
 }
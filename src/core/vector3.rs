@@ -4,6 +4,7 @@ use std::ops::{Add, AddAssign, Div, DivAssign, Index, IndexMut, Mul, MulAssign,
 use num::NumCast;
 
 use core::normal3::Normal3;
+use core::point3::Point3;
 use core::utils::has_nans_3;
 use core::value::Value;
 use core::transform::Transform;
@@ -168,6 +169,16 @@ impl<T: Value> From<Normal3<T>> for Vector3<T> {
     }
 }
 
+impl<T: Value> From<Point3<T>> for Vector3<T> {
+    fn from(p: Point3<T>) -> Self {
+        Self {
+            x: p.x,
+            y: p.y,
+            z: p.z,
+        }
+    }
+}
+
 impl<T: Value> Index<usize> for Vector3<T> {
     type Output = T;
 
@@ -0,0 +1,8 @@
+extern crate memmap2;
+extern crate num;
+
+pub mod accelerators;
+pub mod cameras;
+pub mod core;
+pub mod shapes;
+pub mod textures;
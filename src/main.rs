@@ -1,9 +1,7 @@
-extern crate num;
+extern crate luminary;
 
-mod core;
-
-use core::Vector2i;
-use core::Vector3f;
+use luminary::core::Vector2i;
+use luminary::core::Vector3f;
 
 fn main() {
     let v3 = Vector3f::new(1.0, 2.0, 3.0);
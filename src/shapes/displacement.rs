@@ -0,0 +1,541 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use core::interaction::SurfaceInteraction;
+use core::texture::Texture;
+use core::transform::Transform;
+
+use core::Normal3f;
+use core::Point2f;
+use core::Point3f;
+use core::Vector3f;
+use shapes::triangle::TriangleMesh;
+
+/// How far to move each point of a displaced surface. Scalar textures move
+/// along the shading normal; vector textures give an offset in the
+/// `(tangent, bitangent, normal)` frame.
+#[derive(Clone)]
+pub enum Displacement {
+    Scalar(Arc<dyn Texture<f64>>),
+    Vector(Arc<dyn Texture<Vector3f>>),
+}
+
+/// Controls how finely a mesh is diced before displacing it. Edges are
+/// split until they project to at most `max_edge_pixels` as seen from
+/// `camera_position`, where `pixels_per_unit` is the on-screen length in
+/// pixels of a unit segment at unit distance.
+#[derive(Clone, Copy, Debug)]
+pub struct TessellationSettings {
+    pub camera_position: Point3f,
+    pub pixels_per_unit: f64,
+    pub max_edge_pixels: f64,
+    /// Caps how many times an original edge may be halved.
+    pub max_level: u32,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Vertex {
+    p: Point3f,
+    n: Vector3f,
+    s: Vector3f,
+    /// Gives the bitangent as `sign * n.cross(s)`.
+    sign: f64,
+    uv: Point2f,
+}
+
+impl Vertex {
+    fn midpoint(a: &Vertex, b: &Vertex) -> Vertex {
+        Vertex {
+            p: a.p.lerp(b.p, 0.5),
+            n: normalize_or(a.n + b.n, a.n),
+            s: normalize_or(a.s + b.s, a.s),
+            sign: a.sign,
+            uv: (a.uv + b.uv) * 0.5,
+        }
+    }
+}
+
+struct Tessellator<'a> {
+    settings: &'a TessellationSettings,
+    vertices: Vec<Vertex>,
+    indices: Vec<usize>,
+    midpoints: HashMap<(usize, usize), usize>,
+    levels: HashMap<(usize, usize), u32>,
+}
+
+impl<'a> Tessellator<'a> {
+    fn edge_key(a: usize, b: usize) -> (usize, usize) {
+        if a < b {
+            (a, b)
+        } else {
+            (b, a)
+        }
+    }
+
+    fn level(&self, a: usize, b: usize) -> u32 {
+        *self.levels.get(&Self::edge_key(a, b)).unwrap_or(&0)
+    }
+
+    /// Depends only on the edge itself, so both triangles sharing an edge
+    /// agree on whether to split it and the result has no cracks.
+    fn should_split(&self, a: usize, b: usize) -> bool {
+        if self.level(a, b) >= self.settings.max_level {
+            return false;
+        }
+
+        let pa = self.vertices[a].p;
+        let pb = self.vertices[b].p;
+        let distance = self
+            .settings
+            .camera_position
+            .distance(pa.lerp(pb, 0.5))
+            .max(1e-6);
+
+        pa.distance(pb) * self.settings.pixels_per_unit / distance > self.settings.max_edge_pixels
+    }
+
+    fn midpoint(&mut self, a: usize, b: usize) -> usize {
+        let key = Self::edge_key(a, b);
+
+        if let Some(&m) = self.midpoints.get(&key) {
+            return m;
+        }
+
+        let level = self.level(a, b) + 1;
+        let m = self.vertices.len();
+        let vertex = Vertex::midpoint(&self.vertices[a], &self.vertices[b]);
+
+        self.vertices.push(vertex);
+        self.midpoints.insert(key, m);
+        self.levels.insert(Self::edge_key(a, m), level);
+        self.levels.insert(Self::edge_key(m, b), level);
+
+        m
+    }
+
+    /// Records the level of an edge created inside a split triangle.
+    fn interior_edge(&mut self, a: usize, b: usize, level: u32) {
+        self.levels.entry(Self::edge_key(a, b)).or_insert(level);
+    }
+
+    fn tessellate(&mut self, v: [usize; 3]) {
+        let split = [
+            self.should_split(v[0], v[1]),
+            self.should_split(v[1], v[2]),
+            self.should_split(v[2], v[0]),
+        ];
+
+        let level = (0..3)
+            .map(|i| self.level(v[i], v[(i + 1) % 3]))
+            .max()
+            .unwrap()
+            + 1;
+
+        match split.iter().filter(|&&s| s).count() {
+            0 => self.indices.extend_from_slice(&v),
+            1 => {
+                // Rotate so the split edge is v0-v1.
+                let r = split.iter().position(|&s| s).unwrap();
+                let (a, b, c) = (v[r], v[(r + 1) % 3], v[(r + 2) % 3]);
+                let m = self.midpoint(a, b);
+
+                self.interior_edge(m, c, level);
+                self.tessellate([a, m, c]);
+                self.tessellate([m, b, c]);
+            }
+            2 => {
+                // Rotate so the unsplit edge is v2-v0.
+                let r = (split.iter().position(|&s| !s).unwrap() + 1) % 3;
+                let (a, b, c) = (v[r], v[(r + 1) % 3], v[(r + 2) % 3]);
+                let mab = self.midpoint(a, b);
+                let mbc = self.midpoint(b, c);
+
+                self.interior_edge(mab, mbc, level);
+                self.interior_edge(mab, c, level);
+                self.tessellate([mab, b, mbc]);
+                self.tessellate([a, mab, c]);
+                self.tessellate([mab, mbc, c]);
+            }
+            _ => {
+                let m01 = self.midpoint(v[0], v[1]);
+                let m12 = self.midpoint(v[1], v[2]);
+                let m20 = self.midpoint(v[2], v[0]);
+
+                self.interior_edge(m01, m12, level);
+                self.interior_edge(m12, m20, level);
+                self.interior_edge(m20, m01, level);
+                self.tessellate([v[0], m01, m20]);
+                self.tessellate([m01, v[1], m12]);
+                self.tessellate([m20, m12, v[2]]);
+                self.tessellate([m01, m12, m20]);
+            }
+        }
+    }
+}
+
+impl TriangleMesh {
+    /// Dices the mesh adaptively for the given view and moves every vertex
+    /// by `displacement`, returning a new mesh with normals recomputed from
+    /// the displaced surface. Tangents and bitangent signs are kept when the
+    /// mesh has them, with tangents made perpendicular to the new normals.
+    /// Triangle bounds come from the displaced vertices, so the accelerator
+    /// sees the true extent of the geometry.
+    pub fn displace(
+        &self,
+        displacement: &Displacement,
+        settings: &TessellationSettings,
+    ) -> TriangleMesh {
+        let mut tessellator = Tessellator {
+            settings,
+            vertices: self.base_vertices(),
+            indices: Vec::with_capacity(self.vertex_indices.len()),
+            midpoints: HashMap::new(),
+            levels: HashMap::new(),
+        };
+
+        for triangle in self.vertex_indices.chunks(3) {
+            tessellator.tessellate([triangle[0], triangle[1], triangle[2]]);
+        }
+
+        let vertices = tessellator.vertices;
+        let indices = tessellator.indices;
+
+        let p: Vec<Point3f> = vertices
+            .iter()
+            .map(|vertex| vertex.p + displacement_offset(displacement, vertex))
+            .collect();
+
+        // Area weighted normals of the displaced surface, kept on the same
+        // side as the original shading normals.
+        let mut n = vec![Vector3f::zero(); p.len()];
+
+        for triangle in indices.chunks(3) {
+            let (i0, i1, i2) = (triangle[0], triangle[1], triangle[2]);
+            let mut face = (p[i1] - p[i0]).cross(p[i2] - p[i0]);
+            let reference = vertices[i0].n + vertices[i1].n + vertices[i2].n;
+
+            if face.dot(reference) < 0.0 {
+                face = -face;
+            }
+
+            n[i0] += face;
+            n[i1] += face;
+            n[i2] += face;
+        }
+
+        let n: Vec<Vector3f> = n
+            .iter()
+            .zip(vertices.iter())
+            .map(|(&n, vertex)| normalize_or(n, vertex.n))
+            .collect();
+
+        let s = self.s.as_ref().map(|_| {
+            vertices
+                .iter()
+                .zip(n.iter())
+                .map(|(vertex, &n)| {
+                    normalize_or(vertex.s - n * n.dot(vertex.s), n.coordinate_system().0)
+                })
+                .collect()
+        });
+        let bitangent_signs = self
+            .bitangent_signs
+            .as_ref()
+            .map(|_| vertices.iter().map(|vertex| vertex.sign).collect());
+
+        let uv = self
+            .uv
+            .as_ref()
+            .map(|_| vertices.iter().map(|vertex| vertex.uv).collect());
+
+        let mut mesh = TriangleMesh::new(
+            Transform::new(),
+            self.reverse_orientation,
+            indices,
+            p,
+            Some(n.into_iter().map(Normal3f::from).collect()),
+            s,
+            uv,
+        );
+
+        mesh.bitangent_signs = bitangent_signs;
+        mesh.transform_swaps_handedness = self.transform_swaps_handedness;
+
+        mesh
+    }
+
+    /// Per-vertex frames for displacement. Meshes without normals or
+    /// tangents get area weighted averages of their faces'.
+    fn base_vertices(&self) -> Vec<Vertex> {
+        let mut face_n = vec![Vector3f::zero(); self.p.len()];
+        let mut face_s = vec![Vector3f::zero(); self.p.len()];
+
+        let flip = self.reverse_orientation ^ self.transform_swaps_handedness;
+
+        for triangle in self.vertex_indices.chunks(3) {
+            let (i0, i1, i2) = (triangle[0], triangle[1], triangle[2]);
+            let dp01 = self.p[i1] - self.p[i0];
+            let dp02 = self.p[i2] - self.p[i0];
+            let mut n = dp01.cross(dp02);
+
+            if flip {
+                n = -n;
+            }
+
+            let s = match self.uv {
+                Some(ref uv) => {
+                    let duv01 = uv[i1] - uv[i0];
+                    let duv02 = uv[i2] - uv[i0];
+                    let determinant = duv01.x * duv02.y - duv01.y * duv02.x;
+
+                    if determinant.abs() < 1e-8 {
+                        dp01
+                    } else {
+                        (dp01 * duv02.y - dp02 * duv01.y) / determinant
+                    }
+                }
+                None => dp01,
+            };
+
+            for &i in triangle {
+                face_n[i] += n;
+                face_s[i] += s;
+            }
+        }
+
+        (0..self.p.len())
+            .map(|i| {
+                let n = match self.n {
+                    Some(ref normals) => Vector3f::from(normals[i]),
+                    None => face_n[i],
+                };
+                let n = normalize_or(n, Vector3f::new(0.0, 0.0, 1.0));
+
+                let s = match self.s {
+                    Some(ref tangents) => tangents[i],
+                    None => face_s[i],
+                };
+                let s = s - n * n.dot(s);
+                let s = normalize_or(s, n.coordinate_system().0);
+
+                Vertex {
+                    p: self.p[i],
+                    n,
+                    s,
+                    sign: self
+                        .bitangent_signs
+                        .as_ref()
+                        .map_or(1.0, |signs| signs[i]),
+                    uv: self
+                        .uv
+                        .as_ref()
+                        .map_or(Point2f::new(0.0, 0.0), |uv| uv[i]),
+                }
+            })
+            .collect()
+    }
+}
+
+fn displacement_offset(displacement: &Displacement, vertex: &Vertex) -> Vector3f {
+    let t = vertex.n.cross(vertex.s) * vertex.sign;
+
+    let si = SurfaceInteraction::new(
+        vertex.p,
+        Vector3f::zero(),
+        vertex.uv,
+        Vector3f::zero(),
+        vertex.s,
+        t,
+        0.0,
+    );
+
+    match *displacement {
+        Displacement::Scalar(ref texture) => vertex.n * texture.evaluate(&si),
+        Displacement::Vector(ref texture) => {
+            let d = texture.evaluate(&si);
+
+            vertex.s * d.x + t * d.y + vertex.n * d.z
+        }
+    }
+}
+
+fn normalize_or(v: Vector3f, fallback: Vector3f) -> Vector3f {
+    if v.length_squared() > 0.0 {
+        v.normalize()
+    } else {
+        fallback
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use core::shape::Shape;
+    use core::utils::hash_float;
+    use shapes::triangle::Triangle;
+    use textures::constant::ConstantTexture;
+
+    use core::Bounds3f;
+
+    const EPSILON: f64 = 0.00001;
+
+    struct Ripple;
+
+    impl Texture<f64> for Ripple {
+        fn evaluate(&self, si: &SurfaceInteraction) -> f64 {
+            0.1 * (si.p.x * 10.0).sin()
+        }
+    }
+
+    fn quad() -> TriangleMesh {
+        TriangleMesh::new(
+            Transform::new(),
+            false,
+            vec![0, 1, 2, 0, 2, 3],
+            vec![
+                Point3f::new(0.0, 0.0, 0.0),
+                Point3f::new(1.0, 0.0, 0.0),
+                Point3f::new(1.0, 1.0, 0.0),
+                Point3f::new(0.0, 1.0, 0.0),
+            ],
+            None,
+            None,
+            Some(vec![
+                Point2f::new(0.0, 0.0),
+                Point2f::new(1.0, 0.0),
+                Point2f::new(1.0, 1.0),
+                Point2f::new(0.0, 1.0),
+            ]),
+        )
+    }
+
+    fn settings(camera_z: f64) -> TessellationSettings {
+        TessellationSettings {
+            camera_position: Point3f::new(0.5, 0.5, camera_z),
+            pixels_per_unit: 500.0,
+            max_edge_pixels: 20.0,
+            max_level: 8,
+        }
+    }
+
+    #[test]
+    fn closer_camera_dices_finer() {
+        let displacement = Displacement::Scalar(Arc::new(ConstantTexture::new(0.0)));
+
+        let near = quad().displace(&displacement, &settings(2.0));
+        let far = quad().displace(&displacement, &settings(20.0));
+
+        assert!(near.n_triangles > far.n_triangles);
+        assert!(far.n_triangles > 2);
+    }
+
+    #[test]
+    fn scalar_displacement_moves_along_normal() {
+        let displacement = Displacement::Scalar(Arc::new(ConstantTexture::new(0.25)));
+        let mesh = quad().displace(&displacement, &settings(5.0));
+
+        for (p, n) in mesh.p.iter().zip(mesh.n.as_ref().unwrap().iter()) {
+            assert!((p.z - 0.25).abs() < EPSILON);
+            assert!((n.z - 1.0).abs() < EPSILON);
+        }
+    }
+
+    #[test]
+    fn vector_displacement_uses_tangent_frame() {
+        let displacement = Displacement::Vector(Arc::new(ConstantTexture::new(Vector3f::new(
+            0.5, 0.0, 0.0,
+        ))));
+        let mesh = quad().displace(&displacement, &settings(5.0));
+
+        // The tangent follows increasing u, which is +x on this quad.
+        assert!(mesh.p.iter().all(|p| p.x >= 0.5 - EPSILON));
+    }
+
+    #[test]
+    fn tessellation_has_no_cracks() {
+        let displacement = Displacement::Scalar(Arc::new(Ripple));
+        let mesh = quad().displace(&displacement, &settings(1.5));
+
+        // Every edge of a watertight interior is shared by two triangles;
+        // a T-junction would leave edges used only once inside the quad.
+        let mut counts: HashMap<(usize, usize), usize> = HashMap::new();
+
+        for triangle in mesh.vertex_indices.chunks(3) {
+            for i in 0..3 {
+                let (a, b) = (triangle[i], triangle[(i + 1) % 3]);
+                *counts.entry((a.min(b), a.max(b))).or_insert(0) += 1;
+            }
+        }
+
+        let boundary: f64 = counts
+            .iter()
+            .filter(|&(_, &count)| count == 1)
+            .map(|(&(a, b), _)| {
+                let d = mesh.p[b] - mesh.p[a];
+
+                (d.x * d.x + d.y * d.y).sqrt()
+            })
+            .sum();
+
+        assert!((boundary - 4.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn triangle_bounds_contain_displaced_surface() {
+        let displacement = Displacement::Scalar(Arc::new(Ripple));
+        let mesh = Arc::new(quad().displace(&displacement, &settings(3.0)));
+
+        let bound = Triangle::from_mesh(mesh.clone())
+            .iter()
+            .fold(Bounds3f::zero(), |b, triangle| {
+                b.union_bounds(triangle.world_bound())
+            });
+
+        // Between vertices h apart along x, the tessellation can cut below
+        // a peak of the ripple by at most a * k^2 * h^2 / 8.
+        let x = |i: usize| mesh.p[i].x;
+        let h = mesh
+            .vertex_indices
+            .chunks(3)
+            .flat_map(|t| vec![x(t[0]) - x(t[1]), x(t[1]) - x(t[2]), x(t[2]) - x(t[0])])
+            .fold(0.0, |h, d| d.abs().max(h));
+        let bound = bound.expand(0.1 * 10.0 * 10.0 * h * h / 8.0 + EPSILON);
+
+        // Points of the base quad, moved by the displacement itself.
+        for i in 0..500 {
+            let x = hash_float(&[i as f64, 0.0]);
+            let y = hash_float(&[i as f64, 1.0]);
+
+            assert!(bound.inside(Point3f::new(x, y, 0.1 * (x * 10.0).sin())));
+        }
+    }
+
+    #[test]
+    fn tangent_frame_survives_displacement() {
+        let mut base = quad();
+        base.s = Some(vec![Vector3f::new(1.0, 0.0, 0.0); 4]);
+        base.bitangent_signs = Some(vec![-1.0; 4]);
+
+        let rippled = base.displace(&Displacement::Scalar(Arc::new(Ripple)), &settings(3.0));
+        let tangents = rippled.s.as_ref().unwrap();
+        let signs = rippled.bitangent_signs.as_ref().unwrap();
+
+        assert_eq!(tangents.len(), rippled.p.len());
+        assert!(signs.iter().all(|&s| s == -1.0));
+
+        for (s, n) in tangents.iter().zip(rippled.n.as_ref().unwrap()) {
+            assert!((s.length() - 1.0).abs() < EPSILON);
+            assert!(s.dot(Vector3f::from(*n)).abs() < EPSILON);
+            assert!(s.x > 0.5);
+        }
+
+        // The flipped bitangent sends +y offsets towards -y.
+        let displacement =
+            Displacement::Vector(Arc::new(ConstantTexture::new(Vector3f::new(0.0, 0.5, 0.0))));
+        let shifted = base.displace(&displacement, &settings(5.0));
+
+        assert!(shifted.p.iter().all(|p| p.y <= 0.5 + EPSILON));
+        assert!(shifted.p.iter().any(|p| p.y < 0.0));
+    }
+}
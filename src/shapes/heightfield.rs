@@ -0,0 +1,992 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use core::interaction::{Interaction, SurfaceInteraction};
+use core::ray::Ray;
+use core::sampling::uniform_sample_triangle;
+use core::shape::Shape;
+use core::transform::Transform;
+use core::utils::{clamp, gamma};
+
+use core::Bounds3f;
+use core::Normal3f;
+use core::Point2f;
+use core::Point3f;
+use core::Vector3f;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HeightfieldInterpolation {
+    Bilinear,
+    /// Splits each cell into two triangles along its `(0, 0)`-`(1, 1)`
+    /// diagonal.
+    Triangles,
+}
+
+/// Min/max heights over square blocks of `2^level` cells.
+#[derive(Clone, Debug)]
+struct MinMaxLevel {
+    width: usize,
+    ranges: Vec<(f64, f64)>,
+}
+
+/// A grid of `nx * ny` heights spanning `[0, 1]^2` in object space with
+/// heights along z. Rays walk the grid cell by cell, skipping whole blocks
+/// of cells whose height range the ray passes above or below.
+#[derive(Clone, Debug)]
+pub struct Heightfield {
+    object_to_world: Transform,
+    world_to_object: Transform,
+    reverse_orientation: bool,
+    transform_swaps_handedness: bool,
+    nx: usize,
+    ny: usize,
+    z: Vec<f64>,
+    normals: Vec<Normal3f>,
+    interpolation: HeightfieldInterpolation,
+    min_max: Vec<MinMaxLevel>,
+    triangle_cdf: Vec<f64>,
+    area: f64,
+}
+
+struct CellHit {
+    t: f64,
+    u: f64,
+    v: f64,
+    z: f64,
+    dzdu: f64,
+    dzdv: f64,
+}
+
+impl Heightfield {
+    /// `z` holds the heights row by row, so the height at grid point
+    /// `(x, y)` is `z[y * nx + x]`.
+    pub fn new(
+        object_to_world: Transform,
+        reverse_orientation: bool,
+        nx: usize,
+        ny: usize,
+        z: Vec<f64>,
+        interpolation: HeightfieldInterpolation,
+    ) -> Self {
+        assert!(nx >= 2 && ny >= 2);
+        assert_eq!(nx * ny, z.len());
+
+        let mut heightfield = Self {
+            object_to_world,
+            world_to_object: object_to_world.inverse(),
+            reverse_orientation,
+            transform_swaps_handedness: object_to_world.swaps_handedness(),
+            nx,
+            ny,
+            z,
+            normals: Vec::new(),
+            interpolation,
+            min_max: Vec::new(),
+            triangle_cdf: Vec::new(),
+            area: 0.0,
+        };
+
+        heightfield.normals = heightfield.vertex_normals();
+        heightfield.min_max = heightfield.min_max_levels();
+        heightfield.triangle_cdf = heightfield.triangle_areas();
+        heightfield.area = *heightfield.triangle_cdf.last().unwrap();
+
+        heightfield
+    }
+
+    pub fn from_file<P: AsRef<Path>>(
+        object_to_world: Transform,
+        reverse_orientation: bool,
+        path: P,
+        interpolation: HeightfieldInterpolation,
+    ) -> io::Result<Self> {
+        let (nx, ny, z) = read_heights(path)?;
+
+        if nx < 2 || ny < 2 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "heightfield needs at least 2x2 samples",
+            ));
+        }
+
+        Ok(Self::new(
+            object_to_world,
+            reverse_orientation,
+            nx,
+            ny,
+            z,
+            interpolation,
+        ))
+    }
+
+    fn height(&self, x: usize, y: usize) -> f64 {
+        self.z[y * self.nx + x]
+    }
+
+    fn vertex(&self, x: usize, y: usize) -> Point3f {
+        Point3f::new(
+            x as f64 / (self.nx - 1) as f64,
+            y as f64 / (self.ny - 1) as f64,
+            self.height(x, y),
+        )
+    }
+
+    /// Heights at the `(0, 0)`, `(1, 0)`, `(0, 1)` and `(1, 1)` corners of a
+    /// cell.
+    fn cell_heights(&self, ix: usize, iy: usize) -> [f64; 4] {
+        [
+            self.height(ix, iy),
+            self.height(ix + 1, iy),
+            self.height(ix, iy + 1),
+            self.height(ix + 1, iy + 1),
+        ]
+    }
+
+    /// Smooth per-vertex normals from central differences of the heights.
+    fn vertex_normals(&self) -> Vec<Normal3f> {
+        let mut normals = Vec::with_capacity(self.nx * self.ny);
+
+        for y in 0..self.ny {
+            for x in 0..self.nx {
+                let x0 = x.saturating_sub(1);
+                let x1 = (x + 1).min(self.nx - 1);
+                let y0 = y.saturating_sub(1);
+                let y1 = (y + 1).min(self.ny - 1);
+
+                let dx = (x1 - x0) as f64 / (self.nx - 1) as f64;
+                let dy = (y1 - y0) as f64 / (self.ny - 1) as f64;
+
+                let dzdx = (self.height(x1, y) - self.height(x0, y)) / dx;
+                let dzdy = (self.height(x, y1) - self.height(x, y0)) / dy;
+
+                normals.push(Normal3f::new(-dzdx, -dzdy, 1.0).normalize());
+            }
+        }
+
+        normals
+    }
+
+    fn min_max_levels(&self) -> Vec<MinMaxLevel> {
+        let mut width = self.nx - 1;
+        let mut height = self.ny - 1;
+
+        let mut ranges = Vec::with_capacity(width * height);
+
+        for iy in 0..height {
+            for ix in 0..width {
+                let z = self.cell_heights(ix, iy);
+                let min = z[0].min(z[1]).min(z[2]).min(z[3]);
+                let max = z[0].max(z[1]).max(z[2]).max(z[3]);
+
+                ranges.push((min, max));
+            }
+        }
+
+        let mut levels = vec![MinMaxLevel { width, ranges }];
+
+        while width > 1 || height > 1 {
+            let next_width = width.div_ceil(2);
+            let next_height = height.div_ceil(2);
+            let mut next = Vec::with_capacity(next_width * next_height);
+
+            {
+                let previous = &levels.last().unwrap().ranges;
+
+                for by in 0..next_height {
+                    for bx in 0..next_width {
+                        let mut range = (f64::INFINITY, f64::NEG_INFINITY);
+
+                        for y in (2 * by)..(2 * by + 2).min(height) {
+                            for x in (2 * bx)..(2 * bx + 2).min(width) {
+                                let (min, max) = previous[y * width + x];
+
+                                range = (range.0.min(min), range.1.max(max));
+                            }
+                        }
+
+                        next.push(range);
+                    }
+                }
+            }
+
+            width = next_width;
+            height = next_height;
+
+            levels.push(MinMaxLevel {
+                width,
+                ranges: next,
+            });
+        }
+
+        levels
+    }
+
+    /// Cumulative world space areas of the two triangles in each cell,
+    /// used to sample points by area.
+    fn triangle_areas(&self) -> Vec<f64> {
+        let mut cdf = Vec::with_capacity(2 * (self.nx - 1) * (self.ny - 1));
+        let mut total = 0.0;
+
+        for iy in 0..(self.ny - 1) {
+            for ix in 0..(self.nx - 1) {
+                for triangle in self.cell_triangles(ix, iy).iter() {
+                    let p0 = self.object_to_world.transform(triangle[0]);
+                    let p1 = self.object_to_world.transform(triangle[1]);
+                    let p2 = self.object_to_world.transform(triangle[2]);
+
+                    total += 0.5 * (p1 - p0).cross(p2 - p0).length();
+                    cdf.push(total);
+                }
+            }
+        }
+
+        cdf
+    }
+
+    fn cell_triangles(&self, ix: usize, iy: usize) -> [[Point3f; 3]; 2] {
+        let p00 = self.vertex(ix, iy);
+        let p10 = self.vertex(ix + 1, iy);
+        let p01 = self.vertex(ix, iy + 1);
+        let p11 = self.vertex(ix + 1, iy + 1);
+
+        [[p00, p10, p11], [p00, p11, p01]]
+    }
+
+    /// Intersects the surface inside cell `(ix, iy)` for `t` in `[t0, t1]`,
+    /// with the ray given in grid space where cells are unit squares.
+    fn intersect_cell(
+        &self,
+        ix: usize,
+        iy: usize,
+        o: Point3f,
+        d: Vector3f,
+        t0: f64,
+        t1: f64,
+    ) -> Option<CellHit> {
+        let z = self.cell_heights(ix, iy);
+
+        // Ray position in the cell's local [0, 1]^2 coordinates.
+        let au = o.x - ix as f64;
+        let av = o.y - iy as f64;
+
+        let tolerance = 1e-9;
+        let in_range = |t: f64| t >= t0 - tolerance && t <= t1 + tolerance && t > 0.0;
+        let local = |t: f64| (au + d.x * t, av + d.y * t);
+
+        match self.interpolation {
+            HeightfieldInterpolation::Bilinear => {
+                let a = z[0];
+                let b = z[1] - z[0];
+                let c = z[2] - z[0];
+                let e = z[0] - z[1] - z[2] + z[3];
+
+                let k0 = a + b * au + c * av + e * au * av - o.z;
+                let k1 = b * d.x + c * d.y + e * (au * d.y + d.x * av) - d.z;
+                let k2 = e * d.x * d.y;
+
+                let roots = if k2.abs() < 1e-12 {
+                    if k1 == 0.0 {
+                        return None;
+                    }
+
+                    (-k0 / k1, -k0 / k1)
+                } else {
+                    let discrim = k1 * k1 - 4.0 * k2 * k0;
+
+                    if discrim < 0.0 {
+                        return None;
+                    }
+
+                    let root = discrim.sqrt();
+                    let q = if k1 < 0.0 {
+                        -0.5 * (k1 - root)
+                    } else {
+                        -0.5 * (k1 + root)
+                    };
+
+                    let (r0, r1) = (q / k2, k0 / q);
+
+                    (r0.min(r1), r0.max(r1))
+                };
+
+                for &t in [roots.0, roots.1].iter() {
+                    if !in_range(t) {
+                        continue;
+                    }
+
+                    let (u, v) = local(t);
+                    let (u, v) = (clamp(u, 0.0, 1.0), clamp(v, 0.0, 1.0));
+
+                    return Some(CellHit {
+                        t,
+                        u,
+                        v,
+                        z: a + b * u + c * v + e * u * v,
+                        dzdu: b + e * v,
+                        dzdv: c + e * u,
+                    });
+                }
+
+                None
+            }
+            HeightfieldInterpolation::Triangles => {
+                // Each half of the cell is the plane z0 + dzdu u + dzdv v.
+                let halves = [
+                    (z[0], z[1] - z[0], z[3] - z[1], true),
+                    (z[0], z[3] - z[2], z[2] - z[0], false),
+                ];
+
+                let mut closest: Option<CellHit> = None;
+
+                for &(z0, dzdu, dzdv, lower) in halves.iter() {
+                    let k0 = z0 + dzdu * au + dzdv * av - o.z;
+                    let k1 = dzdu * d.x + dzdv * d.y - d.z;
+
+                    if k1 == 0.0 {
+                        continue;
+                    }
+
+                    let t = -k0 / k1;
+
+                    if !in_range(t) || closest.as_ref().is_some_and(|hit| hit.t <= t) {
+                        continue;
+                    }
+
+                    let (u, v) = local(t);
+                    let (u, v) = (clamp(u, 0.0, 1.0), clamp(v, 0.0, 1.0));
+
+                    if (lower && u + tolerance < v) || (!lower && v + tolerance < u) {
+                        continue;
+                    }
+
+                    closest = Some(CellHit {
+                        t,
+                        u,
+                        v,
+                        z: z0 + dzdu * u + dzdv * v,
+                        dzdu,
+                        dzdv,
+                    });
+                }
+
+                closest
+            }
+        }
+    }
+
+    /// Interpolated vertex normal inside cell `(ix, iy)`.
+    fn shading_normal(&self, ix: usize, iy: usize, u: f64, v: f64) -> Normal3f {
+        let n00 = self.normals[iy * self.nx + ix];
+        let n10 = self.normals[iy * self.nx + ix + 1];
+        let n01 = self.normals[(iy + 1) * self.nx + ix];
+        let n11 = self.normals[(iy + 1) * self.nx + ix + 1];
+
+        let n = match self.interpolation {
+            HeightfieldInterpolation::Bilinear => {
+                n00 * ((1.0 - u) * (1.0 - v)) + n10 * (u * (1.0 - v)) + n01 * ((1.0 - u) * v)
+                    + n11 * (u * v)
+            }
+            HeightfieldInterpolation::Triangles => {
+                if u >= v {
+                    n00 * (1.0 - u) + n10 * (u - v) + n11 * v
+                } else {
+                    n00 * (1.0 - v) + n11 * u + n01 * (v - u)
+                }
+            }
+        };
+
+        n.normalize()
+    }
+
+    fn surface_interaction(&self, ix: usize, iy: usize, hit: &CellHit, ray: &Ray) -> SurfaceInteraction {
+        let ncx = (self.nx - 1) as f64;
+        let ncy = (self.ny - 1) as f64;
+
+        let x = (ix as f64 + hit.u) / ncx;
+        let y = (iy as f64 + hit.v) / ncy;
+        let p = Point3f::new(x, y, hit.z);
+
+        let dpdu = Vector3f::new(1.0, 0.0, hit.dzdu * ncx);
+        let dpdv = Vector3f::new(0.0, 1.0, hit.dzdv * ncy);
+
+        let flip = self.reverse_orientation ^ self.transform_swaps_handedness;
+
+        let mut isect = SurfaceInteraction::new(
+            p,
+            Vector3f::from(p.abs()) * gamma(7),
+            Point2f::new(x, y),
+            -ray.d,
+            dpdu,
+            dpdv,
+            ray.time,
+        )
+        .with_flipped_normal(flip);
+
+        let mut ns = self.shading_normal(ix, iy, hit.u, hit.v);
+
+        if flip {
+            ns = -ns;
+        }
+
+        let ns = Vector3f::from(ns);
+        let mut ss = dpdu.normalize();
+        let ts = ns.cross(ss).normalize();
+        ss = ts.cross(ns);
+
+        isect.set_shading_geometry(ss, ts, Normal3f::zero(), Normal3f::zero(), true);
+
+        isect
+    }
+}
+
+impl Shape for Heightfield {
+    fn object_bound(&self) -> Bounds3f {
+        let top = &self.min_max.last().unwrap().ranges[0];
+
+        Bounds3f::new(
+            Point3f::new(0.0, 0.0, top.0),
+            Point3f::new(1.0, 1.0, top.1),
+        )
+    }
+
+    fn world_bound(&self) -> Bounds3f {
+        self.object_to_world.transform(self.object_bound())
+    }
+
+    fn intersect(&self, r: &Ray) -> Option<(f64, SurfaceInteraction)> {
+        let ray = self.world_to_object.transform(*r);
+        let (t_min, t_max) = self.object_bound().intersect_p(&ray)?;
+
+        let ncx = self.nx - 1;
+        let ncy = self.ny - 1;
+
+        // Walk the grid in a space where every cell is a unit square.
+        let o = Point3f::new(ray.o.x * ncx as f64, ray.o.y * ncy as f64, ray.o.z);
+        let d = Vector3f::new(ray.d.x * ncx as f64, ray.d.y * ncy as f64, ray.d.z);
+
+        let start = o + d * t_min;
+        let mut ix = (start.x.floor().max(0.0) as usize).min(ncx - 1);
+        let mut iy = (start.y.floor().max(0.0) as usize).min(ncy - 1);
+        let mut t = t_min;
+
+        loop {
+            // Use the coarsest block around the current cell that the ray
+            // provably passes over or under; at the finest level, test the
+            // cell itself.
+            let mut level = self.min_max.len() - 1;
+
+            let (t_exit, x_range, y_range, exits_x) = loop {
+                let size = 1 << level;
+                let bx = ix >> level;
+                let by = iy >> level;
+
+                let x_range = (bx * size, ((bx + 1) * size).min(ncx));
+                let y_range = (by * size, ((by + 1) * size).min(ncy));
+
+                let tx = slab_exit(o.x, d.x, x_range);
+                let ty = slab_exit(o.y, d.y, y_range);
+                let t_exit = tx.min(ty).min(t_max);
+
+                let z0 = o.z + d.z * t;
+                let z1 = o.z + d.z * t_exit;
+                let min_max = &self.min_max[level];
+                let (z_min, z_max) = min_max.ranges[by * min_max.width + bx];
+
+                if z0.min(z1) > z_max || z0.max(z1) < z_min {
+                    break (t_exit, x_range, y_range, tx <= ty);
+                }
+
+                if level == 0 {
+                    if let Some(hit) = self.intersect_cell(ix, iy, o, d, t, t_exit) {
+                        let isect = self.surface_interaction(ix, iy, &hit, &ray);
+
+                        return Some((hit.t, self.object_to_world.transform(isect)));
+                    }
+
+                    break (t_exit, x_range, y_range, tx <= ty);
+                }
+
+                level -= 1;
+            };
+
+            if t_exit >= t_max {
+                return None;
+            }
+
+            // Step into the neighbouring cell across the side the ray left
+            // the block through.
+            t = t_exit;
+
+            if exits_x {
+                if d.x > 0.0 {
+                    ix = x_range.1;
+                } else if x_range.0 == 0 {
+                    return None;
+                } else {
+                    ix = x_range.0 - 1;
+                }
+
+                let y = (o.y + d.y * t).floor().max(y_range.0 as f64) as usize;
+                iy = y.min(y_range.1 - 1);
+            } else {
+                if d.y > 0.0 {
+                    iy = y_range.1;
+                } else if y_range.0 == 0 {
+                    return None;
+                } else {
+                    iy = y_range.0 - 1;
+                }
+
+                let x = (o.x + d.x * t).floor().max(x_range.0 as f64) as usize;
+                ix = x.min(x_range.1 - 1);
+            }
+
+            if ix >= ncx || iy >= ncy {
+                return None;
+            }
+        }
+    }
+
+    fn area(&self) -> f64 {
+        self.area
+    }
+
+    /// Samples the triangulated surface by area. For bilinear patches this
+    /// is an approximation of the true surface.
+    fn sample_area(&self, u: Point2f) -> (Interaction, f64) {
+        let target = u.x * self.area;
+        let index = match self
+            .triangle_cdf
+            .binary_search_by(|c| c.partial_cmp(&target).unwrap())
+        {
+            Ok(i) | Err(i) => i.min(self.triangle_cdf.len() - 1),
+        };
+
+        let low = if index == 0 {
+            0.0
+        } else {
+            self.triangle_cdf[index - 1]
+        };
+        let triangle_area = self.triangle_cdf[index] - low;
+        let u_remapped = clamp((target - low) / triangle_area, 0.0, 1.0);
+
+        let cell = index / 2;
+        let triangle = self.cell_triangles(cell % (self.nx - 1), cell / (self.nx - 1))[index % 2];
+
+        let b = uniform_sample_triangle(Point2f::new(u_remapped, u.y));
+        let p_obj = triangle[0] * b.x + triangle[1] * b.y + triangle[2] * (1.0 - b.x - b.y);
+
+        let n_obj = Normal3f::from((triangle[1] - triangle[0]).cross(triangle[2] - triangle[0]));
+        let mut n = self.object_to_world.transform(n_obj).normalize();
+
+        if self.reverse_orientation ^ self.transform_swaps_handedness {
+            n = -n;
+        }
+
+        let p_error = Vector3f::from(p_obj.abs()) * gamma(6);
+        let (p, p_error) = self.object_to_world.transform_point_with_error(p_obj, p_error);
+
+        (
+            Interaction::new(p, n, p_error, Vector3f::zero(), 0.0),
+            1.0 / self.area,
+        )
+    }
+}
+
+/// Parametric distance at which a ray leaves the slab `[range.0, range.1]`
+/// along one axis.
+fn slab_exit(o: f64, d: f64, range: (usize, usize)) -> f64 {
+    if d > 0.0 {
+        (range.1 as f64 - o) / d
+    } else if d < 0.0 {
+        (range.0 as f64 - o) / d
+    } else {
+        f64::INFINITY
+    }
+}
+
+/// Reads a grid of heights from a binary PGM (8 or 16 bit) or a PFM file.
+/// PGM samples are normalized to `[0, 1]`. The first row of the grid is the
+/// top row of the image.
+pub fn read_heights<P: AsRef<Path>>(path: P) -> io::Result<(usize, usize, Vec<f64>)> {
+    let bytes = fs::read(path)?;
+
+    if bytes.starts_with(b"P5") {
+        parse_pgm(&bytes)
+    } else if bytes.starts_with(b"Pf") || bytes.starts_with(b"PF") {
+        parse_pfm(&bytes)
+    } else {
+        Err(invalid_data("unsupported heightfield image format"))
+    }
+}
+
+fn parse_pgm(bytes: &[u8]) -> io::Result<(usize, usize, Vec<f64>)> {
+    let (tokens, offset) = header_tokens(bytes, 4)?;
+
+    let width = parse_token::<usize>(&tokens[1])?;
+    let height = parse_token::<usize>(&tokens[2])?;
+    let max_value = parse_token::<u32>(&tokens[3])?;
+
+    if max_value == 0 || max_value > 65535 {
+        return Err(invalid_data("invalid PGM maximum value"));
+    }
+
+    let bytes_per_sample = if max_value < 256 { 1 } else { 2 };
+    let data = &bytes[offset..];
+
+    if data.len() < data_size(&[width, height, bytes_per_sample])? {
+        return Err(invalid_data("truncated PGM data"));
+    }
+
+    let z = (0..width * height)
+        .map(|i| {
+            let value = if bytes_per_sample == 1 {
+                data[i] as u32
+            } else {
+                (data[2 * i] as u32) << 8 | data[2 * i + 1] as u32
+            };
+
+            value as f64 / max_value as f64
+        })
+        .collect();
+
+    Ok((width, height, z))
+}
+
+fn parse_pfm(bytes: &[u8]) -> io::Result<(usize, usize, Vec<f64>)> {
+    let (tokens, offset) = header_tokens(bytes, 4)?;
+
+    let channels = if tokens[0] == "PF" { 3 } else { 1 };
+    let width = parse_token::<usize>(&tokens[1])?;
+    let height = parse_token::<usize>(&tokens[2])?;
+    let scale = parse_token::<f64>(&tokens[3])?;
+    let little_endian = scale < 0.0;
+
+    let data = &bytes[offset..];
+
+    if data.len() < data_size(&[width, height, channels, 4])? {
+        return Err(invalid_data("truncated PFM data"));
+    }
+
+    let sample = |i: usize| {
+        let b = [data[4 * i], data[4 * i + 1], data[4 * i + 2], data[4 * i + 3]];
+        let bits = if little_endian {
+            u32::from_le_bytes(b)
+        } else {
+            u32::from_be_bytes(b)
+        };
+
+        f32::from_bits(bits) as f64
+    };
+
+    let mut z = vec![0.0; width * height];
+
+    // PFM rows run bottom to top; average the channels of color files.
+    for row in 0..height {
+        for x in 0..width {
+            let i = (row * width + x) * channels;
+            let sum: f64 = (0..channels).map(|c| sample(i + c)).sum();
+
+            z[(height - 1 - row) * width + x] = sum / channels as f64;
+        }
+    }
+
+    Ok((width, height, z))
+}
+
+/// Splits the first `count` whitespace separated tokens off a Netpbm style
+/// header, skipping comments, and returns them with the offset of the data
+/// that follows.
+fn header_tokens(bytes: &[u8], count: usize) -> io::Result<(Vec<String>, usize)> {
+    let mut tokens = Vec::with_capacity(count);
+    let mut i = 0;
+
+    while tokens.len() < count {
+        while i < bytes.len() && (bytes[i] as char).is_ascii_whitespace() {
+            i += 1;
+        }
+
+        if i < bytes.len() && bytes[i] == b'#' {
+            while i < bytes.len() && bytes[i] != b'\n' {
+                i += 1;
+            }
+
+            continue;
+        }
+
+        let start = i;
+
+        while i < bytes.len() && !(bytes[i] as char).is_ascii_whitespace() {
+            i += 1;
+        }
+
+        if start == i {
+            return Err(invalid_data("truncated image header"));
+        }
+
+        tokens.push(String::from_utf8_lossy(&bytes[start..i]).into_owned());
+    }
+
+    // A single whitespace byte separates the header from the data.
+    Ok((tokens, (i + 1).min(bytes.len())))
+}
+
+/// The product of an image's dimensions, failing instead of overflowing on
+/// a hostile header.
+fn data_size(dimensions: &[usize]) -> io::Result<usize> {
+    dimensions
+        .iter()
+        .try_fold(1usize, |size, &d| size.checked_mul(d))
+        .ok_or_else(|| invalid_data("image dimensions too large"))
+}
+
+fn parse_token<T: ::std::str::FromStr>(token: &str) -> io::Result<T> {
+    token
+        .parse()
+        .map_err(|_| invalid_data("malformed image header"))
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64;
+
+    use core::medium::Medium;
+
+    const EPSILON: f64 = 0.00001;
+
+    fn ray(o: Point3f, d: Vector3f) -> Ray {
+        Ray::new(o, d, Medium {}, f64::INFINITY, 0.0)
+    }
+
+    fn bumpy(interpolation: HeightfieldInterpolation) -> Heightfield {
+        let nx = 9;
+        let ny = 7;
+        let z = (0..nx * ny)
+            .map(|i| {
+                let (x, y) = ((i % nx) as f64, (i / nx) as f64);
+
+                0.2 * (x * 0.9).sin() * (y * 0.7).cos()
+            })
+            .collect();
+
+        Heightfield::new(Transform::new(), false, nx, ny, z, interpolation)
+    }
+
+    /// Tests every cell without any empty-space skipping.
+    fn brute_force(heightfield: &Heightfield, r: &Ray) -> Option<f64> {
+        let ncx = heightfield.nx - 1;
+        let ncy = heightfield.ny - 1;
+        let o = Point3f::new(r.o.x * ncx as f64, r.o.y * ncy as f64, r.o.z);
+        let d = Vector3f::new(r.d.x * ncx as f64, r.d.y * ncy as f64, r.d.z);
+
+        let mut closest: Option<f64> = None;
+
+        for iy in 0..ncy {
+            for ix in 0..ncx {
+                let t0 = slab_entry(o, d, ix, iy);
+                let t1 = slab_exit(o.x, d.x, (ix, ix + 1)).min(slab_exit(o.y, d.y, (iy, iy + 1)));
+
+                if t0 > t1 {
+                    continue;
+                }
+
+                if let Some(hit) = heightfield.intersect_cell(ix, iy, o, d, t0, t1) {
+                    if closest.map_or(true, |t| hit.t < t) {
+                        closest = Some(hit.t);
+                    }
+                }
+            }
+        }
+
+        closest
+    }
+
+    fn slab_entry(o: Point3f, d: Vector3f, ix: usize, iy: usize) -> f64 {
+        let entry = |o: f64, d: f64, lo: usize| {
+            if d > 0.0 {
+                (lo as f64 - o) / d
+            } else if d < 0.0 {
+                (lo as f64 + 1.0 - o) / d
+            } else if o >= lo as f64 && o <= lo as f64 + 1.0 {
+                f64::NEG_INFINITY
+            } else {
+                f64::INFINITY
+            }
+        };
+
+        entry(o.x, d.x, ix).max(entry(o.y, d.y, iy)).max(0.0)
+    }
+
+    #[test]
+    fn flat_plane_hit() {
+        let heightfield = Heightfield::new(
+            Transform::new(),
+            false,
+            3,
+            3,
+            vec![0.5; 9],
+            HeightfieldInterpolation::Bilinear,
+        );
+
+        let (t, isect) = heightfield
+            .intersect(&ray(
+                Point3f::new(0.3, 0.6, 2.0),
+                Vector3f::new(0.0, 0.0, -1.0),
+            ))
+            .unwrap();
+
+        assert!((t - 1.5).abs() < EPSILON);
+        assert!((isect.p.z - 0.5).abs() < EPSILON);
+        assert!((isect.n.z - 1.0).abs() < EPSILON);
+        assert!((isect.uv.x - 0.3).abs() < EPSILON);
+    }
+
+    #[test]
+    fn slope_matches_triangles() {
+        let heightfield = Heightfield::new(
+            Transform::new(),
+            false,
+            2,
+            2,
+            vec![0.0, 1.0, 0.0, 1.0],
+            HeightfieldInterpolation::Triangles,
+        );
+
+        let (_, isect) = heightfield
+            .intersect(&ray(
+                Point3f::new(0.25, 0.5, 5.0),
+                Vector3f::new(0.0, 0.0, -1.0),
+            ))
+            .unwrap();
+
+        assert!((isect.p.z - 0.25).abs() < EPSILON);
+        assert!((isect.n.x + 0.5f64.sqrt()).abs() < EPSILON);
+    }
+
+    #[test]
+    fn grid_walk_matches_brute_force() {
+        for &interpolation in [
+            HeightfieldInterpolation::Bilinear,
+            HeightfieldInterpolation::Triangles,
+        ].iter()
+        {
+            let heightfield = bumpy(interpolation);
+            let mut hits = 0;
+
+            for i in 0..50 {
+                let a = i as f64 * 0.37;
+                let r = ray(
+                    Point3f::new(0.5 - a.cos(), 0.5 - a.sin(), 0.3),
+                    Vector3f::new(a.cos(), a.sin(), -0.15 - 0.03 * (i % 7) as f64),
+                );
+
+                let expected = brute_force(&heightfield, &r);
+                let result = heightfield.intersect(&r).map(|(t, _)| t);
+
+                match (expected, result) {
+                    (Some(expected), Some(result)) => {
+                        assert!((expected - result).abs() < 1e-6);
+                        hits += 1;
+                    }
+                    (None, None) => {}
+                    _ => panic!("ray {} disagrees: {:?} vs {:?}", i, expected, result),
+                }
+            }
+
+            assert!(hits > 25);
+        }
+    }
+
+    #[test]
+    fn ray_above_terrain_misses() {
+        let heightfield = bumpy(HeightfieldInterpolation::Bilinear);
+
+        assert!(
+            heightfield
+                .intersect(&ray(
+                    Point3f::new(-1.0, 0.5, 1.0),
+                    Vector3f::new(1.0, 0.0, 0.0),
+                ))
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn sample_area_on_surface() {
+        let heightfield = Heightfield::new(
+            Transform::scale(2.0, 2.0, 1.0),
+            false,
+            3,
+            3,
+            vec![0.5; 9],
+            HeightfieldInterpolation::Triangles,
+        );
+
+        let (intr, pdf) = heightfield.sample_area(Point2f::new(0.7, 0.2));
+
+        assert!((heightfield.area() - 4.0).abs() < EPSILON);
+        assert!((pdf - 0.25).abs() < EPSILON);
+        assert!((intr.p.z - 0.5).abs() < EPSILON);
+        assert!((intr.n.z - 1.0).abs() < EPSILON);
+    }
+
+    #[test]
+    fn sampled_normals_match_hits_when_mirrored() {
+        let heightfield = Heightfield::new(
+            Transform::scale(-1.0, 1.0, 1.0),
+            false,
+            3,
+            3,
+            vec![0.5; 9],
+            HeightfieldInterpolation::Triangles,
+        );
+
+        let (intr, _) = heightfield.sample_area(Point2f::new(0.3, 0.6));
+        let (_, isect) = heightfield
+            .intersect(&ray(
+                Point3f::new(-0.4, 0.5, 2.0),
+                Vector3f::new(0.0, 0.0, -1.0),
+            ))
+            .unwrap();
+
+        assert!((intr.n.z - isect.n.z).abs() < EPSILON);
+    }
+
+    #[test]
+    fn parse_16_bit_pgm() {
+        let mut bytes = b"P5\n# terrain\n2 2\n65535\n".to_vec();
+        bytes.extend_from_slice(&[0x00, 0x00, 0xff, 0xff, 0x80, 0x00, 0x00, 0x01]);
+
+        let (nx, ny, z) = parse_pgm(&bytes).unwrap();
+
+        assert_eq!((2, 2), (nx, ny));
+        assert_eq!(0.0, z[0]);
+        assert_eq!(1.0, z[1]);
+        assert!((z[2] - 32768.0 / 65535.0).abs() < EPSILON);
+    }
+
+    #[test]
+    fn parse_pfm_flips_rows() {
+        let mut bytes = b"Pf\n1 2\n-1.0\n".to_vec();
+        bytes.extend_from_slice(&1.5f32.to_bits().to_le_bytes());
+        bytes.extend_from_slice(&2.5f32.to_bits().to_le_bytes());
+
+        let (nx, ny, z) = parse_pfm(&bytes).unwrap();
+
+        assert_eq!((1, 2), (nx, ny));
+        assert_eq!(vec![2.5, 1.5], z);
+    }
+
+    #[test]
+    fn truncated_headers_are_rejected() {
+        assert!(parse_pgm(b"P5 1 1 255").is_err());
+        assert!(parse_pfm(b"Pf 1 1 -1.0").is_err());
+        assert!(parse_pgm(b"P5 4294967296 4294967296 65535\n").is_err());
+    }
+}
@@ -0,0 +1,478 @@
+use std::collections::HashMap;
+
+use core::utils::clamp;
+
+use core::Normal3f;
+use core::Point3f;
+use core::Vector3f;
+use shapes::triangle::TriangleMesh;
+
+impl TriangleMesh {
+    /// Merges vertices whose positions, and any normals, tangents and uvs,
+    /// all lie within `tolerance` of each other.
+    pub fn weld_vertices(&mut self, tolerance: f64) {
+        let cell_size = tolerance.max(1e-12);
+        let cell = |p: Point3f| {
+            (
+                (p.x / cell_size).floor() as i64,
+                (p.y / cell_size).floor() as i64,
+                (p.z / cell_size).floor() as i64,
+            )
+        };
+
+        let mut grid: HashMap<(i64, i64, i64), Vec<usize>> = HashMap::new();
+        let mut sources = Vec::new();
+        let mut remap = Vec::with_capacity(self.p.len());
+
+        for v in 0..self.p.len() {
+            let (cx, cy, cz) = cell(self.p[v]);
+            let mut found = None;
+
+            'search: for dz in -1..2 {
+                for dy in -1..2 {
+                    for dx in -1..2 {
+                        if let Some(candidates) = grid.get(&(cx + dx, cy + dy, cz + dz)) {
+                            for &w in candidates {
+                                if self.vertices_match(sources[w], v, tolerance) {
+                                    found = Some(w);
+                                    break 'search;
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+
+            let index = match found {
+                Some(w) => w,
+                None => {
+                    sources.push(v);
+                    grid.entry((cx, cy, cz))
+                        .or_default()
+                        .push(sources.len() - 1);
+
+                    sources.len() - 1
+                }
+            };
+
+            remap.push(index);
+        }
+
+        for v in self.vertex_indices.iter_mut() {
+            *v = remap[*v];
+        }
+
+        self.keep_vertices(&sources);
+    }
+
+    fn vertices_match(&self, a: usize, b: usize, tolerance: f64) -> bool {
+        let close = |d: Vector3f| d.abs().max_component() <= tolerance;
+
+        close(self.p[a] - self.p[b])
+            && self.n.as_ref().is_none_or(|n| close(Vector3f::from(n[a] - n[b])))
+            && self.s.as_ref().is_none_or(|s| close(s[a] - s[b]))
+            && self.uv.as_ref().is_none_or(|uv| {
+                let d = uv[a] - uv[b];
+
+                d.x.abs().max(d.y.abs()) <= tolerance
+            })
+            && self
+                .bitangent_signs
+                .as_ref()
+                .is_none_or(|signs| signs[a] == signs[b])
+    }
+
+    /// Replaces the vertex arrays with copies of the vertices listed in
+    /// `sources`, so new vertex `i` is old vertex `sources[i]`.
+    fn keep_vertices(&mut self, sources: &[usize]) {
+        fn select<T: Copy>(values: &[T], sources: &[usize]) -> Vec<T> {
+            sources.iter().map(|&i| values[i]).collect()
+        }
+
+        self.p = select(&self.p, sources);
+        self.n = self.n.as_ref().map(|n| select(n, sources));
+        self.s = self.s.as_ref().map(|s| select(s, sources));
+        self.uv = self.uv.as_ref().map(|uv| select(uv, sources));
+        self.bitangent_signs = self
+            .bitangent_signs
+            .as_ref()
+            .map(|signs| select(signs, sources));
+    }
+
+    /// Gives every corner of the mesh its own key and duplicates vertices
+    /// whose corners disagree. Returns the key of each resulting vertex.
+    fn split_vertices<K: Copy + PartialEq>(&mut self, corner_keys: &[K]) -> Vec<K> {
+        let mut copies: Vec<Vec<(K, usize)>> = vec![Vec::new(); self.p.len()];
+        let mut sources = Vec::with_capacity(self.p.len());
+        let mut keys = Vec::with_capacity(self.p.len());
+
+        for (corner, key) in corner_keys.iter().enumerate() {
+            let v = self.vertex_indices[corner];
+
+            let index = match copies[v].iter().find(|&&(k, _)| k == *key) {
+                Some(&(_, index)) => index,
+                None => {
+                    sources.push(v);
+                    keys.push(*key);
+                    copies[v].push((*key, sources.len() - 1));
+
+                    sources.len() - 1
+                }
+            };
+
+            self.vertex_indices[corner] = index;
+        }
+
+        self.keep_vertices(&sources);
+
+        keys
+    }
+
+    /// Unit normal of face `f` following the winding order and the mesh's
+    /// orientation, along with twice the face's area.
+    fn face_normal(&self, f: usize) -> (Vector3f, f64) {
+        let (p0, p1, p2) = self.face_positions(f);
+        let mut n = (p1 - p0).cross(p2 - p0);
+
+        if self.reverse_orientation ^ self.transform_swaps_handedness {
+            n = -n;
+        }
+
+        let length = n.length();
+
+        if length == 0.0 {
+            (Vector3f::zero(), 0.0)
+        } else {
+            (n / length, length)
+        }
+    }
+
+    fn face_positions(&self, f: usize) -> (Point3f, Point3f, Point3f) {
+        let v = &self.vertex_indices[3 * f..3 * f + 3];
+
+        (self.p[v[0]], self.p[v[1]], self.p[v[2]])
+    }
+
+    /// Interior angle of face `f` at its corner `i`.
+    fn corner_angle(&self, f: usize, i: usize) -> f64 {
+        let v = &self.vertex_indices[3 * f..3 * f + 3];
+        let p = self.p[v[i]];
+        let e0 = self.p[v[(i + 1) % 3]] - p;
+        let e1 = self.p[v[(i + 2) % 3]] - p;
+
+        if e0.length_squared() == 0.0 || e1.length_squared() == 0.0 {
+            return 0.0;
+        }
+
+        clamp(e0.normalize().dot(e1.normalize()), -1.0, 1.0).acos()
+    }
+
+    /// Replaces the normals with averages of the adjacent faces' normals,
+    /// weighted by face area and corner angle. Faces meeting at more than
+    /// `crease_angle` degrees are not smoothed together, which splits the
+    /// vertices along the crease.
+    pub fn compute_normals(&mut self, crease_angle: f64) {
+        let n_faces = self.n_triangles;
+        let cos_crease = crease_angle.to_radians().cos();
+
+        let faces: Vec<(Vector3f, f64)> = (0..n_faces).map(|f| self.face_normal(f)).collect();
+
+        let mut incident: Vec<Vec<(usize, f64)>> = vec![Vec::new(); self.p.len()];
+
+        for f in 0..n_faces {
+            for i in 0..3 {
+                let weight = faces[f].1 * self.corner_angle(f, i);
+
+                incident[self.vertex_indices[3 * f + i]].push((f, weight));
+            }
+        }
+
+        let corner_normals: Vec<[f64; 3]> = (0..3 * n_faces)
+            .map(|corner| {
+                let f = corner / 3;
+                let nf = faces[f].0;
+
+                let mut n = Vector3f::zero();
+
+                for &(g, weight) in incident[self.vertex_indices[corner]].iter() {
+                    if g == f || nf.dot(faces[g].0) >= cos_crease {
+                        n += faces[g].0 * weight;
+                    }
+                }
+
+                let n = if n.length_squared() > 0.0 {
+                    n.normalize()
+                } else {
+                    nf
+                };
+
+                [n.x, n.y, n.z]
+            })
+            .collect();
+
+        // Old normals would only be copied around before being replaced.
+        self.n = None;
+
+        let normals = self.split_vertices(&corner_normals);
+
+        self.n = Some(
+            normals
+                .iter()
+                .map(|n| Normal3f::new(n[0], n[1], n[2]))
+                .collect(),
+        );
+    }
+
+    /// Generates per-vertex tangents and bitangent signs from the uvs the
+    /// way MikkTSpace does, so normal maps baked with it line up. Vertices
+    /// shared by faces with mirrored uvs are split. Meshes without uvs are
+    /// left unchanged.
+    pub fn compute_tangents(&mut self) {
+        if self.uv.is_none() {
+            return;
+        }
+
+        let n_faces = self.n_triangles;
+
+        // Tangent of each face and whether its uvs keep their orientation.
+        let face_tangents: Vec<(Vector3f, f64)> = (0..n_faces)
+            .map(|f| {
+                let v = &self.vertex_indices[3 * f..3 * f + 3];
+                let uv = self.uv.as_ref().unwrap();
+
+                let dp1 = self.p[v[1]] - self.p[v[0]];
+                let dp2 = self.p[v[2]] - self.p[v[0]];
+                let duv1 = uv[v[1]] - uv[v[0]];
+                let duv2 = uv[v[2]] - uv[v[0]];
+
+                let signed_area = duv1.x * duv2.y - duv2.x * duv1.y;
+                let sign = if signed_area < 0.0 { -1.0 } else { 1.0 };
+
+                if signed_area == 0.0 {
+                    return (Vector3f::zero(), sign);
+                }
+
+                ((dp1 * duv2.y - dp2 * duv1.y) * sign, sign)
+            })
+            .collect();
+
+        let corner_signs: Vec<f64> = (0..3 * n_faces)
+            .map(|corner| face_tangents[corner / 3].1)
+            .collect();
+
+        self.bitangent_signs = None;
+        self.s = None;
+
+        let signs = self.split_vertices(&corner_signs);
+
+        let mut tangents = vec![Vector3f::zero(); self.p.len()];
+
+        for (f, &(t, _)) in face_tangents.iter().enumerate() {
+            let (face_normal, _) = self.face_normal(f);
+
+            for i in 0..3 {
+                let v = self.vertex_indices[3 * f + i];
+                let n = match self.n {
+                    Some(ref normals) => Vector3f::from(normals[v]).normalize(),
+                    None => face_normal,
+                };
+
+                let projected = t - n * n.dot(t);
+
+                if projected.length_squared() > 0.0 {
+                    tangents[v] += projected.normalize() * self.corner_angle(f, i);
+                }
+            }
+        }
+
+        for (v, tangent) in tangents.iter_mut().enumerate() {
+            if tangent.length_squared() > 0.0 {
+                *tangent = tangent.normalize();
+            } else {
+                let n = match self.n {
+                    Some(ref normals) => Vector3f::from(normals[v]),
+                    None => Vector3f::new(0.0, 0.0, 1.0),
+                };
+
+                *tangent = n.coordinate_system().0;
+            }
+        }
+
+        self.s = Some(tangents);
+        self.bitangent_signs = Some(signs);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use core::transform::Transform;
+    use core::Point2f;
+
+    const EPSILON: f64 = 0.00001;
+
+    /// A unit cube with eight shared corners.
+    fn cube() -> TriangleMesh {
+        let p = (0..8)
+            .map(|i| {
+                Point3f::new(
+                    (i & 1) as f64,
+                    ((i >> 1) & 1) as f64,
+                    ((i >> 2) & 1) as f64,
+                )
+            })
+            .collect();
+
+        #[cfg_attr(rustfmt, rustfmt_skip)]
+        let indices = vec![
+            0, 2, 1, 1, 2, 3,
+            4, 5, 6, 5, 7, 6,
+            0, 1, 4, 1, 5, 4,
+            2, 6, 3, 3, 6, 7,
+            0, 4, 2, 2, 4, 6,
+            1, 3, 5, 3, 7, 5,
+        ];
+
+        TriangleMesh::new(Transform::new(), false, indices, p, None, None, None)
+    }
+
+    fn quad(uv: Vec<Point2f>) -> TriangleMesh {
+        TriangleMesh::new(
+            Transform::new(),
+            false,
+            vec![0, 1, 2, 0, 2, 3],
+            vec![
+                Point3f::new(0.0, 0.0, 0.0),
+                Point3f::new(1.0, 0.0, 0.0),
+                Point3f::new(1.0, 1.0, 0.0),
+                Point3f::new(0.0, 1.0, 0.0),
+            ],
+            None,
+            None,
+            Some(uv),
+        )
+    }
+
+    #[test]
+    fn smooth_cube_normals_point_out_of_corners() {
+        let mut mesh = cube();
+        mesh.compute_normals(180.0);
+
+        assert_eq!(8, mesh.p.len());
+
+        let normals = mesh.n.as_ref().unwrap();
+        let expected = 1.0 / 3.0f64.sqrt();
+
+        for (p, n) in mesh.p.iter().zip(normals.iter()) {
+            let outward = Vector3f::new(p.x - 0.5, p.y - 0.5, p.z - 0.5);
+
+            assert!((Vector3f::from(*n).dot(outward.normalize()) - 1.0).abs() < EPSILON);
+            assert!((n.x.abs() - expected).abs() < EPSILON);
+        }
+    }
+
+    #[test]
+    fn crease_splits_cube_faces() {
+        let mut mesh = cube();
+        mesh.compute_normals(30.0);
+
+        assert_eq!(24, mesh.p.len());
+
+        for n in mesh.n.as_ref().unwrap() {
+            assert!((n.abs().max_component() - 1.0).abs() < EPSILON);
+        }
+    }
+
+    #[test]
+    fn weld_merges_triangle_soup() {
+        let mut mesh = cube();
+        let soup: Vec<Point3f> = mesh
+            .vertex_indices
+            .iter()
+            .map(|&v| mesh.p[v] + Vector3f::new(1e-7, 0.0, -1e-7))
+            .collect();
+
+        mesh.vertex_indices = (0..soup.len()).collect();
+        mesh.p = soup;
+        mesh.weld_vertices(1e-5);
+
+        assert_eq!(8, mesh.p.len());
+        assert_eq!(36, mesh.vertex_indices.len());
+    }
+
+    #[test]
+    fn weld_keeps_uv_seams() {
+        let mut mesh = quad(vec![
+            Point2f::new(0.0, 0.0),
+            Point2f::new(1.0, 0.0),
+            Point2f::new(1.0, 1.0),
+            Point2f::new(0.0, 1.0),
+        ]);
+
+        mesh.p.push(mesh.p[0]);
+        mesh.uv.as_mut().unwrap().push(Point2f::new(0.5, 0.5));
+        mesh.p.push(mesh.p[1]);
+        mesh.uv.as_mut().unwrap().push(Point2f::new(1.0, 0.0));
+        mesh.weld_vertices(1e-5);
+
+        assert_eq!(5, mesh.p.len());
+    }
+
+    #[test]
+    fn tangents_follow_u() {
+        let mut mesh = quad(vec![
+            Point2f::new(0.0, 0.0),
+            Point2f::new(1.0, 0.0),
+            Point2f::new(1.0, 1.0),
+            Point2f::new(0.0, 1.0),
+        ]);
+        mesh.compute_normals(180.0);
+        mesh.compute_tangents();
+
+        for (s, sign) in mesh
+            .s
+            .as_ref()
+            .unwrap()
+            .iter()
+            .zip(mesh.bitangent_signs.as_ref().unwrap())
+        {
+            assert!((s.x - 1.0).abs() < EPSILON);
+            assert_eq!(1.0, *sign);
+        }
+    }
+
+    #[test]
+    fn mirrored_uvs_flip_sign_and_split() {
+        // The second triangle's uvs are mirrored in u.
+        let mut mesh = TriangleMesh::new(
+            Transform::new(),
+            false,
+            vec![0, 1, 2, 1, 3, 2],
+            vec![
+                Point3f::new(0.0, 0.0, 0.0),
+                Point3f::new(1.0, 0.0, 0.0),
+                Point3f::new(0.0, 1.0, 0.0),
+                Point3f::new(1.0, 1.0, 0.0),
+            ],
+            None,
+            None,
+            Some(vec![
+                Point2f::new(0.0, 0.0),
+                Point2f::new(1.0, 0.0),
+                Point2f::new(0.0, 1.0),
+                Point2f::new(0.0, 0.0),
+            ]),
+        );
+
+        mesh.compute_tangents();
+
+        // The shared edge's two vertices are duplicated.
+        assert_eq!(6, mesh.p.len());
+
+        let signs = mesh.bitangent_signs.as_ref().unwrap();
+
+        assert_eq!(1.0, signs[mesh.vertex_indices[0]]);
+        assert_eq!(-1.0, signs[mesh.vertex_indices[3]]);
+    }
+}
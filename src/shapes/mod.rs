@@ -0,0 +1,7 @@
+pub mod displacement;
+pub mod heightfield;
+pub mod mesh_attributes;
+pub mod point_cloud;
+pub mod sdf;
+pub mod sphere;
+pub mod triangle;
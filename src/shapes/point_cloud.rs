@@ -0,0 +1,654 @@
+use std::cmp::Ordering;
+use std::f64::consts::PI;
+
+use core::interaction::{Interaction, SurfaceInteraction};
+use core::ray::Ray;
+use core::sampling::{concentric_sample_disk, uniform_sample_sphere};
+use core::shape::{area_to_solid_angle_pdf, sample_from_ref_by_area, Shape};
+use core::transform::Transform;
+use core::utils::{clamp, gamma, solve_quadratic};
+
+use core::Bounds3f;
+use core::Normal3f;
+use core::Point2f;
+use core::Point3f;
+use core::Vector3f;
+
+const MAX_POINTS_IN_LEAF: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PointShape {
+    Sphere,
+    /// A disc facing along the point's normal, or facing the ray for
+    /// clouds without normals.
+    Disc,
+}
+
+/// Node of the cloud's own BVH, stored depth first. Interior nodes keep
+/// their first child right after them and `offset` points at the second;
+/// leaves cover `n_points` points starting at `offset`.
+#[derive(Clone, Copy, Debug)]
+struct PointCloudNode {
+    bounds: Bounds3f,
+    offset: u32,
+    n_points: u16,
+    axis: u8,
+}
+
+/// A large set of small spheres or discs kept in single precision world
+/// space arrays. Points are reordered while building the internal BVH, so
+/// point indices refer to that order.
+#[derive(Clone, Debug)]
+pub struct PointCloud {
+    point_shape: PointShape,
+    positions: Vec<[f32; 3]>,
+    normals: Option<Vec<[f32; 3]>>,
+    /// Either one radius shared by all points or one per point.
+    radii: Vec<f32>,
+    colors: Option<Vec<[u8; 3]>>,
+    nodes: Vec<PointCloudNode>,
+    /// Running sum of the point areas, for picking points by area.
+    area_cdf: Vec<f64>,
+    area: f64,
+}
+
+impl PointCloud {
+    /// Radii are scaled by the length `object_to_world` gives a unit
+    /// vector along x, so the transform should scale uniformly.
+    pub fn new(
+        object_to_world: Transform,
+        point_shape: PointShape,
+        positions: Vec<[f32; 3]>,
+        normals: Option<Vec<[f32; 3]>>,
+        radii: Vec<f32>,
+        colors: Option<Vec<[u8; 3]>>,
+    ) -> Self {
+        let n = positions.len();
+
+        assert!(n > 0 && n <= u32::MAX as usize);
+        assert!(radii.len() == 1 || radii.len() == n);
+        assert!(normals.as_ref().is_none_or(|normals| normals.len() == n));
+        assert!(colors.as_ref().is_none_or(|colors| colors.len() == n));
+
+        let scale = object_to_world
+            .transform(Vector3f::new(1.0, 0.0, 0.0))
+            .length() as f32;
+
+        let mut cloud = Self {
+            point_shape,
+            positions: positions
+                .iter()
+                .map(|&p| to_f32(Vector3f::from(object_to_world.transform(to_point(p)))))
+                .collect(),
+            normals: normals.map(|normals| {
+                normals
+                    .iter()
+                    .map(|&n| {
+                        let n = object_to_world.transform(Normal3f::from(to_vector(n)));
+
+                        to_f32(Vector3f::from(n.normalize()))
+                    })
+                    .collect()
+            }),
+            radii: radii.iter().map(|&r| r * scale).collect(),
+            colors,
+            nodes: Vec::new(),
+            area_cdf: Vec::new(),
+            area: 0.0,
+        };
+
+        let mut order: Vec<u32> = (0..n as u32).collect();
+        let mut nodes = Vec::with_capacity(2 * n / MAX_POINTS_IN_LEAF + 1);
+
+        cloud.build(&mut order, 0, &mut nodes);
+        cloud.reorder(&order);
+        cloud.nodes = nodes;
+        cloud.area_cdf = (0..n)
+            .scan(0.0, |sum, i| {
+                *sum += cloud.point_area(i);
+                Some(*sum)
+            })
+            .collect();
+        cloud.area = cloud.area_cdf[n - 1];
+
+        cloud
+    }
+
+    pub fn len(&self) -> usize {
+        self.positions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.positions.is_empty()
+    }
+
+    pub fn position(&self, i: usize) -> Point3f {
+        to_point(self.positions[i])
+    }
+
+    pub fn normal(&self, i: usize) -> Option<Normal3f> {
+        self.normals
+            .as_ref()
+            .map(|normals| Normal3f::from(to_vector(normals[i])))
+    }
+
+    pub fn radius(&self, i: usize) -> f64 {
+        if self.radii.len() == 1 {
+            self.radii[0] as f64
+        } else {
+            self.radii[i] as f64
+        }
+    }
+
+    pub fn color(&self, i: usize) -> Option<[u8; 3]> {
+        self.colors.as_ref().map(|colors| colors[i])
+    }
+
+    fn point_bounds(&self, i: usize) -> Bounds3f {
+        let p = self.position(i);
+        let r = self.radius(i);
+
+        Bounds3f::new(p, p).expand(r)
+    }
+
+    fn point_area(&self, i: usize) -> f64 {
+        let r = self.radius(i);
+
+        match self.point_shape {
+            PointShape::Sphere => 4.0 * PI * r * r,
+            PointShape::Disc => PI * r * r,
+        }
+    }
+
+    /// Builds the subtree over `points`, which start at `offset` in the
+    /// final point order, splitting at the median of the widest axis.
+    fn build(&self, points: &mut [u32], offset: usize, nodes: &mut Vec<PointCloudNode>) {
+        let bounds = points
+            .iter()
+            .fold(Bounds3f::zero(), |b, &i| b.union_bounds(self.point_bounds(i as usize)));
+
+        if points.len() <= MAX_POINTS_IN_LEAF {
+            nodes.push(PointCloudNode {
+                bounds,
+                offset: offset as u32,
+                n_points: points.len() as u16,
+                axis: 0,
+            });
+
+            return;
+        }
+
+        let centroid_bounds = points
+            .iter()
+            .fold(Bounds3f::zero(), |b, &i| b.union(self.position(i as usize)));
+        let axis = centroid_bounds.maximum_extent();
+
+        let mid = points.len() / 2;
+        points.select_nth_unstable_by(mid, |&a, &b| {
+            let pa = self.positions[a as usize][axis];
+            let pb = self.positions[b as usize][axis];
+
+            pa.partial_cmp(&pb).unwrap_or(Ordering::Equal)
+        });
+
+        let index = nodes.len();
+        nodes.push(PointCloudNode {
+            bounds,
+            offset: 0,
+            n_points: 0,
+            axis: axis as u8,
+        });
+
+        let (left, right) = points.split_at_mut(mid);
+
+        self.build(left, offset, nodes);
+        nodes[index].offset = nodes.len() as u32;
+        self.build(right, offset + mid, nodes);
+    }
+
+    fn reorder(&mut self, order: &[u32]) {
+        fn permute<T: Copy>(values: &[T], order: &[u32]) -> Vec<T> {
+            order.iter().map(|&i| values[i as usize]).collect()
+        }
+
+        self.positions = permute(&self.positions, order);
+        self.normals = self.normals.as_ref().map(|n| permute(n, order));
+        self.colors = self.colors.as_ref().map(|c| permute(c, order));
+
+        if self.radii.len() > 1 {
+            self.radii = permute(&self.radii, order);
+        }
+    }
+
+    /// Finds the closest point hit by `ray` along with its index.
+    pub fn intersect_point(&self, r: &Ray) -> Option<(f64, SurfaceInteraction, usize)> {
+        let mut ray = *r;
+        let mut closest = None;
+
+        let dir_is_negative = [ray.d.x < 0.0, ray.d.y < 0.0, ray.d.z < 0.0];
+        let mut stack = Vec::with_capacity(64);
+        let mut current = 0;
+
+        loop {
+            let node = self.nodes[current];
+
+            if node.bounds.intersect_p(&ray).is_some() {
+                if node.n_points > 0 {
+                    let start = node.offset as usize;
+
+                    for i in start..start + node.n_points as usize {
+                        if let Some(t) = self.intersect_one(&ray, i) {
+                            ray.t_max = t;
+                            closest = Some((t, i));
+                        }
+                    }
+                } else if dir_is_negative[node.axis as usize] {
+                    stack.push(current + 1);
+                    current = node.offset as usize;
+                    continue;
+                } else {
+                    stack.push(node.offset as usize);
+                    current += 1;
+                    continue;
+                }
+            }
+
+            match stack.pop() {
+                Some(next) => current = next,
+                None => break,
+            }
+        }
+
+        closest.map(|(t, i)| (t, self.surface_interaction(&ray, t, i), i))
+    }
+
+    fn intersect_one(&self, ray: &Ray, i: usize) -> Option<f64> {
+        let c = self.position(i);
+        let r = self.radius(i);
+
+        match self.point_shape {
+            PointShape::Sphere => {
+                let o = ray.o - c;
+                let a = ray.d.length_squared();
+                let b = 2.0 * ray.d.dot(o);
+                let cc = o.length_squared() - r * r;
+
+                let (t0, t1) = solve_quadratic(a, b, cc)?;
+
+                // Leave some room for the error in the quadratic's terms.
+                let t_min = gamma(7) * (o.length() + r) / a.sqrt();
+
+                if t0 > t_min && t0 < ray.t_max {
+                    Some(t0)
+                } else if t1 > t_min && t1 < ray.t_max {
+                    Some(t1)
+                } else {
+                    None
+                }
+            }
+            PointShape::Disc => {
+                let n = self.disc_normal(ray, i);
+                let denominator = n.dot(ray.d);
+
+                if denominator == 0.0 {
+                    return None;
+                }
+
+                let t = n.dot(c - ray.o) / denominator;
+
+                if t <= gamma(7) * ray.o.distance(c) / ray.d.length() || t >= ray.t_max {
+                    return None;
+                }
+
+                if ray.at(t).distance_squared(c) > r * r {
+                    return None;
+                }
+
+                Some(t)
+            }
+        }
+    }
+
+    fn disc_normal(&self, ray: &Ray, i: usize) -> Vector3f {
+        match self.normals {
+            Some(ref normals) => to_vector(normals[i]),
+            None => -ray.d.normalize(),
+        }
+    }
+
+    fn surface_interaction(&self, ray: &Ray, t: f64, i: usize) -> SurfaceInteraction {
+        let c = self.position(i);
+        let r = self.radius(i);
+
+        let (p, n, p_error) = match self.point_shape {
+            PointShape::Sphere => {
+                let n = (ray.at(t) - c).normalize();
+
+                // Reproject onto the sphere to undo the error in t.
+                let p = c + n * r;
+                let p_error = Vector3f::from(p.abs()) * gamma(5);
+
+                (p, n, p_error)
+            }
+            PointShape::Disc => {
+                let n = self.disc_normal(ray, i);
+                let p = ray.at(t);
+                let p_error = Vector3f::from(p.abs()) * gamma(7);
+
+                (p, n, p_error)
+            }
+        };
+
+        let (dpdu, dpdv) = n.coordinate_system();
+
+        let d = p - c;
+        let uv = Point2f::new(
+            (d.dot(dpdu).atan2(d.dot(dpdv)) + PI) / (2.0 * PI),
+            (d.length() / r).min(1.0),
+        );
+
+        SurfaceInteraction::new(p, p_error, uv, -ray.d, dpdu * r, dpdv * r, ray.time)
+    }
+
+    /// Picks a point with probability proportional to its area, returning
+    /// it with `u.x` remapped to [0, 1) within the point's share.
+    fn pick_point(&self, u: Point2f) -> (usize, Point2f) {
+        let target = u.x * self.area;
+        let i = match self
+            .area_cdf
+            .binary_search_by(|c| c.partial_cmp(&target).unwrap())
+        {
+            Ok(i) | Err(i) => i.min(self.area_cdf.len() - 1),
+        };
+
+        let low = if i == 0 { 0.0 } else { self.area_cdf[i - 1] };
+        let u_remapped = clamp((target - low) / (self.area_cdf[i] - low), 0.0, 1.0);
+
+        (i, Point2f::new(u_remapped, u.y))
+    }
+
+    /// A uniformly distributed position on point `i`, with discs lying
+    /// across `disc_normal`.
+    fn sample_point(&self, i: usize, u: Point2f, disc_normal: Vector3f) -> Interaction {
+        let c = self.position(i);
+        let r = self.radius(i);
+
+        let (p, normal) = match self.point_shape {
+            PointShape::Sphere => {
+                let w = uniform_sample_sphere(u);
+
+                (c + w * r, w)
+            }
+            PointShape::Disc => {
+                let (s, t) = disc_normal.coordinate_system();
+                let d = concentric_sample_disk(u);
+
+                (c + s * (d.x * r) + t * (d.y * r), disc_normal)
+            }
+        };
+
+        let p_error = Vector3f::from(p.abs()) * gamma(5);
+
+        Interaction::new(p, Normal3f::from(normal), p_error, Vector3f::zero(), 0.0)
+    }
+}
+
+impl Shape for PointCloud {
+    fn object_bound(&self) -> Bounds3f {
+        self.world_bound()
+    }
+
+    fn world_bound(&self) -> Bounds3f {
+        self.nodes[0].bounds
+    }
+
+    fn intersect(&self, ray: &Ray) -> Option<(f64, SurfaceInteraction)> {
+        self.intersect_point(ray).map(|(t, isect, _)| (t, isect))
+    }
+
+    fn area(&self) -> f64 {
+        self.area
+    }
+
+    /// Picks a point in proportion to its area and then a position on it
+    /// uniformly, so the whole cloud is sampled uniformly by area. Discs
+    /// without normals face +z here; see `sample_from_ref`.
+    fn sample_area(&self, u: Point2f) -> (Interaction, f64) {
+        let (i, u) = self.pick_point(u);
+        let normal = match self.normals {
+            Some(ref normals) => to_vector(normals[i]),
+            None => Vector3f::new(0.0, 0.0, 1.0),
+        };
+
+        (self.sample_point(i, u, normal), 1.0 / self.area)
+    }
+
+    /// Discs without normals face the ray that hits them, so when sampled
+    /// for a reference point they face it too.
+    fn sample_from_ref(&self, reference: &Interaction, u: Point2f) -> (Interaction, f64) {
+        if self.point_shape == PointShape::Sphere || self.normals.is_some() {
+            return sample_from_ref_by_area(self, reference, u);
+        }
+
+        let (i, u) = self.pick_point(u);
+        let to_reference = reference.p - self.position(i);
+        let normal = if to_reference.length_squared() > 0.0 {
+            to_reference.normalize()
+        } else {
+            Vector3f::new(0.0, 0.0, 1.0)
+        };
+
+        let intr = self.sample_point(i, u, normal);
+        let pdf = area_to_solid_angle_pdf(reference, &intr, 1.0 / self.area);
+
+        (intr, pdf)
+    }
+}
+
+fn to_point(p: [f32; 3]) -> Point3f {
+    Point3f::new(p[0] as f64, p[1] as f64, p[2] as f64)
+}
+
+fn to_vector(v: [f32; 3]) -> Vector3f {
+    Vector3f::new(v[0] as f64, v[1] as f64, v[2] as f64)
+}
+
+fn to_f32(v: Vector3f) -> [f32; 3] {
+    [v.x as f32, v.y as f32, v.z as f32]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64;
+
+    use core::medium::Medium;
+
+    const EPSILON: f64 = 0.0001;
+
+    fn ray(o: Point3f, d: Vector3f) -> Ray {
+        Ray::new(o, d, Medium {}, f64::INFINITY, 0.0)
+    }
+
+    /// Points on a jittered grid in the z = 0 plane.
+    fn grid(n: usize) -> Vec<[f32; 3]> {
+        (0..n * n)
+            .map(|i| {
+                let (x, y) = ((i % n) as f32, (i / n) as f32);
+                let jitter = ((i * 7919) % 13) as f32 * 0.01;
+
+                [x + jitter, y - jitter, 0.0]
+            })
+            .collect()
+    }
+
+    fn brute_force(cloud: &PointCloud, r: &Ray) -> Option<f64> {
+        (0..cloud.len())
+            .filter_map(|i| cloud.intersect_one(r, i))
+            .fold(None, |closest, t| match closest {
+                Some(c) if c <= t => Some(c),
+                _ => Some(t),
+            })
+    }
+
+    #[test]
+    fn bvh_matches_brute_force() {
+        let cloud = PointCloud::new(
+            Transform::new(),
+            PointShape::Sphere,
+            grid(20),
+            None,
+            vec![0.4],
+            None,
+        );
+
+        for i in 0..100 {
+            let a = i as f64 * 0.61;
+            let r = ray(
+                Point3f::new(10.0 + 15.0 * a.cos(), 10.0 + 15.0 * a.sin(), 3.0),
+                Vector3f::new(-a.cos(), -a.sin(), -0.2 - 0.01 * (i % 5) as f64),
+            );
+
+            let expected = brute_force(&cloud, &r);
+            let result = cloud.intersect(&r).map(|(t, _)| t);
+
+            match (expected, result) {
+                (Some(expected), Some(result)) => assert!((expected - result).abs() < EPSILON),
+                (None, None) => {}
+                _ => panic!("ray {} disagrees: {:?} vs {:?}", i, expected, result),
+            }
+        }
+    }
+
+    #[test]
+    fn sphere_hit_reports_point() {
+        let cloud = PointCloud::new(
+            Transform::translate(Vector3f::new(0.0, 0.0, 1.0)),
+            PointShape::Sphere,
+            vec![[0.0, 0.0, 0.0], [5.0, 0.0, 0.0]],
+            None,
+            vec![0.5, 1.0],
+            Some(vec![[255, 0, 0], [0, 255, 0]]),
+        );
+
+        let (t, isect, i) = cloud
+            .intersect_point(&ray(
+                Point3f::new(5.0, 0.0, 10.0),
+                Vector3f::new(0.0, 0.0, -1.0),
+            ))
+            .unwrap();
+
+        assert!((t - 8.0).abs() < EPSILON);
+        assert!((isect.n.z - 1.0).abs() < EPSILON);
+        assert_eq!(Some([0, 255, 0]), cloud.color(i));
+        assert!((cloud.radius(i) - 1.0).abs() < EPSILON);
+    }
+
+    #[test]
+    fn discs_follow_normals() {
+        let cloud = PointCloud::new(
+            Transform::new(),
+            PointShape::Disc,
+            vec![[0.0, 0.0, 0.0]],
+            Some(vec![[1.0, 0.0, 0.0]]),
+            vec![1.0],
+            None,
+        );
+
+        // Seen edge on the disc has no area.
+        assert!(
+            cloud
+                .intersect(&ray(Point3f::new(0.0, 0.0, 5.0), Vector3f::new(0.0, 0.0, -1.0)))
+                .is_none()
+        );
+
+        let (t, isect) = cloud
+            .intersect(&ray(Point3f::new(5.0, 0.5, 0.5), Vector3f::new(-1.0, 0.0, 0.0)))
+            .unwrap();
+
+        assert!((t - 5.0).abs() < EPSILON);
+        assert!((isect.n.x.abs() - 1.0).abs() < EPSILON);
+        assert!(
+            cloud
+                .intersect(&ray(Point3f::new(5.0, 0.8, 0.8), Vector3f::new(-1.0, 0.0, 0.0)))
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn sample_area_on_point() {
+        let cloud = PointCloud::new(
+            Transform::new(),
+            PointShape::Sphere,
+            vec![[0.0, 0.0, 0.0], [3.0, 0.0, 0.0]],
+            None,
+            vec![1.0],
+            None,
+        );
+
+        let (intr, pdf) = cloud.sample_area(Point2f::new(0.75, 0.3));
+
+        assert!((cloud.area() - 8.0 * PI).abs() < EPSILON);
+        assert!((pdf - 1.0 / (8.0 * PI)).abs() < EPSILON);
+        assert!((intr.p.distance(Point3f::new(3.0, 0.0, 0.0)) - 1.0).abs() < EPSILON);
+    }
+
+    #[test]
+    fn mixed_radii_are_sampled_by_area() {
+        let cloud = PointCloud::new(
+            Transform::new(),
+            PointShape::Sphere,
+            vec![[0.0, 0.0, 0.0], [5.0, 0.0, 0.0]],
+            None,
+            vec![1.0, 2.0],
+            None,
+        );
+        let n = 1000;
+        let mut on_large = 0;
+
+        for i in 0..n {
+            let u = Point2f::new((i as f64 + 0.5) / n as f64, 0.3);
+            let (intr, pdf) = cloud.sample_area(u);
+
+            assert!((pdf * cloud.area() - 1.0).abs() < EPSILON);
+
+            if intr.p.x > 2.5 {
+                on_large += 1;
+            }
+        }
+
+        // The larger sphere has four times the area of the smaller one.
+        assert!((on_large as f64 / n as f64 - 0.8).abs() < 0.01);
+
+        // A sample on the top of the large sphere agrees with the pdf of the
+        // same direction, which only knows the total area.
+        let reference = Interaction::from_point(Point3f::new(5.0, 0.0, 10.0), 0.0);
+        let (intr, pdf) = cloud.sample_from_ref(&reference, Point2f::new(0.25, 0.1));
+        let wi = (intr.p - reference.p).normalize();
+
+        assert!((pdf - cloud.pdf_from_ref(&reference, wi)).abs() < 1e-3 * pdf);
+    }
+
+    #[test]
+    fn discs_without_normals_are_sampled_facing_the_reference() {
+        let cloud = PointCloud::new(
+            Transform::new(),
+            PointShape::Disc,
+            vec![[0.0, 0.0, 0.0], [0.0, 3.0, 0.0]],
+            None,
+            vec![0.5, 0.25],
+            None,
+        );
+        let reference = Interaction::from_point(Point3f::new(10.0, 0.0, 0.0), 0.0);
+
+        for i in 0..20 {
+            let u = Point2f::new((i as f64 + 0.5) / 20.0, 0.7);
+            let (intr, pdf) = cloud.sample_from_ref(&reference, u);
+            let wi = (intr.p - reference.p).normalize();
+
+            // Hits face the ray, so the sampled disc must lie across it too.
+            assert!(Vector3f::from(intr.n).abs_dot(wi) > 0.95);
+            assert!((pdf - cloud.pdf_from_ref(&reference, wi)).abs() < 0.05 * pdf);
+        }
+    }
+}
@@ -0,0 +1,588 @@
+use std::f64::consts::PI;
+
+use core::interaction::{Interaction, SurfaceInteraction};
+use core::medium::Medium;
+use core::ray::Ray;
+use core::sampling::uniform_sample_sphere;
+use core::shape::Shape;
+use core::spherical::{spherical_phi, spherical_theta};
+use core::transform::Transform;
+use core::utils::clamp;
+
+use core::Bounds3f;
+use core::Normal3f;
+use core::Point2f;
+use core::Point3f;
+use core::Vector3f;
+
+// Sphere tracing gives up after this many steps and reports a miss.
+const MAX_STEPS: usize = 512;
+
+// Cells per axis of the grid used to estimate the surface area.
+const AREA_ESTIMATE_RESOLUTION: usize = 48;
+
+/// A signed distance function built from primitives centered at the origin
+/// and operators on them. Negative distances are inside.
+#[derive(Clone, Debug)]
+pub enum Sdf {
+    Sphere {
+        radius: f64,
+    },
+    Box {
+        half_extents: Vector3f,
+    },
+    /// A torus around the z axis.
+    Torus {
+        major_radius: f64,
+        minor_radius: f64,
+    },
+    Capsule {
+        a: Point3f,
+        b: Point3f,
+        radius: f64,
+    },
+    Translate(Box<Sdf>, Vector3f),
+    Union(Box<Sdf>, Box<Sdf>),
+    /// Union blended over a distance of about `k`.
+    SmoothUnion(Box<Sdf>, Box<Sdf>, f64),
+    /// The first shape with the second one carved out of it.
+    Subtract(Box<Sdf>, Box<Sdf>),
+    /// Repeats the shape with the given period along each axis; a zero
+    /// period leaves that axis alone.
+    Repeat(Box<Sdf>, Vector3f),
+    /// Twists the shape around the z axis by the given radians per unit.
+    Twist(Box<Sdf>, f64),
+}
+
+impl Sdf {
+    pub fn sphere(radius: f64) -> Self {
+        Sdf::Sphere { radius }
+    }
+
+    pub fn cuboid(half_extents: Vector3f) -> Self {
+        Sdf::Box { half_extents }
+    }
+
+    pub fn torus(major_radius: f64, minor_radius: f64) -> Self {
+        Sdf::Torus {
+            major_radius,
+            minor_radius,
+        }
+    }
+
+    pub fn capsule(a: Point3f, b: Point3f, radius: f64) -> Self {
+        Sdf::Capsule { a, b, radius }
+    }
+
+    pub fn translate(self, offset: Vector3f) -> Self {
+        Sdf::Translate(Box::new(self), offset)
+    }
+
+    pub fn union(self, other: Self) -> Self {
+        Sdf::Union(Box::new(self), Box::new(other))
+    }
+
+    pub fn smooth_union(self, other: Self, k: f64) -> Self {
+        Sdf::SmoothUnion(Box::new(self), Box::new(other), k)
+    }
+
+    pub fn subtract(self, other: Self) -> Self {
+        Sdf::Subtract(Box::new(self), Box::new(other))
+    }
+
+    pub fn repeat(self, period: Vector3f) -> Self {
+        Sdf::Repeat(Box::new(self), period)
+    }
+
+    pub fn twist(self, rate: f64) -> Self {
+        Sdf::Twist(Box::new(self), rate)
+    }
+
+    pub fn distance(&self, p: Point3f) -> f64 {
+        match *self {
+            Sdf::Sphere { radius } => Vector3f::from(p).length() - radius,
+            Sdf::Box { half_extents } => {
+                let q = Vector3f::from(p).abs() - half_extents;
+                let outside = q.max(Vector3f::zero()).length();
+
+                outside + q.max_component().min(0.0)
+            }
+            Sdf::Torus {
+                major_radius,
+                minor_radius,
+            } => {
+                let ring = (p.x * p.x + p.y * p.y).sqrt() - major_radius;
+
+                (ring * ring + p.z * p.z).sqrt() - minor_radius
+            }
+            Sdf::Capsule { a, b, radius } => {
+                let pa = p - a;
+                let ba = b - a;
+                let length_squared = ba.length_squared();
+
+                let h = if length_squared == 0.0 {
+                    0.0
+                } else {
+                    clamp(pa.dot(ba) / length_squared, 0.0, 1.0)
+                };
+
+                (pa - ba * h).length() - radius
+            }
+            Sdf::Translate(ref sdf, offset) => sdf.distance(p - offset),
+            Sdf::Union(ref a, ref b) => a.distance(p).min(b.distance(p)),
+            Sdf::SmoothUnion(ref a, ref b, k) => {
+                let d0 = a.distance(p);
+                let d1 = b.distance(p);
+
+                if k <= 0.0 {
+                    return d0.min(d1);
+                }
+
+                let h = clamp(0.5 + 0.5 * (d1 - d0) / k, 0.0, 1.0);
+
+                d1 + (d0 - d1) * h - k * h * (1.0 - h)
+            }
+            Sdf::Subtract(ref a, ref b) => a.distance(p).max(-b.distance(p)),
+            Sdf::Repeat(ref sdf, period) => {
+                let mut q = p;
+
+                for i in 0..3 {
+                    if period[i] > 0.0 {
+                        q[i] -= period[i] * (p[i] / period[i]).round();
+                    }
+                }
+
+                sdf.distance(q)
+            }
+            Sdf::Twist(ref sdf, rate) => {
+                let (sin, cos) = (-rate * p.z).sin_cos();
+
+                sdf.distance(Point3f::new(
+                    cos * p.x - sin * p.y,
+                    sin * p.x + cos * p.y,
+                    p.z,
+                ))
+            }
+        }
+    }
+
+    /// Upper bound on how fast `distance` changes for points within
+    /// `radius` of the z axis. Sphere tracing divides by it so that warping
+    /// operators never make it step through the surface.
+    pub fn lipschitz(&self, radius: f64) -> f64 {
+        match *self {
+            Sdf::Sphere { .. } | Sdf::Box { .. } | Sdf::Torus { .. } | Sdf::Capsule { .. } => 1.0,
+            Sdf::Translate(ref sdf, offset) => sdf.lipschitz(radius + offset.length()),
+            Sdf::Union(ref a, ref b)
+            | Sdf::SmoothUnion(ref a, ref b, _)
+            | Sdf::Subtract(ref a, ref b) => a.lipschitz(radius).max(b.lipschitz(radius)),
+            Sdf::Repeat(ref sdf, period) => {
+                if period.x > 0.0 && period.y > 0.0 {
+                    let cell_radius = 0.5 * (period.x * period.x + period.y * period.y).sqrt();
+
+                    sdf.lipschitz(radius.min(cell_radius))
+                } else {
+                    sdf.lipschitz(radius)
+                }
+            }
+            Sdf::Twist(ref sdf, rate) => {
+                sdf.lipschitz(radius) * (1.0 + (rate * radius) * (rate * radius)).sqrt()
+            }
+        }
+    }
+}
+
+/// A shape given implicitly by a signed distance function and intersected
+/// by sphere tracing. Distance functions such as repeated ones need not be
+/// bounded, so the caller supplies the object space bounds to trace in.
+#[derive(Clone, Debug)]
+pub struct SdfShape {
+    object_to_world: Transform,
+    world_to_object: Transform,
+    reverse_orientation: bool,
+    transform_swaps_handedness: bool,
+    sdf: Sdf,
+    bounds: Bounds3f,
+    epsilon: f64,
+    lipschitz: f64,
+    area: f64,
+}
+
+impl SdfShape {
+    /// Rays hit the surface once they are within `epsilon` of it; the same
+    /// distance is used for the normal's central differences.
+    pub fn new(
+        object_to_world: Transform,
+        reverse_orientation: bool,
+        sdf: Sdf,
+        bounds: Bounds3f,
+        epsilon: f64,
+    ) -> Self {
+        let radius = (0..8)
+            .map(|i| {
+                let p = bounds.corner(i);
+
+                (p.x * p.x + p.y * p.y).sqrt()
+            })
+            .fold(0.0, f64::max);
+
+        let mut shape = Self {
+            object_to_world,
+            world_to_object: object_to_world.inverse(),
+            reverse_orientation,
+            transform_swaps_handedness: object_to_world.swaps_handedness(),
+            lipschitz: sdf.lipschitz(radius),
+            sdf,
+            bounds,
+            epsilon,
+            area: 0.0,
+        };
+
+        shape.area = shape.estimate_area();
+
+        shape
+    }
+
+    fn distance(&self, p: Point3f) -> f64 {
+        self.sdf.distance(p) / self.lipschitz
+    }
+
+    /// Object space gradient of the distance function by central
+    /// differences.
+    fn gradient(&self, p: Point3f) -> Vector3f {
+        let h = self.epsilon;
+        let mut g = Vector3f::zero();
+
+        for i in 0..3 {
+            let mut offset = Vector3f::zero();
+            offset[i] = h;
+
+            g[i] = self.sdf.distance(p + offset) - self.sdf.distance(p - offset);
+        }
+
+        g
+    }
+
+    /// Measures the volume of a thin shell around the surface on a grid over
+    /// the bounds and divides by its thickness.
+    fn estimate_area(&self) -> f64 {
+        let n = AREA_ESTIMATE_RESOLUTION;
+        let diagonal = self.bounds.diagonal();
+        let cell = diagonal / n as f64;
+        let half_width = cell.max_component();
+
+        let mut shell = 0;
+
+        for z in 0..n {
+            for y in 0..n {
+                for x in 0..n {
+                    let p = self.bounds.p_min + Vector3f::new(
+                        (x as f64 + 0.5) * cell.x,
+                        (y as f64 + 0.5) * cell.y,
+                        (z as f64 + 0.5) * cell.z,
+                    );
+
+                    if self.sdf.distance(p).abs() < half_width {
+                        shell += 1;
+                    }
+                }
+            }
+        }
+
+        let object_area = shell as f64 * cell.x * cell.y * cell.z / (2.0 * half_width);
+
+        // Assumes the transform scales uniformly.
+        let scale = self
+            .object_to_world
+            .transform(Vector3f::new(1.0, 0.0, 0.0))
+            .length();
+
+        object_area * scale * scale
+    }
+
+    fn trace(&self, ray: &Ray) -> Option<(f64, Point3f)> {
+        let (t0, t1) = self.bounds.intersect_p(ray)?;
+        let inv_length = 1.0 / ray.d.length();
+
+        // Rays leaving the surface from inside march on the negated distance
+        // so they find the way out.
+        let mut t = t0;
+        let sign = if self.distance(ray.o + ray.d * t).is_sign_negative() {
+            -1.0
+        } else {
+            1.0
+        };
+
+        for _ in 0..MAX_STEPS {
+            let p = ray.o + ray.d * t;
+            let d = sign * self.distance(p);
+
+            if d < self.epsilon {
+                return Some((t, p));
+            }
+
+            t += d * inv_length;
+
+            if t > t1 {
+                return None;
+            }
+        }
+
+        None
+    }
+}
+
+impl Shape for SdfShape {
+    fn object_bound(&self) -> Bounds3f {
+        self.bounds
+    }
+
+    fn world_bound(&self) -> Bounds3f {
+        self.object_to_world.transform(self.bounds)
+    }
+
+    fn intersect(&self, r: &Ray) -> Option<(f64, SurfaceInteraction)> {
+        let ray = self.world_to_object.transform(*r);
+        let (t_hit, p_hit) = self.trace(&ray)?;
+
+        let n = self.gradient(p_hit).normalize();
+        let (dpdu, dpdv) = n.coordinate_system();
+
+        let (center, _) = self.bounds.bounding_sphere();
+        let w = (p_hit - center).normalize();
+        let uv = Point2f::new(spherical_phi(w) / (2.0 * PI), spherical_theta(w) / PI);
+
+        // The hit lies anywhere within epsilon of the true surface.
+        let p_error = Vector3f::new(1.0, 1.0, 1.0) * (2.0 * self.epsilon);
+
+        let isect = SurfaceInteraction::new(p_hit, p_error, uv, -ray.d, dpdu, dpdv, ray.time)
+            .with_flipped_normal(self.reverse_orientation ^ self.transform_swaps_handedness);
+
+        Some((t_hit, self.object_to_world.transform(isect)))
+    }
+
+    fn area(&self) -> f64 {
+        self.area
+    }
+
+    /// Picks a direction from the center of the bounds and traces inward
+    /// from outside them. The pdf assumes this is uniform by area, which is
+    /// only approximately true. Directions that miss the surface, such as
+    /// those through the hole of a torus, give a pdf of zero.
+    fn sample_area(&self, u: Point2f) -> (Interaction, f64) {
+        let (center, radius) = self.bounds.bounding_sphere();
+        let w = uniform_sample_sphere(u);
+
+        let ray = Ray::new(
+            center + w * (radius * 1.01),
+            -w,
+            Medium {},
+            2.02 * radius,
+            0.0,
+        );
+
+        let (p, mut n, pdf) = match self.trace(&ray) {
+            Some((_, p)) => {
+                let n = Normal3f::from(self.gradient(p).normalize());
+
+                (p, n, 1.0 / self.area)
+            }
+            None => (center, Normal3f::from(w), 0.0),
+        };
+
+        if self.reverse_orientation ^ self.transform_swaps_handedness {
+            n = -n;
+        }
+
+        let p_error = Vector3f::new(1.0, 1.0, 1.0) * (2.0 * self.epsilon);
+        let (p, p_error) = self.object_to_world.transform_point_with_error(p, p_error);
+        let n = self.object_to_world.transform(n).normalize();
+
+        (Interaction::new(p, n, p_error, Vector3f::zero(), 0.0), pdf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64;
+
+    const EPSILON: f64 = 0.001;
+
+    fn ray(o: Point3f, d: Vector3f) -> Ray {
+        Ray::new(o, d, Medium {}, f64::INFINITY, 0.0)
+    }
+
+    fn shape(sdf: Sdf, extent: f64) -> SdfShape {
+        SdfShape::new(
+            Transform::new(),
+            false,
+            sdf,
+            Bounds3f::new(
+                Point3f::new(-extent, -extent, -extent),
+                Point3f::new(extent, extent, extent),
+            ),
+            0.00001,
+        )
+    }
+
+    #[test]
+    fn sphere_matches_analytic_hit() {
+        let sphere = shape(Sdf::sphere(1.0), 1.5);
+
+        let (t, isect) = sphere
+            .intersect(&ray(
+                Point3f::new(0.0, 0.0, -5.0),
+                Vector3f::new(0.0, 0.0, 2.0),
+            ))
+            .unwrap();
+
+        assert!((t - 2.0).abs() < EPSILON);
+        assert!((isect.p.z + 1.0).abs() < EPSILON);
+        assert!((isect.n.z + 1.0).abs() < EPSILON);
+    }
+
+    #[test]
+    fn box_normal_is_face_normal() {
+        let cuboid = shape(Sdf::cuboid(Vector3f::new(1.0, 0.5, 0.5)), 2.0);
+
+        let (_, isect) = cuboid
+            .intersect(&ray(
+                Point3f::new(5.0, 0.1, 0.2),
+                Vector3f::new(-1.0, 0.0, 0.0),
+            ))
+            .unwrap();
+
+        assert!((isect.p.x - 1.0).abs() < EPSILON);
+        assert!((isect.n.x - 1.0).abs() < EPSILON);
+    }
+
+    #[test]
+    fn torus_hole_is_empty() {
+        let torus = shape(Sdf::torus(1.0, 0.25), 1.5);
+
+        assert!(
+            torus
+                .intersect(&ray(Point3f::new(0.0, 0.0, -5.0), Vector3f::new(0.0, 0.0, 1.0)))
+                .is_none()
+        );
+
+        let (t, _) = torus
+            .intersect(&ray(Point3f::new(1.0, 0.0, -5.0), Vector3f::new(0.0, 0.0, 1.0)))
+            .unwrap();
+
+        assert!((t - 4.75).abs() < EPSILON);
+    }
+
+    #[test]
+    fn subtract_carves_hole() {
+        let sdf = Sdf::cuboid(Vector3f::new(1.0, 1.0, 1.0))
+            .subtract(Sdf::capsule(
+                Point3f::new(0.0, 0.0, -2.0),
+                Point3f::new(0.0, 0.0, 2.0),
+                0.5,
+            ));
+        let carved = shape(sdf, 1.5);
+
+        assert!(
+            carved
+                .intersect(&ray(Point3f::new(0.0, 0.0, -5.0), Vector3f::new(0.0, 0.0, 1.0)))
+                .is_none()
+        );
+        assert!(
+            carved
+                .intersect(&ray(Point3f::new(0.75, 0.0, -5.0), Vector3f::new(0.0, 0.0, 1.0)))
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn smooth_union_fills_gap() {
+        let a = Sdf::sphere(0.5).translate(Vector3f::new(-0.6, 0.0, 0.0));
+        let b = Sdf::sphere(0.5).translate(Vector3f::new(0.6, 0.0, 0.0));
+
+        assert!(a.clone().union(b.clone()).distance(Point3f::zero()) > 0.0);
+        assert!(a.smooth_union(b, 0.5).distance(Point3f::zero()) < 0.0);
+    }
+
+    #[test]
+    fn repeat_and_twist_stay_on_surface() {
+        let sdf = Sdf::cuboid(Vector3f::new(0.2, 0.2, 2.0))
+            .twist(1.5)
+            .repeat(Vector3f::new(1.0, 1.0, 0.0));
+        let field = shape(sdf.clone(), 2.0);
+
+        for i in 0..20 {
+            let a = i as f64 * 0.3;
+            let r = ray(
+                Point3f::new(3.0 * a.cos(), 3.0 * a.sin(), 0.1 * i as f64 - 1.0),
+                Vector3f::new(-a.cos(), -a.sin(), 0.05),
+            );
+
+            if let Some((_, isect)) = field.intersect(&r) {
+                assert!(sdf.distance(isect.p).abs() < 0.0001);
+            }
+        }
+    }
+
+    #[test]
+    fn ray_from_inside_finds_exit() {
+        let sphere = shape(Sdf::sphere(1.0), 1.5);
+
+        let (t, isect) = sphere
+            .intersect(&ray(Point3f::zero(), Vector3f::new(0.0, 1.0, 0.0)))
+            .unwrap();
+
+        assert!((t - 1.0).abs() < EPSILON);
+        assert!((isect.n.y - 1.0).abs() < EPSILON);
+    }
+
+    #[test]
+    fn area_estimate_of_sphere() {
+        let sphere = shape(Sdf::sphere(1.0), 1.2);
+
+        assert!((sphere.area() - 4.0 * PI).abs() / (4.0 * PI) < 0.05);
+
+        let (intr, _) = sphere.sample_area(Point2f::new(0.3, 0.8));
+
+        assert!((Vector3f::from(intr.p).length() - 1.0).abs() < EPSILON);
+    }
+
+    #[test]
+    fn sample_through_torus_hole_has_zero_pdf() {
+        let torus = shape(Sdf::torus(1.0, 0.25), 1.5);
+
+        let (intr, pdf) = torus.sample_area(Point2f::new(0.0, 0.0));
+
+        assert_eq!(pdf, 0.0);
+        assert!(!intr.n.x.is_nan() && !intr.n.y.is_nan() && !intr.n.z.is_nan());
+
+        let (intr, pdf) = torus.sample_area(Point2f::new(0.5, 0.5));
+
+        assert!(pdf > 0.0);
+        assert!(torus.sdf.distance(intr.p).abs() < EPSILON);
+    }
+
+    #[test]
+    fn mirrored_sample_normal_matches_intersection() {
+        let sphere = SdfShape::new(
+            Transform::scale(-1.0, 1.0, 1.0),
+            false,
+            Sdf::sphere(1.0),
+            Bounds3f::new(Point3f::new(-1.5, -1.5, -1.5), Point3f::new(1.5, 1.5, 1.5)),
+            0.00001,
+        );
+
+        for i in 0..8 {
+            let u = Point2f::new((i as f64 + 0.5) / 8.0, 0.3);
+            let (intr, pdf) = sphere.sample_area(u);
+
+            assert!(pdf > 0.0);
+
+            let w = Vector3f::from(intr.p);
+            let (_, isect) = sphere.intersect(&ray(intr.p + w * 3.0, -w)).unwrap();
+
+            assert!(Vector3f::from(intr.n).dot(Vector3f::from(isect.n)) > 1.0 - EPSILON);
+        }
+    }
+}
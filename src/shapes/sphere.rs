@@ -0,0 +1,430 @@
+use std::f64::consts::PI;
+
+use core::interaction::{offset_ray_origin, Interaction, SurfaceInteraction};
+use core::ray::Ray;
+use core::sampling::uniform_cone_pdf;
+use core::shape::{pdf_from_ref_by_area, sample_from_ref_by_area, Shape};
+use core::spherical::spherical_direction_in;
+use core::transform::Transform;
+use core::utils::{clamp, gamma, lerp, safe_acos, safe_sqrt, solve_quadratic};
+
+use core::Bounds3f;
+use core::Normal3f;
+use core::Point2f;
+use core::Point3f;
+use core::Vector3f;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Sphere {
+    object_to_world: Transform,
+    world_to_object: Transform,
+    reverse_orientation: bool,
+    transform_swaps_handedness: bool,
+    radius: f64,
+    z_min: f64,
+    z_max: f64,
+    theta_z_min: f64,
+    theta_z_max: f64,
+    phi_max: f64,
+}
+
+impl Sphere {
+    pub fn new(
+        object_to_world: Transform,
+        reverse_orientation: bool,
+        radius: f64,
+        z_min: f64,
+        z_max: f64,
+        phi_max: f64,
+    ) -> Self {
+        Self {
+            object_to_world,
+            world_to_object: object_to_world.inverse(),
+            reverse_orientation,
+            transform_swaps_handedness: object_to_world.swaps_handedness(),
+            radius,
+            z_min: clamp(z_min.min(z_max), -radius, radius),
+            z_max: clamp(z_min.max(z_max), -radius, radius),
+            theta_z_min: clamp(z_min.min(z_max) / radius, -1.0, 1.0).acos(),
+            theta_z_max: clamp(z_min.max(z_max) / radius, -1.0, 1.0).acos(),
+            phi_max: clamp(phi_max, 0.0, 360.0).to_radians(),
+        }
+    }
+
+    pub fn full(object_to_world: Transform, radius: f64) -> Self {
+        Self::new(object_to_world, false, radius, -radius, radius, 360.0)
+    }
+
+    /// Flips a world space normal the same way `intersect` does, so sampled
+    /// points face the same side as hits.
+    fn orient(&self, n: Normal3f) -> Normal3f {
+        if self.reverse_orientation ^ self.transform_swaps_handedness {
+            -n
+        } else {
+            n
+        }
+    }
+
+    /// Projects an object space hit back onto the surface and returns it
+    /// with its phi if it survives the z and phi clipping.
+    fn clip(&self, p: Point3f) -> Option<(Point3f, f64)> {
+        let mut p = p * (self.radius / p.distance(Point3f::zero()));
+
+        if p.x == 0.0 && p.y == 0.0 {
+            p.x = 1e-5 * self.radius;
+        }
+
+        let mut phi = p.y.atan2(p.x);
+
+        if phi < 0.0 {
+            phi += 2.0 * PI;
+        }
+
+        if (self.z_min > -self.radius && p.z < self.z_min)
+            || (self.z_max < self.radius && p.z > self.z_max)
+            || phi > self.phi_max
+        {
+            return None;
+        }
+
+        Some((p, phi))
+    }
+}
+
+impl Shape for Sphere {
+    fn object_bound(&self) -> Bounds3f {
+        Bounds3f::new(
+            Point3f::new(-self.radius, -self.radius, self.z_min),
+            Point3f::new(self.radius, self.radius, self.z_max),
+        )
+    }
+
+    fn world_bound(&self) -> Bounds3f {
+        self.object_to_world.transform(self.object_bound())
+    }
+
+    fn intersect(&self, r: &Ray) -> Option<(f64, SurfaceInteraction)> {
+        let ray = self.world_to_object.transform(*r);
+
+        let o = Vector3f::from(ray.o);
+        let a = ray.d.length_squared();
+        let b = 2.0 * ray.d.dot(o);
+        let c = o.length_squared() - self.radius * self.radius;
+
+        let (t0, t1) = solve_quadratic(a, b, c)?;
+
+        if t0 > ray.t_max || t1 <= 0.0 {
+            return None;
+        }
+
+        let mut t_hit = t0;
+        let mut hit = None;
+
+        if t_hit > 0.0 {
+            hit = self.clip(ray.at(t_hit));
+        }
+
+        if hit.is_none() {
+            if t1 > ray.t_max {
+                return None;
+            }
+
+            t_hit = t1;
+            hit = self.clip(ray.at(t_hit));
+        }
+
+        let (p_hit, phi) = hit?;
+
+        // Parameterize the hit and find the partial derivatives of the
+        // position and, through the Weingarten equations, of the normal.
+        let u = phi / self.phi_max;
+        let cos_theta = p_hit.z / self.radius;
+        let theta = safe_acos(cos_theta);
+        let theta_range = self.theta_z_max - self.theta_z_min;
+        let v = (theta - self.theta_z_min) / theta_range;
+
+        let z_radius = (p_hit.x * p_hit.x + p_hit.y * p_hit.y).sqrt();
+        let cos_phi = p_hit.x / z_radius;
+        let sin_phi = p_hit.y / z_radius;
+        let sin_theta = safe_sqrt(1.0 - cos_theta * cos_theta);
+
+        let dpdu = Vector3f::new(-self.phi_max * p_hit.y, self.phi_max * p_hit.x, 0.0);
+        let dpdv = Vector3f::new(
+            p_hit.z * cos_phi,
+            p_hit.z * sin_phi,
+            -self.radius * sin_theta,
+        ) * theta_range;
+
+        let d2pduu = Vector3f::new(p_hit.x, p_hit.y, 0.0) * (-self.phi_max * self.phi_max);
+        let d2pduv = Vector3f::new(-sin_phi, cos_phi, 0.0) * (theta_range * p_hit.z * self.phi_max);
+        let d2pdvv = Vector3f::from(p_hit) * (-theta_range * theta_range);
+
+        let (dndu, dndv) = weingarten(dpdu, dpdv, d2pduu, d2pduv, d2pdvv);
+
+        let p_error = Vector3f::from(p_hit.abs() * gamma(5));
+        let isect = SurfaceInteraction::new(
+            p_hit,
+            p_error,
+            Point2f::new(u, v),
+            -ray.d,
+            dpdu,
+            dpdv,
+            ray.time,
+        )
+        .with_normal_derivatives(dndu, dndv)
+        .with_flipped_normal(self.reverse_orientation ^ self.transform_swaps_handedness);
+
+        Some((t_hit, self.object_to_world.transform(isect)))
+    }
+
+    fn area(&self) -> f64 {
+        self.phi_max * self.radius * (self.z_max - self.z_min)
+    }
+
+    /// Samples z and phi uniformly within the clipped ranges, which is
+    /// uniform by area since a sphere's zones of equal height have equal
+    /// area.
+    fn sample_area(&self, u: Point2f) -> (Interaction, f64) {
+        let z = lerp(u.x, self.z_min, self.z_max);
+        let r = safe_sqrt(self.radius * self.radius - z * z);
+        let phi = u.y * self.phi_max;
+
+        let p_obj = Point3f::new(r * phi.cos(), r * phi.sin(), z);
+        let n = Normal3f::new(p_obj.x, p_obj.y, p_obj.z);
+        let n = self.orient(self.object_to_world.transform(n).normalize());
+
+        let p_obj = p_obj * (self.radius / p_obj.distance(Point3f::zero()));
+        let p_error = Vector3f::from(p_obj.abs() * gamma(5));
+        let (p, p_error) = self
+            .object_to_world
+            .transform_point_with_error(p_obj, p_error);
+
+        let intr = Interaction::new(p, n, p_error, Vector3f::zero(), 0.0);
+
+        (intr, 1.0 / self.area())
+    }
+
+    /// Samples the cone of directions the sphere subtends from `reference`
+    /// when it lies outside, which is far less noisy than area sampling.
+    fn sample_from_ref(&self, reference: &Interaction, u: Point2f) -> (Interaction, f64) {
+        let p_center = self.object_to_world.transform(Point3f::zero());
+        let p_origin = offset_ray_origin(
+            reference.p,
+            reference.p_error,
+            reference.n,
+            p_center - reference.p,
+        );
+
+        if p_origin.distance_squared(p_center) <= self.radius * self.radius {
+            return sample_from_ref_by_area(self, reference, u);
+        }
+
+        let dc = reference.p.distance(p_center);
+        let inv_dc = 1.0 / dc;
+        let wc = (p_center - reference.p) * inv_dc;
+        let (wc_x, wc_y) = wc.coordinate_system();
+
+        let sin_theta_max = self.radius * inv_dc;
+        let sin_theta_max_2 = sin_theta_max * sin_theta_max;
+        let cos_theta_max = (1.0 - sin_theta_max_2).max(0.0).sqrt();
+
+        let cos_theta = (cos_theta_max - 1.0) * u.x + 1.0;
+        let sin_theta_2 = 1.0 - cos_theta * cos_theta;
+        let phi = u.y * 2.0 * PI;
+
+        // Find the angle alpha from the sphere's center to the sampled point.
+        let cos_alpha = sin_theta_2 / sin_theta_max
+            + cos_theta * (1.0 - sin_theta_2 / sin_theta_max_2).max(0.0).sqrt();
+        let sin_alpha = (1.0 - cos_alpha * cos_alpha).max(0.0).sqrt();
+
+        let n_world = spherical_direction_in(sin_alpha, cos_alpha, phi, -wc_x, -wc_y, -wc);
+        let p_world = p_center + n_world * self.radius;
+
+        let n = self.orient(Normal3f::from(n_world));
+        let p_error = Vector3f::from(p_world.abs() * gamma(5));
+
+        let intr = Interaction::new(p_world, n, p_error, Vector3f::zero(), reference.time);
+
+        (intr, uniform_cone_pdf(cos_theta_max))
+    }
+
+    fn pdf_from_ref(&self, reference: &Interaction, wi: Vector3f) -> f64 {
+        let p_center = self.object_to_world.transform(Point3f::zero());
+        let p_origin = offset_ray_origin(
+            reference.p,
+            reference.p_error,
+            reference.n,
+            p_center - reference.p,
+        );
+
+        if p_origin.distance_squared(p_center) <= self.radius * self.radius {
+            return pdf_from_ref_by_area(self, reference, wi);
+        }
+
+        let sin_theta_max_2 = self.radius * self.radius / reference.p.distance_squared(p_center);
+        let cos_theta_max = (1.0 - sin_theta_max_2).max(0.0).sqrt();
+
+        uniform_cone_pdf(cos_theta_max)
+    }
+}
+
+/// Computes the normal's partial derivatives from the first and second
+/// partial derivatives of the surface position.
+pub fn weingarten(
+    dpdu: Vector3f,
+    dpdv: Vector3f,
+    d2pduu: Vector3f,
+    d2pduv: Vector3f,
+    d2pdvv: Vector3f,
+) -> (Normal3f, Normal3f) {
+    let e1 = dpdu.dot(dpdu);
+    let f1 = dpdu.dot(dpdv);
+    let g1 = dpdv.dot(dpdv);
+
+    let n = dpdu.cross(dpdv).normalize();
+    let e2 = n.dot(d2pduu);
+    let f2 = n.dot(d2pduv);
+    let g2 = n.dot(d2pdvv);
+
+    let inv_egf2 = 1.0 / (e1 * g1 - f1 * f1);
+
+    let dndu = dpdu * ((f2 * f1 - e2 * g1) * inv_egf2) + dpdv * ((e2 * f1 - f2 * e1) * inv_egf2);
+    let dndv = dpdu * ((g2 * f1 - f2 * g1) * inv_egf2) + dpdv * ((f2 * f1 - g2 * e1) * inv_egf2);
+
+    (Normal3f::from(dndu), Normal3f::from(dndv))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64;
+
+    use core::medium::Medium;
+    use core::utils::hash_float;
+
+    const EPSILON: f64 = 0.00001;
+
+    fn unit_sphere_at(z: f64) -> Sphere {
+        Sphere::full(Transform::translate(Vector3f::new(0.0, 0.0, z)), 1.0)
+    }
+
+    #[test]
+    fn intersect_hits_near_side() {
+        let sphere = unit_sphere_at(0.0);
+        let ray = Ray::new(
+            Point3f::new(0.0, 0.0, -5.0),
+            Vector3f::new(0.0, 0.0, 1.0),
+            Medium {},
+            f64::INFINITY,
+            0.0,
+        );
+
+        let (t, isect) = sphere.intersect(&ray).unwrap();
+
+        assert!((t - 4.0).abs() < EPSILON);
+        assert!((isect.p.z + 1.0).abs() < EPSILON);
+        assert!((isect.n.z + 1.0).abs() < EPSILON);
+        assert!((isect.uv.y - 0.0).abs() < EPSILON);
+    }
+
+    #[test]
+    fn intersect_differential_geometry() {
+        let sphere = unit_sphere_at(0.0);
+        let ray = Ray::new(
+            Point3f::new(-5.0, 0.0, 0.0),
+            Vector3f::new(1.0, 0.0, 0.0),
+            Medium {},
+            f64::INFINITY,
+            0.0,
+        );
+
+        let (_, isect) = sphere.intersect(&ray).unwrap();
+
+        // On a unit sphere the normal derivatives equal the position ones.
+        assert!((isect.n.x + 1.0).abs() < EPSILON);
+        assert!((isect.uv.x - 0.5).abs() < EPSILON);
+        assert!((isect.uv.y - 0.5).abs() < EPSILON);
+        assert!((isect.dndu.y - isect.dpdu.y).abs() < EPSILON);
+        assert!((isect.dndv.z - isect.dpdv.z).abs() < EPSILON);
+    }
+
+    #[test]
+    fn intersect_misses() {
+        let sphere = unit_sphere_at(0.0);
+        let ray = Ray::new(
+            Point3f::new(2.0, 0.0, -5.0),
+            Vector3f::new(0.0, 0.0, 1.0),
+            Medium {},
+            f64::INFINITY,
+            0.0,
+        );
+
+        assert!(sphere.intersect(&ray).is_none());
+    }
+
+    #[test]
+    fn sample_area_on_surface() {
+        let sphere = unit_sphere_at(3.0);
+        let (intr, pdf) = sphere.sample_area(Point2f::new(0.25, 0.75));
+
+        assert!((intr.p.distance(Point3f::new(0.0, 0.0, 3.0)) - 1.0).abs() < EPSILON);
+        assert!((pdf - 1.0 / (4.0 * PI)).abs() < EPSILON);
+    }
+
+    #[test]
+    fn sample_area_stays_on_partial_sphere() {
+        let sphere = Sphere::new(Transform::new(), false, 1.0, -0.5, 0.25, 90.0);
+
+        for i in 0..16 {
+            let u = Point2f::new(hash_float(&[i as f64, 0.0]), hash_float(&[i as f64, 1.0]));
+            let (intr, pdf) = sphere.sample_area(u);
+
+            assert!(sphere.clip(intr.p).is_some());
+            assert!((pdf - 1.0 / sphere.area()).abs() < EPSILON);
+        }
+    }
+
+    #[test]
+    fn mirrored_sample_normals_match_intersect() {
+        let sphere = Sphere::full(Transform::scale(-1.0, 1.0, 1.0), 1.0);
+        let reference = Interaction::from_point(Point3f::new(0.0, 0.0, -3.0), 0.0);
+
+        let (area_intr, _) = sphere.sample_area(Point2f::new(0.0, 0.5));
+        let (cone_intr, _) = sphere.sample_from_ref(&reference, Point2f::new(0.0, 0.0));
+
+        for intr in &[area_intr, cone_intr] {
+            let w = Vector3f::from(intr.p);
+            let ray = Ray::new(intr.p + w * 3.0, -w, Medium {}, f64::INFINITY, 0.0);
+            let (_, isect) = sphere.intersect(&ray).unwrap();
+
+            assert!(Vector3f::from(intr.n).dot(Vector3f::from(isect.n)) > 1.0 - EPSILON);
+        }
+    }
+
+    #[test]
+    fn sample_from_ref_uses_cone() {
+        let sphere = unit_sphere_at(2.0);
+        let reference = Interaction::from_point(Point3f::zero(), 0.0);
+
+        let (intr, pdf) = sphere.sample_from_ref(&reference, Point2f::new(0.5, 0.5));
+
+        let cos_theta_max = (1.0 - 0.25f64).sqrt();
+
+        assert!((intr.p.distance(Point3f::new(0.0, 0.0, 2.0)) - 1.0).abs() < EPSILON);
+        assert!((pdf - uniform_cone_pdf(cos_theta_max)).abs() < EPSILON);
+        assert!(
+            (sphere.pdf_from_ref(&reference, Vector3f::new(0.0, 0.0, 1.0)) - pdf).abs() < EPSILON
+        );
+    }
+
+    #[test]
+    fn sample_from_ref_inside_uses_area() {
+        let sphere = unit_sphere_at(0.0);
+        let reference = Interaction::from_point(Point3f::zero(), 0.0);
+
+        let (intr, pdf) = sphere.sample_from_ref(&reference, Point2f::new(0.5, 0.5));
+
+        assert!((intr.p.distance(Point3f::zero()) - 1.0).abs() < EPSILON);
+        assert!((pdf - 1.0 / (4.0 * PI)).abs() < EPSILON);
+    }
+}
@@ -0,0 +1,573 @@
+use std::sync::Arc;
+
+use core::interaction::{Interaction, SurfaceInteraction};
+use core::ray::Ray;
+use core::sampling::{sample_spherical_triangle, spherical_triangle_area, uniform_sample_triangle};
+use core::shape::{pdf_from_ref_by_area, sample_from_ref_by_area, Shape};
+use core::transform::Transform;
+use core::utils::gamma;
+
+use core::Bounds3f;
+use core::Normal3f;
+use core::Point2f;
+use core::Point3f;
+use core::Vector3f;
+
+// Spherical triangle sampling is numerically unreliable for triangles that
+// subtend very small or very large solid angles, so those fall back to area
+// sampling.
+const MIN_SPHERICAL_SAMPLE_AREA: f64 = 3e-4;
+const MAX_SPHERICAL_SAMPLE_AREA: f64 = 6.22;
+
+/// Vertex data shared by all the triangles of a mesh. Positions, normals
+/// and tangents are stored in world space.
+#[derive(Clone, Debug)]
+pub struct TriangleMesh {
+    pub n_triangles: usize,
+    pub vertex_indices: Vec<usize>,
+    pub p: Vec<Point3f>,
+    pub n: Option<Vec<Normal3f>>,
+    pub s: Option<Vec<Vector3f>>,
+    pub uv: Option<Vec<Point2f>>,
+    /// Per-vertex signs giving the bitangent as `sign * n.cross(s)`, as
+    /// used by normal maps baked in MikkTSpace.
+    pub bitangent_signs: Option<Vec<f64>>,
+    pub reverse_orientation: bool,
+    pub transform_swaps_handedness: bool,
+}
+
+impl TriangleMesh {
+    pub fn new(
+        object_to_world: Transform,
+        reverse_orientation: bool,
+        vertex_indices: Vec<usize>,
+        p: Vec<Point3f>,
+        n: Option<Vec<Normal3f>>,
+        s: Option<Vec<Vector3f>>,
+        uv: Option<Vec<Point2f>>,
+    ) -> Self {
+        assert!(vertex_indices.len().is_multiple_of(3));
+
+        Self {
+            n_triangles: vertex_indices.len() / 3,
+            vertex_indices,
+            p: p.into_iter()
+                .map(|p| object_to_world.transform(p))
+                .collect(),
+            n: n.map(|n| {
+                n.into_iter()
+                    .map(|n| object_to_world.transform(n))
+                    .collect()
+            }),
+            s: s.map(|s| {
+                s.into_iter()
+                    .map(|s| object_to_world.transform(s))
+                    .collect()
+            }),
+            uv,
+            bitangent_signs: None,
+            reverse_orientation,
+            transform_swaps_handedness: object_to_world.swaps_handedness(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Triangle {
+    mesh: Arc<TriangleMesh>,
+    v: usize,
+}
+
+impl Triangle {
+    pub fn new(mesh: Arc<TriangleMesh>, triangle_index: usize) -> Self {
+        assert!(triangle_index < mesh.n_triangles);
+
+        Self {
+            mesh,
+            v: 3 * triangle_index,
+        }
+    }
+
+    pub fn from_mesh(mesh: Arc<TriangleMesh>) -> Vec<Self> {
+        (0..mesh.n_triangles)
+            .map(|i| Self::new(mesh.clone(), i))
+            .collect()
+    }
+
+    fn vertices(&self) -> (Point3f, Point3f, Point3f) {
+        let indices = &self.mesh.vertex_indices[self.v..self.v + 3];
+
+        (
+            self.mesh.p[indices[0]],
+            self.mesh.p[indices[1]],
+            self.mesh.p[indices[2]],
+        )
+    }
+
+    /// Geometric normal oriented to agree with the shading normals when the
+    /// mesh has them, or with the mesh's handedness otherwise.
+    fn geometric_normal(&self, b: [f64; 3]) -> Normal3f {
+        let (p0, p1, p2) = self.vertices();
+        let n = Normal3f::from((p0 - p2).cross(p1 - p2).normalize());
+
+        if let Some(ref normals) = self.mesh.n {
+            let indices = &self.mesh.vertex_indices[self.v..self.v + 3];
+            let ns = normals[indices[0]] * b[0]
+                + normals[indices[1]] * b[1]
+                + normals[indices[2]] * b[2];
+
+            n.face_forward(Vector3f::from(ns))
+        } else if self.mesh.reverse_orientation ^ self.mesh.transform_swaps_handedness {
+            -n
+        } else {
+            n
+        }
+    }
+
+    fn uvs(&self) -> [Point2f; 3] {
+        match self.mesh.uv {
+            Some(ref uv) => {
+                let indices = &self.mesh.vertex_indices[self.v..self.v + 3];
+
+                [uv[indices[0]], uv[indices[1]], uv[indices[2]]]
+            }
+            None => [
+                Point2f::new(0.0, 0.0),
+                Point2f::new(1.0, 0.0),
+                Point2f::new(1.0, 1.0),
+            ],
+        }
+    }
+
+    /// Builds the hit record for barycentrics `b`, including the shading
+    /// frame from the mesh's per-vertex normals and tangents.
+    fn surface_interaction(&self, b: [f64; 3], ray: &Ray) -> Option<SurfaceInteraction> {
+        let (p0, p1, p2) = self.vertices();
+        let uv = self.uvs();
+        let indices = &self.mesh.vertex_indices[self.v..self.v + 3];
+
+        let duv02 = uv[0] - uv[2];
+        let duv12 = uv[1] - uv[2];
+        let dp02 = p0 - p2;
+        let dp12 = p1 - p2;
+
+        let determinant = duv02.x * duv12.y - duv02.y * duv12.x;
+        let degenerate_uv = determinant.abs() < 1e-8;
+
+        let mut dpdu = Vector3f::zero();
+        let mut dpdv = Vector3f::zero();
+
+        if !degenerate_uv {
+            let inv_det = 1.0 / determinant;
+
+            dpdu = (dp02 * duv12.y - dp12 * duv02.y) * inv_det;
+            dpdv = (dp12 * duv02.x - dp02 * duv12.x) * inv_det;
+        }
+
+        if degenerate_uv || dpdu.cross(dpdv).length_squared() == 0.0 {
+            let ng = (p2 - p0).cross(p1 - p0);
+
+            if ng.length_squared() == 0.0 {
+                return None;
+            }
+
+            let (u, v) = ng.normalize().coordinate_system();
+            dpdu = u;
+            dpdv = v;
+        }
+
+        let p_hit = p0 * b[0] + p1 * b[1] + p2 * b[2];
+        let uv_hit = uv[0] * b[0] + uv[1] * b[1] + uv[2] * b[2];
+
+        let p_abs_sum = (p0 * b[0]).abs() + (p1 * b[1]).abs() + (p2 * b[2]).abs();
+        let p_error = Vector3f::from(p_abs_sum) * gamma(7);
+
+        let mut isect =
+            SurfaceInteraction::new(p_hit, p_error, uv_hit, -ray.d, dpdu, dpdv, ray.time);
+
+        // The geometric normal follows the winding order rather than the uv
+        // parameterization.
+        let mut n = Normal3f::from(dp02.cross(dp12).normalize());
+
+        if self.mesh.reverse_orientation ^ self.mesh.transform_swaps_handedness {
+            n = -n;
+        }
+
+        isect.n = n;
+        isect.shading.n = n;
+
+        if self.mesh.n.is_none() && self.mesh.s.is_none() {
+            return Some(isect);
+        }
+
+        let ns = match self.mesh.n {
+            Some(ref normals) => {
+                let ns = normals[indices[0]] * b[0]
+                    + normals[indices[1]] * b[1]
+                    + normals[indices[2]] * b[2];
+
+                if ns.length_squared() > 0.0 {
+                    ns.normalize()
+                } else {
+                    isect.n
+                }
+            }
+            None => isect.n,
+        };
+
+        let mut ss = match self.mesh.s {
+            Some(ref s) => {
+                let ss = s[indices[0]] * b[0] + s[indices[1]] * b[1] + s[indices[2]] * b[2];
+
+                if ss.length_squared() > 0.0 {
+                    ss
+                } else {
+                    isect.dpdu
+                }
+            }
+            None => isect.dpdu,
+        }
+        .normalize();
+
+        let mut ts = Vector3f::from(ns).cross(ss);
+
+        if ts.length_squared() > 0.0 {
+            ts = ts.normalize();
+            ss = ts.cross(Vector3f::from(ns));
+        } else {
+            let (s, t) = Vector3f::from(ns).coordinate_system();
+            ss = s;
+            ts = t;
+        }
+
+        let (dndu, dndv) = match self.mesh.n {
+            Some(ref normals) => {
+                let n0 = normals[indices[0]];
+                let n1 = normals[indices[1]];
+                let n2 = normals[indices[2]];
+
+                let dn1 = Vector3f::from(n0 - n2);
+                let dn2 = Vector3f::from(n1 - n2);
+
+                if degenerate_uv {
+                    let dn = Vector3f::from(n2 - n0).cross(Vector3f::from(n1 - n0));
+
+                    if dn.length_squared() == 0.0 {
+                        (Normal3f::zero(), Normal3f::zero())
+                    } else {
+                        let (dndu, dndv) = dn.coordinate_system();
+
+                        (Normal3f::from(dndu), Normal3f::from(dndv))
+                    }
+                } else {
+                    let inv_det = 1.0 / determinant;
+
+                    (
+                        Normal3f::from((dn1 * duv12.y - dn2 * duv02.y) * inv_det),
+                        Normal3f::from((dn2 * duv02.x - dn1 * duv12.x) * inv_det),
+                    )
+                }
+            }
+            None => (Normal3f::zero(), Normal3f::zero()),
+        };
+
+        isect.set_shading_geometry(ss, ts, dndu, dndv, true);
+
+        Some(isect)
+    }
+
+    fn interaction_at(&self, b: [f64; 3], time: f64) -> Interaction {
+        let (p0, p1, p2) = self.vertices();
+
+        let p = p0 * b[0] + p1 * b[1] + p2 * b[2];
+        let p_abs_sum = (p0 * b[0]).abs() + (p1 * b[1]).abs() + (p2 * b[2]).abs();
+        let p_error = Vector3f::from(p_abs_sum) * gamma(6);
+
+        Interaction::new(p, self.geometric_normal(b), p_error, Vector3f::zero(), time)
+    }
+}
+
+/// Keeps the part of a convex polygon where `distance` is not negative.
+fn clip_polygon<F: Fn(Point3f) -> f64>(polygon: &[Point3f], distance: F) -> Vec<Point3f> {
+    let mut clipped = Vec::with_capacity(polygon.len() + 1);
+
+    for (i, &p) in polygon.iter().enumerate() {
+        let q = polygon[(i + 1) % polygon.len()];
+        let (dp, dq) = (distance(p), distance(q));
+
+        if dp >= 0.0 {
+            clipped.push(p);
+        }
+
+        if (dp >= 0.0) != (dq >= 0.0) {
+            let t = dp / (dp - dq);
+            clipped.push(p + (q - p) * t);
+        }
+    }
+
+    clipped
+}
+
+impl Shape for Triangle {
+    fn object_bound(&self) -> Bounds3f {
+        // Triangles are stored in world space, so there is no separate
+        // object space to bound.
+        self.world_bound()
+    }
+
+    fn world_bound(&self) -> Bounds3f {
+        let (p0, p1, p2) = self.vertices();
+
+        Bounds3f::new(p0, p1).union(p2)
+    }
+
+    fn clipped_world_bound(&self, clip: Bounds3f) -> Bounds3f {
+        let (p0, p1, p2) = self.vertices();
+        let mut polygon = vec![p0, p1, p2];
+
+        // Sutherland-Hodgman against each of the six slab planes.
+        for axis in 0..3 {
+            polygon = clip_polygon(&polygon, |p| p[axis] - clip.p_min[axis]);
+            polygon = clip_polygon(&polygon, |p| clip.p_max[axis] - p[axis]);
+        }
+
+        polygon
+            .iter()
+            .fold(Bounds3f::zero(), |b, &p| b.union(p))
+            .intersection(clip)
+    }
+
+    fn intersect(&self, ray: &Ray) -> Option<(f64, SurfaceInteraction)> {
+        let (p0, p1, p2) = self.vertices();
+
+        // Translate the vertices so the ray origin is at zero, permute the
+        // axes so the ray's largest direction component is z, then shear so
+        // the ray points down +z.
+        let mut p0t = p0 - ray.o;
+        let mut p1t = p1 - ray.o;
+        let mut p2t = p2 - ray.o;
+
+        let kz = ray.d.abs().max_dimension();
+        let kx = if kz + 1 == 3 { 0 } else { kz + 1 };
+        let ky = if kx + 1 == 3 { 0 } else { kx + 1 };
+
+        let d = ray.d.permute(kx, ky, kz);
+        p0t = p0t.permute(kx, ky, kz);
+        p1t = p1t.permute(kx, ky, kz);
+        p2t = p2t.permute(kx, ky, kz);
+
+        let sx = -d.x / d.z;
+        let sy = -d.y / d.z;
+        let sz = 1.0 / d.z;
+
+        p0t.x += sx * p0t.z;
+        p0t.y += sy * p0t.z;
+        p1t.x += sx * p1t.z;
+        p1t.y += sy * p1t.z;
+        p2t.x += sx * p2t.z;
+        p2t.y += sy * p2t.z;
+
+        let e0 = p1t.x * p2t.y - p1t.y * p2t.x;
+        let e1 = p2t.x * p0t.y - p2t.y * p0t.x;
+        let e2 = p0t.x * p1t.y - p0t.y * p1t.x;
+
+        if (e0 < 0.0 || e1 < 0.0 || e2 < 0.0) && (e0 > 0.0 || e1 > 0.0 || e2 > 0.0) {
+            return None;
+        }
+
+        let det = e0 + e1 + e2;
+
+        if det == 0.0 {
+            return None;
+        }
+
+        p0t.z *= sz;
+        p1t.z *= sz;
+        p2t.z *= sz;
+
+        let t_scaled = e0 * p0t.z + e1 * p1t.z + e2 * p2t.z;
+
+        if det < 0.0 && (t_scaled >= 0.0 || t_scaled < ray.t_max * det) {
+            return None;
+        }
+
+        if det > 0.0 && (t_scaled <= 0.0 || t_scaled > ray.t_max * det) {
+            return None;
+        }
+
+        let inv_det = 1.0 / det;
+        let b = [e0 * inv_det, e1 * inv_det, e2 * inv_det];
+        let t = t_scaled * inv_det;
+
+        // Make sure t is conservatively greater than zero given the error in
+        // the transformed vertices.
+        let max_zt = Vector3f::new(p0t.z, p1t.z, p2t.z).abs().max_component();
+        let delta_z = gamma(3) * max_zt;
+
+        let max_xt = Vector3f::new(p0t.x, p1t.x, p2t.x).abs().max_component();
+        let max_yt = Vector3f::new(p0t.y, p1t.y, p2t.y).abs().max_component();
+        let delta_x = gamma(5) * (max_xt + max_zt);
+        let delta_y = gamma(5) * (max_yt + max_zt);
+
+        let delta_e = 2.0 * (gamma(2) * max_xt * max_yt + delta_y * max_xt + delta_x * max_yt);
+        let max_e = Vector3f::new(e0, e1, e2).abs().max_component();
+        let delta_t =
+            3.0 * (gamma(3) * max_e * max_zt + delta_e * max_zt + delta_z * max_e) * inv_det.abs();
+
+        if t <= delta_t {
+            return None;
+        }
+
+        let isect = self.surface_interaction(b, ray)?;
+
+        Some((t, isect))
+    }
+
+    fn area(&self) -> f64 {
+        let (p0, p1, p2) = self.vertices();
+
+        0.5 * (p1 - p0).cross(p2 - p0).length()
+    }
+
+    fn sample_area(&self, u: Point2f) -> (Interaction, f64) {
+        let b = uniform_sample_triangle(u);
+
+        (
+            self.interaction_at([b.x, b.y, 1.0 - b.x - b.y], 0.0),
+            1.0 / self.area(),
+        )
+    }
+
+    /// Samples the spherical triangle the triangle projects to around
+    /// `reference` so that every sample carries the same solid angle pdf.
+    fn sample_from_ref(&self, reference: &Interaction, u: Point2f) -> (Interaction, f64) {
+        let (p0, p1, p2) = self.vertices();
+        let solid_angle = spherical_triangle_area(p0, p1, p2, reference.p);
+
+        if !(MIN_SPHERICAL_SAMPLE_AREA..=MAX_SPHERICAL_SAMPLE_AREA).contains(&solid_angle) {
+            return sample_from_ref_by_area(self, reference, u);
+        }
+
+        match sample_spherical_triangle(p0, p1, p2, reference.p, u) {
+            Some((b, pdf)) => (self.interaction_at(b, reference.time), pdf),
+            None => (self.interaction_at([1.0 / 3.0; 3], reference.time), 0.0),
+        }
+    }
+
+    fn pdf_from_ref(&self, reference: &Interaction, wi: Vector3f) -> f64 {
+        let (p0, p1, p2) = self.vertices();
+        let solid_angle = spherical_triangle_area(p0, p1, p2, reference.p);
+
+        if !(MIN_SPHERICAL_SAMPLE_AREA..=MAX_SPHERICAL_SAMPLE_AREA).contains(&solid_angle) {
+            return pdf_from_ref_by_area(self, reference, wi);
+        }
+
+        if !self.intersect_p(&reference.spawn_ray(wi)) {
+            return 0.0;
+        }
+
+        1.0 / solid_angle
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64;
+
+    use core::medium::Medium;
+
+    const EPSILON: f64 = 0.00001;
+
+    fn unit_triangle(z: f64) -> Triangle {
+        let mesh = TriangleMesh::new(
+            Transform::translate(Vector3f::new(0.0, 0.0, z)),
+            false,
+            vec![0, 1, 2],
+            vec![
+                Point3f::new(0.0, 0.0, 0.0),
+                Point3f::new(1.0, 0.0, 0.0),
+                Point3f::new(0.0, 1.0, 0.0),
+            ],
+            None,
+            None,
+            None,
+        );
+
+        Triangle::from_mesh(Arc::new(mesh)).remove(0)
+    }
+
+    #[test]
+    fn clipped_bound_follows_the_triangle() {
+        let triangle = unit_triangle(0.0);
+
+        // Only the triangle's thin tip lies above y = 0.9.
+        let clip = Bounds3f::new(Point3f::new(-1.0, 0.9, -1.0), Point3f::new(2.0, 2.0, 1.0));
+        let clipped = triangle.clipped_world_bound(clip);
+
+        assert!((clipped.p_min.y - 0.9).abs() < EPSILON);
+        assert!((clipped.p_max.y - 1.0).abs() < EPSILON);
+        assert!((clipped.p_max.x - 0.1).abs() < EPSILON);
+
+        let missed = Bounds3f::new(Point3f::new(0.8, 0.8, -1.0), Point3f::new(2.0, 2.0, 1.0));
+        assert!(triangle.clipped_world_bound(missed).is_empty());
+    }
+
+    #[test]
+    fn intersect_hits() {
+        let triangle = unit_triangle(2.0);
+        let ray = Ray::new(
+            Point3f::new(0.25, 0.25, 0.0),
+            Vector3f::new(0.0, 0.0, 1.0),
+            Medium {},
+            f64::INFINITY,
+            0.0,
+        );
+
+        let (t, isect) = triangle.intersect(&ray).unwrap();
+
+        assert!((t - 2.0).abs() < EPSILON);
+        assert!((isect.p.x - 0.25).abs() < EPSILON);
+        assert!((isect.p.z - 2.0).abs() < EPSILON);
+        assert!((isect.n.z.abs() - 1.0).abs() < EPSILON);
+        assert!((isect.dpdu.x - 1.0).abs() < EPSILON);
+        assert!((isect.dpdv.y - 1.0).abs() < EPSILON);
+    }
+
+    #[test]
+    fn intersect_misses() {
+        let triangle = unit_triangle(2.0);
+        let ray = Ray::new(
+            Point3f::new(0.75, 0.75, 0.0),
+            Vector3f::new(0.0, 0.0, 1.0),
+            Medium {},
+            f64::INFINITY,
+            0.0,
+        );
+
+        assert!(triangle.intersect(&ray).is_none());
+    }
+
+    #[test]
+    fn sample_area_inside_triangle() {
+        let triangle = unit_triangle(2.0);
+        let (intr, pdf) = triangle.sample_area(Point2f::new(0.3, 0.9));
+
+        assert!(intr.p.x >= 0.0 && intr.p.y >= 0.0 && intr.p.x + intr.p.y <= 1.0 + EPSILON);
+        assert!((intr.p.z - 2.0).abs() < EPSILON);
+        assert!((pdf - 2.0).abs() < EPSILON);
+    }
+
+    #[test]
+    fn sample_from_ref_matches_pdf() {
+        let triangle = unit_triangle(1.0);
+        let reference = Interaction::from_point(Point3f::new(0.2, 0.2, 0.0), 0.0);
+
+        let (intr, pdf) = triangle.sample_from_ref(&reference, Point2f::new(0.6, 0.3));
+        let wi = (intr.p - reference.p).normalize();
+
+        assert!((intr.p.z - 1.0).abs() < EPSILON);
+        assert!(pdf > 0.0);
+        assert!((triangle.pdf_from_ref(&reference, wi) - pdf).abs() < EPSILON);
+    }
+}
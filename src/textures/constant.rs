@@ -0,0 +1,19 @@
+use core::interaction::SurfaceInteraction;
+use core::texture::Texture;
+
+#[derive(Clone, Copy, Debug)]
+pub struct ConstantTexture<T> {
+    value: T,
+}
+
+impl<T: Copy> ConstantTexture<T> {
+    pub fn new(value: T) -> Self {
+        Self { value }
+    }
+}
+
+impl<T: Copy + Send + Sync> Texture<T> for ConstantTexture<T> {
+    fn evaluate(&self, _si: &SurfaceInteraction) -> T {
+        self.value
+    }
+}